@@ -0,0 +1,109 @@
+//! Derive macros for the Twinleaf I/O protocol
+//!
+//! `#[derive(TioRpc)]` implements `TioRpcRequestable`, `TioRpcReplyable` and
+//! `TioRpcReplyableFixedSize` for a struct whose fields are all fixed size,
+//! such as numbers, tuples of numbers or other such structs. Fields are
+//! packed in declaration order, without padding, as devices expect:
+//! ```ignore
+//! use twinleaf::tio::util::TioRpc;
+//!
+//! #[derive(TioRpc)]
+//! struct Gain {
+//!     channel: u8,
+//!     gain: f32,
+//! }
+//!
+//! let gain: Gain = port.rpc("adc.gain", Gain { channel: 1, gain: 2.0 })?;
+//! ```
+//! It is re-exported by `twinleaf` with the `derive` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index};
+
+#[proc_macro_derive(TioRpc)]
+pub fn derive_tio_rpc(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return syn::Error::new_spanned(&input.ident, "TioRpc can only be derived for structs")
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let members: Vec<_> = match fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| {
+                let ident = &field.ident;
+                quote!(#ident)
+            })
+            .collect(),
+        Fields::Unnamed(fields) => (0..fields.unnamed.len())
+            .map(|i| {
+                let index = Index::from(i);
+                quote!(#index)
+            })
+            .collect(),
+        Fields::Unit => vec![],
+    };
+    let vars: Vec<_> = (0..members.len())
+        .map(|i| quote::format_ident!("field{}", i))
+        .collect();
+    let construct = match fields {
+        Fields::Named(_) => quote!(#name { #(#members: #vars),* }),
+        Fields::Unnamed(_) => quote!(#name ( #(#vars),* )),
+        Fields::Unit => quote!(#name),
+    };
+
+    let util = quote!(::twinleaf::tio::util);
+    let mut generics = input.generics.clone();
+    {
+        let where_clause = generics.make_where_clause();
+        for ty in &types {
+            where_clause.predicates.push(syn::parse_quote!(
+                #ty: #util::TioRpcRequestable<#ty>
+                    + #util::TioRpcReplyable<#ty>
+                    + #util::TioRpcReplyableFixedSize
+            ));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    quote! {
+        impl #impl_generics #util::TioRpcRequestable<#name #ty_generics>
+            for #name #ty_generics #where_clause
+        {
+            fn to_request(&self) -> #util::__private::Vec<u8> {
+                let mut ret = #util::__private::Vec::new();
+                #(ret.extend(#util::TioRpcRequestable::<#types>::to_request(&self.#members));)*
+                ret
+            }
+        }
+
+        impl #impl_generics #util::TioRpcReplyable<#name #ty_generics>
+            for #name #ty_generics #where_clause
+        {
+            fn from_reply_prefix(
+                reply: &[u8],
+            ) -> ::core::result::Result<(#name #ty_generics, &[u8]), ()> {
+                let rest = reply;
+                #(let (#vars, rest) =
+                    <#types as #util::TioRpcReplyable<#types>>::from_reply_prefix(rest)?;)*
+                ::core::result::Result::Ok((#construct, rest))
+            }
+        }
+
+        impl #impl_generics #util::TioRpcReplyableFixedSize
+            for #name #ty_generics #where_clause
+        {
+        }
+    }
+    .into()
+}
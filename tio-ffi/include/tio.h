@@ -0,0 +1,55 @@
+/* C interface to the Twinleaf I/O library, see tio-ffi/src/lib.rs. */
+
+#ifndef TIO_H
+#define TIO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TIO_OK 0
+#define TIO_ERR_INVALID (-1)
+#define TIO_ERR_PORT (-2)
+#define TIO_ERR_IO (-3)
+#define TIO_ERR_TOO_BIG (-4)
+
+typedef struct TioProxy tio_proxy;
+typedef struct TioSubscription tio_subscription;
+
+/* Pointers are valid only for the duration of the callback. */
+typedef struct {
+    uint8_t stream_id;
+    uint8_t segment_id;
+    uint32_t n;
+    double time;
+    double unix_time; /* NaN if the device time is not Unix time */
+    uint8_t placeholder;
+    size_t n_columns;
+    const char *const *names;
+    const double *values; /* NaN if unknown */
+} tio_sample;
+
+typedef void (*tio_sample_callback)(void *user, const tio_sample *sample);
+
+tio_proxy *tio_proxy_new(const char *url);
+void tio_proxy_free(tio_proxy *proxy);
+
+/* Returns TIO_OK, a negative TIO_ERR_ code, or the positive RPC error code
+ * returned by the device. */
+int32_t tio_device_rpc(const tio_proxy *proxy, const char *route, const char *method,
+                       const uint8_t *arg, size_t arg_len,
+                       uint8_t *reply, size_t reply_cap, size_t *reply_len);
+
+/* The callback is called from a library thread until tio_unsubscribe returns. */
+tio_subscription *tio_subscribe(const tio_proxy *proxy, const char *route,
+                                tio_sample_callback callback, void *user);
+void tio_unsubscribe(tio_subscription *sub);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
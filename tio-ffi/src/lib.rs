@@ -0,0 +1,265 @@
+//! C interface to the Twinleaf I/O library
+//!
+//! Exposes the proxy, RPCs and decoded sample data to C and C++ acquisition
+//! software, as declared in `include/tio.h`:
+//! ```c
+//! tio_proxy *proxy = tio_proxy_new("/dev/ttyACM0");
+//! char name[64];
+//! size_t len;
+//! if (tio_device_rpc(proxy, "/", "dev.name", NULL, 0, name, sizeof(name), &len) == TIO_OK)
+//!     printf("%.*s\n", (int)len, name);
+//! tio_subscription *sub = tio_subscribe(proxy, "/", on_sample, NULL);
+//! ...
+//! tio_unsubscribe(sub);
+//! tio_proxy_free(proxy);
+//! ```
+//! Strings are NUL terminated UTF-8. Objects returned by the library must
+//! be released with the matching `_free` or `tio_unsubscribe` call.
+
+use twinleaf::data::Device;
+use twinleaf::tio::proto::DeviceRoute;
+use twinleaf::tio::proxy;
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The call succeeded.
+pub const TIO_OK: i32 = 0;
+/// An argument is null or invalid, such as a malformed route.
+pub const TIO_ERR_INVALID: i32 = -1;
+/// The proxy could not open a port to the device.
+pub const TIO_ERR_PORT: i32 = -2;
+/// The RPC could not be sent, or no reply was received.
+pub const TIO_ERR_IO: i32 = -3;
+/// The reply does not fit in the buffer given.
+pub const TIO_ERR_TOO_BIG: i32 = -4;
+
+/// How long subscriptions wait for data before checking if they are
+/// cancelled.
+static SUBSCRIPTION_POLL: Duration = Duration::from_millis(10);
+
+/// Proxy to a sensor, see `proxy::Interface`.
+pub struct TioProxy {
+    interface: proxy::Interface,
+}
+
+/// Sample passed to subscription callbacks. Pointers are valid only for
+/// the duration of the callback.
+#[repr(C)]
+pub struct TioSample {
+    pub stream_id: u8,
+    pub segment_id: u8,
+    /// Sample number in the segment.
+    pub n: u32,
+    /// Time since the device epoch, in seconds.
+    pub time: f64,
+    /// Unix time in seconds, or NaN if the device time is not referenced
+    /// to Unix time.
+    pub unix_time: f64,
+    /// Non-zero for NaN samples filling a gap in the data.
+    pub placeholder: u8,
+    pub n_columns: usize,
+    /// `n_columns` column names.
+    pub names: *const *const c_char,
+    /// `n_columns` values, NaN if unknown.
+    pub values: *const f64,
+}
+
+/// Called with each sample received, and the user pointer given to
+/// `tio_subscribe`.
+pub type TioSampleCallback = extern "C" fn(user: *mut c_void, sample: *const TioSample);
+
+/// Data subscription, delivering samples from its own thread.
+pub struct TioSubscription {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// The user pointer, passed back to the callback from the subscription
+/// thread. It is up to the caller to make that safe.
+struct UserData(*mut c_void);
+unsafe impl Send for UserData {}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+unsafe fn route_arg(route: *const c_char) -> Option<DeviceRoute> {
+    DeviceRoute::from_str(str_arg(route)?).ok()
+}
+
+/// Start a proxy for the sensor at `url`, see `port::Port::new` for the
+/// formats. Returns null if `url` is not valid UTF-8.
+///
+/// # Safety
+/// `url` must be null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tio_proxy_new(url: *const c_char) -> *mut TioProxy {
+    match str_arg(url) {
+        Some(url) => Box::into_raw(Box::new(TioProxy {
+            interface: proxy::Interface::new(url),
+        })),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Stop the proxy. Subscriptions keep their own connection, and must be
+/// cancelled separately.
+///
+/// # Safety
+/// `proxy` must be null or returned by `tio_proxy_new`, and not used again.
+#[no_mangle]
+pub unsafe extern "C" fn tio_proxy_free(proxy: *mut TioProxy) {
+    if !proxy.is_null() {
+        drop(Box::from_raw(proxy));
+    }
+}
+
+/// Call the RPC `method` of the device at `route` with the `arg_len` bytes
+/// of `arg`, and store the reply in `reply`, of `reply_cap` bytes, and its
+/// length in `reply_len`. Returns `TIO_OK`, one of the negative `TIO_ERR_`
+/// codes, or the positive error code returned by the device.
+///
+/// # Safety
+/// `proxy` must come from `tio_proxy_new`, `route` and `method` must be NUL
+/// terminated strings, and `arg` and `reply` must be valid for their
+/// lengths (or null if zero).
+#[no_mangle]
+pub unsafe extern "C" fn tio_device_rpc(
+    proxy: *const TioProxy,
+    route: *const c_char,
+    method: *const c_char,
+    arg: *const u8,
+    arg_len: usize,
+    reply: *mut u8,
+    reply_cap: usize,
+    reply_len: *mut usize,
+) -> i32 {
+    let (Some(proxy), Some(route), Some(method)) =
+        (proxy.as_ref(), route_arg(route), str_arg(method))
+    else {
+        return TIO_ERR_INVALID;
+    };
+    if (arg.is_null() && arg_len > 0) || (reply.is_null() && reply_cap > 0) {
+        return TIO_ERR_INVALID;
+    }
+    let arg = if arg_len > 0 {
+        std::slice::from_raw_parts(arg, arg_len)
+    } else {
+        &[]
+    };
+    let Ok(port) = proxy.interface.device_rpc(route) else {
+        return TIO_ERR_PORT;
+    };
+    let ret = match port.raw_rpc(method, arg) {
+        Ok(ret) => ret,
+        Err(proxy::RpcError::ExecError(err)) => {
+            return i32::from(u16::from(err.error)).max(1);
+        }
+        Err(_) => return TIO_ERR_IO,
+    };
+    if !reply_len.is_null() {
+        *reply_len = ret.len();
+    }
+    if ret.len() > reply_cap {
+        return TIO_ERR_TOO_BIG;
+    }
+    if !ret.is_empty() {
+        std::ptr::copy_nonoverlapping(ret.as_ptr(), reply, ret.len());
+    }
+    TIO_OK
+}
+
+/// Deliver the samples of the device at `route` to `callback`, called from
+/// a thread of the library with `user`. Returns null if the arguments are
+/// invalid or the device cannot be reached.
+///
+/// # Safety
+/// `proxy` must come from `tio_proxy_new` and `route` must be a NUL
+/// terminated string. `callback` must be safe to call from another thread
+/// with `user` until `tio_unsubscribe` returns.
+#[no_mangle]
+pub unsafe extern "C" fn tio_subscribe(
+    proxy: *const TioProxy,
+    route: *const c_char,
+    callback: TioSampleCallback,
+    user: *mut c_void,
+) -> *mut TioSubscription {
+    let (Some(proxy), Some(route)) = (proxy.as_ref(), route_arg(route)) else {
+        return std::ptr::null_mut();
+    };
+    let Ok(port) = proxy.interface.device_full(route) else {
+        return std::ptr::null_mut();
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let user = UserData(user);
+    let thread = thread::spawn(move || {
+        // Move the whole wrapper, rather than capturing its pointer.
+        let user = user;
+        let mut device = Device::new(port);
+        let mut names: Vec<CString> = vec![];
+        while !thread_stop.load(Ordering::Relaxed) {
+            let samples = device.drain();
+            if samples.is_empty() {
+                thread::sleep(SUBSCRIPTION_POLL);
+            }
+            for sample in samples {
+                if sample.meta_changed || names.len() != sample.columns.len() {
+                    names = sample
+                        .columns
+                        .iter()
+                        .map(|col| CString::new(col.desc.name.clone()).unwrap_or_default())
+                        .collect();
+                }
+                let name_ptrs: Vec<*const c_char> = names.iter().map(|n| n.as_ptr()).collect();
+                let values: Vec<f64> = sample
+                    .columns
+                    .iter()
+                    .map(|col| col.value.as_f64().unwrap_or(f64::NAN))
+                    .collect();
+                let ffi_sample = TioSample {
+                    stream_id: sample.stream.stream_id,
+                    segment_id: sample.segment.segment_id,
+                    n: sample.n,
+                    time: sample.timestamp_begin(),
+                    unix_time: sample.unix_time().unwrap_or(f64::NAN),
+                    placeholder: u8::from(sample.placeholder),
+                    n_columns: values.len(),
+                    names: name_ptrs.as_ptr(),
+                    values: values.as_ptr(),
+                };
+                callback(user.0, &ffi_sample);
+            }
+        }
+    });
+    Box::into_raw(Box::new(TioSubscription {
+        stop,
+        thread: Some(thread),
+    }))
+}
+
+/// Stop a subscription. No callbacks are made once this returns.
+///
+/// # Safety
+/// `sub` must be null or returned by `tio_subscribe`, and not used again.
+/// It must not be called from the callback.
+#[no_mangle]
+pub unsafe extern "C" fn tio_unsubscribe(sub: *mut TioSubscription) {
+    if sub.is_null() {
+        return;
+    }
+    let mut sub = Box::from_raw(sub);
+    sub.stop.store(true, Ordering::Relaxed);
+    if let Some(thread) = sub.thread.take() {
+        // The thread ends with an error if the device went away.
+        let _ = thread.join();
+    }
+}
@@ -1,6 +1,10 @@
 use tio::{proto::DeviceRoute, proxy, util};
 use twinleaf::{
-    data::{ColumnData, Device},
+    data::{
+        alert::{AlertEngine, Rule},
+        monitor::Monitor,
+        ColumnData, Device,
+    },
     tio,
 };
 
@@ -224,6 +228,104 @@ async fn run_monitor() {
     }
 }
 
+fn run_dash(args: &[String]) -> std::io::Result<()> {
+    let opts = tio_opts();
+    let (_matches, root, route) = tio_parseopts(opts, args);
+
+    let (status_send, status_recv) = crossbeam::channel::unbounded();
+    let proxy = proxy::Interface::builder()
+        .url(&root)
+        .status(status_send)
+        .spawn();
+    let mut device = Device::new(proxy.device_full(route).unwrap());
+    let mut monitor = Monitor::new();
+    let mut stdout = stdout();
+
+    loop {
+        for sample in device.drain() {
+            monitor.push_sample(&sample);
+        }
+        for event in status_recv.try_iter() {
+            monitor.push_event(&event);
+        }
+        monitor.set_link_status(proxy.link_status());
+
+        stdout.execute(MoveTo(0, 0))?;
+        stdout.execute(Clear(ClearType::All))?;
+        for line in monitor.render() {
+            println!("{}\r", line);
+        }
+
+        if crossterm::event::poll(Duration::from_millis(250))? {
+            if let Event::Key(key_event) = crossterm::event::read()? {
+                if key_event.code == KeyCode::Char('q')
+                    || key_event.code == KeyCode::Esc
+                    || (key_event.code == KeyCode::Char('c')
+                        && key_event.modifiers == KeyModifiers::CONTROL)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn parse_rule(spec: &str) -> Rule {
+    let parts: Vec<&str> = spec.split(':').collect();
+    match parts.as_slice() {
+        ["range", column, min, max] => {
+            Rule::out_of_range(column, min.parse().unwrap(), max.parse().unwrap())
+        }
+        ["nan", column] => Rule::not_a_number(column),
+        ["rate", stream, rate] => {
+            Rule::rate_below(stream, rate.parse().unwrap(), Duration::from_secs(5))
+        }
+        _ => panic!("Invalid rule {}", spec),
+    }
+}
+
+fn run_alerts(args: &[String]) {
+    let mut opts = tio_opts();
+    opts.optopt(
+        "d",
+        "",
+        "seconds a condition must hold before alerting (default 0)",
+        "secs",
+    );
+    opts.optopt("y", "", "hysteresis of the thresholds (default 0)", "value");
+    let (matches, root, route) = tio_parseopts(opts, args);
+    let debounce =
+        Duration::from_secs_f64(matches.opt_str("d").map_or(0.0, |d| d.parse().unwrap()));
+    let hysteresis: f64 = matches.opt_str("y").map_or(0.0, |y| y.parse().unwrap());
+
+    let (status_send, status_recv) = crossbeam::channel::unbounded();
+    let proxy = proxy::Interface::builder()
+        .url(&root)
+        .status(status_send)
+        .spawn();
+    let mut device = Device::new(proxy.device_full(route).unwrap());
+
+    let mut alerts = AlertEngine::new();
+    for spec in &matches.free {
+        alerts.add(parse_rule(spec).debounce(debounce).hysteresis(hysteresis));
+    }
+    alerts.add(Rule::reconnects());
+    alerts.add(Rule::disconnects());
+    alerts.add(Rule::protocol_errors());
+    alerts.on_alert(|alert| println!("{}", alert));
+
+    loop {
+        for sample in device.drain() {
+            alerts.push_sample(&sample);
+        }
+        for event in status_recv.try_iter() {
+            alerts.push_event(&event);
+        }
+        alerts.poll();
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
 fn main() -> std::io::Result<()> {
     let mut args: Vec<String> = std::env::args().collect();
     if args.len() < 2{
@@ -244,10 +346,29 @@ fn main() -> std::io::Result<()> {
             stdout.execute(Show)?;
             disable_raw_mode()?;
         }
+        "dash" => {
+            let mut stdout = stdout();
+
+            enable_raw_mode()?;
+            stdout.execute(EnterAlternateScreen)?;
+            stdout.execute(Hide)?;
+
+            let result = run_dash(&args[2..]);
+            stdout.execute(LeaveAlternateScreen)?;
+            stdout.execute(Show)?;
+            disable_raw_mode()?;
+            result?;
+        }
+        "alerts" => {
+            run_alerts(&args[2..]);
+        }
         _ => {
             println!("Usage:");
             println!(" tio-monitor help");
             println!(" tio-monitor run [yaml_file_path]"); 
+            println!(" tio-monitor dash [-r url] [-s sensor]");
+            println!(" tio-monitor alerts [-r url] [-s sensor] [-d secs] [-y hysteresis] rule...");
+            println!("  rules: range:column:min:max, nan:column, rate:stream:min_rate");
         }
     }
 
@@ -9,7 +9,8 @@ use std::io;
 use std::net::TcpListener;
 use std::process::ExitCode;
 use std::time::Duration;
-use tio::{proto, proxy};
+use tio::{port, proto, proxy};
+use twinleaf::shutdown::Shutdown;
 use twinleaf::tio;
 
 // Unfortunately we cannot access USB details via the serialport module, so
@@ -90,6 +91,49 @@ fn create_listener_thread(
     Ok(())
 }
 
+/// Check in the background that the device speaks a protocol version
+/// this proxy understands, warning otherwise.
+fn check_protocol_version(port: proxy::Port, tf: String) {
+    std::thread::spawn(move || match port.protocol_version() {
+        Ok(version) if version.is_supported() => {}
+        Ok(version) => {
+            log!(
+                tf,
+                "Warning: sensor reports unsupported protocol version {:?}",
+                version
+            );
+        }
+        Err(err) => {
+            log!(tf, "Failed to query sensor protocol version: {:?}", err);
+        }
+    });
+}
+
+/// Broadcast beacons announcing the proxy, with the name and serial number
+/// of the sensor.
+/// Name and serial number of the device served, or empty strings if unknown.
+fn device_identity(proxy: &proxy::Interface, subtree: &proto::DeviceRoute) -> (String, String) {
+    match proxy.device_rpc(subtree.clone()) {
+        Ok(port) => (
+            port.get::<String>("dev.name").unwrap_or_default(),
+            port.get::<String>("dev.serial").unwrap_or_default(),
+        ),
+        Err(_) => (String::new(), String::new()),
+    }
+}
+
+fn announce(
+    proxy: &proxy::Interface,
+    subtree: &proto::DeviceRoute,
+    tcp_port: u16,
+) -> io::Result<tio::discovery::Beacon> {
+    let (name, serial) = device_identity(proxy, subtree);
+    tio::discovery::Beacon::new(
+        tio::discovery::Announcement::new(&name, &serial, tcp_port),
+        Duration::from_secs(2),
+    )
+}
+
 fn main() -> ExitCode {
     let mut opts = Options::new();
     opts.optopt(
@@ -103,6 +147,42 @@ fn main() -> ExitCode {
         "",
         "Kick off slow clients, instead of dropping traffic.",
     );
+    opts.optopt(
+        "",
+        "memory-budget",
+        "Limit on the memory used by packets queued to clients (default: unlimited)",
+        "MiB",
+    );
+    opts.optopt(
+        "",
+        "rpc-rate",
+        "Limit on the RPC requests each client sends to each device, per second, with an optional burst (default: unlimited)",
+        "rate[:burst]",
+    );
+    opts.optmulti(
+        "",
+        "hub-rate",
+        "Also negotiate the rate of the link to the device at this route, and of the hubs on the way (repeatable)",
+        "route:bps",
+    );
+    opts.optmulti(
+        "",
+        "autorate-rpcs",
+        "Negotiate rates with these RPCs, for older firmwares, trying each in turn (repeatable, default dev.port.rate.near,dev.port.rate)",
+        "query,set",
+    );
+    opts.optmulti(
+        "",
+        "attach",
+        "Also serve the device tree of another proxy under this route, e.g. /7:tcp://field-laptop (repeatable)",
+        "route:url",
+    );
+    opts.optopt(
+        "",
+        "ttl",
+        "TTL of packets from this host, limiting how many hops they are forwarded (default: 0, no limit)",
+        "hops",
+    );
     opts.optopt("s", "", "Sensor subtree to look at (default /)", "path");
     opts.optflag("v", "", "Verbose output");
     opts.optflag("d", "", "Debugging output");
@@ -118,8 +198,43 @@ fn main() -> ExitCode {
         "dump",
         "Dump traffic data through the proxy (does not include internal heartbeats)",
     );
+    opts.optflag(
+        "",
+        "strict",
+        "Report any data from the sensor breaking the packet framing, instead of recovering from it",
+    );
+    opts.optopt(
+        "",
+        "event-log",
+        "Record the proxy events to this file, to review with tio-tool events",
+        "file",
+    );
     opts.optflag("", "auto", "Automatically connect to a USB sensor if there is a single device on the system that could be a Twinleaf device");
     opts.optflag("", "enum", "Enumerate all serial devices, then quit");
+    opts.optflag(
+        "",
+        "announce",
+        "Broadcast beacons on the local network, so that the proxy can be found with tio-tool discover",
+    );
+    opts.optflag(
+        "",
+        "mdns",
+        "Advertise the proxy over mDNS as a _tio._tcp service, to connect to it as mdns://<device name>",
+    );
+    #[cfg(feature = "metrics")]
+    opts.optopt(
+        "",
+        "metrics",
+        "Serve Prometheus metrics over HTTP on this address (e.g. 0.0.0.0:9855)",
+        "addr",
+    );
+    #[cfg(feature = "control")]
+    opts.optopt(
+        "",
+        "control",
+        "Serve the HTTP control API on this address (e.g. 127.0.0.1:7856)",
+        "addr",
+    );
 
     let mut args: Vec<String> = env::args().collect();
 
@@ -166,7 +281,13 @@ fn main() -> ExitCode {
                     println!("Possible tio ports:");
                     found_any = true;
                 }
-                println!(" * {}", dev.url);
+                // Also show how to open the device regardless of its port.
+                match tio::port::identify(&dev.url, Duration::from_millis(500)) {
+                    Ok((name, serial)) => {
+                        println!(" * {} ({}, serial-number://{})", dev.url, name, serial)
+                    }
+                    Err(_) => println!(" * {}", dev.url),
+                }
             }
         }
         if !found_any {
@@ -199,11 +320,100 @@ fn main() -> ExitCode {
 
     let disconnect_slow = matches.opt_present("k");
 
+    let budget = if let Some(mib) = matches.opt_str("memory-budget") {
+        let mib = if let Ok(mib) = mib.parse::<usize>() {
+            mib
+        } else {
+            die_usage!("Invalid memory budget '{}'", mib);
+        };
+        let policy = if disconnect_slow {
+            tio::budget::BudgetPolicy::DropClient
+        } else {
+            tio::budget::BudgetPolicy::DropPackets
+        };
+        Some(tio::budget::MemoryBudget::new(mib * 1024 * 1024, policy))
+    } else {
+        None
+    };
+
+    let rpc_rate_limit = if let Some(spec) = matches.opt_str("rpc-rate") {
+        let (rate, burst) = match spec.split_once(':') {
+            Some((rate, burst)) => (rate.parse::<f64>(), burst.parse::<u32>()),
+            None => (spec.parse::<f64>(), Ok(1)),
+        };
+        match (rate, burst) {
+            (Ok(rate), Ok(burst)) if rate > 0.0 => Some(proxy::RpcRateLimit::new(rate, burst)),
+            _ => die_usage!("Invalid RPC rate limit '{}'", spec),
+        }
+    } else {
+        None
+    };
+
+    let mut hub_rates: Vec<(proto::DeviceRoute, u32)> = vec![];
+    for spec in matches.opt_strs("hub-rate") {
+        let parsed = spec.rsplit_once(':').and_then(|(route, bps)| {
+            Some((
+                proto::DeviceRoute::from_str(route).ok()?,
+                bps.parse::<u32>().ok()?,
+            ))
+        });
+        match parsed {
+            Some((route, bps)) if !route.is_empty() && bps > 0 => hub_rates.push((route, bps)),
+            _ => die_usage!("Invalid hub rate '{}'", spec),
+        }
+    }
+
+    let mut autorate_rpcs = vec![];
+    for spec in matches.opt_strs("autorate-rpcs") {
+        match spec.split_once(',') {
+            Some((query, set)) if !query.is_empty() && !set.is_empty() => {
+                autorate_rpcs.push(proxy::AutoRateRpcs::new(query, set))
+            }
+            _ => die_usage!("Invalid autorate RPCs '{}'", spec),
+        }
+    }
+
+    let mut attach: Vec<(proto::DeviceRoute, String)> = vec![];
+    for spec in matches.opt_strs("attach") {
+        let parsed = spec
+            .split_once(':')
+            .and_then(|(route, url)| Some((proto::DeviceRoute::from_str(route).ok()?, url)));
+        match parsed {
+            Some((route, url)) if !route.is_empty() && !url.is_empty() => {
+                attach.push((route, url.to_string()))
+            }
+            _ => die_usage!("Invalid attached proxy '{}'", spec),
+        }
+    }
+
+    let default_ttl = match matches.opt_str("ttl").map(|s| s.parse::<usize>()) {
+        None => 0,
+        Some(Ok(ttl)) if ttl <= proto::TIO_PACKET_MAX_TTL => ttl,
+        Some(_) => die_usage!("Invalid TTL, must be at most {}", proto::TIO_PACKET_MAX_TTL),
+    };
+
     let verbose = matches.opt_present("v");
     let debugging = matches.opt_present("d");
     let dump_traffic = matches.opt_present("dump");
+    let strict = matches.opt_present("strict");
+    let parse_mode = if strict {
+        port::ParseMode::Strict
+    } else {
+        port::ParseMode::Lenient
+    };
     let tf = matches.opt_str("t").unwrap_or("%T%.3f ".to_string());
 
+    #[cfg(feature = "metrics")]
+    let metrics = if let Some(addr) = matches.opt_str("metrics") {
+        let metrics = twinleaf::metrics::Metrics::new();
+        if let Err(e) = metrics.serve(addr.as_str()) {
+            die!("Failed to start metrics server on {}: {:?}", addr, e);
+        }
+        Some(metrics)
+    } else {
+        None
+    };
+
     if (matches.free.len() == 0) && !auto_sensor {
         die_usage!("need sensor url or --auto");
     }
@@ -279,8 +489,37 @@ fn main() -> ExitCode {
     };
 
     let (status_send, port_status) = crossbeam::channel::bounded::<proxy::Event>(100);
-    let proxy =
-        proxy::Interface::new_proxy(&sensor_url, Some(reconnect_timeout), Some(status_send));
+    let mut builder = proxy::Interface::builder()
+        .url(&sensor_url)
+        .reconnect(reconnect_timeout)
+        .status(status_send)
+        .budget(budget)
+        .rpc_rate_limit(rpc_rate_limit)
+        .default_ttl(default_ttl)
+        .parse_mode(parse_mode);
+    for (route, bps) in hub_rates {
+        builder = builder.autorate_route(route, bps);
+    }
+    if !autorate_rpcs.is_empty() {
+        builder = builder.autorate_rpcs(autorate_rpcs);
+    }
+    let proxy = builder.spawn();
+
+    // Proxies of the attached trees, kept running until exiting.
+    let mut downstreams = vec![];
+    for (route, url) in attach {
+        let downstream = proxy::Interface::builder()
+            .url(&url)
+            .reconnect(reconnect_timeout)
+            .spawn();
+        let attached = downstream
+            .tree_full()
+            .and_then(|port| proxy.attach(route.clone(), port));
+        if let Err(err) = attached {
+            die!("Failed to attach {} under {}: {:?}", url, route, err);
+        }
+        downstreams.push(downstream);
+    }
 
     // This is used by the proxy itself to communicate with the device tree.
     // for now only used to receive log messages and dump traffic.
@@ -296,6 +535,61 @@ fn main() -> ExitCode {
         );
     };
 
+    if let Ok(port) = proxy.device_rpc(subtree.clone()) {
+        check_protocol_version(port, tf.clone());
+    }
+
+    let _beacon = if matches.opt_present("announce") {
+        match announce(&proxy, &subtree, tcp_port) {
+            Ok(beacon) => Some(beacon),
+            Err(err) => die!("Failed to start announcing the proxy: {:?}", err),
+        }
+    } else {
+        None
+    };
+
+    let _advertiser = if matches.opt_present("mdns") {
+        let (name, serial) = device_identity(&proxy, &subtree);
+        match tio::mdns::Advertiser::new(&name, &serial, tcp_port) {
+            Ok(advertiser) => Some(advertiser),
+            Err(err) => die!("Failed to advertise the proxy over mDNS: {:?}", err),
+        }
+    } else {
+        None
+    };
+
+    #[cfg(feature = "control")]
+    if let Some(addr) = matches.opt_str("control") {
+        let server = match twinleaf::control::ControlServer::new(&proxy) {
+            Ok(server) => server,
+            Err(err) => die!("Failed to start control server: {:?}", err),
+        };
+        if let Err(err) = server.serve(addr.as_str()) {
+            die!("Failed to start control server on {}: {:?}", addr, err);
+        }
+    }
+
+    let mut event_log = if let Some(path) = matches.opt_str("event-log") {
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+        {
+            Ok(file) => Some(tio::eventlog::EventRecorder::new(file)),
+            Err(err) => die!("Failed to open event log '{}': {:?}", path, err),
+        }
+    } else {
+        None
+    };
+
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        log!(tf, "Failed to install signal handlers: {:?}", e);
+    }
+    let started = std::time::Instant::now();
+    let mut n_clients: u64 = 0;
+    let link_tick = crossbeam::channel::tick(Duration::from_secs(1));
+
     use crossbeam::select;
     loop {
         select! {
@@ -317,44 +611,61 @@ fn main() -> ExitCode {
                     if verbose {
                         log!(tf, "Accepted client from {}", addr);
                     }
-                    let port = proxy.new_port(Some(Duration::from_millis(2000)), subtree.clone(), usize::MAX, true, true).expect("Failed to create new proxy port");
+                    n_clients += 1;
+                    let port = proxy.new_port(Some(Duration::from_millis(2000)), subtree.clone(), usize::MAX, proxy::ForwardingPolicy::all()).expect("Failed to create new proxy port");
                     let tf = tf.clone();
                     std::thread::spawn(move || {
                         let mut is_slow = false;
                         let mut dropped: usize = 0;
+                        // Returns false if the client should be disconnected
+                        let mut forward = |pkt: tio::Packet| -> bool {
+                            match client.try_send(pkt) {
+                                Err(tio::SendError::Full) => {
+                                    if disconnect_slow {
+                                        log!(tf, "Disconnecting client {} due to slowness", addr);
+                                        return false;
+                                    } else if verbose {
+                                        if !is_slow {
+                                            is_slow = true;
+                                            log!(tf, "Client {} is not keeping up and is dropping packets", addr);
+                                        }
+                                        dropped += 1;
+                                    }
+                                }
+                                Ok(()) => {
+                                    if verbose && is_slow {
+                                        log!(tf, "Client {} resuming after having dropped {} packets", addr, dropped);
+                                        is_slow = false;
+                                        dropped = 0;
+                                    }
+                                }
+                                _ => {
+                                    if verbose {
+                                        log!(tf, "Client {} exiting", addr);
+                                    }
+                                    return false;
+                                }
+                            }
+                            true
+                        };
                         loop {
                             select! {
-                                recv(port.receiver()) -> res => {
+                                recv(port.rpc_receiver()) -> res => {
                                     let pkt = if let Ok(pkt) = res { pkt } else {
                                         log!(tf, "Disconnecting client {} due to internal error receiving tio data in thread", addr);
                                             break;
                                     };
-                                    match client.try_send(pkt) {
-                                        Err(tio::SendError::Full) => {
-                                            if disconnect_slow {
-                                                log!(tf, "Disconnecting client {} due to slowness", addr);
-                                                break;
-                                            } else if verbose {
-                                                if !is_slow {
-                                                    is_slow = true;
-                                                    log!(tf, "Client {} is not keeping up and is dropping packets", addr);
-                                                }
-                                                dropped += 1;
-                                            }
-                                        }
-                                        Ok(()) => {
-                                            if verbose && is_slow {
-                                                log!(tf, "Client {} resuming after having dropped {} packets", addr, dropped);
-                                                is_slow = false;
-                                                dropped = 0;
-                                            }
-                                        }
-                                        _ => {
-                                            if verbose {
-                                                log!(tf, "Client {} exiting", addr);
-                                            }
+                                    if !forward(pkt) {
+                                        break;
+                                    }
+                                }
+                                recv(port.receiver()) -> res => {
+                                    let pkt = if let Ok(pkt) = res { pkt } else {
+                                        log!(tf, "Disconnecting client {} due to internal error receiving tio data in thread", addr);
                                             break;
-                                        }
+                                    };
+                                    if !forward(pkt) {
+                                        break;
                                     }
                                 }
                                 recv(client_rx) -> res => {
@@ -383,20 +694,56 @@ fn main() -> ExitCode {
                     die!("Listener thread died unexpectedly");
                 }
             }
+            recv(shutdown.receiver()) -> _ => {
+                log!(tf, "Shutting down after {:.0?}, served {} clients", started.elapsed(), n_clients);
+                break;
+            }
             recv(port_status) -> status => {
                 if let Ok(evt) = status {
+                    if let Some(recorder) = &mut event_log {
+                        if let Err(err) = recorder.record(&evt) {
+                            log!(tf, "Failed to record event, stopping the event log: {:?}", err);
+                            event_log = None;
+                        }
+                    }
+                    #[cfg(feature = "metrics")]
+                    if let Some(metrics) = &metrics {
+                        metrics.record_event(&evt);
+                    }
                     match evt {
                         proxy::Event::SensorDisconnected => {
                             log!(tf, "Sensor disconnected");
                         }
                         proxy::Event::SensorReconnected => {
                             log!(tf, "Sensor reconnected");
+                            if let Ok(port) = proxy.device_rpc(subtree.clone()) {
+                                check_protocol_version(port, tf.clone());
+                            }
+                        }
+                        proxy::Event::UrlChanged(url) => {
+                            log!(tf, "Sensor url changed to {}", url);
+                        }
+                        proxy::Event::RootDeviceSleeping => {
+                            log!(tf, "Sensor entering low power mode");
+                        }
+                        proxy::Event::RootDeviceAwake => {
+                            log!(tf, "Sensor woke up from low power mode");
                         }
-                        proxy::Event::FailedToReconnect => {
-                            log!(tf, "Stopping reconnection attempts due to timeout");
+                        proxy::Event::DownstreamAttached(route) => {
+                            log!(tf, "Attached proxy under {}", route);
                         }
-                        proxy::Event::FailedToConnect => {
-                            log!(tf, "Fatal proxy error: failed to connect to sensor");
+                        proxy::Event::DownstreamDetached(route) => {
+                            log!(tf, "Attached proxy under {} is gone", route);
+                        }
+                        proxy::Event::PortBusy(owner) => match owner {
+                            Some(pid) => log!(tf, "Sensor port is in use by process {}", pid),
+                            None => log!(tf, "Sensor port is in use"),
+                        },
+                        proxy::Event::FailedToReconnect(err) => {
+                            log!(tf, "Stopping reconnection attempts due to timeout: {}", err);
+                        }
+                        proxy::Event::FailedToConnect(err) => {
+                            log!(tf, "Fatal proxy error: failed to connect to sensor: {}", err);
                         }
                         proxy::Event::FatalError(err) => {
                             log!(tf, "Fatal proxy error: {:?}", err);
@@ -408,7 +755,7 @@ fn main() -> ExitCode {
                                     log!(tf, "Text: {}", txt);
                                 }
                                 other => {
-                                    if verbose || debugging {
+                                    if verbose || debugging || strict {
                                         log!(tf, "Protocol error: {:?}", other);
                                     }
                                 }
@@ -426,6 +773,12 @@ fn main() -> ExitCode {
                     break;
                 }
             }
+            recv(link_tick) -> _ => {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &metrics {
+                    metrics.record_link_status(&proxy.link_status());
+                }
+            }
             recv(proxy_port.receiver()) -> pkt_or_err => {
                 if let Ok(pkt) = pkt_or_err {
                     if dump_traffic {
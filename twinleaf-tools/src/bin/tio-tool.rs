@@ -1,67 +1,48 @@
 use tio::proto::DeviceRoute;
 use tio::proxy;
+use tio::rawlog::{self, LogReader, LogSplitter, SplitBy};
 use tio::util;
+use tio::watchdog::{Recovery, Watchdog, WatchdogEvent};
+use twinleaf::data::settings::{RpcInfo, RpcValueType};
 use twinleaf::data::{ColumnData, DeviceDataParser};
+use twinleaf::shutdown::Shutdown;
 use twinleaf::tio;
 
 use std::env;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::path::Path;
 
 use getopts::Options;
 
 struct RpcMeta {
-    arg_type: String,
-    size: usize,
-    read: bool,
-    write: bool,
-    persistent: bool,
+    info: RpcInfo,
     unknown: bool,
 }
 
 impl RpcMeta {
     pub fn parse(meta: u16) -> RpcMeta {
-        let size = ((meta >> 4) & 0xF) as usize;
-        let atype = meta & 0xF;
         RpcMeta {
-            arg_type: match atype {
-                0 => match size {
-                    1 => "u8",
-                    2 => "u16",
-                    4 => "u32",
-                    8 => "u64",
-                    _ => "",
-                },
-                1 => match size {
-                    1 => "i8",
-                    2 => "i16",
-                    4 => "i32",
-                    8 => "i64",
-                    _ => "",
-                },
-                2 => match size {
-                    4 => "f32",
-                    8 => "f64",
-                    _ => "",
-                },
-                3 => "string",
-                _ => "",
-            }
-            .to_string(),
-            size: size,
-            read: (meta & 0x0100) != 0,
-            write: (meta & 0x0200) != 0,
-            persistent: (meta & 0x0400) != 0,
+            info: RpcInfo::parse("", meta),
             unknown: meta == 0,
         }
     }
 
+    pub fn arg_type(&self) -> String {
+        match self.info.value_type {
+            RpcValueType::UInt(size) => format!("u{}", size * 8),
+            RpcValueType::Int(size) => format!("i{}", size * 8),
+            RpcValueType::Float(size) => format!("f{}", size * 8),
+            RpcValueType::String(_) => "string".to_string(),
+            RpcValueType::Unknown => "".to_string(),
+        }
+    }
+
     pub fn type_str(&self) -> String {
-        if (self.arg_type == "string") && (self.size != 0) {
-            format!("string<{}>", self.size)
-        } else {
-            self.arg_type.clone()
+        match self.info.value_type {
+            RpcValueType::String(size) if size != 0 => format!("string<{}>", size),
+            _ => self.arg_type(),
         }
     }
 
@@ -71,9 +52,9 @@ impl RpcMeta {
         } else {
             format!(
                 "{}{}{}",
-                if self.read { "R" } else { "-" },
-                if self.write { "W" } else { "-" },
-                if self.persistent { "P" } else { "-" }
+                if self.info.readable { "R" } else { "-" },
+                if self.info.writable { "W" } else { "-" },
+                if self.info.persistent { "P" } else { "-" }
             )
         }
     }
@@ -146,7 +127,7 @@ fn get_rpctype(
     //RpcMeta::parse(u16::from_le_bytes(
     //    rpc_get_reply(&rx, 2).unwrap()[0..2].try_into().unwrap(),
     //))
-    RpcMeta::parse(device.rpc("rpc.info", name).unwrap()).arg_type
+    RpcMeta::parse(device.rpc("rpc.info", name).unwrap()).arg_type()
 }
 
 fn rpc(args: &[String]) -> std::io::Result<String> {
@@ -328,6 +309,407 @@ fn dump(args: &[String]) {
     }
 }
 
+fn sniff(args: &[String]) {
+    let mut opts = tio_opts();
+    opts.optopt(
+        "w",
+        "",
+        "also write the captured packets to a pcapng file",
+        "path",
+    );
+    let (matches, root, _route) = tio_parseopts(&opts, args);
+
+    let mut pcapng = matches.opt_str("w").map(|path| {
+        let file = std::io::BufWriter::new(File::create(&path).unwrap());
+        (tio::pcapng::PcapngWriter::new(file).unwrap(), path)
+    });
+
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        eprintln!("Failed to install signal handlers: {:?}", e);
+    }
+
+    let proxy = proxy::Interface::new(&root);
+    let sniffer = proxy.sniffer().unwrap();
+
+    loop {
+        let sniffed = crossbeam::select! {
+            recv(sniffer.receiver()) -> sniffed => {
+                if let Ok(sniffed) = sniffed { sniffed } else { break }
+            }
+            recv(shutdown.receiver()) -> _ => break,
+        };
+        let time = chrono::DateTime::<chrono::Local>::from(sniffed.timestamp);
+        let direction = match (sniffed.direction, sniffed.client) {
+            (proxy::Direction::ToDevice, Some(0)) => "proxy->dev".to_string(),
+            (proxy::Direction::ToDevice, Some(client)) => format!("{}->dev", client),
+            (proxy::Direction::ToDevice, None) => "->dev".to_string(),
+            (proxy::Direction::FromDevice, _) => "dev->".to_string(),
+        };
+        println!(
+            "{} {:>10} {} {:?}",
+            time.format("%T%.6f"),
+            direction,
+            sniffed.packet.routing,
+            sniffed.packet.payload
+        );
+        if let Some((writer, _)) = &mut pcapng {
+            writer.write(&sniffed).unwrap();
+        }
+    }
+
+    if let Some((writer, path)) = pcapng {
+        let n_packets = writer.packets();
+        writer.into_inner().unwrap();
+        eprintln!("Wrote {} packets to {}", n_packets, path);
+    }
+}
+
+fn hexdump(args: &[String]) {
+    let opts = tio_opts();
+    let (_matches, root, _route) = tio_parseopts(&opts, args);
+
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        eprintln!("Failed to install signal handlers: {:?}", e);
+    }
+
+    let (tap, raw) = crossbeam::channel::bounded::<Vec<u8>>(1024);
+    let _proxy = proxy::Interface::builder().url(&root).raw_tap(tap).spawn();
+
+    let mut offset = 0usize;
+    loop {
+        let data = crossbeam::select! {
+            recv(raw) -> data => {
+                if let Ok(data) = data { data } else { break }
+            }
+            recv(shutdown.receiver()) -> _ => break,
+        };
+        let time = chrono::Local::now();
+        for line in data.chunks(16) {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!(
+                "{} {:08x}  {:<47}  |{}|",
+                time.format("%T%.6f"),
+                offset,
+                hex.join(" "),
+                ascii
+            );
+            offset += line.len();
+        }
+    }
+}
+
+fn console(args: &[String]) {
+    let opts = tio_opts();
+    let (_matches, root, route) = tio_parseopts(&opts, args);
+
+    let proxy = proxy::Interface::new(&root);
+    for line in proxy.console(route).unwrap().iter() {
+        println!("{}", line);
+    }
+}
+
+/// Running statistics and recent history of a data column.
+struct ColumnSummary {
+    name: String,
+    units: String,
+    n: u64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+    recent: std::collections::VecDeque<f64>,
+}
+
+/// Number of most recent samples used to estimate spectra.
+static REPORT_SPECTRUM_SAMPLES: usize = 1024;
+
+impl ColumnSummary {
+    fn new(name: &str, units: &str) -> ColumnSummary {
+        ColumnSummary {
+            name: name.to_string(),
+            units: units.to_string(),
+            n: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            recent: std::collections::VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x - self.mean);
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        if self.recent.len() == REPORT_SPECTRUM_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(x);
+    }
+
+    fn stddev(&self) -> f64 {
+        if self.n > 1 {
+            (self.m2 / (self.n - 1) as f64).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    /// Amplitude spectral density of the recent samples, with a Hann
+    /// window, as (frequency, density) pairs excluding DC.
+    fn spectrum(&self, sample_rate: f64) -> Vec<(f64, f64)> {
+        let n = self.recent.len();
+        if n < 16 {
+            return vec![];
+        }
+        let mean = self.recent.iter().sum::<f64>() / n as f64;
+        let window: Vec<f64> = (0..n)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos())
+            .collect();
+        let norm = window.iter().map(|w| w * w).sum::<f64>() * sample_rate;
+        let x: Vec<f64> = self
+            .recent
+            .iter()
+            .zip(&window)
+            .map(|(x, w)| (x - mean) * w)
+            .collect();
+        (1..n / 2)
+            .map(|k| {
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, x) in x.iter().enumerate() {
+                    let phase = 2.0 * std::f64::consts::PI * (k * i) as f64 / n as f64;
+                    re += x * phase.cos();
+                    im -= x * phase.sin();
+                }
+                let psd = 2.0 * (re * re + im * im) / norm;
+                (k as f64 * sample_rate / n as f64, psd.sqrt())
+            })
+            .collect()
+    }
+}
+
+fn report(args: &[String]) -> std::io::Result<()> {
+    use twinleaf::data::Device;
+    let mut opts = tio_opts();
+    opts.optopt(
+        "d",
+        "",
+        "seconds of data to summarize (default 60)",
+        "seconds",
+    );
+    opts.optopt("f", "", "path of the report (default stdout)", "path");
+    let (matches, root, route) = tio_parseopts(&opts, args);
+    let duration = match matches.opt_str("d") {
+        Some(secs) => std::time::Duration::from_secs_f64(secs.parse().expect("invalid duration")),
+        None => std::time::Duration::from_secs(60),
+    };
+
+    let proxy = proxy::Interface::new(&root);
+    let rpc_port = proxy.device_rpc(route.clone()).unwrap();
+    let mut device = Device::new(proxy.device_full(route.clone()).unwrap());
+    let meta = device.get_metadata();
+
+    let mut out = String::new();
+    use std::fmt::Write as _;
+    let _ = writeln!(out, "# Device report: {}\n", meta.device.name);
+    let _ = writeln!(
+        out,
+        "Generated {} for `{}` at route `{}`.\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S %Z"),
+        root,
+        route
+    );
+    let _ = writeln!(out, "## Identity\n");
+    let _ = writeln!(out, "| | |\n|---|---|");
+    let _ = writeln!(out, "| Name | {} |", meta.device.name);
+    let _ = writeln!(out, "| Serial number | {} |", meta.device.serial_number);
+    let _ = writeln!(out, "| Firmware | {} |", meta.device.firmware_hash);
+    let _ = writeln!(out, "| Session | {} |", meta.device.session_id);
+    match rpc_port.protocol_version() {
+        Ok(version) => {
+            let _ = writeln!(out, "| Protocol | {:?} |", version);
+        }
+        Err(err) => {
+            let _ = writeln!(out, "| Protocol | unknown ({:?}) |", err);
+        }
+    }
+
+    let _ = writeln!(out, "\n## Settings\n");
+    let _ = writeln!(out, "| Name | Type | Access | Value |\n|---|---|---|---|");
+    let nrpcs: u16 = rpc_port.get("rpc.listinfo").unwrap_or(0);
+    for rpc_id in 0u16..nrpcs {
+        let (meta, name): (u16, String) = match rpc_port.rpc("rpc.listinfo", rpc_id) {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+        let meta = RpcMeta::parse(meta);
+        // Only read values: calling anything else could have side effects.
+        if !meta.info.readable || meta.info.value_type == RpcValueType::Unknown {
+            continue;
+        }
+        let value = match rpc_port.raw_rpc(&name, &[]) {
+            Ok(reply) => match meta.info.decode(&reply) {
+                Some(value) => value.to_string(),
+                None => "?".to_string(),
+            },
+            Err(err) => format!("error: {:?}", err),
+        };
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} |",
+            name,
+            meta.type_str(),
+            meta.perm_str(),
+            value
+        );
+    }
+
+    let mut ids: Vec<u8> = meta.streams.keys().cloned().collect();
+    ids.sort();
+    let _ = writeln!(out, "\n## Streams\n");
+    let _ = writeln!(
+        out,
+        "| Id | Name | Columns | Rate (Hz) | Filter |\n|---|---|---|---|---|"
+    );
+    for id in &ids {
+        let stream = &meta.streams[id];
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} | {:?} {} Hz |",
+            id,
+            stream.stream.name,
+            stream
+                .columns
+                .iter()
+                .map(|c| c.name.clone())
+                .collect::<Vec<String>>()
+                .join(", "),
+            f64::from(stream.segment.sampling_rate) / f64::from(stream.segment.decimation),
+            stream.segment.filter_type,
+            stream.segment.filter_cutoff
+        );
+    }
+
+    eprintln!("Collecting {:?} of data", duration);
+    let mut summaries: std::collections::BTreeMap<u8, (f64, Vec<ColumnSummary>)> =
+        std::collections::BTreeMap::new();
+    let end = std::time::Instant::now() + duration;
+    while std::time::Instant::now() < end {
+        let sample = match device.try_next() {
+            Some(sample) => sample,
+            None => {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+        };
+        let entry = summaries
+            .entry(sample.stream.stream_id)
+            .or_insert_with(|| (0.0, vec![]));
+        if entry.1.len() != sample.columns.len() || sample.meta_changed {
+            entry.1 = sample
+                .columns
+                .iter()
+                .map(|c| ColumnSummary::new(&c.desc.name, &c.desc.units))
+                .collect();
+        }
+        entry.0 = 1.0 / sample.period();
+        for (summary, col) in entry.1.iter_mut().zip(&sample.columns) {
+            if let Some(x) = col.value.as_f64() {
+                summary.add(x);
+            }
+        }
+    }
+
+    let _ = writeln!(out, "\n## Statistics\n");
+    let _ = writeln!(out, "Over {:.0?} of data.\n", duration);
+    let _ = writeln!(
+        out,
+        "| Stream | Column | Samples | Mean | Std dev | Min | Max | Units |\n|---|---|---|---|---|---|---|---|"
+    );
+    for (id, (_, columns)) in &summaries {
+        for col in columns {
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {:.6} | {:.6} | {:.6} | {:.6} | {} |",
+                id,
+                col.name,
+                col.n,
+                col.mean,
+                col.stddev(),
+                col.min,
+                col.max,
+                col.units
+            );
+        }
+    }
+
+    let _ = writeln!(out, "\n## Spectra\n");
+    let _ = writeln!(
+        out,
+        "Amplitude spectral density of the last {} samples of each column: median noise floor and strongest peaks.\n",
+        REPORT_SPECTRUM_SAMPLES
+    );
+    let _ = writeln!(
+        out,
+        "| Stream | Column | Noise floor (/√Hz) | Peaks (Hz: /√Hz) |\n|---|---|---|---|"
+    );
+    for (id, (rate, columns)) in &summaries {
+        for col in columns {
+            let spectrum = col.spectrum(*rate);
+            if spectrum.is_empty() {
+                continue;
+            }
+            let mut densities: Vec<f64> = spectrum.iter().map(|(_, d)| *d).collect();
+            densities.sort_by(|a, b| a.total_cmp(b));
+            let floor = densities[densities.len() / 2];
+            let mut peaks: Vec<(f64, f64)> = spectrum
+                .windows(3)
+                .filter(|w| w[1].1 > w[0].1 && w[1].1 > w[2].1 && w[1].1 > 10.0 * floor)
+                .map(|w| w[1])
+                .collect();
+            peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+            peaks.truncate(5);
+            let _ = writeln!(
+                out,
+                "| {} | {} | {:.3e} {} | {} |",
+                id,
+                col.name,
+                floor,
+                col.units,
+                peaks
+                    .iter()
+                    .map(|(f, d)| format!("{:.2}: {:.3e}", f, d))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    match matches.opt_str("f") {
+        Some(path) => {
+            std::fs::write(&path, out)?;
+            eprintln!("Report written to {}", path);
+        }
+        None => print!("{}", out),
+    }
+    Ok(())
+}
+
 fn meta_dump(args: &[String]) {
     use twinleaf::data::Device;
     let opts = tio_opts();
@@ -348,6 +730,318 @@ fn meta_dump(args: &[String]) {
     }
 }
 
+fn settings_dump(args: &[String]) {
+    use twinleaf::data::Device;
+    let mut opts = tio_opts();
+    opts.optopt(
+        "f",
+        "",
+        "path of file where to store the settings (defaults to stdout)",
+        "path",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+
+    let proxy = proxy::Interface::new(&root);
+    let device = proxy.device_full(route).unwrap();
+    let mut device = Device::new(device);
+
+    let settings = match device.dump_settings() {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Failed to read settings: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = matches.opt_str("f") {
+        std::fs::write(path, settings.to_toml()).unwrap();
+    } else {
+        print!("{}", settings.to_toml());
+    }
+}
+
+fn settings_load(args: &[String]) {
+    use twinleaf::data::settings::SettingsSnapshot;
+    use twinleaf::data::Device;
+    let mut opts = tio_opts();
+    opts.optflag(
+        "n",
+        "",
+        "dry run: only show the settings which would change",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+    if matches.free.len() != 1 {
+        print!("{}", opts.usage("Expected the path of a settings file"));
+        return;
+    }
+
+    let doc = std::fs::read_to_string(&matches.free[0]).unwrap();
+    let settings = match SettingsSnapshot::from_toml(&doc) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Invalid settings file: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let proxy = proxy::Interface::new(&root);
+    let device = proxy.device_full(route).unwrap();
+    let mut device = Device::new(device);
+
+    let dry_run = matches.opt_present("n");
+    match device.load_settings(&settings, dry_run) {
+        Ok(updates) => {
+            for update in &updates {
+                println!("{}: {} -> {}", update.name, update.current, update.new);
+            }
+            if updates.is_empty() {
+                println!("Settings already up to date");
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to apply settings: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Settings of the device at `source` if it is a URL, or else from the file
+/// at path `source`.
+fn read_settings(
+    source: &str,
+    route: &DeviceRoute,
+) -> Result<twinleaf::data::settings::SettingsSnapshot, String> {
+    use twinleaf::data::settings::SettingsSnapshot;
+    use twinleaf::data::Device;
+    if source.contains("://") {
+        let proxy = proxy::Interface::new(source);
+        let device = proxy
+            .device_full(route.clone())
+            .map_err(|err| format!("{:?}", err))?;
+        Device::new(device)
+            .dump_settings()
+            .map_err(|err| format!("{:?}", err))
+    } else {
+        let doc = std::fs::read_to_string(source).map_err(|err| err.to_string())?;
+        SettingsSnapshot::from_toml(&doc).map_err(|err| format!("{:?}", err))
+    }
+}
+
+fn settings_diff(args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt(
+        "s",
+        "",
+        "sensor path in the sensor tree of devices (default /)",
+        "path",
+    );
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print!("{}", opts.usage("Invalid tool invocation"));
+            panic!("{}", f.to_string())
+        }
+    };
+    if matches.free.len() != 2 {
+        print!(
+            "{}",
+            opts.usage("Expected two settings files or device URLs")
+        );
+        return;
+    }
+    let route = if let Some(path) = matches.opt_str("s") {
+        DeviceRoute::from_str(&path).unwrap()
+    } else {
+        DeviceRoute::root()
+    };
+
+    let mut snapshots = vec![];
+    for source in &matches.free {
+        match read_settings(source, &route) {
+            Ok(settings) => snapshots.push(settings),
+            Err(err) => {
+                eprintln!("Failed to read settings from {}: {}", source, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let diffs = snapshots[0].diff(&snapshots[1]);
+    let show = |value: &Option<twinleaf::data::settings::SettingValue>| match value {
+        Some(value) => value.to_string(),
+        None => "(none)".to_string(),
+    };
+    for diff in &diffs {
+        println!(
+            "{}: {} | {}",
+            diff.name,
+            show(&diff.left),
+            show(&diff.right)
+        );
+    }
+    if !diffs.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn discover(args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt(
+        "t",
+        "",
+        "how long to listen for announcements (default 3)",
+        "seconds",
+    );
+    opts.optflag("m", "", "browse for servers advertised over mDNS instead");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print!("{}", opts.usage("Invalid tool invocation"));
+            panic!("{}", f.to_string())
+        }
+    };
+    let seconds: f64 = matches
+        .opt_str("t")
+        .map(|t| t.parse().expect("Invalid duration"))
+        .unwrap_or(3.0);
+    let duration = std::time::Duration::from_secs_f64(seconds);
+
+    if matches.opt_present("m") {
+        let found = match tio::mdns::browse(duration) {
+            Ok(found) => found,
+            Err(err) => {
+                eprintln!("Failed to browse mDNS: {:?}", err);
+                std::process::exit(1);
+            }
+        };
+        if found.is_empty() {
+            println!("No devices found");
+        }
+        for dev in found {
+            println!("{}  {} (serial {})", dev.url(), dev.name, dev.serial);
+        }
+        return;
+    }
+
+    let found = match tio::discovery::Listener::discover(duration) {
+        Ok(found) => found,
+        Err(err) => {
+            eprintln!("Failed to listen for announcements: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    if found.is_empty() {
+        println!("No devices found");
+    }
+    for dev in found {
+        println!("{}  {} (serial {})", dev.url(), dev.name, dev.serial);
+    }
+}
+
+fn topology(args: &[String]) {
+    let mut opts = tio_opts();
+    opts.optopt(
+        "t",
+        "",
+        "how long to listen for devices (default 3)",
+        "seconds",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+    let seconds: f64 = matches
+        .opt_str("t")
+        .map(|t| t.parse().expect("Invalid duration"))
+        .unwrap_or(3.0);
+    let duration = std::time::Duration::from_secs_f64(seconds);
+
+    let proxy = proxy::Interface::new(&root);
+    let port = proxy.subtree_full(route.clone()).unwrap();
+    std::thread::sleep(duration);
+
+    let topology = port.topology();
+    if topology.is_empty() {
+        println!("No devices found");
+    }
+    for (relative, activity) in topology {
+        println!(
+            "{:<12} {:>8} packets, last seen {:.1}s ago{}",
+            route.absolute_route(&relative).to_string(),
+            activity.packets,
+            activity.last_seen.elapsed().as_secs_f64(),
+            if activity.is_alive(duration / 2) {
+                ""
+            } else {
+                " (gone)"
+            }
+        );
+    }
+}
+
+fn reboot(args: &[String]) {
+    let mut opts = tio_opts();
+    opts.optopt(
+        "t",
+        "",
+        "how long to wait for the device (default 30)",
+        "seconds",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+    let seconds: f64 = matches
+        .opt_str("t")
+        .map(|t| t.parse().expect("Invalid duration"))
+        .unwrap_or(30.0);
+    let timeout = std::time::Duration::from_secs_f64(seconds);
+
+    // The device can disappear for a while as it reboots.
+    let proxy = proxy::Interface::builder()
+        .url(&root)
+        .reconnect(timeout)
+        .spawn();
+    let mut device = twinleaf::data::Device::new(proxy.device_full(route).unwrap());
+    match device.reboot(timeout) {
+        Ok(meta) => println!(
+            "{} {} rebooted",
+            meta.device.name, meta.device.serial_number
+        ),
+        Err(err) => {
+            eprintln!("Reboot failed: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn ping(args: &[String]) {
+    let mut opts = tio_opts();
+    opts.optopt("t", "", "timeout (default 1)", "seconds");
+    opts.optopt("c", "", "number of pings (default 1)", "count");
+    let (matches, root, route) = tio_parseopts(&opts, args);
+    let timeout: f64 = matches
+        .opt_str("t")
+        .map(|t| t.parse().expect("Invalid timeout"))
+        .unwrap_or(1.0);
+    let count: u32 = matches
+        .opt_str("c")
+        .map(|c| c.parse().expect("Invalid count"))
+        .unwrap_or(1);
+    let timeout = std::time::Duration::from_secs_f64(timeout);
+
+    let proxy = proxy::Interface::new(&root);
+    let port = proxy.tree_rpc().unwrap();
+    let mut failed = false;
+    for i in 0..count {
+        if i > 0 {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+        match port.ping(route.clone(), timeout) {
+            Ok(rtt) => println!("{}: {:.1} ms", route, rtt.as_secs_f64() * 1000.0),
+            Err(err) => {
+                println!("{}: {:?}", route, err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
 fn print_sample(sample: &twinleaf::data::Sample) {
     use twinleaf::data::ColumnData;
     if sample.meta_changed {
@@ -395,7 +1089,184 @@ fn data_dump(args: &[String]) {
     }
 }
 
+fn jsonl(args: &[String]) {
+    use std::io::Write;
+    use twinleaf::data::{jsonl, Device};
+    let mut opts = tio_opts();
+    opts.optflag("p", "", "output raw packets rather than samples");
+    opts.optopt(
+        "l",
+        "",
+        "serve the output to TCP clients on this address rather than stdout",
+        "address",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+
+    // Lines go to stdout, or to all the clients currently connected.
+    let clients = matches.opt_str("l").map(|addr| {
+        let listener = std::net::TcpListener::bind(&addr).unwrap();
+        let clients = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                accepted.lock().unwrap().push(stream);
+            }
+        });
+        clients
+    });
+    let output = |line: String| match &clients {
+        Some(clients) => {
+            let line = line + "\n";
+            clients
+                .lock()
+                .unwrap()
+                .retain_mut(|client: &mut std::net::TcpStream| {
+                    client.write_all(line.as_bytes()).is_ok()
+                });
+        }
+        None => println!("{}", line),
+    };
+
+    let proxy = proxy::Interface::new(&root);
+    if matches.opt_present("p") {
+        let port = proxy.subtree_full(route).unwrap();
+        while let Ok(pkt) = port.recv() {
+            output(jsonl::packet_to_json(&pkt));
+        }
+    } else {
+        let mut device = Device::new(proxy.device_full(route).unwrap());
+        loop {
+            output(jsonl::sample_to_json(&device.next()));
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn mqtt(args: &[String]) {
+    use twinleaf::data::Device;
+    use twinleaf::mqtt::{Publisher, Topics};
+    let mut opts = tio_opts();
+    opts.reqopt("b", "", "MQTT broker to publish to", "host:port");
+    opts.optopt("i", "", "MQTT client id (default tio-tool)", "id");
+    opts.optopt(
+        "t",
+        "",
+        "topic of column values, or 'none' (default twinleaf/{device}/{stream}/{column})",
+        "topic",
+    );
+    opts.optopt(
+        "j",
+        "",
+        "also publish samples as JSON to this topic",
+        "topic",
+    );
+    opts.optopt(
+        "e",
+        "",
+        "topic of events, or 'none' (default twinleaf/{device}/events)",
+        "topic",
+    );
+    let (matches, root, route) = tio_parseopts(&opts, args);
+
+    let defaults = Topics::default();
+    let template = |opt: &str, default: Option<String>| match matches.opt_str(opt) {
+        Some(t) if t == "none" => None,
+        Some(t) => Some(t),
+        None => default,
+    };
+    let topics = Topics {
+        columns: template("t", defaults.columns),
+        samples: template("j", defaults.samples),
+        events: template("e", defaults.events),
+    };
+    let client_id = matches.opt_str("i").unwrap_or("tio-tool".to_string());
+    let broker = matches.opt_str("b").unwrap();
+    let mut publisher = match Publisher::connect(broker.as_str(), &client_id, topics) {
+        Ok(publisher) => publisher,
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {:?}", broker, err);
+            return;
+        }
+    };
+
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        eprintln!("Failed to install signal handlers: {:?}", e);
+    }
+
+    let (status_send, status) = crossbeam::channel::bounded::<proxy::Event>(100);
+    let proxy = proxy::Interface::builder()
+        .url(&root)
+        .status(status_send)
+        .spawn();
+    let mut device = Device::new(proxy.device_full(route).unwrap());
+    let serial = device.get_metadata().device.serial_number.clone();
+
+    let mut result = publisher.publish_event(&serial, "SensorConnected");
+    while result.is_ok() && !shutdown.is_requested() {
+        while let Ok(event) = status.try_recv() {
+            use proxy::Event;
+            if let Event::SensorDisconnected
+            | Event::SensorReconnected
+            | Event::FailedToReconnect(_)
+            | Event::RootDeviceRestarted
+            | Event::RootDeviceSleeping
+            | Event::RootDeviceAwake
+            | Event::LinkOverloaded(..)
+            | Event::LinkLoadNormal(..) = event
+            {
+                result = result.and(publisher.publish_event(&serial, &format!("{:?}", event)));
+            }
+        }
+        let samples = device.drain();
+        if samples.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            result = result.and(publisher.poll());
+        }
+        for sample in samples {
+            result = result.and(publisher.publish_sample(&sample));
+        }
+    }
+    match result {
+        Ok(()) => {
+            let _ = publisher.disconnect();
+        }
+        Err(err) => eprintln!("Failed to publish to {}: {:?}", broker, err),
+    }
+}
+
+#[cfg(feature = "lsl")]
+fn lsl(args: &[String]) {
+    use twinleaf::data::Device;
+    use twinleaf::lsl::Outlets;
+    let opts = tio_opts();
+    let (_matches, root, route) = tio_parseopts(&opts, args);
+
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        eprintln!("Failed to install signal handlers: {:?}", e);
+    }
+
+    let proxy = proxy::Interface::new(&root);
+    let mut device = Device::new(proxy.device_full(route).unwrap());
+    let mut outlets = Outlets::new();
+
+    while !shutdown.is_requested() {
+        let samples = device.drain();
+        if samples.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        for sample in samples {
+            if let Err(err) = outlets.push(&sample) {
+                eprintln!("Failed to push to LSL: {:?}", err);
+                return;
+            }
+        }
+    }
+}
+
 fn log(args: &[String]) {
+    use twinleaf::data::Device;
     let output_path = chrono::Local::now().format("log.%Y%m%d-%H%M%S.tio");
     let mut opts = tio_opts();
     opts.optopt(
@@ -408,30 +1279,137 @@ fn log(args: &[String]) {
         "path",
     );
     opts.optflag("u", "", "unbuffered output");
+    opts.optflag(
+        "z",
+        "",
+        "compress the log with zstd, adding .zst to its path",
+    );
+    opts.optflag(
+        "i",
+        "",
+        "write an index alongside the log (<path>.idx), for fast seeking",
+    );
+    opts.optflag(
+        "n",
+        "",
+        "do not embed the device metadata at the start of the log and after reboots",
+    );
+    opts.optopt(
+        "b",
+        "",
+        "split the log into a log per device route and/or session",
+        "route,session",
+    );
+    opts.optopt(
+        "W",
+        "",
+        "restart the acquisition when no data is received for this long",
+        "secs",
+    );
+    opts.optopt(
+        "E",
+        "",
+        "RPC to call first to restart a stalled acquisition, before reconnecting",
+        "rpc",
+    );
     let (matches, root, route) = tio_parseopts(&opts, args);
     if matches.free.len() != 0 {
         print!("{}", opts.usage("Unexpected argument"));
         return;
     }
 
-    let output_path = if let Some(path) = matches.opt_str("f") {
+    let mut output_path = if let Some(path) = matches.opt_str("f") {
         path
     } else {
         output_path.to_string()
     };
+    if matches.opt_present("z") && !rawlog::is_compressed(Path::new(&output_path)) {
+        output_path.push_str(".zst");
+    }
 
     let proxy = proxy::Interface::new(&root);
 
-    let mut file = File::create(output_path).unwrap();
+    let split = match matches.opt_str("b").unwrap_or_default().parse::<SplitBy>() {
+        Ok(split) => split,
+        Err(err) => {
+            print!("{}", opts.usage(&err));
+            return;
+        }
+    };
+    // A new port for each snapshot, to fetch the metadata of the current
+    // session rather than a cached one.
+    let snapshot = |pkt_route: &DeviceRoute| match proxy.device_rpc(route.absolute_route(pkt_route))
+    {
+        Ok(port) => Device::new(port).get_metadata().make_updates(),
+        Err(_) => vec![],
+    };
+    let mut log =
+        LogSplitter::new(Path::new(&output_path), split).indexed(matches.opt_present("i"));
+    if !matches.opt_present("n") {
+        log = log.with_metadata(snapshot);
+    }
     let sync = matches.opt_present("u");
 
-    for pkt in proxy.device_full(route).unwrap().iter() {
-        let raw = pkt.serialize().unwrap();
-        file.write_all(&raw).unwrap();
-        if sync {
-            file.flush().unwrap();
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.install_signal_handlers() {
+        eprintln!("Failed to install signal handlers: {:?}", e);
+    }
+
+    let mut watchdog = matches.opt_str("W").map(|secs| {
+        let mut actions = vec![];
+        if let Some(rpc) = matches.opt_str("E") {
+            actions.push(Recovery::Rpc {
+                name: rpc,
+                arg: vec![],
+            });
+        }
+        actions.extend([Recovery::Reconnect, Recovery::Reconnect, Recovery::Fatal]);
+        Watchdog::new(proxy.device_rpc(route.clone()).unwrap())
+            .stall_timeout(std::time::Duration::from_secs_f64(secs.parse().unwrap()))
+            .actions(actions)
+    });
+    let ticks = if watchdog.is_some() {
+        crossbeam::channel::tick(std::time::Duration::from_millis(500))
+    } else {
+        crossbeam::channel::never()
+    };
+
+    let port = proxy.device_full(route.clone()).unwrap();
+    'logging: loop {
+        crossbeam::select! {
+            recv(port.receiver()) -> pkt => {
+                let pkt = if let Ok(pkt) = pkt { pkt } else { break };
+                log.write(&pkt).unwrap();
+                if sync {
+                    log.flush().unwrap();
+                }
+                if let Some(watchdog) = &mut watchdog {
+                    watchdog.observe(&pkt);
+                }
+            }
+            recv(ticks) -> _ => {
+                let Some(watchdog) = &mut watchdog else { continue };
+                for event in watchdog.poll() {
+                    eprintln!("Watchdog: {:?}", event);
+                    if event == WatchdogEvent::Fatal {
+                        break 'logging;
+                    }
+                }
+            }
+            recv(shutdown.receiver()) -> _ => break,
         }
     }
+    log.flush().unwrap();
+    eprintln!(
+        "Logged {} packets ({} bytes) to {}",
+        log.packets(),
+        log.bytes(),
+        log.paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
 }
 
 fn log_metadata(args: &[String]) {
@@ -461,40 +1439,120 @@ fn log_metadata(args: &[String]) {
     };
     let mut file = File::create(output_path).unwrap();
 
-    file.write_all(&meta.device.make_update().serialize().unwrap())
-        .unwrap();
-    for (_id, stream) in meta.streams {
-        file.write_all(&stream.stream.make_update().serialize().unwrap())
-            .unwrap();
-        file.write_all(&stream.segment.make_update().serialize().unwrap())
-            .unwrap();
-        for col in stream.columns {
-            file.write_all(&col.make_update().serialize().unwrap())
-                .unwrap();
-        }
+    for pkt in meta.make_updates() {
+        file.write_all(&pkt.serialize().unwrap()).unwrap();
     }
 }
 
 fn log_dump(args: &[String]) {
     for path in args {
-        let mut rest: &[u8] = &std::fs::read(path).unwrap();
-        while rest.len() > 0 {
-            let (pkt, len) = tio::Packet::deserialize(rest).unwrap();
-            rest = &rest[len..];
+        for pkt in LogReader::open(Path::new(path)).unwrap() {
+            let pkt = pkt.unwrap();
             println!("{:?}", pkt);
         }
     }
 }
 
+fn log_split(args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt(
+        "b",
+        "",
+        "split by device route and/or session (default route)",
+        "route,session",
+    );
+    opts.optflag("i", "", "write an index alongside each log");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print!("{}", opts.usage(&f.to_string()));
+            return;
+        }
+    };
+    let by = matches.opt_str("b").unwrap_or("route".to_string());
+    let split = match by.parse::<SplitBy>() {
+        Ok(split) if split != SplitBy::default() => split,
+        Ok(_) => {
+            print!("{}", opts.usage("Nothing to split by"));
+            return;
+        }
+        Err(err) => {
+            print!("{}", opts.usage(&err));
+            return;
+        }
+    };
+    if matches.free.is_empty() {
+        print!("{}", opts.usage("Expected the logs to split"));
+        return;
+    }
+
+    for path in &matches.free {
+        let path = Path::new(path);
+        let mut splitter = LogSplitter::new(path, split).indexed(matches.opt_present("i"));
+        for pkt in LogReader::open(path).unwrap() {
+            splitter.write(&pkt.unwrap()).unwrap();
+        }
+        splitter.flush().unwrap();
+        for split_path in splitter.paths() {
+            println!("{}", split_path.display());
+        }
+    }
+}
+
+fn log_convert(args: &[String]) {
+    use twinleaf::data::convert::log_to_csv;
+    let mut opts = Options::new();
+    opts.optopt(
+        "m",
+        "",
+        "metadata snapshot, from log-metadata, for logs without metadata",
+        "path",
+    );
+    opts.optopt(
+        "o",
+        "",
+        "prefix of the CSV files (default the log path without extension)",
+        "prefix",
+    );
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print!("{}", opts.usage(&f.to_string()));
+            return;
+        }
+    };
+    if matches.free.len() != 1 {
+        print!("{}", opts.usage("Expected the log to convert"));
+        return;
+    }
+
+    let log = Path::new(&matches.free[0]);
+    let base = match matches.opt_str("o") {
+        Some(base) => base.into(),
+        None if rawlog::is_compressed(log) => log.with_extension("").with_extension(""),
+        None => log.with_extension(""),
+    };
+    let metadata = matches.opt_str("m");
+    match log_to_csv(log, metadata.as_deref().map(Path::new), &base) {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path.display());
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to convert {}: {}", log.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn log_data_dump(args: &[String]) {
     use twinleaf::data::DeviceDataParser;
     let mut parser = DeviceDataParser::new(args.len() > 1);
 
     for path in args {
-        let mut rest: &[u8] = &std::fs::read(path).unwrap();
-        while rest.len() > 0 {
-            let (pkt, len) = tio::Packet::deserialize(rest).unwrap();
-            rest = &rest[len..];
+        for pkt in LogReader::open(Path::new(path)).unwrap() {
+            let pkt = pkt.unwrap();
             for sample in parser.process_packet(&pkt) {
                 print_sample(&sample);
             }
@@ -502,6 +1560,93 @@ fn log_data_dump(args: &[String]) {
     }
 }
 
+fn log_gaps(args: &[String]) {
+    let mut parser = DeviceDataParser::new(args.len() > 1);
+
+    for path in args {
+        for pkt in LogReader::open(Path::new(path)).unwrap() {
+            let pkt = pkt.unwrap();
+            parser.process_packet(&pkt);
+        }
+    }
+
+    let mut streams: Vec<_> = parser.gap_stats().iter().collect();
+    streams.sort_by_key(|(id, _)| **id);
+    for (id, stats) in streams {
+        println!(
+            "stream {}: {} samples, {} missing in {} gaps ({:.3}%), {} resets",
+            id,
+            stats.received,
+            stats.missing,
+            stats.gaps,
+            100.0 * stats.loss(),
+            stats.resets
+        );
+        if let Some(gap) = stats.last_gap {
+            println!(
+                "  last gap: segment {}, samples {}..{}",
+                gap.segment_id,
+                gap.first,
+                gap.first + gap.count
+            );
+        }
+    }
+}
+
+fn events(args: &[String]) {
+    let mut opts = Options::new();
+    opts.optflag(
+        "a",
+        "",
+        "show all events, including the routine RPC bookkeeping",
+    );
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print!("{}", opts.usage("Invalid tool invocation"));
+            panic!("{}", f.to_string())
+        }
+    };
+    if matches.free.is_empty() {
+        print!("{}", opts.usage("Usage: tio-tool events [-a] <events.log>"));
+        std::process::exit(1);
+    }
+    let all = matches.opt_present("a");
+
+    for path in &matches.free {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Failed to open '{}': {:?}", path, err);
+                std::process::exit(1);
+            }
+        };
+        for recorded in tio::eventlog::EventReader::new(std::io::BufReader::new(file)) {
+            let recorded = match recorded {
+                Ok(recorded) => recorded,
+                Err(err) => {
+                    eprintln!("{}: {}", path, err);
+                    std::process::exit(1);
+                }
+            };
+            if !all
+                && matches!(
+                    recorded.event,
+                    proxy::Event::RpcRemap(..) | proxy::Event::RpcRestore(..)
+                )
+            {
+                continue;
+            }
+            let time = chrono::DateTime::<chrono::Local>::from(recorded.time);
+            println!(
+                "{} {:?}",
+                time.format("%Y-%m-%d %H:%M:%S%.3f"),
+                recorded.event
+            );
+        }
+    }
+}
+
 //match
 fn match_value(data: ColumnData) -> String {
     let data_type = match data {
@@ -526,10 +1671,8 @@ fn log_csv(args: &[String]) -> std::io::Result<()> {
     let mut first: bool = true;
 
     for path in &args[2..] {
-        let mut rest: &[u8] = &std::fs::read(path).unwrap();
-        while rest.len() > 0 {
-            let (pkt, len) = tio::Packet::deserialize(rest).unwrap();
-            rest = &rest[len..];
+        for pkt in LogReader::open(Path::new(path)).unwrap() {
+            let pkt = pkt.unwrap();
             for sample in parser.process_packet(&pkt) {
                 //match stream id
                 if sample.stream.stream_id == id as u8 {
@@ -735,18 +1878,50 @@ fn main() {
         "dump" => {
             dump(&args[2..]); //.unwrap();
         }
+        "sniff" => {
+            sniff(&args[2..]);
+        }
+        "hexdump" => {
+            hexdump(&args[2..]);
+        }
+        "console" => {
+            console(&args[2..]);
+        }
+        "report" => {
+            report(&args[2..]).unwrap();
+        }
         "log" => {
             log(&args[2..]); //.unwrap();
         }
+        "jsonl" => {
+            jsonl(&args[2..]);
+        }
+        #[cfg(feature = "mqtt")]
+        "mqtt" => {
+            mqtt(&args[2..]);
+        }
+        #[cfg(feature = "lsl")]
+        "lsl" => {
+            lsl(&args[2..]);
+        }
         "log-metadata" => {
             log_metadata(&args[2..]); //.unwrap();
         }
         "log-dump" => {
             log_dump(&args[2..]); //.unwrap();
         }
+        "log-split" => {
+            log_split(&args[2..]);
+        }
+        "log-convert" => {
+            log_convert(&args[2..]);
+        }
         "log-data-dump" => {
             log_data_dump(&args[2..]); //.unwrap();
         }
+        "log-gaps" => {
+            log_gaps(&args[2..]);
+        }
         "log-csv" => {
             let _ = log_csv(&args[1..]); //.unwrap();
         }
@@ -762,15 +1937,49 @@ fn main() {
         "capture" => {
             read_capture(&args[2..]);
         }
+        "settings-dump" => {
+            settings_dump(&args[2..]);
+        }
+        "settings-load" => {
+            settings_load(&args[2..]);
+        }
+        "settings-diff" => {
+            settings_diff(&args[2..]);
+        }
+        "discover" => {
+            discover(&args[2..]);
+        }
+        "topology" => {
+            topology(&args[2..]);
+        }
+        "ping" => {
+            ping(&args[2..]);
+        }
+        "reboot" => {
+            reboot(&args[2..]);
+        }
+        "events" => {
+            events(&args[2..]);
+        }
         _ => {
             // TODO: do usage right
             println!("Usage:");
             println!(" tio-tool help");
             println!(" tio-tool dump [-r url] [-s sensor]");
-            println!(" tio-tool log [-r url] [-s sensor] [-f filename] [-u]");
+            println!(" tio-tool sniff [-r url] [-w capture.pcapng]");
+            println!(" tio-tool hexdump [-r url]");
+            println!(" tio-tool console [-r url] [-s sensor]");
+            println!(" tio-tool report [-r url] [-s sensor] [-d seconds] [-f report.md]");
+            println!(" tio-tool log [-r url] [-s sensor] [-f filename] [-u] [-z] [-i] [-n] [-b route,session]");
+            println!(" tio-tool jsonl [-r url] [-s sensor] [-p] [-l address]");
+            println!(" tio-tool mqtt [-r url] [-s sensor] -b broker [-i id] [-t topic] [-j topic] [-e topic]");
+            println!(" tio-tool lsl [-r url] [-s sensor]");
             println!(" tio-tool log-metadata [-r url] [-s sensor] [-f filename]");
             println!(" tio-tool log-dump filename [filename ...]");
+            println!(" tio-tool log-split [-b route,session] [-i] filename [filename ...]");
+            println!(" tio-tool log-convert [-m metadata] [-o prefix] filename");
             println!(" tio-tool log-data-dump filename [filename ...]");
+            println!(" tio-tool log-gaps filename [filename ...]");
             println!(" tio-tool log-csv <stream id> [metadata] <csv>");
             println!(" tio-tool rpc-list [-r url] [-s sensor]");
             println!(" tio-tool rpc [-r url] [-s sensor] [-t type] [-d] <rpc-name> [rpc-arg]");
@@ -779,6 +1988,14 @@ fn main() {
             println!(" tio-tool data-dump [-r url] [-s sensor]");
             println!(" tio-tool meta-dump [-r url] [-s sensor]");
             println!(" tio-tool capture <rpc-prefix> <data-type>");
+            println!(" tio-tool settings-dump [-r url] [-s sensor] [-f settings.toml]");
+            println!(" tio-tool settings-load [-r url] [-s sensor] [-n] <settings.toml>");
+            println!(" tio-tool settings-diff [-s sensor] <settings.toml|url> <settings.toml|url>");
+            println!(" tio-tool discover [-t seconds] [-m]");
+            println!(" tio-tool topology [-r url] [-s sensor] [-t seconds]");
+            println!(" tio-tool ping [-r url] [-s sensor] [-t seconds] [-c count]");
+            println!(" tio-tool reboot [-r url] [-s sensor] [-t seconds]");
+            println!(" tio-tool events [-a] <events.log>");
         }
     }
 }
@@ -0,0 +1,74 @@
+//! Cost of broadcasting sample data to the clients of a proxy.
+//!
+//! The proxy hands a copy of every stream data packet to each client. This
+//! compares copying packets as done now, sharing the sample data, with deep
+//! copying it as was done when payloads were stored in a `Vec<u8>`.
+//!
+//! Run with `cargo bench -p twinleaf --bench broadcast`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use twinleaf::tio::proto::{DeviceRoute, Packet, Payload, StreamDataPayload};
+
+static N_PACKETS: usize = 100_000;
+static SAMPLE_DATA_SIZE: usize = 480;
+
+fn stream_packet(n: u32) -> Packet {
+    Packet {
+        payload: Payload::StreamData(StreamDataPayload {
+            stream_id: 1,
+            first_sample_n: n,
+            segment_id: 0,
+            data: vec![0x5a; SAMPLE_DATA_SIZE].into(),
+        }),
+        routing: DeviceRoute::root(),
+        ttl: 0,
+        received: None,
+    }
+}
+
+fn broadcast(packets: &[Packet], clients: usize, copy: impl Fn(&Packet) -> Packet) -> Duration {
+    let start = Instant::now();
+    for pkt in packets {
+        for _ in 0..clients {
+            black_box(copy(pkt));
+        }
+    }
+    start.elapsed()
+}
+
+fn deep_copy(pkt: &Packet) -> Packet {
+    let payload = match &pkt.payload {
+        Payload::StreamData(data) => Payload::StreamData(StreamDataPayload {
+            data: data.data.to_vec().into(),
+            ..data.clone()
+        }),
+        payload => payload.clone(),
+    };
+    Packet {
+        payload,
+        routing: pkt.routing.clone(),
+        ttl: pkt.ttl,
+        received: pkt.received,
+    }
+}
+
+fn main() {
+    let packets: Vec<Packet> = (0..N_PACKETS as u32).map(stream_packet).collect();
+    println!(
+        "{} packets of {} bytes of sample data",
+        N_PACKETS, SAMPLE_DATA_SIZE
+    );
+    for clients in [1, 4, 16, 64] {
+        let shared = broadcast(&packets, clients, Packet::clone);
+        let copied = broadcast(&packets, clients, deep_copy);
+        let per_packet = |d: Duration| d.as_nanos() as f64 / N_PACKETS as f64;
+        println!(
+            "{:3} clients: shared {:8.1} ns/packet, deep copy {:8.1} ns/packet ({:.1}x)",
+            clients,
+            per_packet(shared),
+            per_packet(copied),
+            copied.as_secs_f64() / shared.as_secs_f64()
+        );
+    }
+}
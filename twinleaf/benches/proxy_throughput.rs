@@ -0,0 +1,100 @@
+//! Throughput of the proxy, from the device to its clients.
+//!
+//! A fake device on a loopback TCP connection sends stream data packets in
+//! batches, each once every client received the previous one, since the
+//! proxy drops what it cannot keep up with. With many clients, the proxy
+//! spends a good part of its time selecting on their channels between
+//! batches of packets from the device.
+//!
+//! Run with `cargo bench -p twinleaf --bench proxy_throughput`.
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use twinleaf::tio::proto::{DeviceRoute, Packet, Payload, StreamDataPayload};
+use twinleaf::tio::proxy;
+
+static N_PACKETS: usize = 32_000;
+/// Packets sent at once, which fit in the queue from the device port. With
+/// one packet per batch, the proxy goes through its main loop for each.
+static BATCHES: [usize; 2] = [1, 32];
+static SAMPLE_DATA_SIZE: usize = 32;
+
+fn stream_packet(n: u32) -> Vec<u8> {
+    Packet {
+        payload: Payload::StreamData(StreamDataPayload {
+            stream_id: 1,
+            first_sample_n: n,
+            segment_id: 0,
+            data: vec![0x5a; SAMPLE_DATA_SIZE].into(),
+        }),
+        routing: DeviceRoute::root(),
+        ttl: 0,
+        received: None,
+    }
+    .serialize()
+    .unwrap()
+}
+
+/// Time for `clients` clients to each receive `N_PACKETS` packets, sent in
+/// batches of `batch`.
+fn run(clients: usize, batch: usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("tcp://{}", listener.local_addr().unwrap());
+    let proxy = proxy::Interface::new(&url);
+    let (mut device, _) = listener.accept().unwrap();
+
+    // Discard what the proxy sends to the device.
+    let mut device_rx = device.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while matches!(device_rx.read(&mut buf), Ok(n) if n > 0) {}
+    });
+
+    let ports: Vec<proxy::Port> = (0..clients)
+        .map(|_| proxy.device_full(DeviceRoute::root()).unwrap())
+        .collect();
+    // Let the proxy set up its clients.
+    thread::sleep(Duration::from_millis(100));
+
+    // The clients are drained from this thread, so that the measure is not
+    // dominated by waking up a thread per client.
+    let data: Vec<u8> = (0..batch as u32).flat_map(stream_packet).collect();
+    let mut received = vec![0; clients];
+    let start = Instant::now();
+    for sent in (batch..=N_PACKETS).step_by(batch) {
+        device.write_all(&data).unwrap();
+        for (port, received) in ports.iter().zip(received.iter_mut()) {
+            while *received < sent {
+                let pkt = port
+                    .receiver()
+                    .recv_timeout(Duration::from_secs(10))
+                    .expect("packets were lost");
+                if let Payload::StreamData(_) = pkt.payload {
+                    *received += 1;
+                }
+            }
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    println!(
+        "{} packets of {} bytes of sample data",
+        N_PACKETS, SAMPLE_DATA_SIZE
+    );
+    for batch in BATCHES {
+        for clients in [1, 4, 16, 64] {
+            let elapsed = run(clients, batch);
+            println!(
+                "batches of {:2}, {:3} clients: {:9.0} packets/s, {:6.2} us/packet",
+                batch,
+                clients,
+                N_PACKETS as f64 / elapsed.as_secs_f64(),
+                elapsed.as_secs_f64() * 1e6 / N_PACKETS as f64
+            );
+        }
+    }
+}
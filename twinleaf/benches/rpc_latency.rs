@@ -0,0 +1,101 @@
+//! Round-trip time of RPCs through the proxy, under stream load.
+//!
+//! RPC replies go through the same proxy loop as the stream data sent to
+//! every client, so a proxy which falls behind on data delays RPCs as well.
+//! This calls RPCs of a simulated device streaming at a high rate, while
+//! more and more clients receive its data.
+//!
+//! Run with `cargo bench -p twinleaf --bench rpc_latency`.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use twinleaf::tio::proto::DeviceRoute;
+use twinleaf::tio::proxy;
+
+static N_RPCS: usize = 10_000;
+static SIM_URL: &str = "sim://bench?rate=5000&columns=8";
+
+struct Results {
+    /// Round-trip times of the RPCs which succeeded, sorted.
+    times: Vec<Duration>,
+    failed: usize,
+    /// Rate of the packets received by all the clients.
+    rate: f64,
+}
+
+/// Call `N_RPCS` RPCs, with `clients` clients receiving the data.
+fn run(clients: usize) -> Results {
+    let proxy = proxy::Interface::connect(SIM_URL, Duration::from_secs(5)).unwrap();
+    let running = Arc::new(AtomicBool::new(true));
+    let received = Arc::new(AtomicUsize::new(0));
+    let receivers: Vec<_> = (0..clients)
+        .map(|_| {
+            let port = proxy.device_full(DeviceRoute::root()).unwrap();
+            let running = running.clone();
+            let received = received.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    if port
+                        .receiver()
+                        .recv_timeout(Duration::from_millis(100))
+                        .is_ok()
+                    {
+                        received.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+    // Warm up, and let the data start flowing.
+    port.raw_rpc("dev.name", &[]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let before = received.load(Ordering::Relaxed);
+    let mut times = vec![];
+    let mut failed = 0;
+    for _ in 0..N_RPCS {
+        let t = Instant::now();
+        match port.raw_rpc("dev.name", &[]) {
+            Ok(_) => times.push(t.elapsed()),
+            Err(_) => failed += 1,
+        }
+    }
+    let rate = (received.load(Ordering::Relaxed) - before) as f64 / start.elapsed().as_secs_f64();
+
+    running.store(false, Ordering::Relaxed);
+    for receiver in receivers {
+        receiver.join().unwrap();
+    }
+    times.sort();
+    Results {
+        times,
+        failed,
+        rate,
+    }
+}
+
+fn main() {
+    println!("{} RPCs to {}", N_RPCS, SIM_URL);
+    for clients in [0, 1, 4, 16] {
+        let results = run(clients);
+        let times = &results.times;
+        let percentile = |p: usize| match times.len() {
+            0 => f64::NAN,
+            n => times[(n - 1) * p / 100].as_secs_f64() * 1e6,
+        };
+        println!(
+            "{:2} clients ({:8.0} packets/s): median {:7.1} us, 99% {:7.1} us, max {:7.1} us, {} failed",
+            clients,
+            results.rate,
+            percentile(50),
+            percentile(99),
+            percentile(100),
+            results.failed
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! Packets deserialized from arbitrary bytes, in every protocol version,
+//! and serialized back when valid.
+//!
+//! Run with `cargo fuzz run packets` from the `twinleaf` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use twinleaf::tio::proto::{Packet, ProtocolVersion};
+
+fuzz_target!(|data: &[u8]| {
+    for version in [
+        ProtocolVersion::Legacy,
+        ProtocolVersion::Metadata,
+        ProtocolVersion::Unknown(0),
+    ] {
+        for pkt in Packet::deserialize_all(data, version).into_iter().flatten() {
+            let _ = pkt.serialize();
+        }
+    }
+});
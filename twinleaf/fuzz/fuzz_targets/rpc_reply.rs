@@ -0,0 +1,30 @@
+//! RPC reply payloads parsed from arbitrary bytes as the reply types
+//! provided by the library.
+//!
+//! Run with `cargo fuzz run rpc_reply` from the `twinleaf` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use twinleaf::tio::util::{parse_reply, Be, LengthPrefixed, LengthPrefixedString, RestAsString};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_reply::<()>(data);
+    let _ = parse_reply::<u8>(data);
+    let _ = parse_reply::<i32>(data);
+    let _ = parse_reply::<u64>(data);
+    let _ = parse_reply::<f32>(data);
+    let _ = parse_reply::<f64>(data);
+    let _ = parse_reply::<Be<u32>>(data);
+    let _ = parse_reply::<String>(data);
+    let _ = parse_reply::<RestAsString>(data);
+    let _ = parse_reply::<Vec<u16>>(data);
+    let _ = parse_reply::<Vec<()>>(data);
+    let _ = parse_reply::<(u8, String)>(data);
+    let _ = parse_reply::<(u32, RestAsString)>(data);
+    let _ = parse_reply::<LengthPrefixed<f32>>(data);
+    let _ = parse_reply::<LengthPrefixed<u32, u32>>(data);
+    let _ = parse_reply::<LengthPrefixed<(), u64>>(data);
+    let _ = parse_reply::<LengthPrefixedString<u8>>(data);
+    let _ = parse_reply::<(LengthPrefixedString, u32)>(data);
+});
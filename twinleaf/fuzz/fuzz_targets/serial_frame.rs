@@ -0,0 +1,21 @@
+//! Packets decoded from arbitrary serial frames. Most inputs fail the CRC,
+//! so the frame is also tried with a valid CRC appended.
+//!
+//! Run with `cargo fuzz run serial_frame` from the `twinleaf` directory.
+
+#![no_main]
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use libfuzzer_sys::fuzz_target;
+use twinleaf::tio::port;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = port::decode_frame(data);
+    let mut frame = data.to_vec();
+    frame.extend(
+        Crc::<u32>::new(&CRC_32_ISO_HDLC)
+            .checksum(data)
+            .to_le_bytes(),
+    );
+    let _ = port::decode_frame(&frame);
+});
@@ -0,0 +1,402 @@
+//! Control
+//!
+//! HTTP API to administer a running proxy remotely, without shell access to
+//! the acquisition machine. Enabled with the `control` feature.
+//!
+//! A `ControlServer` watches the traffic of the proxy to keep track of the
+//! rate of the streams of the devices in the tree, and answers, in JSON:
+//! - `GET /devices`: the devices seen, with their name, serial number and
+//!   the packets and rate of each of their streams.
+//! - `GET /link`: the status of the link to the root device.
+//! - `GET /devices/<route>/rpcs`: the RPCs of a device, as listed by
+//!   `rpc.listinfo`.
+//! - `GET /devices/<route>/rpc/<name>`: the reply of an RPC called without
+//!   argument, decoded according to its type.
+//! - `POST /devices/<route>/rpc/<name>`: the reply of an RPC called with the
+//!   request body as argument, a number or a quoted string.
+//!
+//! where `<route>` is the route of the device without its leading slash,
+//! and is empty for the root device:
+//! ```sh
+//! curl http://localhost:7856/devices/0/rpc/dev.name
+//! curl -d 200 http://localhost:7856/devices/0/rpc/data.rate
+//! ```
+//! There is no authentication: the server should only listen on trusted
+//! networks.
+
+use crate::data::jsonl;
+use crate::data::settings::{self, RpcInfo, RpcValueType, SettingValue};
+use crate::tio::proto::{DeviceRoute, Payload, RpcErrorCode};
+use crate::tio::proxy::{self, PortError, RpcError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum size of an HTTP request accepted by the server.
+static MAX_REQUEST_SIZE: usize = 8192;
+/// Interval over which stream rates are measured.
+static RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct StreamStats {
+    packets: u64,
+    /// Packets since the start of the current rate window.
+    window_packets: u64,
+    /// Packets per second over the last rate window.
+    rate: f64,
+}
+
+/// Streams of each device.
+type Stats = Arc<Mutex<BTreeMap<DeviceRoute, BTreeMap<u8, StreamStats>>>>;
+
+/// HTTP control API of a proxy.
+pub struct ControlServer {
+    stats: Stats,
+    rpc_port: proxy::Port,
+    /// Name and serial number of each device, once queried.
+    identities: HashMap<DeviceRoute, (String, String)>,
+    /// RPCs of each device, as last listed.
+    rpcs: HashMap<DeviceRoute, Vec<RpcInfo>>,
+}
+
+/// Reply to a request: HTTP status and JSON body.
+type Response = (&'static str, String);
+
+fn error_response(status: &'static str, msg: &str) -> Response {
+    (status, format!("{{\"error\":{}}}", jsonl::string(msg)))
+}
+
+fn rpc_error_response(err: RpcError) -> Response {
+    match err {
+        RpcError::ExecError(err) if matches!(err.error, RpcErrorCode::NotFound) => {
+            error_response("404 Not Found", "no such RPC")
+        }
+        RpcError::ExecError(err) => error_response("502 Bad Gateway", &format!("{:?}", err.error)),
+        RpcError::TypeError => error_response("502 Bad Gateway", "unexpected reply"),
+        err => error_response("504 Gateway Timeout", &format!("{:?}", err)),
+    }
+}
+
+fn value_to_json(value: &SettingValue) -> String {
+    match value {
+        SettingValue::Int(x) => x.to_string(),
+        SettingValue::UInt(x) => x.to_string(),
+        SettingValue::Float(x) => jsonl::number(*x),
+        SettingValue::String(s) => jsonl::string(s),
+    }
+}
+
+fn type_name(value_type: RpcValueType) -> String {
+    match value_type {
+        RpcValueType::UInt(size) => format!("u{}", size * 8),
+        RpcValueType::Int(size) => format!("i{}", size * 8),
+        RpcValueType::Float(size) => format!("f{}", size * 8),
+        RpcValueType::String(_) => "string".to_string(),
+        RpcValueType::Unknown => "unknown".to_string(),
+    }
+}
+
+impl ControlServer {
+    /// Create a control server for `proxy`, and start tracking its traffic.
+    pub fn new(proxy: &proxy::Interface) -> Result<ControlServer, PortError> {
+        let data_port = proxy.tree_full()?;
+        let rpc_port = proxy.tree_rpc()?;
+        let stats = Stats::default();
+        let thread_stats = stats.clone();
+        thread::spawn(move || ControlServer::track(data_port, thread_stats));
+        Ok(ControlServer {
+            stats,
+            rpc_port,
+            identities: HashMap::new(),
+            rpcs: HashMap::new(),
+        })
+    }
+
+    /// Account for the packets received by `port` until the proxy goes away.
+    fn track(port: proxy::Port, stats: Stats) {
+        let mut window_start = Instant::now();
+        loop {
+            let pkt = match port.receiver().recv_timeout(RATE_WINDOW) {
+                Ok(pkt) => Some(pkt),
+                Err(crossbeam::channel::RecvTimeoutError::Timeout) => None,
+                Err(crossbeam::channel::RecvTimeoutError::Disconnected) => return,
+            };
+            let mut stats = stats.lock().expect("control stats lock poisoned");
+            if let Some(pkt) = pkt {
+                if let Payload::StreamData(data) = pkt.payload {
+                    let streams = stats.entry(pkt.routing).or_default();
+                    let stream = streams.entry(data.stream_id).or_default();
+                    stream.packets += 1;
+                    stream.window_packets += 1;
+                }
+            }
+            let elapsed = window_start.elapsed();
+            if elapsed >= RATE_WINDOW {
+                for stream in stats.values_mut().flat_map(|streams| streams.values_mut()) {
+                    stream.rate = stream.window_packets as f64 / elapsed.as_secs_f64();
+                    stream.window_packets = 0;
+                }
+                window_start = Instant::now();
+            }
+        }
+    }
+
+    fn devices(&mut self) -> Response {
+        let topology = self.rpc_port.topology();
+        for route in topology.keys() {
+            if self.identities.contains_key(route) {
+                continue;
+            }
+            if let Ok(port) = self.rpc_port.device_port(route.clone()) {
+                let identity = (
+                    port.get::<String>("dev.name").unwrap_or_default(),
+                    port.get::<String>("dev.serial").unwrap_or_default(),
+                );
+                self.identities.insert(route.clone(), identity);
+            }
+        }
+
+        let stats = self.stats.lock().expect("control stats lock poisoned");
+        let no_streams = BTreeMap::new();
+        let mut ret = "[".to_string();
+        for (i, (route, activity)) in topology.iter().enumerate() {
+            let (name, serial) = self.identities.get(route).cloned().unwrap_or_default();
+            let _ = write!(
+                ret,
+                "{}{{\"route\":{},\"name\":{},\"serial\":{},\"packets\":{},\"last_seen\":{},\"streams\":[",
+                if i == 0 { "" } else { "," },
+                jsonl::string(&route.to_string()),
+                jsonl::string(&name),
+                jsonl::string(&serial),
+                activity.packets,
+                jsonl::number(activity.last_seen.elapsed().as_secs_f64()),
+            );
+            let streams = stats.get(route).unwrap_or(&no_streams);
+            for (j, (id, stream)) in streams.iter().enumerate() {
+                let _ = write!(
+                    ret,
+                    "{}{{\"id\":{},\"packets\":{},\"rate\":{}}}",
+                    if j == 0 { "" } else { "," },
+                    id,
+                    stream.packets,
+                    jsonl::number(stream.rate),
+                );
+            }
+            ret += "]}";
+        }
+        ret += "]";
+        ("200 OK", ret)
+    }
+
+    fn link(&self) -> Response {
+        let status = self.rpc_port.link_status();
+        let opt = |x: Option<u32>| x.map_or("null".to_string(), |x| x.to_string());
+        let counters = &status.counters;
+        let body = format!(
+            "{{\"connected\":{},\"rate_bps\":{},\"default_bps\":{},\"target_bps\":{},\"autorate\":{},\"rx_bps\":{},\"overloaded\":{},\"error_rate\":{},\"packets\":{},\"crc_errors\":{},\"framing_errors\":{},\"resyncs\":{}}}",
+            status.connected,
+            opt(status.rate_bps),
+            opt(status.default_bps),
+            opt(status.target_bps),
+            jsonl::string(&format!("{:?}", status.autorate)),
+            opt(status.rx_bps),
+            status.overloaded,
+            jsonl::number(status.error_rate()),
+            counters.packets,
+            counters.crc_errors,
+            counters.framing_errors,
+            counters.resyncs,
+        );
+        ("200 OK", body)
+    }
+
+    /// RPCs of the device of `port`, or the response to give if they
+    /// cannot be listed.
+    fn list_rpcs(port: &proxy::Port) -> Result<Vec<RpcInfo>, Response> {
+        let n_rpcs: u16 = port.get("rpc.listinfo").map_err(rpc_error_response)?;
+        let mut ret = vec![];
+        for rpc_id in 0..n_rpcs {
+            let (meta, name): (u16, String) = port
+                .rpc("rpc.listinfo", rpc_id)
+                .map_err(rpc_error_response)?;
+            ret.push(RpcInfo::parse(&name, meta));
+        }
+        Ok(ret)
+    }
+
+    fn rpcs(&mut self, route: DeviceRoute) -> Response {
+        let port = match self.rpc_port.device_port(route.clone()) {
+            Ok(port) => port,
+            Err(err) => return error_response("503 Service Unavailable", &format!("{:?}", err)),
+        };
+        let rpcs = match ControlServer::list_rpcs(&port) {
+            Ok(rpcs) => rpcs,
+            Err(response) => return response,
+        };
+        let mut ret = "[".to_string();
+        for (i, info) in rpcs.iter().enumerate() {
+            let _ = write!(
+                ret,
+                "{}{{\"name\":{},\"type\":{},\"readable\":{},\"writable\":{},\"persistent\":{}}}",
+                if i == 0 { "" } else { "," },
+                jsonl::string(&info.name),
+                jsonl::string(&type_name(info.value_type)),
+                info.readable,
+                info.writable,
+                info.persistent,
+            );
+        }
+        ret += "]";
+        self.rpcs.insert(route, rpcs);
+        ("200 OK", ret)
+    }
+
+    fn rpc(&mut self, route: DeviceRoute, name: &str, body: &str) -> Response {
+        let port = match self.rpc_port.device_port(route.clone()) {
+            Ok(port) => port,
+            Err(err) => return error_response("503 Service Unavailable", &format!("{:?}", err)),
+        };
+        if !self.rpcs.contains_key(&route) {
+            match ControlServer::list_rpcs(&port) {
+                Ok(rpcs) => {
+                    self.rpcs.insert(route.clone(), rpcs);
+                }
+                Err(response) => return response,
+            }
+        }
+        let Some(info) = self.rpcs[&route].iter().find(|info| info.name == name) else {
+            return error_response("404 Not Found", "no such RPC");
+        };
+        let body = body.trim();
+        let arg = if body.is_empty() {
+            vec![]
+        } else {
+            match settings::parse_toml_value(body).and_then(|value| info.encode(&value)) {
+                Some(arg) => arg,
+                None => {
+                    let msg = format!("expected a {} argument", type_name(info.value_type));
+                    return error_response("400 Bad Request", &msg);
+                }
+            }
+        };
+        let reply = match port.raw_rpc(name, &arg) {
+            Ok(reply) => reply,
+            Err(err) => return rpc_error_response(err),
+        };
+        let value = if reply.is_empty() {
+            "null".to_string()
+        } else if let Some(value) = info.decode(&reply) {
+            value_to_json(&value)
+        } else {
+            // Replies of unknown type are given as hex.
+            let mut hex = String::new();
+            for byte in &reply {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            jsonl::string(&hex)
+        };
+        ("200 OK", format!("{{\"value\":{}}}", value))
+    }
+
+    fn route(&mut self, method: &str, path: &str, body: &str) -> Response {
+        let path = path.split('?').next().unwrap_or(path);
+        if path == "/link" {
+            return match method {
+                "GET" => self.link(),
+                _ => error_response("405 Method Not Allowed", "method not allowed"),
+            };
+        }
+        let Some(rest) = path.strip_prefix("/devices") else {
+            return error_response("404 Not Found", "not found");
+        };
+        let rest = rest.trim_end_matches('/');
+        if rest.is_empty() {
+            return match method {
+                "GET" => self.devices(),
+                _ => error_response("405 Method Not Allowed", "method not allowed"),
+            };
+        }
+        let (route, target) = if let Some(route) = rest.strip_suffix("/rpcs") {
+            (route, None)
+        } else if let Some((route, name)) = rest.rsplit_once("/rpc/") {
+            (route, Some(name))
+        } else {
+            return error_response("404 Not Found", "not found");
+        };
+        let Ok(route) = DeviceRoute::from_str(route) else {
+            return error_response("400 Bad Request", "invalid route");
+        };
+        match (method, target) {
+            ("GET", None) => self.rpcs(route),
+            ("GET", Some(name)) => self.rpc(route, name, ""),
+            ("POST", Some(name)) => self.rpc(route, name, body),
+            _ => error_response("405 Method Not Allowed", "method not allowed"),
+        }
+    }
+
+    fn handle_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        let header_end = loop {
+            if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let n = stream.read(&mut buf)?;
+            if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..n]);
+        };
+        let header = String::from_utf8_lossy(&request[..header_end]).to_string();
+        let content_length = header
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        if header_end + content_length > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        while request.len() < header_end + content_length {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let body = String::from_utf8_lossy(&request[header_end..]);
+
+        let mut words = header.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some(method), Some(path)) => self.route(method, path, &body),
+            _ => error_response("400 Bad Request", "malformed request"),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    /// Serve the API over HTTP on `addr`, from a background thread.
+    /// Requests are handled one at a time, so that RPCs from different
+    /// operators do not interleave.
+    pub fn serve<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Errors only affect the one request, so they are ignored.
+                let _ = self.handle_connection(stream);
+            }
+        }))
+    }
+}
@@ -0,0 +1,429 @@
+//! Alerts
+//!
+//! Rules watched over long unattended runs, to notify an operator when
+//! something goes wrong. A rule is either a condition on the samples of the
+//! device, such as a column out of range, a column with no valid value, or
+//! a stream whose rate dropped, or a match on the status events of the
+//! proxy, such as reconnections or protocol errors.
+//!
+//! A condition on samples raises an alert once it held for the debounce
+//! time of its rule, and clears it once it no longer holds, past the
+//! hysteresis of the rule for thresholds. An event raises a momentary
+//! alert, at most once per debounce time. Alerts are passed to callbacks
+//! and sent to subscribers:
+//! ```no_run
+//! # use twinleaf::data::{alert::{AlertEngine, Rule}, Device};
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! # use std::time::Duration;
+//! let (tx, events) = crossbeam::channel::unbounded();
+//! let proxy = proxy::Interface::builder().url("tcp://localhost").status(tx).spawn();
+//! let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! let mut alerts = AlertEngine::new();
+//! alerts.add(Rule::out_of_range("therm", 10.0, 40.0).hysteresis(0.5));
+//! alerts.add(Rule::rate_below("vector", 90.0, Duration::from_secs(5)));
+//! alerts.add(Rule::reconnects());
+//! alerts.on_alert(|alert| eprintln!("{}", alert));
+//! loop {
+//!     std::thread::sleep(Duration::from_millis(100));
+//!     for sample in device.drain() {
+//!         alerts.push_sample(&sample);
+//!     }
+//!     for event in events.try_iter() {
+//!         alerts.push_event(&event);
+//!     }
+//!     alerts.poll();
+//! }
+//! ```
+
+use super::Sample;
+use crate::tio::proxy::Event;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel;
+
+/// What a rule watches.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// A column of any stream is below `min` or above `max`.
+    OutOfRange { column: String, min: f64, max: f64 },
+    /// A column of any stream has an unknown or NaN value.
+    NotANumber { column: String },
+    /// Fewer than `rate` samples per second of the stream named `stream`
+    /// were received over the last `window`, including none at all.
+    RateBelow {
+        stream: String,
+        rate: f64,
+        window: Duration,
+    },
+    /// A status event of the proxy matched.
+    Event(fn(&Event) -> bool),
+}
+
+/// A condition to alert on, and how to alert.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    /// How long a condition must hold before the alert is raised, or the
+    /// minimum time between two alerts of an event rule.
+    pub debounce: Duration,
+    /// Margin inside the threshold by which the value must come back for
+    /// the alert to clear, so that a value hovering around it does not
+    /// raise alerts repeatedly.
+    pub hysteresis: f64,
+}
+
+impl Rule {
+    fn new(name: String, condition: Condition) -> Rule {
+        Rule {
+            name,
+            condition,
+            debounce: Duration::ZERO,
+            hysteresis: 0.0,
+        }
+    }
+
+    pub fn out_of_range(column: &str, min: f64, max: f64) -> Rule {
+        Rule::new(
+            format!("{} out of range", column),
+            Condition::OutOfRange {
+                column: column.to_string(),
+                min,
+                max,
+            },
+        )
+    }
+
+    pub fn not_a_number(column: &str) -> Rule {
+        Rule::new(
+            format!("{} invalid", column),
+            Condition::NotANumber {
+                column: column.to_string(),
+            },
+        )
+    }
+
+    pub fn rate_below(stream: &str, rate: f64, window: Duration) -> Rule {
+        Rule::new(
+            format!("{} rate low", stream),
+            Condition::RateBelow {
+                stream: stream.to_string(),
+                rate,
+                window,
+            },
+        )
+    }
+
+    pub fn on_event(name: &str, matches: fn(&Event) -> bool) -> Rule {
+        Rule::new(name.to_string(), Condition::Event(matches))
+    }
+
+    /// The sensor reconnected after the link was lost.
+    pub fn reconnects() -> Rule {
+        Rule::on_event("sensor reconnected", |event| {
+            matches!(event, Event::SensorReconnected)
+        })
+    }
+
+    /// The proxy failed to reconnect, or the link failed for good.
+    pub fn disconnects() -> Rule {
+        Rule::on_event("sensor disconnected", |event| {
+            matches!(
+                event,
+                Event::SensorDisconnected | Event::FailedToReconnect(_) | Event::FatalError(_)
+            )
+        })
+    }
+
+    pub fn protocol_errors() -> Rule {
+        Rule::on_event("protocol error", |event| {
+            matches!(event, Event::ProtocolError(_))
+        })
+    }
+
+    pub fn named(mut self, name: &str) -> Rule {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn debounce(mut self, debounce: Duration) -> Rule {
+        self.debounce = debounce;
+        self
+    }
+
+    pub fn hysteresis(mut self, hysteresis: f64) -> Rule {
+        self.hysteresis = hysteresis;
+        self
+    }
+}
+
+/// Change of the state of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertState {
+    Raised,
+    Cleared,
+    /// An event rule matched. Event alerts are never active.
+    Event,
+}
+
+/// Notification of a rule.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub rule: String,
+    pub state: AlertState,
+    /// What triggered the alert, such as the value of the column.
+    pub detail: String,
+    pub time: SystemTime,
+}
+
+impl fmt::Display for Alert {
+    /// `secs.micros STATE rule: detail`, with the Unix time of the alert.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let state = match self.state {
+            AlertState::Raised => "RAISED",
+            AlertState::Cleared => "CLEARED",
+            AlertState::Event => "EVENT",
+        };
+        write!(
+            f,
+            "{}.{:06} {} {}",
+            t.as_secs(),
+            t.subsec_micros(),
+            state,
+            self.rule
+        )?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+struct RuleState {
+    rule: Rule,
+    active: bool,
+    /// When the condition started holding, if it does.
+    since: Option<Instant>,
+    /// When an event rule last alerted.
+    last_event: Option<Instant>,
+    /// When the rule started watching, and when the samples of the stream
+    /// of a rate rule were received.
+    started: Instant,
+    received: VecDeque<Instant>,
+}
+
+impl RuleState {
+    /// Track whether the condition holds, returning the alert if the rule
+    /// changed state.
+    fn update(&mut self, holds: bool, detail: String, now: Instant) -> Option<Alert> {
+        let state = if holds {
+            let since = *self.since.get_or_insert(now);
+            if self.active || now.duration_since(since) < self.rule.debounce {
+                return None;
+            }
+            self.active = true;
+            AlertState::Raised
+        } else {
+            self.since = None;
+            if !self.active {
+                return None;
+            }
+            self.active = false;
+            AlertState::Cleared
+        };
+        Some(Alert {
+            rule: self.rule.name.clone(),
+            state,
+            detail,
+            time: SystemTime::now(),
+        })
+    }
+
+    fn check_sample(&mut self, sample: &Sample, now: Instant) -> Option<Alert> {
+        match &self.rule.condition {
+            Condition::OutOfRange { column, min, max } => {
+                let value = sample.column(column)?.value.as_f64()?;
+                let margin = if self.active {
+                    self.rule.hysteresis
+                } else {
+                    0.0
+                };
+                let holds = value < min + margin || value > max - margin;
+                self.update(holds, format!("{} = {}", column, value), now)
+            }
+            Condition::NotANumber { column } => {
+                let value = sample.column(column)?.value.as_f64();
+                let holds = value.is_none_or(f64::is_nan);
+                let detail = match value {
+                    Some(value) => format!("{} = {}", column, value),
+                    None => format!("{} unknown", column),
+                };
+                self.update(holds, detail, now)
+            }
+            Condition::RateBelow { stream, .. } => {
+                if sample.stream.name == *stream {
+                    self.received.push_back(now);
+                }
+                self.check_rate(now)
+            }
+            Condition::Event(_) => None,
+        }
+    }
+
+    fn check_rate(&mut self, now: Instant) -> Option<Alert> {
+        let Condition::RateBelow { rate, window, .. } = self.rule.condition else {
+            return None;
+        };
+        while self
+            .received
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > window)
+        {
+            self.received.pop_front();
+        }
+        // The rate is not known until a full window was watched.
+        if now.duration_since(self.started) < window {
+            return None;
+        }
+        let measured = self.received.len() as f64 / window.as_secs_f64();
+        let margin = if self.active {
+            self.rule.hysteresis
+        } else {
+            0.0
+        };
+        let holds = measured < rate + margin;
+        self.update(holds, format!("{:.2} samples/s", measured), now)
+    }
+
+    fn check_event(&mut self, event: &Event, now: Instant) -> Option<Alert> {
+        let Condition::Event(matches) = self.rule.condition else {
+            return None;
+        };
+        if !matches(event) {
+            return None;
+        }
+        if self
+            .last_event
+            .is_some_and(|last| now.duration_since(last) < self.rule.debounce)
+        {
+            return None;
+        }
+        self.last_event = Some(now);
+        Some(Alert {
+            rule: self.rule.name.clone(),
+            state: AlertState::Event,
+            detail: format!("{:?}", event),
+            time: SystemTime::now(),
+        })
+    }
+}
+
+type AlertCallback = Box<dyn FnMut(&Alert) + Send>;
+
+/// Evaluates rules on the samples and events pushed, and notifies of the
+/// alerts.
+pub struct AlertEngine {
+    rules: Vec<RuleState>,
+    callbacks: Vec<AlertCallback>,
+    subscribers: Vec<channel::Sender<Alert>>,
+}
+
+impl Default for AlertEngine {
+    fn default() -> AlertEngine {
+        AlertEngine::new()
+    }
+}
+
+impl AlertEngine {
+    pub fn new() -> AlertEngine {
+        AlertEngine {
+            rules: vec![],
+            callbacks: vec![],
+            subscribers: vec![],
+        }
+    }
+
+    pub fn add(&mut self, rule: Rule) {
+        self.rules.push(RuleState {
+            rule,
+            active: false,
+            since: None,
+            last_event: None,
+            started: Instant::now(),
+            received: VecDeque::new(),
+        });
+    }
+
+    /// Call `callback` with every alert.
+    pub fn on_alert<F: FnMut(&Alert) + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Receive every alert.
+    pub fn subscribe(&mut self) -> channel::Receiver<Alert> {
+        let (tx, rx) = channel::unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn notify(&mut self, alerts: Vec<Alert>) {
+        for alert in alerts {
+            for callback in &mut self.callbacks {
+                callback(&alert);
+            }
+            self.subscribers
+                .retain(|sub| sub.send(alert.clone()).is_ok());
+        }
+    }
+
+    /// Evaluate the rules on the samples on a sample received. Placeholders
+    /// filling gaps are not received samples, so they are ignored.
+    pub fn push_sample(&mut self, sample: &Sample) {
+        if sample.placeholder {
+            return;
+        }
+        let now = Instant::now();
+        let alerts = self
+            .rules
+            .iter_mut()
+            .filter_map(|rule| rule.check_sample(sample, now))
+            .collect();
+        self.notify(alerts);
+    }
+
+    /// Evaluate the event rules on a status event of the proxy.
+    pub fn push_event(&mut self, event: &Event) {
+        let now = Instant::now();
+        let alerts = self
+            .rules
+            .iter_mut()
+            .filter_map(|rule| rule.check_event(event, now))
+            .collect();
+        self.notify(alerts);
+    }
+
+    /// Evaluate the rate rules, which can alert without receiving any
+    /// sample. To be called regularly.
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        let alerts = self
+            .rules
+            .iter_mut()
+            .filter_map(|rule| rule.check_rate(now))
+            .collect();
+        self.notify(alerts);
+    }
+
+    /// Names of the rules whose alert is raised.
+    pub fn active(&self) -> Vec<&str> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.active)
+            .map(|rule| rule.rule.name.as_str())
+            .collect()
+    }
+}
@@ -0,0 +1,247 @@
+//! Calibration
+//!
+//! Reading and writing the calibration tables of a device, so that
+//! calibration utilities can work with named coefficients rather than raw
+//! bytes. A table `<table>` is exposed by the device with RPCs:
+//! - `<table>.read`, with the index of a chunk of the table as `u16`,
+//!   returning that chunk. The table ends with an empty chunk, a chunk
+//!   shorter than the first one, or an `InvalidArgs` error;
+//! - `<table>.write`, with the index of a chunk as `u16` followed by the
+//!   chunk. The table ends with the first chunk shorter than the others,
+//!   which can be empty;
+//! - `<table>.crc`, returning the CRC-32 of the table as `u32`, the same
+//!   as the serial framing, to check it was transferred intact.
+//!
+//! The table is a list of coefficients, each with a name of up to 255
+//! bytes, a type which is `DataType::Float32` or `DataType::Float64`, and
+//! up to 255 values. A coefficient is encoded as the length of its name,
+//! its name, its type and the number of values as `u8`, and then the
+//! values, little endian.
+//! ```no_run
+//! # use twinleaf::data::Device;
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let mut device = Device::new(proxy.device_rpc(DeviceRoute::root()).unwrap());
+//! let mut cal = device.read_calibration("cal").unwrap();
+//! println!("{:?}", cal.get("field.gain"));
+//! cal.set("field.offset", vec![0.1, -0.2, 0.05]);
+//! device.write_calibration("cal", &cal).unwrap();
+//! ```
+
+use super::Device;
+use crate::tio::proto::{DataType, RpcErrorCode};
+use crate::tio::proxy::RpcError;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+/// Size of the chunks the tables are written in.
+static CALIBRATION_CHUNK_SIZE: usize = 128;
+
+/// Failure to read or write a calibration table.
+#[derive(Debug, Clone)]
+pub enum CalibrationError {
+    /// An RPC failed.
+    Rpc(String, Box<RpcError>),
+    /// The table is larger than can be transferred in chunks.
+    TooLarge(usize),
+    /// The CRC of the table reported by the device is not the one of the
+    /// table transferred, given second.
+    ChecksumMismatch(u32, u32),
+    /// The table is not a valid list of coefficients, from this offset.
+    InvalidTable(usize),
+    /// The coefficient cannot be encoded, as its name or number of values
+    /// is too long, or its type is not a float.
+    InvalidCoefficient(String),
+}
+
+/// Named coefficient of a calibration table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coefficient {
+    pub name: String,
+    /// How the values are stored, `DataType::Float32` or `DataType::Float64`.
+    pub data_type: DataType,
+    pub values: Vec<f64>,
+}
+
+/// Calibration table of a device, with its coefficients in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CalibrationTable {
+    pub coefficients: Vec<Coefficient>,
+}
+
+impl CalibrationTable {
+    pub fn new() -> CalibrationTable {
+        CalibrationTable::default()
+    }
+
+    /// Values of the coefficient `name`, if the table has it.
+    pub fn get(&self, name: &str) -> Option<&[f64]> {
+        self.coefficients
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| &c.values[..])
+    }
+
+    /// Set the values of the coefficient `name`, keeping its type, or add
+    /// it at the end of the table as `DataType::Float32`.
+    pub fn set(&mut self, name: &str, values: Vec<f64>) {
+        match self.coefficients.iter_mut().find(|c| c.name == name) {
+            Some(coefficient) => coefficient.values = values,
+            None => self.coefficients.push(Coefficient {
+                name: name.to_string(),
+                data_type: DataType::Float32,
+                values,
+            }),
+        }
+    }
+
+    /// Decode a table read from a device.
+    pub fn decode(raw: &[u8]) -> Result<CalibrationTable, CalibrationError> {
+        let mut ret = CalibrationTable::new();
+        let mut offset = 0;
+        while offset < raw.len() {
+            let invalid = CalibrationError::InvalidTable(offset);
+            let name_len = usize::from(raw[offset]);
+            let header = raw
+                .get(offset + 1..offset + 3 + name_len)
+                .ok_or(invalid.clone())?;
+            let name = std::str::from_utf8(&header[..name_len]).map_err(|_| invalid.clone())?;
+            let data_type = DataType::from(header[name_len]);
+            let n_values = usize::from(header[name_len + 1]);
+            let size = match data_type {
+                DataType::Float32 | DataType::Float64 => data_type.size(),
+                _ => return Err(invalid),
+            };
+            let start = offset + 3 + name_len;
+            let data = raw
+                .get(start..start + n_values * size)
+                .ok_or(invalid.clone())?;
+            let values = data
+                .chunks_exact(size)
+                .map(|value| match data_type {
+                    DataType::Float32 => {
+                        f32::from_le_bytes(value.try_into().expect("4 bytes")).into()
+                    }
+                    _ => f64::from_le_bytes(value.try_into().expect("8 bytes")),
+                })
+                .collect();
+            ret.coefficients.push(Coefficient {
+                name: name.to_string(),
+                data_type,
+                values,
+            });
+            offset = start + data.len();
+        }
+        Ok(ret)
+    }
+
+    /// Encode the table to write it to a device.
+    pub fn encode(&self) -> Result<Vec<u8>, CalibrationError> {
+        let mut ret = vec![];
+        for coefficient in &self.coefficients {
+            let invalid = || CalibrationError::InvalidCoefficient(coefficient.name.clone());
+            let name_len = u8::try_from(coefficient.name.len()).map_err(|_| invalid())?;
+            let n_values = u8::try_from(coefficient.values.len()).map_err(|_| invalid())?;
+            ret.push(name_len);
+            ret.extend(coefficient.name.as_bytes());
+            ret.push(coefficient.data_type.into());
+            ret.push(n_values);
+            for value in &coefficient.values {
+                match coefficient.data_type {
+                    DataType::Float32 => ret.extend((*value as f32).to_le_bytes()),
+                    DataType::Float64 => ret.extend(value.to_le_bytes()),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// CRC of a table, as computed by the devices.
+fn calibration_crc(raw: &[u8]) -> u32 {
+    Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(raw)
+}
+
+impl Device {
+    fn calibration_rpc(&mut self, name: &str, arg: &[u8]) -> Result<Vec<u8>, CalibrationError> {
+        self.raw_rpc(name, arg)
+            .map_err(|err| CalibrationError::Rpc(name.to_string(), Box::new(err)))
+    }
+
+    /// Check `raw` against the CRC of `table` reported by the device.
+    fn check_calibration_crc(&mut self, table: &str, raw: &[u8]) -> Result<(), CalibrationError> {
+        let name = format!("{}.crc", table);
+        let reply = self.calibration_rpc(&name, &[])?;
+        let device_crc = match reply.try_into() {
+            Ok(bytes) => u32::from_le_bytes(bytes),
+            Err(_) => return Err(CalibrationError::Rpc(name, Box::new(RpcError::TypeError))),
+        };
+        let crc = calibration_crc(raw);
+        if device_crc != crc {
+            return Err(CalibrationError::ChecksumMismatch(device_crc, crc));
+        }
+        Ok(())
+    }
+
+    /// Read the calibration table `table` as is, checking its CRC.
+    pub fn read_calibration_raw(&mut self, table: &str) -> Result<Vec<u8>, CalibrationError> {
+        let name = format!("{}.read", table);
+        let mut ret = vec![];
+        let mut chunk_size = None;
+        for index in 0..=u16::MAX {
+            let chunk = match self.raw_rpc(&name, &index.to_le_bytes()) {
+                Ok(chunk) => chunk,
+                Err(RpcError::ExecError(err)) if matches!(err.error, RpcErrorCode::InvalidArgs) => {
+                    break;
+                }
+                Err(err) => return Err(CalibrationError::Rpc(name, Box::new(err))),
+            };
+            ret.extend(&chunk);
+            let size = *chunk_size.get_or_insert(chunk.len());
+            if chunk.is_empty() || chunk.len() < size {
+                break;
+            }
+        }
+        self.check_calibration_crc(table, &ret)?;
+        Ok(ret)
+    }
+
+    /// Write the calibration table `table` as is, and check that the
+    /// device got it intact.
+    pub fn write_calibration_raw(
+        &mut self,
+        table: &str,
+        raw: &[u8],
+    ) -> Result<(), CalibrationError> {
+        let name = format!("{}.write", table);
+        // The last chunk is shorter than the others, so empty if the table
+        // is a whole number of chunks.
+        let n_chunks = raw.len() / CALIBRATION_CHUNK_SIZE + 1;
+        if n_chunks > usize::from(u16::MAX) + 1 {
+            return Err(CalibrationError::TooLarge(raw.len()));
+        }
+        for index in 0..n_chunks {
+            let start = index * CALIBRATION_CHUNK_SIZE;
+            let end = (start + CALIBRATION_CHUNK_SIZE).min(raw.len());
+            let mut arg = (index as u16).to_le_bytes().to_vec();
+            arg.extend(&raw[start..end]);
+            self.calibration_rpc(&name, &arg)?;
+        }
+        self.check_calibration_crc(table, raw)
+    }
+
+    /// Read and decode the calibration table `table`.
+    pub fn read_calibration(&mut self, table: &str) -> Result<CalibrationTable, CalibrationError> {
+        CalibrationTable::decode(&self.read_calibration_raw(table)?)
+    }
+
+    /// Encode and write the calibration table `table`.
+    pub fn write_calibration(
+        &mut self,
+        table: &str,
+        calibration: &CalibrationTable,
+    ) -> Result<(), CalibrationError> {
+        self.write_calibration_raw(table, &calibration.encode()?)
+    }
+}
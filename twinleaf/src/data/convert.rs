@@ -0,0 +1,252 @@
+//! Offline conversion
+//!
+//! Decodes the samples of a raw packet log, as written by `tio-tool log`,
+//! without the device, and writes them as CSV, one file per stream. The
+//! metadata needed to decode the samples is taken from the metadata packets
+//! of the log, or from a snapshot of the metadata of the device, as written
+//! by `tio-tool log-metadata`, for logs which do not have it:
+//! ```no_run
+//! # use twinleaf::data::convert::log_to_csv;
+//! # use std::path::Path;
+//! let meta = Path::new("meta.tio");
+//! let paths = log_to_csv(Path::new("run.tio"), Some(meta), Path::new("run")).unwrap();
+//! ```
+//!
+//! Each CSV file starts with a header of `n,time,unix_time` and the names
+//! of the columns, followed by a line per sample: its sample number, its
+//! time since the device epoch, its Unix time if the device time is
+//! referenced to Unix time (otherwise empty), and the values of its
+//! columns, empty if unknown.
+
+use super::{ColumnData, DeviceDataParser, Sample};
+use crate::tio::proto::{DeviceRoute, Packet};
+use crate::tio::rawlog::LogReader;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Decodes the samples of the devices in a log, with a parser per route.
+pub struct LogDecoder {
+    parsers: HashMap<DeviceRoute, DeviceDataParser>,
+    ignore_session: bool,
+}
+
+impl Default for LogDecoder {
+    fn default() -> LogDecoder {
+        LogDecoder::new()
+    }
+}
+
+impl LogDecoder {
+    pub fn new() -> LogDecoder {
+        LogDecoder {
+            parsers: HashMap::new(),
+            ignore_session: false,
+        }
+    }
+
+    /// Decode the samples of the device at `route` with the metadata
+    /// `packets` of a snapshot, whatever the session of the device in the
+    /// log.
+    pub fn load_metadata<'a>(
+        &mut self,
+        route: &DeviceRoute,
+        packets: impl IntoIterator<Item = &'a Packet>,
+    ) {
+        self.ignore_session = true;
+        let parser = self
+            .parsers
+            .entry(route.clone())
+            .or_insert_with(|| DeviceDataParser::new(true));
+        for pkt in packets {
+            parser.process_packet(pkt);
+        }
+    }
+
+    /// The samples decoded from a packet of the log, from the device at
+    /// the route of the packet.
+    pub fn process_packet(&mut self, pkt: &Packet) -> Vec<Sample> {
+        let ignore_session = self.ignore_session;
+        self.parsers
+            .entry(pkt.routing.clone())
+            .or_insert_with(|| DeviceDataParser::new(ignore_session))
+            .process_packet(pkt)
+    }
+}
+
+/// Field of a CSV line, quoted if needed.
+fn field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Writes the samples of a stream as CSV.
+pub struct StreamCsv<W: Write> {
+    out: W,
+    /// Names of the columns in the header, once written.
+    columns: Option<Vec<String>>,
+}
+
+impl<W: Write> StreamCsv<W> {
+    pub fn new(out: W) -> StreamCsv<W> {
+        StreamCsv { out, columns: None }
+    }
+
+    fn column_names(sample: &Sample) -> Vec<String> {
+        sample
+            .columns
+            .iter()
+            .map(|col| col.desc.name.clone())
+            .collect()
+    }
+
+    /// Whether the sample has the columns of the header, so that it can be
+    /// written. Always true before the first sample.
+    pub fn accepts(&self, sample: &Sample) -> bool {
+        self.columns
+            .as_ref()
+            .is_none_or(|columns| *columns == StreamCsv::<W>::column_names(sample))
+    }
+
+    /// Write a sample, preceded by the header for the first one. Returns an
+    /// `InvalidInput` error if the sample does not have the columns of the
+    /// header.
+    pub fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        if !self.accepts(sample) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sample columns do not match the header",
+            ));
+        }
+        if self.columns.is_none() {
+            let columns = StreamCsv::<W>::column_names(sample);
+            let mut header = "n,time,unix_time".to_string();
+            for name in &columns {
+                header.push(',');
+                header.push_str(&field(name));
+            }
+            writeln!(self.out, "{}", header)?;
+            self.columns = Some(columns);
+        }
+        let mut line = format!(
+            "{},{},{}",
+            sample.n,
+            sample.timestamp_begin(),
+            sample.unix_time().map_or(String::new(), |t| t.to_string())
+        );
+        for col in &sample.columns {
+            line.push(',');
+            match col.value {
+                ColumnData::Int(x) => line.push_str(&x.to_string()),
+                ColumnData::UInt(x) => line.push_str(&x.to_string()),
+                ColumnData::Float(x) => line.push_str(&x.to_string()),
+                ColumnData::Unknown => {}
+            }
+        }
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Path of the CSV file of the stream `stream` of the device at `route`,
+/// named after `base`, e.g. `run.vector.csv` for the root device or
+/// `run.0.vector.csv` for the device at `/0`. When the columns of a stream
+/// change, its samples continue in a new file, numbered from 2 with `part`.
+pub fn csv_path(base: &Path, route: &DeviceRoute, stream: &str, part: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    for hop in route.iter() {
+        name.push(format!(".{}", hop));
+    }
+    name.push(format!(".{}", stream.replace(['/', '\\'], "_")));
+    if part > 1 {
+        name.push(format!(".{}", part));
+    }
+    name.push(".csv");
+    PathBuf::from(name)
+}
+
+/// CSV file of a stream being written.
+struct StreamFile {
+    csv: StreamCsv<BufWriter<File>>,
+    part: usize,
+}
+
+/// Writes samples to a CSV file per stream of each device.
+pub struct CsvExporter {
+    base: PathBuf,
+    streams: HashMap<(DeviceRoute, u8), StreamFile>,
+    paths: Vec<PathBuf>,
+}
+
+impl CsvExporter {
+    /// Write the CSV files at the paths given by `csv_path` for `base`.
+    pub fn new(base: &Path) -> CsvExporter {
+        CsvExporter {
+            base: base.to_path_buf(),
+            streams: HashMap::new(),
+            paths: vec![],
+        }
+    }
+
+    /// Write a sample of the device at `route`, creating the file of its
+    /// stream as needed.
+    pub fn write(&mut self, route: &DeviceRoute, sample: &Sample) -> io::Result<()> {
+        let key = (route.clone(), sample.stream.stream_id);
+        let part = match self.streams.get_mut(&key) {
+            Some(file) if file.csv.accepts(sample) => return file.csv.write(sample),
+            Some(file) => {
+                file.csv.flush()?;
+                file.part + 1
+            }
+            None => 1,
+        };
+        let path = csv_path(&self.base, route, &sample.stream.name, part);
+        let mut csv = StreamCsv::new(BufWriter::new(File::create(&path)?));
+        csv.write(sample)?;
+        self.paths.push(path);
+        self.streams.insert(key, StreamFile { csv, part });
+        Ok(())
+    }
+
+    /// Paths of the files written so far.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for file in self.streams.values_mut() {
+            file.csv.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert the log at `log` to CSV files named after `base`, decoding the
+/// samples of the root device with the metadata snapshot at `metadata` if
+/// given. Returns the paths of the files written.
+pub fn log_to_csv(log: &Path, metadata: Option<&Path>, base: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut decoder = LogDecoder::new();
+    if let Some(metadata) = metadata {
+        let packets = LogReader::open(metadata)?.collect::<io::Result<Vec<Packet>>>()?;
+        decoder.load_metadata(&DeviceRoute::root(), &packets);
+    }
+    let mut exporter = CsvExporter::new(base);
+    for pkt in LogReader::open(log)? {
+        let pkt = pkt?;
+        for sample in decoder.process_packet(&pkt) {
+            if !sample.placeholder {
+                exporter.write(&pkt.routing, &sample)?;
+            }
+        }
+    }
+    exporter.flush()?;
+    Ok(exporter.paths().to_vec())
+}
@@ -0,0 +1,340 @@
+//! Filters
+//!
+//! Digital filtering of decoded samples, typically to remove power line
+//! interference or out of band noise before exporting data. A
+//! `FilterPipeline` is a sequence of `Filter` stages, each applied to
+//! selected columns of every stream in turn:
+//! ```
+//! # use twinleaf::data::filter::{Filter, FilterPipeline};
+//! let pipeline = FilterPipeline::new()
+//!     .stage(Filter::notch(60.0, 30.0))
+//!     .stage(Filter::lowpass(20.0).columns(&["field"]));
+//! ```
+//!
+//! Filters are designed for the sampling rate of each stream when its first
+//! sample is processed, and again whenever the stream metadata changes.
+//! Their state is kept per stream and column, so one pipeline can process
+//! the samples of all the streams of a device. Filtered columns hold float
+//! values, whatever the data type of the column.
+
+use super::{Column, ColumnData, Sample};
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// Quality factor of the second order Butterworth response.
+static BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// Type of filter, with frequencies in Hz.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterKind {
+    /// Second order Butterworth low-pass IIR filter.
+    LowPass { cutoff: f64 },
+    /// Second order Butterworth high-pass IIR filter.
+    HighPass { cutoff: f64 },
+    /// Second order IIR notch filter. The higher the quality factor `q`,
+    /// the narrower the notch.
+    Notch { freq: f64, q: f64 },
+    /// Windowed-sinc low-pass FIR filter with `taps` coefficients. Linear
+    /// phase, delaying the signal by half of its length.
+    FirLowPass { cutoff: f64, taps: usize },
+    /// FIR filter with the given coefficients.
+    Fir { coefficients: Vec<f64> },
+}
+
+/// A filter stage, applied to all columns unless restricted to some.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// Names of the columns to filter. All columns if empty.
+    pub columns: Vec<String>,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Filter {
+        Filter {
+            kind,
+            columns: vec![],
+        }
+    }
+
+    pub fn lowpass(cutoff: f64) -> Filter {
+        Filter::new(FilterKind::LowPass { cutoff })
+    }
+
+    pub fn highpass(cutoff: f64) -> Filter {
+        Filter::new(FilterKind::HighPass { cutoff })
+    }
+
+    pub fn notch(freq: f64, q: f64) -> Filter {
+        Filter::new(FilterKind::Notch { freq, q })
+    }
+
+    pub fn fir_lowpass(cutoff: f64, taps: usize) -> Filter {
+        Filter::new(FilterKind::FirLowPass { cutoff, taps })
+    }
+
+    pub fn fir(coefficients: &[f64]) -> Filter {
+        Filter::new(FilterKind::Fir {
+            coefficients: coefficients.to_vec(),
+        })
+    }
+
+    /// Only filter the columns with these names.
+    pub fn columns(mut self, names: &[&str]) -> Filter {
+        self.columns = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    fn applies_to(&self, column: &str) -> bool {
+        self.columns.is_empty() || self.columns.iter().any(|name| name == column)
+    }
+
+    /// Filter state for a sampling `rate` in Hz. Filters with frequencies
+    /// not below the Nyquist frequency pass the signal through unchanged.
+    fn design(&self, rate: f64) -> FilterState {
+        let valid = |freq: f64| freq > 0.0 && freq < rate / 2.0;
+        match self.kind {
+            FilterKind::LowPass { cutoff } if valid(cutoff) => {
+                FilterState::Biquad(Biquad::lowpass(cutoff / rate, BUTTERWORTH_Q))
+            }
+            FilterKind::HighPass { cutoff } if valid(cutoff) => {
+                FilterState::Biquad(Biquad::highpass(cutoff / rate, BUTTERWORTH_Q))
+            }
+            FilterKind::Notch { freq, q } if valid(freq) && q > 0.0 => {
+                FilterState::Biquad(Biquad::notch(freq / rate, q))
+            }
+            FilterKind::FirLowPass { cutoff, taps } if valid(cutoff) && taps > 0 => {
+                FilterState::Fir(Fir::new(windowed_sinc(cutoff / rate, taps)))
+            }
+            FilterKind::Fir { ref coefficients } if !coefficients.is_empty() => {
+                FilterState::Fir(Fir::new(coefficients.clone()))
+            }
+            _ => FilterState::PassThrough,
+        }
+    }
+}
+
+/// Second order IIR section, in transposed direct form II.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+    primed: bool,
+}
+
+impl Biquad {
+    /// Coefficients from the Audio EQ Cookbook, for a frequency relative to
+    /// the sampling rate.
+    fn from_cookbook(freq: f64, q: f64, b: impl Fn(f64) -> [f64; 3]) -> Biquad {
+        let w0 = 2.0 * PI * freq;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b = b(cos);
+        Biquad {
+            b: [b[0] / a0, b[1] / a0, b[2] / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            z: [0.0; 2],
+            primed: false,
+        }
+    }
+
+    fn lowpass(freq: f64, q: f64) -> Biquad {
+        Biquad::from_cookbook(freq, q, |cos| {
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0]
+        })
+    }
+
+    fn highpass(freq: f64, q: f64) -> Biquad {
+        Biquad::from_cookbook(freq, q, |cos| {
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0]
+        })
+    }
+
+    fn notch(freq: f64, q: f64) -> Biquad {
+        Biquad::from_cookbook(freq, q, |cos| [1.0, -2.0 * cos, 1.0])
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let [b0, b1, b2] = self.b;
+        let [a1, a2] = self.a;
+        if !self.primed {
+            // Start in the steady state for a constant input, to avoid a
+            // transient at the beginning of the data.
+            let y = x * (b0 + b1 + b2) / (1.0 + a1 + a2);
+            self.z = [y - b0 * x, b2 * x - a2 * y];
+            self.primed = true;
+        }
+        let y = b0 * x + self.z[0];
+        self.z[0] = b1 * x - a1 * y + self.z[1];
+        self.z[1] = b2 * x - a2 * y;
+        y
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Fir {
+    coefficients: Vec<f64>,
+    history: Vec<f64>,
+    next: usize,
+}
+
+impl Fir {
+    fn new(coefficients: Vec<f64>) -> Fir {
+        Fir {
+            coefficients,
+            history: vec![],
+            next: 0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        if self.history.is_empty() {
+            // Same as the input having been constant before.
+            self.history = vec![x; self.coefficients.len()];
+        }
+        self.history[self.next] = x;
+        let n = self.history.len();
+        let y = self
+            .coefficients
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c * self.history[(self.next + n - i) % n])
+            .sum();
+        self.next = (self.next + 1) % n;
+        y
+    }
+}
+
+/// Low-pass FIR coefficients, with a Hamming window and unity DC gain.
+fn windowed_sinc(freq: f64, taps: usize) -> Vec<f64> {
+    let center = (taps - 1) as f64 / 2.0;
+    let mut coefficients: Vec<f64> = (0..taps)
+        .map(|i| {
+            let t = i as f64 - center;
+            let sinc = if t == 0.0 {
+                2.0 * freq
+            } else {
+                (2.0 * PI * freq * t).sin() / (PI * t)
+            };
+            let window = if taps > 1 {
+                0.54 - 0.46 * (2.0 * PI * i as f64 / (taps - 1) as f64).cos()
+            } else {
+                1.0
+            };
+            sinc * window
+        })
+        .collect();
+    let gain: f64 = coefficients.iter().sum();
+    for c in coefficients.iter_mut() {
+        *c /= gain;
+    }
+    coefficients
+}
+
+#[derive(Debug, Clone)]
+enum FilterState {
+    PassThrough,
+    Biquad(Biquad),
+    Fir(Fir),
+}
+
+impl FilterState {
+    fn process(&mut self, x: f64) -> f64 {
+        match self {
+            FilterState::PassThrough => x,
+            FilterState::Biquad(biquad) => biquad.process(x),
+            FilterState::Fir(fir) => fir.process(x),
+        }
+    }
+}
+
+/// Filter state of the columns of one stream.
+struct StreamFilters {
+    rate: f64,
+    /// For each column, the state of the stages applied to it.
+    columns: Vec<Vec<FilterState>>,
+}
+
+/// Sequence of filters applied to decoded samples.
+#[derive(Default)]
+pub struct FilterPipeline {
+    stages: Vec<Filter>,
+    streams: HashMap<u8, StreamFilters>,
+}
+
+impl FilterPipeline {
+    pub fn new() -> FilterPipeline {
+        FilterPipeline::default()
+    }
+
+    /// Add a stage, after the ones already added.
+    pub fn stage(mut self, filter: Filter) -> FilterPipeline {
+        self.stages.push(filter);
+        self.streams.clear();
+        self
+    }
+
+    fn stream_filters(&self, sample: &Sample) -> StreamFilters {
+        let rate = 1.0 / sample.period();
+        StreamFilters {
+            rate,
+            columns: sample
+                .columns
+                .iter()
+                .map(|col| {
+                    self.stages
+                        .iter()
+                        .filter(|stage| stage.applies_to(&col.desc.name))
+                        .map(|stage| stage.design(rate))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// Filter a sample, returning the sample with its filtered columns.
+    /// Samples must be processed in order for each stream.
+    pub fn process(&mut self, sample: &Sample) -> Sample {
+        let stream_id = sample.stream.stream_id;
+        let rate = 1.0 / sample.period();
+        let outdated = match self.streams.get(&stream_id) {
+            Some(filters) => {
+                sample.meta_changed
+                    || filters.rate != rate
+                    || filters.columns.len() != sample.columns.len()
+            }
+            None => true,
+        };
+        if outdated {
+            let filters = self.stream_filters(sample);
+            self.streams.insert(stream_id, filters);
+        }
+        let filters = self.streams.get_mut(&stream_id).unwrap();
+
+        let mut ret = sample.clone();
+        for (col, stages) in ret.columns.iter_mut().zip(filters.columns.iter_mut()) {
+            if stages.is_empty() {
+                continue;
+            }
+            let Some(mut value) = col.value.as_f64() else {
+                continue;
+            };
+            for stage in stages.iter_mut() {
+                value = stage.process(value);
+            }
+            *col = Column {
+                value: ColumnData::Float(value),
+                desc: col.desc.clone(),
+            };
+        }
+        ret
+    }
+
+    /// Forget the state of all filters, as when the data is discontinuous.
+    pub fn reset(&mut self) {
+        self.streams.clear();
+    }
+}
@@ -0,0 +1,277 @@
+//! Gradiometer
+//!
+//! Differential processing of two sensors in a gradiometer configuration.
+//! A `Gradiometer` receives decoded samples from two devices in the tree,
+//! matches them in time, applies a per-sensor linear calibration to
+//! selected columns, and computes their difference or ratio.
+//!
+//! The output is a synthetic `Sample` stream with its own stream and
+//! column metadata, so anything consuming device samples can consume
+//! gradiometer samples as well.
+
+use super::{Column, ColumnData, Sample};
+use crate::tio::proto::meta::{ColumnMetadata, StreamMetadata};
+use crate::tio::proto::{DataType, DeviceRoute};
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Default stream id used for the synthetic gradiometer stream. Device stream
+/// ids are small, so this is very unlikely to collide with a real stream.
+pub static GRADIOMETER_STREAM_ID: u8 = 0xF0;
+
+/// Maximum number of unmatched samples retained per sensor. If one side
+/// stops producing data, older samples from the other side are discarded.
+static MAX_PENDING_SAMPLES: usize = 1024;
+
+/// Operation combining the two calibrated values of a `ColumnPair`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairOp {
+    /// `a - b`
+    Difference,
+    /// `a / b`
+    Ratio,
+}
+
+/// Linear calibration applied to a raw column value: `gain * value + offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub gain: f64,
+    pub offset: f64,
+}
+
+impl Calibration {
+    pub fn new(gain: f64, offset: f64) -> Calibration {
+        Calibration { gain, offset }
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        self.gain * value + self.offset
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration::new(1.0, 0.0)
+    }
+}
+
+fn calibrated_value(sample: &Sample, column: &str, cal: &Calibration) -> f64 {
+    match sample.column(column).and_then(|col| col.value.as_f64()) {
+        Some(v) => cal.apply(v),
+        None => f64::NAN,
+    }
+}
+
+/// A pair of matched columns, one from each sensor, producing one output column.
+#[derive(Debug, Clone)]
+pub struct ColumnPair {
+    /// Name of the output column.
+    pub name: String,
+    /// Column name on the first sensor.
+    pub column_a: String,
+    /// Column name on the second sensor.
+    pub column_b: String,
+    pub op: PairOp,
+    pub cal_a: Calibration,
+    pub cal_b: Calibration,
+}
+
+impl ColumnPair {
+    pub fn new(name: &str, column_a: &str, column_b: &str, op: PairOp) -> ColumnPair {
+        ColumnPair {
+            name: name.to_string(),
+            column_a: column_a.to_string(),
+            column_b: column_b.to_string(),
+            op,
+            cal_a: Calibration::default(),
+            cal_b: Calibration::default(),
+        }
+    }
+
+    /// Output `column_a - column_b`.
+    pub fn difference(name: &str, column_a: &str, column_b: &str) -> ColumnPair {
+        Self::new(name, column_a, column_b, PairOp::Difference)
+    }
+
+    /// Output `column_a / column_b`.
+    pub fn ratio(name: &str, column_a: &str, column_b: &str) -> ColumnPair {
+        Self::new(name, column_a, column_b, PairOp::Ratio)
+    }
+
+    /// Set the calibration of each sensor's column.
+    pub fn calibrated(mut self, cal_a: Calibration, cal_b: Calibration) -> ColumnPair {
+        self.cal_a = cal_a;
+        self.cal_b = cal_b;
+        self
+    }
+
+    /// Compute the output value. Missing or non-numeric inputs yield NaN.
+    fn compute(&self, a: &Sample, b: &Sample) -> f64 {
+        let va = calibrated_value(a, &self.column_a, &self.cal_a);
+        let vb = calibrated_value(b, &self.column_b, &self.cal_b);
+        match self.op {
+            PairOp::Difference => va - vb,
+            PairOp::Ratio => va / vb,
+        }
+    }
+
+    fn metadata(&self, stream_id: u8, index: usize, reference: &Sample) -> ColumnMetadata {
+        let units = match (self.op, reference.column(&self.column_a)) {
+            (PairOp::Difference, Some(col)) => col.desc.units.clone(),
+            _ => "".to_string(),
+        };
+        let symbol = match self.op {
+            PairOp::Difference => "-",
+            PairOp::Ratio => "/",
+        };
+        ColumnMetadata {
+            stream_id,
+            index,
+            data_type: DataType::Float64,
+            name: self.name.clone(),
+            units,
+            description: format!("{} {} {}", self.column_a, symbol, self.column_b),
+        }
+    }
+}
+
+/// Pipeline stage combining the same stream from two sensors.
+pub struct Gradiometer {
+    route_a: DeviceRoute,
+    route_b: DeviceRoute,
+    stream_id: u8,
+    pairs: Vec<ColumnPair>,
+    name: String,
+    output_stream_id: u8,
+
+    pending_a: VecDeque<Sample>,
+    pending_b: VecDeque<Sample>,
+    stream: Option<Arc<StreamMetadata>>,
+    columns: Vec<Arc<ColumnMetadata>>,
+    dropped: u64,
+}
+
+impl Gradiometer {
+    /// Combine `stream_id` from the devices at `route_a` and `route_b`,
+    /// producing one output column per entry in `pairs`.
+    pub fn new(
+        route_a: DeviceRoute,
+        route_b: DeviceRoute,
+        stream_id: u8,
+        pairs: Vec<ColumnPair>,
+    ) -> Gradiometer {
+        Gradiometer {
+            route_a,
+            route_b,
+            stream_id,
+            pairs,
+            name: "gradiometer".to_string(),
+            output_stream_id: GRADIOMETER_STREAM_ID,
+            pending_a: VecDeque::new(),
+            pending_b: VecDeque::new(),
+            stream: None,
+            columns: vec![],
+            dropped: 0,
+        }
+    }
+
+    /// Change the name and id of the synthetic output stream.
+    pub fn with_output_stream(mut self, name: &str, stream_id: u8) -> Gradiometer {
+        self.name = name.to_string();
+        self.output_stream_id = stream_id;
+        self.stream = None;
+        self
+    }
+
+    /// Number of samples discarded because no matching sample was found on
+    /// the other sensor.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Feed a sample received from the device at `route`. Samples from other
+    /// routes or streams are ignored. Returns any gradiometer samples that
+    /// became available.
+    pub fn process_sample(&mut self, route: &DeviceRoute, sample: &Sample) -> Vec<Sample> {
+        if sample.stream.stream_id != self.stream_id {
+            return vec![];
+        }
+        let queue = if *route == self.route_a {
+            &mut self.pending_a
+        } else if *route == self.route_b {
+            &mut self.pending_b
+        } else {
+            return vec![];
+        };
+        queue.push_back(sample.clone());
+        if queue.len() > MAX_PENDING_SAMPLES {
+            queue.pop_front();
+            self.dropped += 1;
+        }
+
+        let mut ret = vec![];
+        while let (Some(a), Some(b)) = (self.pending_a.front(), self.pending_b.front()) {
+            let (ta, tb) = (a.timestamp_begin(), b.timestamp_begin());
+            // Samples are considered simultaneous if they are within
+            // half a sample period of each other.
+            let tolerance = a.period() / 2.0;
+            if (ta - tb).abs() <= tolerance {
+                let a = self.pending_a.pop_front().expect("front exists");
+                let b = self.pending_b.pop_front().expect("front exists");
+                ret.push(self.combine(&a, &b));
+            } else if ta < tb {
+                self.pending_a.pop_front();
+                self.dropped += 1;
+            } else {
+                self.pending_b.pop_front();
+                self.dropped += 1;
+            }
+        }
+        ret
+    }
+
+    fn combine(&mut self, a: &Sample, b: &Sample) -> Sample {
+        let meta_changed = match &self.stream {
+            Some(stream) => stream.n_segments != a.stream.n_segments,
+            None => true,
+        };
+        if meta_changed {
+            self.stream = Some(Arc::new(StreamMetadata {
+                stream_id: self.output_stream_id,
+                name: self.name.clone(),
+                n_columns: self.pairs.len(),
+                n_segments: a.stream.n_segments,
+                sample_size: self.pairs.len() * DataType::Float64.size(),
+                buf_samples: a.stream.buf_samples,
+            }));
+            self.columns = self
+                .pairs
+                .iter()
+                .enumerate()
+                .map(|(i, pair)| Arc::new(pair.metadata(self.output_stream_id, i, a)))
+                .collect();
+        }
+
+        Sample {
+            n: a.n,
+            columns: self
+                .pairs
+                .iter()
+                .zip(self.columns.iter())
+                .map(|(pair, desc)| Column {
+                    value: ColumnData::Float(pair.compute(a, b)),
+                    desc: desc.clone(),
+                })
+                .collect(),
+            segment: a.segment.clone(),
+            stream: self.stream.as_ref().expect("stream metadata").clone(),
+            device: a.device.clone(),
+            segment_changed: a.segment_changed || meta_changed,
+            meta_changed,
+            placeholder: a.placeholder || b.placeholder,
+            // Complete once the later of the two was received.
+            received: a.received.max(b.received),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! History
+//!
+//! In-memory ring buffer of recent samples. A `SampleHistory` retains the
+//! samples of each stream received over the last configured duration of
+//! sample time, within an optional memory limit, and can be queried for any
+//! time range within it, for example to plot recent data or to save the data
+//! around an event. To share it between threads, wrap it in a `Mutex`.
+//!
+//! Times are in seconds, as returned by `Sample::timestamp_begin`.
+
+use super::{Column, Sample};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Approximate memory used by a sample in the history.
+fn sample_size(sample: &Sample) -> usize {
+    std::mem::size_of::<Sample>() + sample.columns.len() * std::mem::size_of::<Column>()
+}
+
+#[derive(Default)]
+struct StreamHistory {
+    samples: VecDeque<Sample>,
+    bytes: usize,
+}
+
+impl StreamHistory {
+    fn pop_front(&mut self) -> Option<Sample> {
+        let sample = self.samples.pop_front()?;
+        self.bytes -= sample_size(&sample);
+        Some(sample)
+    }
+
+    fn first_time(&self) -> Option<f64> {
+        self.samples.front().map(|s| s.timestamp_begin())
+    }
+
+    fn last_time(&self) -> Option<f64> {
+        self.samples.back().map(|s| s.timestamp_begin())
+    }
+
+    /// Index of the first sample at or after time `t`.
+    fn index_of(&self, t: f64) -> usize {
+        self.samples.partition_point(|s| s.timestamp_begin() < t)
+    }
+}
+
+/// Recent samples of the streams of a device.
+pub struct SampleHistory {
+    duration: f64,
+    max_bytes: Option<usize>,
+    bytes: usize,
+    streams: BTreeMap<u8, StreamHistory>,
+    evicted: u64,
+}
+
+impl SampleHistory {
+    /// Retain the last `duration` of samples of each stream.
+    pub fn new(duration: Duration) -> SampleHistory {
+        SampleHistory {
+            duration: duration.as_secs_f64(),
+            max_bytes: None,
+            bytes: 0,
+            streams: BTreeMap::new(),
+            evicted: 0,
+        }
+    }
+
+    /// Limit the memory used by the samples to about `max_bytes`, discarding
+    /// the oldest samples of any stream first when over the limit.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> SampleHistory {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Add a sample. Samples of each stream must be added in order; a
+    /// sample earlier than the last one of its stream, as when the device
+    /// restarts, discards the history of the stream.
+    pub fn push(&mut self, sample: &Sample) {
+        let t = sample.timestamp_begin();
+        let stream = self.streams.entry(sample.stream.stream_id).or_default();
+        if stream.last_time().is_some_and(|last| t < last) {
+            self.bytes -= stream.bytes;
+            *stream = StreamHistory::default();
+        }
+        let size = sample_size(sample);
+        stream.samples.push_back(sample.clone());
+        stream.bytes += size;
+        self.bytes += size;
+        while stream
+            .first_time()
+            .is_some_and(|first| t - first > self.duration)
+        {
+            self.bytes -= sample_size(&stream.pop_front().unwrap());
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            while self.bytes > max_bytes && self.evict_oldest() {}
+        }
+    }
+
+    /// Add several samples, as returned by `Device::drain`.
+    pub fn extend<'a>(&mut self, samples: impl IntoIterator<Item = &'a Sample>) {
+        for sample in samples {
+            self.push(sample);
+        }
+    }
+
+    /// Discard the oldest sample of any stream.
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self
+            .streams
+            .iter()
+            .filter_map(|(id, stream)| Some((*id, stream.first_time()?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((id, _)) = oldest else {
+            return false;
+        };
+        let sample = self.streams.get_mut(&id).unwrap().pop_front().unwrap();
+        self.bytes -= sample_size(&sample);
+        self.evicted += 1;
+        true
+    }
+
+    /// Samples of stream `stream_id` with a time in `[start, end)`.
+    pub fn range(&self, stream_id: u8, start: f64, end: f64) -> Vec<Sample> {
+        let Some(stream) = self.streams.get(&stream_id) else {
+            return vec![];
+        };
+        let (first, last) = (stream.index_of(start), stream.index_of(end));
+        stream
+            .samples
+            .range(first..last.max(first))
+            .cloned()
+            .collect()
+    }
+
+    /// Samples of stream `stream_id` over the last `duration` before its
+    /// latest sample.
+    pub fn latest(&self, stream_id: u8, duration: Duration) -> Vec<Sample> {
+        match self.time_span(stream_id) {
+            Some((_, last)) => self.range(stream_id, last - duration.as_secs_f64(), f64::INFINITY),
+            None => vec![],
+        }
+    }
+
+    /// Time of the first and last samples retained for stream `stream_id`.
+    pub fn time_span(&self, stream_id: u8) -> Option<(f64, f64)> {
+        let stream = self.streams.get(&stream_id)?;
+        Some((stream.first_time()?, stream.last_time()?))
+    }
+
+    /// Ids of the streams with samples retained.
+    pub fn streams(&self) -> Vec<u8> {
+        self.streams
+            .iter()
+            .filter(|(_, stream)| !stream.samples.is_empty())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Number of samples retained for stream `stream_id`.
+    pub fn len(&self, stream_id: u8) -> usize {
+        self.streams.get(&stream_id).map_or(0, |s| s.samples.len())
+    }
+
+    /// Approximate memory used by the samples retained.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of samples discarded early to stay within the memory limit.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn clear(&mut self) {
+        self.streams.clear();
+        self.bytes = 0;
+    }
+}
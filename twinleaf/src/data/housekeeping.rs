@@ -0,0 +1,336 @@
+//! Housekeeping
+//!
+//! Periodic polling of slow scalar values, such as temperatures or supply
+//! voltages, which devices expose as RPCs rather than as data streams.
+//! A `HousekeepingPoller` issues the configured RPCs at their own rates
+//! and publishes the results as a synthetic `Sample` stream, so they can
+//! be recorded and processed like any other device stream.
+//!
+//! Each tick of the poller produces one sample containing every column.
+//! Values which were not polled in a given tick keep the last value read.
+
+use super::{Column, ColumnData, Sample};
+use crate::tio::proto::meta::{
+    ColumnMetadata, DeviceMetadata, MetadataEpoch, MetadataFilter, SegmentMetadata, StreamMetadata,
+};
+use crate::tio::proto::{DataType, DeviceRoute, Payload};
+use crate::tio::{proxy, util};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default stream id used for the synthetic housekeeping stream.
+pub static HOUSEKEEPING_STREAM_ID: u8 = 0xF1;
+
+/// Sampling rate advertised in the segment metadata. The tick period is
+/// expressed as a decimation of this rate, so it has millisecond resolution.
+static HOUSEKEEPING_TIMEBASE_HZ: u32 = 1000;
+
+/// A value to poll.
+#[derive(Debug, Clone)]
+pub struct HousekeepingRpc {
+    /// Name of the RPC to call, with no argument.
+    pub rpc: String,
+    /// Name of the output column.
+    pub name: String,
+    /// Type of the RPC reply.
+    pub data_type: DataType,
+    pub units: String,
+    /// Poll once every this many ticks.
+    pub every: u32,
+}
+
+impl HousekeepingRpc {
+    /// Poll `rpc` every tick, publishing it as a column with the same name.
+    pub fn new(rpc: &str, data_type: DataType) -> HousekeepingRpc {
+        HousekeepingRpc {
+            rpc: rpc.to_string(),
+            name: rpc.to_string(),
+            data_type,
+            units: "".to_string(),
+            every: 1,
+        }
+    }
+
+    pub fn named(mut self, name: &str) -> HousekeepingRpc {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn units(mut self, units: &str) -> HousekeepingRpc {
+        self.units = units.to_string();
+        self
+    }
+
+    /// Poll once every `ticks` ticks instead of every tick.
+    pub fn every(mut self, ticks: u32) -> HousekeepingRpc {
+        self.every = ticks.max(1);
+        self
+    }
+}
+
+struct PolledValue {
+    spec: HousekeepingRpc,
+    value: ColumnData,
+}
+
+struct PendingTick {
+    n: u32,
+    outstanding: HashMap<u16, usize>,
+}
+
+/// Polls a set of RPCs on a device and produces housekeeping samples.
+pub struct HousekeepingPoller {
+    port: proxy::Port,
+    period: Duration,
+    values: Vec<PolledValue>,
+    device: Arc<DeviceMetadata>,
+    name: String,
+    stream_id: u8,
+
+    start: Option<Instant>,
+    next_tick: u32,
+    pending: Option<PendingTick>,
+    next_rpc_id: u16,
+    segment: Option<Arc<SegmentMetadata>>,
+    stream: Option<Arc<StreamMetadata>>,
+    columns: Vec<Arc<ColumnMetadata>>,
+    meta_changed: bool,
+    errors: u64,
+    overruns: u64,
+}
+
+impl HousekeepingPoller {
+    /// Poll the device reachable through `port` (typically obtained from
+    /// `device_rpc`) once every `period`.
+    pub fn new(port: proxy::Port, period: Duration) -> HousekeepingPoller {
+        HousekeepingPoller {
+            port,
+            period,
+            values: vec![],
+            device: Arc::new(DeviceMetadata {
+                serial_number: "".to_string(),
+                firmware_hash: "".to_string(),
+                n_streams: 1,
+                session_id: 0,
+                name: "".to_string(),
+            }),
+            name: "housekeeping".to_string(),
+            stream_id: HOUSEKEEPING_STREAM_ID,
+            start: None,
+            next_tick: 0,
+            pending: None,
+            next_rpc_id: 1,
+            segment: None,
+            stream: None,
+            columns: vec![],
+            meta_changed: true,
+            errors: 0,
+            overruns: 0,
+        }
+    }
+
+    /// Add a value to poll.
+    pub fn add(&mut self, rpc: HousekeepingRpc) {
+        self.values.push(PolledValue {
+            spec: rpc,
+            value: ColumnData::Unknown,
+        });
+        self.stream = None;
+    }
+
+    /// Attach the metadata of the polled device to the generated samples.
+    pub fn with_device(mut self, device: Arc<DeviceMetadata>) -> HousekeepingPoller {
+        self.device = device;
+        self
+    }
+
+    /// Change the name and id of the synthetic output stream.
+    pub fn with_output_stream(mut self, name: &str, stream_id: u8) -> HousekeepingPoller {
+        self.name = name.to_string();
+        self.stream_id = stream_id;
+        self.stream = None;
+        self.segment = None;
+        self
+    }
+
+    /// Number of RPCs which failed or returned a reply of the wrong size.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Number of ticks skipped because the previous tick's RPCs were still
+    /// outstanding.
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    /// Time at which `poll` next has work to do, other than processing replies.
+    pub fn next_deadline(&self) -> Instant {
+        match self.start {
+            Some(start) => start + self.period * self.next_tick,
+            None => Instant::now(),
+        }
+    }
+
+    /// Issue any RPCs that are due and process the replies received so
+    /// far, without blocking. Returns the samples for completed ticks.
+    pub fn poll(&mut self) -> Result<Vec<Sample>, Box<proxy::RpcError>> {
+        let mut ret = vec![];
+        self.process_replies(&mut ret)?;
+
+        let now = Instant::now();
+        let start = *self.start.get_or_insert(now);
+        while now >= start + self.period * self.next_tick {
+            let n = self.next_tick;
+            self.next_tick += 1;
+            if self.pending.is_some() {
+                self.overruns += 1;
+                continue;
+            }
+            self.start_tick(n)?;
+            self.finish_tick(&mut ret);
+        }
+        Ok(ret)
+    }
+
+    fn start_tick(&mut self, n: u32) -> Result<(), Box<proxy::RpcError>> {
+        self.update_metadata();
+        let mut outstanding = HashMap::new();
+        for (i, val) in self.values.iter().enumerate() {
+            if !n.is_multiple_of(val.spec.every) {
+                continue;
+            }
+            let id = self.next_rpc_id;
+            // Skip zero, which blocking RPCs on the same port use.
+            self.next_rpc_id = self.next_rpc_id.wrapping_add(1).max(1);
+            let req =
+                util::PacketBuilder::make_rpc_request(&val.spec.rpc, &[], id, DeviceRoute::root());
+            if let Err(err) = self.port.send(req) {
+                return Err(Box::new(proxy::RpcError::SendFailed(err)));
+            }
+            outstanding.insert(id, i);
+        }
+        self.pending = Some(PendingTick { n, outstanding });
+        Ok(())
+    }
+
+    fn process_replies(&mut self, samples: &mut Vec<Sample>) -> Result<(), Box<proxy::RpcError>> {
+        loop {
+            let pkt = match self.port.try_recv() {
+                Ok(pkt) => pkt,
+                Err(proxy::RecvError::WouldBlock) => break,
+                Err(err) => return Err(Box::new(proxy::RpcError::RecvFailed(err))),
+            };
+            let pending = if let Some(pending) = &mut self.pending {
+                pending
+            } else {
+                continue;
+            };
+            match pkt.payload {
+                Payload::RpcReply(rep) => {
+                    if let Some(i) = pending.outstanding.remove(&rep.id) {
+                        let val = &mut self.values[i];
+                        val.value = if rep.reply.len() == val.spec.data_type.size() {
+                            Column::from_le_bytes(&rep.reply, self.columns[i].clone()).value
+                        } else {
+                            self.errors += 1;
+                            ColumnData::Unknown
+                        };
+                    }
+                }
+                Payload::RpcError(err) => {
+                    if let Some(i) = pending.outstanding.remove(&err.id) {
+                        self.values[i].value = ColumnData::Unknown;
+                        self.errors += 1;
+                    }
+                }
+                _ => {}
+            }
+            self.finish_tick(samples);
+        }
+        Ok(())
+    }
+
+    fn finish_tick(&mut self, samples: &mut Vec<Sample>) {
+        let n = match &self.pending {
+            Some(pending) if pending.outstanding.is_empty() => pending.n,
+            _ => return,
+        };
+        self.pending = None;
+        samples.push(self.make_sample(n));
+    }
+
+    fn update_metadata(&mut self) {
+        if self.segment.is_none() {
+            let start_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs() as u32)
+                .unwrap_or(0);
+            self.segment = Some(Arc::new(SegmentMetadata {
+                stream_id: self.stream_id,
+                segment_id: 0,
+                flags: 0x03,
+                time_ref_epoch: MetadataEpoch::Unix,
+                time_ref_serial: self.device.serial_number.clone(),
+                time_ref_session_id: self.device.session_id,
+                start_time,
+                sampling_rate: HOUSEKEEPING_TIMEBASE_HZ,
+                decimation: (self.period.as_millis() as u32).max(1),
+                filter_cutoff: 0.0,
+                filter_type: MetadataFilter::Unfiltered,
+            }));
+            self.meta_changed = true;
+        }
+        if self.stream.is_none() {
+            self.stream = Some(Arc::new(StreamMetadata {
+                stream_id: self.stream_id,
+                name: self.name.clone(),
+                n_columns: self.values.len(),
+                n_segments: 1,
+                sample_size: self.values.iter().map(|v| v.spec.data_type.size()).sum(),
+                buf_samples: 0,
+            }));
+            self.columns = self
+                .values
+                .iter()
+                .enumerate()
+                .map(|(index, val)| {
+                    Arc::new(ColumnMetadata {
+                        stream_id: self.stream_id,
+                        index,
+                        data_type: val.spec.data_type,
+                        name: val.spec.name.clone(),
+                        units: val.spec.units.clone(),
+                        description: format!("housekeeping: {}", val.spec.rpc),
+                    })
+                })
+                .collect();
+            self.meta_changed = true;
+        }
+    }
+
+    fn make_sample(&mut self, n: u32) -> Sample {
+        let meta_changed = std::mem::replace(&mut self.meta_changed, false);
+        Sample {
+            n,
+            columns: self
+                .values
+                .iter()
+                .zip(self.columns.iter())
+                .map(|(val, desc)| Column {
+                    value: val.value.clone(),
+                    desc: desc.clone(),
+                })
+                .collect(),
+            segment: self.segment.as_ref().expect("segment metadata").clone(),
+            stream: self.stream.as_ref().expect("stream metadata").clone(),
+            device: self.device.clone(),
+            segment_changed: meta_changed,
+            meta_changed,
+            placeholder: false,
+            received: None,
+        }
+    }
+}
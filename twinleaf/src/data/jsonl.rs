@@ -0,0 +1,114 @@
+//! JSON lines
+//!
+//! Writes samples, or raw packets, as one JSON object per line, so that
+//! shell pipelines and programs in other languages can consume live sensor
+//! data without linking this crate. A sample looks like:
+//! ```json
+//! {"device":"SN123","stream":1,"stream_name":"vector","segment":0,"n":42,"time":0.42,"unix_time":null,"columns":{"x":1.5,"y":-0.25}}
+//! ```
+//! where `time` is since the device epoch and `unix_time` is given if the
+//! device time is referenced to Unix time. Placeholder samples filling gaps
+//! have `"placeholder":true` and null values. A packet looks like:
+//! ```json
+//! {"route":"/0","ttl":0,"type":"StreamData","data":"0a0010..."}
+//! ```
+//! with `data` the packet as sent on the wire, in hex.
+
+use super::{ColumnData, Sample};
+use crate::tio::proto::Packet;
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// `s` as a JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(ret, "\\u{:04x}", c as u32);
+            }
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// `x` as a JSON number, or null if it is not finite, which JSON cannot
+/// represent.
+pub(crate) fn number(x: f64) -> String {
+    if x.is_finite() {
+        format!("{}", x)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// The sample as a JSON object, without a trailing newline.
+pub fn sample_to_json(sample: &Sample) -> String {
+    let mut ret = format!(
+        "{{\"device\":{},\"stream\":{},\"stream_name\":{},\"segment\":{},\"n\":{},\"time\":{},\"unix_time\":{}",
+        string(&sample.device.serial_number),
+        sample.stream.stream_id,
+        string(&sample.stream.name),
+        sample.segment.segment_id,
+        sample.n,
+        number(sample.timestamp_begin()),
+        sample.unix_time().map_or("null".to_string(), number),
+    );
+    if sample.placeholder {
+        ret += ",\"placeholder\":true";
+    }
+    ret += ",\"columns\":{";
+    for (i, col) in sample.columns.iter().enumerate() {
+        let value = match col.value {
+            ColumnData::Int(x) => x.to_string(),
+            ColumnData::UInt(x) => x.to_string(),
+            ColumnData::Float(x) => number(x),
+            ColumnData::Unknown => "null".to_string(),
+        };
+        let sep = if i == 0 { "" } else { "," };
+        let _ = write!(ret, "{}{}:{}", sep, string(&col.desc.name), value);
+    }
+    ret += "}}";
+    ret
+}
+
+/// The packet as a JSON object, without a trailing newline. Packets which
+/// cannot be serialized have no `data`.
+pub fn packet_to_json(pkt: &Packet) -> String {
+    let desc = format!("{:?}", pkt.payload);
+    let kind = desc.split('(').next().unwrap_or(&desc);
+    let mut ret = format!(
+        "{{\"route\":{},\"ttl\":{},\"type\":{}",
+        string(&pkt.routing.to_string()),
+        pkt.ttl,
+        string(kind),
+    );
+    if let Ok(raw) = pkt.serialize() {
+        ret += ",\"data\":\"";
+        for byte in raw {
+            let _ = write!(ret, "{:02x}", byte);
+        }
+        ret += "\"";
+    }
+    ret += "}";
+    ret
+}
+
+/// Write the sample as a line of JSON.
+pub fn write_sample<W: Write>(mut out: W, sample: &Sample) -> io::Result<()> {
+    writeln!(out, "{}", sample_to_json(sample))
+}
+
+/// Write the packet as a line of JSON.
+pub fn write_packet<W: Write>(mut out: W, pkt: &Packet) -> io::Result<()> {
+    writeln!(out, "{}", packet_to_json(pkt))
+}
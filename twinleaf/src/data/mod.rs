@@ -1,3 +1,18 @@
+pub mod alert;
+pub mod calibration;
+pub mod convert;
+pub mod filter;
+pub mod gradiometer;
+pub mod history;
+pub mod housekeeping;
+pub mod jsonl;
+pub mod monitor;
+pub mod session;
+pub mod settings;
+pub mod stats;
+pub mod timebase;
+pub mod trigger;
+
 use super::tio;
 use proto::DeviceRoute;
 use tio::{proto, proxy, util};
@@ -138,6 +153,18 @@ pub enum ColumnData {
     Unknown,
 }
 
+impl ColumnData {
+    /// Numeric value as a float, if known.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ColumnData::Int(x) => Some(*x as f64),
+            ColumnData::UInt(x) => Some(*x as f64),
+            ColumnData::Float(x) => Some(*x),
+            ColumnData::Unknown => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Column {
     pub value: ColumnData,
@@ -202,6 +229,11 @@ pub struct Sample {
     pub device: Arc<DeviceMetadata>,
     pub segment_changed: bool,
     pub meta_changed: bool,
+    /// The sample was not received from the device: it fills a gap in the
+    /// sample numbers, with NaN values, see `DeviceDataParser::fill_gaps`.
+    pub placeholder: bool,
+    /// When the packet of the sample was received by the host.
+    pub received: Option<proto::ReceiveTime>,
 }
 
 impl Sample {
@@ -215,8 +247,76 @@ impl Sample {
             1.0 / f64::from(self.segment.sampling_rate) * f64::from(self.segment.decimation);
         f64::from(self.segment.start_time) + period * f64::from(self.n + 1)
     }
+
+    /// Timebase of the sample, from its segment.
+    pub fn timebase(&self) -> timebase::Timebase {
+        timebase::Timebase::from_segment(&self.segment)
+    }
+
+    /// Unix time at which the sample was taken, in seconds, if the device
+    /// time is referenced to Unix time.
+    pub fn unix_time(&self) -> Option<f64> {
+        self.timebase().unix_time(self.n)
+    }
+
+    /// Absolute time at which the sample was taken, if the device time is
+    /// referenced to Unix time.
+    pub fn system_time(&self) -> Option<std::time::SystemTime> {
+        self.timebase().system_time(self.n)
+    }
+
+    /// Time between consecutive samples, in seconds.
+    pub fn period(&self) -> f64 {
+        1.0 / f64::from(self.segment.sampling_rate) * f64::from(self.segment.decimation)
+    }
+
+    /// Look up a column by name.
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|col| col.desc.name == name)
+    }
+}
+
+/// Samples lost in a stream, found from jumps in the sample numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub segment_id: u8,
+    /// Number of the first missing sample.
+    pub first: u32,
+    /// Number of missing samples.
+    pub count: u32,
+}
+
+/// Integrity of the data received from a stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GapStats {
+    /// Samples received.
+    pub received: u64,
+    /// Samples missing between received samples.
+    pub missing: u64,
+    /// Number of gaps the missing samples were in.
+    pub gaps: u64,
+    /// Times the sample numbers went back within a segment, so that
+    /// missing samples could not be counted.
+    pub resets: u64,
+    pub last_gap: Option<Gap>,
+}
+
+impl GapStats {
+    /// Fraction of the samples which went missing.
+    pub fn loss(&self) -> f64 {
+        let total = self.received + self.missing;
+        if total == 0 {
+            0.0
+        } else {
+            self.missing as f64 / total as f64
+        }
+    }
 }
 
+/// Largest gap filled with placeholder samples. Larger gaps are only
+/// counted, as they are more likely a device reset than lost data.
+static MAX_FILLED_GAP: u32 = 100_000;
+
 #[derive(Debug)]
 pub struct DeviceStreamMetadata {
     pub stream: Arc<StreamMetadata>,
@@ -239,6 +339,8 @@ struct DeviceStream {
     id: u8,
     last_seg: u8,
     last_sample_number: u32,
+    /// Segment and number of the sample expected next.
+    next_sample: Option<(u8, u32)>,
     segment_changed: bool,
     meta_changed: bool,
 }
@@ -277,10 +379,68 @@ impl DeviceStream {
         ret
     }
 
+    fn make_sample(
+        &mut self,
+        n: u32,
+        columns: Vec<Column>,
+        segment: &Arc<SegmentMetadata>,
+        stream: &Arc<StreamMetadata>,
+        dev: &Arc<DeviceMetadata>,
+        placeholder: bool,
+    ) -> Sample {
+        let sample = Sample {
+            n,
+            columns,
+            segment: segment.clone(),
+            stream: stream.clone(),
+            device: dev.clone(),
+            segment_changed: self.segment_changed,
+            meta_changed: self.meta_changed,
+            placeholder,
+            received: None,
+        };
+        self.segment_changed = false;
+        self.meta_changed = false;
+        sample
+    }
+
+    /// Account for the samples missing before `first`, returning how many
+    /// placeholders to insert for them.
+    fn check_gap(&mut self, segment_id: u8, first: u32, stats: &mut GapStats, fill: bool) -> u32 {
+        let Some((seg, next)) = self.next_sample else {
+            return 0;
+        };
+        if seg != segment_id {
+            return 0;
+        }
+        if first < next {
+            stats.resets += 1;
+            return 0;
+        }
+        let count = first - next;
+        if count == 0 {
+            return 0;
+        }
+        stats.missing += u64::from(count);
+        stats.gaps += 1;
+        stats.last_gap = Some(Gap {
+            segment_id,
+            first: next,
+            count,
+        });
+        if fill && count <= MAX_FILLED_GAP {
+            count
+        } else {
+            0
+        }
+    }
+
     fn process_samples(
         &mut self,
         data: &tio::proto::StreamDataPayload,
         dev: Arc<DeviceMetadata>,
+        stats: &mut GapStats,
+        fill_gaps: bool,
     ) -> Vec<Sample> {
         // Update this first, so even if we can't parse the sample, the right
         // request will be sent out next
@@ -321,26 +481,35 @@ impl DeviceStream {
         let segment = self.segment.as_ref().unwrap().clone();
 
         let mut ret = vec![];
+        let missing = self.check_gap(segment.segment_id, data.first_sample_n, stats, fill_gaps);
+        for n in (data.first_sample_n - missing)..data.first_sample_n {
+            let columns = self
+                .columns
+                .iter()
+                .map(|col| Column {
+                    value: ColumnData::Float(f64::NAN),
+                    desc: col.metadata.clone(),
+                })
+                .collect();
+            ret.push(self.make_sample(n, columns, &segment, &stream, &dev, true));
+        }
+
         let mut sample_n = data.first_sample_n;
         let mut offset = 0;
 
         // TODO: validate size
         while offset < data.data.len() {
             let raw_sample = &data.data[offset..(offset + stream.sample_size)];
-            ret.push(Sample {
-                n: sample_n,
-                columns: self.parse_sample(raw_sample),
-                segment: segment.clone(),
-                stream: stream.clone(),
-                device: dev.clone(),
-                segment_changed: self.segment_changed,
-                meta_changed: self.meta_changed,
-            });
-            self.segment_changed = false;
-            self.meta_changed = false;
+            let columns = self.parse_sample(raw_sample);
+            ret.push(self.make_sample(sample_n, columns, &segment, &stream, &dev, false));
+            stats.received += 1;
             offset += stream.sample_size;
             sample_n += 1;
         }
+        if sample_n != data.first_sample_n {
+            self.last_sample_number = sample_n - 1;
+        }
+        self.next_sample = Some((segment.segment_id, sample_n));
 
         ret
     }
@@ -365,10 +534,33 @@ pub struct DeviceFullMetadata {
     pub streams: HashMap<u8, DeviceStreamMetadata>,
 }
 
+impl DeviceFullMetadata {
+    /// Metadata update packets announcing all of the metadata, from which
+    /// a `DeviceDataParser` can decode the samples of the device, e.g. to
+    /// snapshot it into a log. The packets are routed to the root device.
+    pub fn make_updates(&self) -> Vec<tio::Packet> {
+        let mut ret = vec![self.device.make_update()];
+        let mut ids: Vec<&u8> = self.streams.keys().collect();
+        ids.sort();
+        for id in ids {
+            let stream = &self.streams[id];
+            ret.push(stream.stream.make_update());
+            ret.push(stream.segment.make_update());
+            ret.extend(stream.columns.iter().map(|col| col.make_update()));
+        }
+        ret
+    }
+}
+
 pub struct DeviceDataParser {
     device: Option<Arc<DeviceMetadata>>,
     streams: HashMap<u8, DeviceStream>,
     ignore_session: bool,
+    /// Kept across metadata reloads.
+    gaps: HashMap<u8, GapStats>,
+    fill_gaps: bool,
+    /// Timebases announced by legacy devices, by id.
+    legacy_timebases: HashMap<u16, timebase::Timebase>,
 }
 
 impl DeviceDataParser {
@@ -377,9 +569,35 @@ impl DeviceDataParser {
             device: None,
             streams: HashMap::new(),
             ignore_session: ignore_session,
+            gaps: HashMap::new(),
+            fill_gaps: false,
+            legacy_timebases: HashMap::new(),
         }
     }
 
+    /// Current timebase of a stream, from its segment metadata.
+    pub fn timebase(&self, stream_id: u8) -> Option<timebase::Timebase> {
+        let segment = self.streams.get(&stream_id)?.segment.as_ref()?;
+        Some(timebase::Timebase::from_segment(segment))
+    }
+
+    /// Latest timebase announced by a legacy device with id `id`.
+    pub fn legacy_timebase(&self, id: u16) -> Option<&timebase::Timebase> {
+        self.legacy_timebases.get(&id)
+    }
+
+    /// Insert placeholder samples, with NaN values, for the samples missing
+    /// from streams, so that data loss shows in the output. Disabled by
+    /// default.
+    pub fn fill_gaps(&mut self, fill: bool) {
+        self.fill_gaps = fill;
+    }
+
+    /// Missing sample statistics of each stream seen so far.
+    pub fn gap_stats(&self) -> &HashMap<u8, GapStats> {
+        &self.gaps
+    }
+
     fn get_stream<'a>(&'a mut self, stream_id: u8) -> &'a mut DeviceStream {
         if !self.streams.contains_key(&stream_id) {
             self.streams.insert(
@@ -391,6 +609,7 @@ impl DeviceDataParser {
                     id: stream_id,
                     last_seg: 0,
                     last_sample_number: 0,
+                    next_sample: None,
                     segment_changed: true,
                     meta_changed: true,
                 },
@@ -506,6 +725,10 @@ impl DeviceDataParser {
                 }
             }
             tio::proto::Payload::Metadata(mp) => self.process_metadata(&mp.content, true),
+            tio::proto::Payload::LegacyTimebaseUpdate(info) => {
+                self.legacy_timebases
+                    .insert(info.id, timebase::Timebase::from_legacy(info));
+            }
             tio::proto::Payload::Heartbeat(hb) => {
                 if let tio::proto::HeartbeatPayload::Session(session_id) = hb {
                     if let Some(dev) = &self.device {
@@ -525,8 +748,16 @@ impl DeviceDataParser {
                         self.streams.clear();
                     } else {
                         let ndev = dev.clone();
+                        let fill_gaps = self.fill_gaps;
+                        let mut stats = self.gaps.remove(&data.stream_id).unwrap_or_default();
                         let dstream = self.get_stream(data.stream_id);
-                        return dstream.process_samples(data, ndev);
+                        let mut samples =
+                            dstream.process_samples(data, ndev, &mut stats, fill_gaps);
+                        self.gaps.insert(data.stream_id, stats);
+                        for sample in &mut samples {
+                            sample.received = pkt.received;
+                        }
+                        return samples;
                     }
                 }
             }
@@ -576,6 +807,124 @@ impl DeviceDataParser {
 }
 
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// RPC rebooting a device.
+static REBOOT_RPC: &str = "dev.reboot";
+
+/// Failure of `Device::reboot`.
+#[derive(Debug, Clone)]
+pub enum RebootError {
+    /// The device refused to reboot, with this error.
+    Refused(proto::RpcErrorCode),
+    /// The device did not come back, or its metadata could not be fetched,
+    /// in time.
+    Timeout,
+    /// The proxy is gone, for example because it could not reconnect.
+    ProxyDisconnected,
+}
+
+/// Failure of `Device::start_streams` or `Device::stop_streams`.
+#[derive(Debug, Clone)]
+pub enum StreamsError {
+    /// The device has no stream with this id.
+    UnknownStream(u8),
+    /// The device refused to start or stop the stream with this id, with
+    /// this error.
+    Refused(u8, proto::RpcErrorCode),
+    /// The device did not answer for these streams in time, or no data of
+    /// them was received in time after starting them.
+    Timeout(Vec<u8>),
+    /// The proxy is gone, for example because it could not reconnect.
+    ProxyDisconnected,
+}
+
+impl StreamsError {
+    fn from_rpc(id: u8, err: proxy::RpcError) -> StreamsError {
+        match err {
+            proxy::RpcError::ExecError(err) => match err.error {
+                proto::RpcErrorCode::Timeout => StreamsError::Timeout(vec![id]),
+                code => StreamsError::Refused(id, code),
+            },
+            _ => StreamsError::ProxyDisconnected,
+        }
+    }
+}
+
+/// Setting enabling the stream `name`, with 1 to start it and 0 to stop it.
+fn stream_active_rpc(name: &str) -> String {
+    format!("{}.data.active", name)
+}
+
+/// Streams started with `Device::start_streams`, which are stopped when
+/// this is dropped, including when unwinding from a panic, so that a
+/// program failing does not leave its sensors filling a slow link with
+/// data. Only aborting the process, or the proxy going away, leaves them
+/// running.
+#[must_use = "the streams are stopped when the guard is dropped"]
+pub struct StreamsGuard {
+    port: proxy::Port,
+    rpcs: Vec<String>,
+    timeout: Duration,
+}
+
+impl StreamsGuard {
+    /// Keep the streams running after the guard is dropped.
+    pub fn keep_running(mut self) {
+        self.rpcs.clear();
+    }
+}
+
+impl Drop for StreamsGuard {
+    fn drop(&mut self) {
+        // Failures are ignored, as there is nothing more to do about them.
+        set_streams_active(&self.port, &self.rpcs, false, self.timeout);
+    }
+}
+
+/// Start or stop streams with their settings, in `rpcs`. The requests are
+/// all sent before waiting for the replies, which time out after `timeout`.
+/// Returns the index of the first one which failed, with its error.
+fn set_streams_active(
+    port: &proxy::Port,
+    rpcs: &[String],
+    active: bool,
+    timeout: Duration,
+) -> Option<(usize, proxy::RpcError)> {
+    wait_streams_active(send_streams_active(port, rpcs, active, timeout))
+}
+
+/// Send the requests of `set_streams_active`, without waiting for them.
+fn send_streams_active<'a>(
+    port: &'a proxy::Port,
+    rpcs: &[String],
+    active: bool,
+    timeout: Duration,
+) -> Vec<proxy::RpcHandle<'a>> {
+    let arg = [u8::from(active)];
+    rpcs.iter()
+        .map(|rpc| port.start_rpc_with_timeout(rpc, &arg, timeout))
+        .collect()
+}
+
+/// Wait for the requests sent by `send_streams_active`, returning the index
+/// of the first one which failed, with its error.
+fn wait_streams_active(handles: Vec<proxy::RpcHandle>) -> Option<(usize, proxy::RpcError)> {
+    let mut failed = None;
+    for (i, handle) in handles.into_iter().enumerate() {
+        if let (Err(err), None) = (handle.wait(), &failed) {
+            failed = Some((i, err));
+        }
+    }
+    failed
+}
+
+/// A new value published by the device for one of its settings.
+#[derive(Debug, Clone)]
+pub struct SettingChange {
+    pub name: String,
+    pub value: Vec<u8>,
+}
 
 //#[deprecated(note = "this API is in active development and may change")]
 pub struct Device {
@@ -583,6 +932,11 @@ pub struct Device {
     parser: DeviceDataParser,
     n_reqs: usize,
     sample_queue: VecDeque<Sample>,
+    /// Latest published value of each setting.
+    settings: HashMap<String, Vec<u8>>,
+    setting_watchers: Vec<crossbeam::channel::Sender<SettingChange>>,
+    /// Session of the device, to notice when it restarts.
+    session: Option<u32>,
 }
 
 impl Device {
@@ -592,9 +946,51 @@ impl Device {
             parser: DeviceDataParser::new(false),
             n_reqs: 0,
             sample_queue: VecDeque::new(),
+            settings: HashMap::new(),
+            setting_watchers: vec![],
+            session: None,
         }
     }
 
+    /// Devices publish a setting when its value changes by sending a request
+    /// for the setting's RPC, with the new value as argument.
+    fn publish_setting(&mut self, name: &str, value: &[u8]) {
+        if self.settings.get(name).map(|v| &v[..]) == Some(value) {
+            return;
+        }
+        self.settings.insert(name.to_string(), value.to_vec());
+        let change = SettingChange {
+            name: name.to_string(),
+            value: value.to_vec(),
+        };
+        // Watchers which do not keep up miss changes; gone ones are dropped.
+        self.setting_watchers.retain(|watcher| {
+            !matches!(
+                watcher.try_send(change.clone()),
+                Err(crossbeam::channel::TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// Latest value published by the device for setting `name`, if any.
+    /// The cache is updated as packets are processed by `next`, `try_next`
+    /// or `drain`.
+    pub fn cached<T: util::TioRpcReplyable<T>>(&self, name: &str) -> Option<T> {
+        T::from_reply(self.settings.get(name)?).ok()
+    }
+
+    /// Same as `cached`, returning the raw value.
+    pub fn cached_raw(&self, name: &str) -> Option<&[u8]> {
+        self.settings.get(name).map(|v| &v[..])
+    }
+
+    /// Receive every change to published settings from now on.
+    pub fn setting_changes(&mut self) -> crossbeam::channel::Receiver<SettingChange> {
+        let (tx, rx) = crossbeam::channel::bounded(64);
+        self.setting_watchers.push(tx);
+        rx
+    }
+
     fn internal_rpcs(&mut self) {
         if self.n_reqs == 0 {
             let reqs = self.parser.requests();
@@ -621,6 +1017,19 @@ impl Device {
                     return Some(pkt);
                 }
             }
+            tio::proto::Payload::RpcRequest(req) => {
+                if let proto::RpcMethod::Name(name) = &req.method {
+                    self.publish_setting(name, &req.arg);
+                }
+                return None;
+            }
+            tio::proto::Payload::Heartbeat(tio::proto::HeartbeatPayload::Session(session)) => {
+                // Settings are back to their defaults after a restart.
+                let old_session = self.session.replace(*session);
+                if old_session.is_some_and(|s| s != *session) {
+                    self.settings.clear();
+                }
+            }
             _ => {}
         }
 
@@ -629,6 +1038,16 @@ impl Device {
         None
     }
 
+    /// See `DeviceDataParser::fill_gaps`.
+    pub fn fill_gaps(&mut self, fill: bool) {
+        self.parser.fill_gaps(fill);
+    }
+
+    /// Missing sample statistics of each stream, see `GapStats`.
+    pub fn gap_stats(&self) -> &HashMap<u8, GapStats> {
+        self.parser.gap_stats()
+    }
+
     pub fn get_metadata(&mut self) -> DeviceFullMetadata {
         loop {
             if self.n_reqs == 0 {
@@ -647,6 +1066,152 @@ impl Device {
         }
     }
 
+    /// Reboot the device, and return once it is usable again: it restarted,
+    /// as announced by its new session, and its metadata was fetched again.
+    /// Devices which reset their USB port when rebooting are only found
+    /// again if the proxy reconnects, see `ProxyBuilder::reconnect`.
+    /// Samples received until then are kept.
+    pub fn reboot(&mut self, timeout: Duration) -> Result<DeviceFullMetadata, RebootError> {
+        let deadline = Instant::now() + timeout;
+        // The current session is needed to tell when the device restarted.
+        while self.session.is_none() {
+            let pkt = self.recv_until(deadline)?;
+            self.process_packet(pkt);
+        }
+        let old_session = self.session;
+        let req = util::PacketBuilder::make_rpc_request(REBOOT_RPC, &[], 0, DeviceRoute::root());
+        if self.dev_port.send(req).is_err() {
+            return Err(RebootError::ProxyDisconnected);
+        }
+        // The reply can be lost as the device reboots, or the proxy cancel
+        // the request as the device disconnects, which is fine.
+        loop {
+            let pkt = self.recv_until(deadline)?;
+            match self.process_packet(pkt).map(|pkt| pkt.payload) {
+                Some(proto::Payload::RpcReply(_)) => break,
+                Some(proto::Payload::RpcError(err)) => match err.error {
+                    proto::RpcErrorCode::Timeout | proto::RpcErrorCode::Undefined => break,
+                    code => return Err(RebootError::Refused(code)),
+                },
+                _ => {}
+            }
+        }
+        // Wait for the heartbeat of the new session, after which the parser
+        // fetches the metadata again.
+        while self.session == old_session {
+            let pkt = self.recv_until(deadline)?;
+            self.process_packet(pkt);
+        }
+        loop {
+            if self.n_reqs == 0 {
+                match self.parser.get_metadata() {
+                    Ok(full_meta) => return Ok(full_meta),
+                    Err(reqs) => {
+                        for req in reqs {
+                            if self.dev_port.send(req).is_err() {
+                                return Err(RebootError::ProxyDisconnected);
+                            }
+                            self.n_reqs += 1;
+                        }
+                    }
+                }
+            }
+            let pkt = self.recv_until(deadline)?;
+            self.process_packet(pkt);
+        }
+    }
+
+    /// Start the streams with these ids, and return once data of all of them
+    /// was received, or fail after `timeout`. Either all of the streams are
+    /// started or none: they are stopped again if any fails to start. They
+    /// are stopped when the returned guard is dropped, unless it is told to
+    /// keep them running. Samples received meanwhile are kept.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use twinleaf::{data::Device, tio::{proto::DeviceRoute, proxy}};
+    /// let proxy = proxy::Interface::new("tcp://localhost");
+    /// let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+    /// let _streams = device.start_streams(&[1, 2], Duration::from_secs(2)).unwrap();
+    /// for _ in 0..1000 {
+    ///     println!("{:?}", device.next().columns);
+    /// }
+    /// // Both streams stop here, or if the loop above panics.
+    /// ```
+    pub fn start_streams(
+        &mut self,
+        ids: &[u8],
+        timeout: Duration,
+    ) -> Result<StreamsGuard, StreamsError> {
+        let deadline = Instant::now() + timeout;
+        let guard = StreamsGuard {
+            port: self.streams_port()?,
+            rpcs: self.stream_active_rpcs(ids)?,
+            timeout,
+        };
+        // On failure, the guard stops the streams already started.
+        if let Some((i, err)) = set_streams_active(&guard.port, &guard.rpcs, true, timeout) {
+            return Err(StreamsError::from_rpc(ids[i], err));
+        }
+        self.wait_stream_data(ids, deadline)?;
+        Ok(guard)
+    }
+
+    /// Wait until data of all the streams with these ids was received, or
+    /// fail at `deadline`. Samples received meanwhile are kept.
+    fn wait_stream_data(&mut self, ids: &[u8], deadline: Instant) -> Result<(), StreamsError> {
+        let mut pending: Vec<u8> = ids.to_vec();
+        while !pending.is_empty() {
+            let pkt = self.recv_until(deadline).map_err(|err| match err {
+                RebootError::Timeout => StreamsError::Timeout(pending.clone()),
+                _ => StreamsError::ProxyDisconnected,
+            })?;
+            if let proto::Payload::StreamData(data) = &pkt.payload {
+                pending.retain(|id| *id != data.stream_id);
+            }
+            self.process_packet(pkt);
+        }
+        Ok(())
+    }
+
+    /// Stop the streams with these ids, and return once the device
+    /// acknowledged it. Data already sent by then might still be received.
+    pub fn stop_streams(&mut self, ids: &[u8], timeout: Duration) -> Result<(), StreamsError> {
+        let port = self.streams_port()?;
+        let rpcs = self.stream_active_rpcs(ids)?;
+        match set_streams_active(&port, &rpcs, false, timeout) {
+            Some((i, err)) => Err(StreamsError::from_rpc(ids[i], err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Port of the device to start and stop streams with.
+    fn streams_port(&self) -> Result<proxy::Port, StreamsError> {
+        self.dev_port
+            .client(&proxy::ClientOptions::new().rpc_only())
+            .map_err(|_| StreamsError::ProxyDisconnected)
+    }
+
+    /// Settings enabling the streams with these ids.
+    fn stream_active_rpcs(&mut self, ids: &[u8]) -> Result<Vec<String>, StreamsError> {
+        let meta = self.get_metadata();
+        ids.iter()
+            .map(|id| match meta.streams.get(id) {
+                Some(stream) => Ok(stream_active_rpc(&stream.stream.name)),
+                None => Err(StreamsError::UnknownStream(*id)),
+            })
+            .collect()
+    }
+
+    /// Wait for a packet until `deadline`.
+    fn recv_until(&self, deadline: Instant) -> Result<tio::Packet, RebootError> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        crossbeam::select! {
+            recv(self.dev_port.rpc_receiver()) -> pkt => pkt.map_err(|_| RebootError::ProxyDisconnected),
+            recv(self.dev_port.receiver()) -> pkt => pkt.map_err(|_| RebootError::ProxyDisconnected),
+            default(timeout) => Err(RebootError::Timeout),
+        }
+    }
+
     pub fn next(&mut self) -> Sample {
         loop {
             if !self.sample_queue.is_empty() {
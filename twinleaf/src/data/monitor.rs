@@ -0,0 +1,224 @@
+//! Live monitor
+//!
+//! Gathers what is worth watching while bringing up a sensor in the field:
+//! the rate at which the samples of each stream come in, the latest value
+//! of each column, the RPCs going through the proxy and the state of the
+//! link to the sensor. A `Monitor` is fed the samples of a data client and
+//! the status events of the proxy, and renders a plain text summary, which
+//! `tio-monitor dash` redraws in a terminal:
+//! ```no_run
+//! # use twinleaf::data::{monitor::Monitor, Device};
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! let (tx, events) = crossbeam::channel::unbounded();
+//! let proxy = proxy::Interface::builder().url("tcp://localhost").status(tx).spawn();
+//! let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! let mut monitor = Monitor::new();
+//! loop {
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//!     for sample in device.drain() {
+//!         monitor.push_sample(&sample);
+//!     }
+//!     for event in events.try_iter() {
+//!         monitor.push_event(&event);
+//!     }
+//!     monitor.set_link_status(proxy.link_status());
+//!     println!("{}", monitor.render().join("\n"));
+//! }
+//! ```
+
+use super::stats::{StreamStatistics, StreamStats};
+use super::{ColumnData, Sample};
+use crate::tio::proxy::{Event, LinkStatus};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Window of sample time over which stream rates are measured.
+pub static RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Number of recent events kept.
+pub static RECENT_EVENTS: usize = 8;
+
+/// Counts of the RPCs which went through the proxy, from its events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcActivity {
+    /// Requests forwarded to the sensor.
+    pub sent: u64,
+    /// Replies routed back to their client.
+    pub completed: u64,
+    pub timeouts: u64,
+    pub cancelled: u64,
+    /// Requests delayed because too many were in flight.
+    pub throttled: u64,
+}
+
+/// Summary of the live activity of a device and its proxy.
+pub struct Monitor {
+    stats: StreamStatistics,
+    latest: BTreeMap<u8, Sample>,
+    rpcs: RpcActivity,
+    /// Recent events other than the routine RPC bookkeeping.
+    events: VecDeque<(SystemTime, String)>,
+    link: Option<LinkStatus>,
+}
+
+impl Default for Monitor {
+    fn default() -> Monitor {
+        Monitor::new()
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor {
+            stats: StreamStatistics::new(RATE_WINDOW),
+            latest: BTreeMap::new(),
+            rpcs: RpcActivity::default(),
+            events: VecDeque::new(),
+            link: None,
+        }
+    }
+
+    /// Account for a sample received. Placeholders filling gaps only count
+    /// towards the rate.
+    pub fn push_sample(&mut self, sample: &Sample) {
+        self.stats.push(sample);
+        if !sample.placeholder {
+            self.latest.insert(sample.stream.stream_id, sample.clone());
+        }
+    }
+
+    /// Account for a status event of the proxy.
+    pub fn push_event(&mut self, event: &Event) {
+        match event {
+            Event::RpcRemap(..) => self.rpcs.sent += 1,
+            Event::RpcRestore(..) => self.rpcs.completed += 1,
+            Event::RpcTimeout(_) => self.rpcs.timeouts += 1,
+            Event::RpcCancel(_) => self.rpcs.cancelled += 1,
+            Event::RpcThrottled(_) => self.rpcs.throttled += 1,
+            _ => {}
+        }
+        if !matches!(
+            event,
+            Event::RpcRemap(..) | Event::RpcRestore(..) | Event::RpcQueued(_)
+        ) {
+            self.events
+                .push_back((SystemTime::now(), format!("{:?}", event)));
+            if self.events.len() > RECENT_EVENTS {
+                self.events.pop_front();
+            }
+        }
+    }
+
+    /// Update the state of the link, as returned by
+    /// `proxy::Interface::link_status`.
+    pub fn set_link_status(&mut self, status: LinkStatus) {
+        self.link = Some(status);
+    }
+
+    /// Rates and statistics of the streams, over the last `RATE_WINDOW`.
+    pub fn streams(&self) -> Vec<StreamStats> {
+        self.stats.streams()
+    }
+
+    /// Latest sample of a stream.
+    pub fn latest(&self, stream_id: u8) -> Option<&Sample> {
+        self.latest.get(&stream_id)
+    }
+
+    pub fn rpcs(&self) -> RpcActivity {
+        self.rpcs
+    }
+
+    /// Recent events, oldest first, as text with the time they were
+    /// received.
+    pub fn events(&self) -> impl Iterator<Item = &(SystemTime, String)> {
+        self.events.iter()
+    }
+
+    pub fn link_status(&self) -> Option<&LinkStatus> {
+        self.link.as_ref()
+    }
+
+    fn render_link(link: &LinkStatus) -> String {
+        let mut ret = format!(
+            "Link: {}",
+            if link.connected {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+        if let Some(rate) = link.rate_bps {
+            ret += &format!(", {} bps ({:?})", rate, link.autorate);
+        }
+        if let Some(rx) = link.rx_bps {
+            ret += &format!(", receiving {} bps", rx);
+        }
+        if link.overloaded {
+            ret += ", OVERLOADED";
+        }
+        let counters = &link.counters;
+        ret += &format!(
+            ", {} packets, {} CRC errors, {} framing errors",
+            counters.packets, counters.crc_errors, counters.framing_errors
+        );
+        ret
+    }
+
+    /// The summary, as lines of text.
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(sample) = self.latest.values().next() {
+            lines.push(format!(
+                "{}  Serial: {}",
+                sample.device.name, sample.device.serial_number
+            ));
+        }
+        if let Some(link) = &self.link {
+            lines.push(Monitor::render_link(link));
+        }
+        let rpcs = &self.rpcs;
+        lines.push(format!(
+            "RPCs: {} sent, {} completed, {} timed out, {} cancelled, {} throttled",
+            rpcs.sent, rpcs.completed, rpcs.timeouts, rpcs.cancelled, rpcs.throttled
+        ));
+        for stream in self.streams() {
+            lines.push(String::new());
+            lines.push(format!(
+                "Stream {} {}: {:.2} samples/s",
+                stream.stream_id, stream.name, stream.rate
+            ));
+            let Some(sample) = self.latest.get(&stream.stream_id) else {
+                continue;
+            };
+            let width = sample
+                .columns
+                .iter()
+                .map(|col| col.desc.name.len())
+                .max()
+                .unwrap_or(0);
+            for col in &sample.columns {
+                let value = match col.value {
+                    ColumnData::Int(x) => format!("{}", x),
+                    ColumnData::UInt(x) => format!("{}", x),
+                    ColumnData::Float(x) => format!("{:.4}", x),
+                    ColumnData::Unknown => "?".to_string(),
+                };
+                lines.push(format!(
+                    "  {:<width$} {} {}",
+                    col.desc.name, value, col.desc.units
+                ));
+            }
+        }
+        if !self.events.is_empty() {
+            lines.push(String::new());
+            lines.push("Recent events:".to_string());
+            let now = SystemTime::now();
+            for (time, event) in &self.events {
+                let age = now.duration_since(*time).unwrap_or_default();
+                lines.push(format!("  {:>7.1}s ago  {}", age.as_secs_f64(), event));
+            }
+        }
+        lines
+    }
+}
@@ -0,0 +1,326 @@
+//! Sessions
+//!
+//! Synchronized acquisition from several devices of a tree, for example the
+//! sensors of a gradiometer. A `Session` starts the streams of all of its
+//! devices together, sending all the requests before waiting for any reply,
+//! and fails if the devices acknowledged them further apart than a bounded
+//! skew. It stops them all together as well, including when it is dropped.
+//! The packets of all the devices can be logged to a single raw log, where
+//! they are told apart by their route.
+//!
+//! The clocks of the devices are aligned on the clock of the host: the
+//! offset of each device is estimated as the smallest difference between
+//! the time a sample is received and its time on the device, which is the
+//! one least delayed on its way. `Session::host_time` then gives the time of
+//! a sample on the host clock, so that samples of different devices can be
+//! compared. The offsets are estimated again when a device restarts.
+//! ```no_run
+//! # use std::{path::Path, time::Duration};
+//! # use twinleaf::data::session::Session;
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy, rawlog::LogWriter};
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let routes = [
+//!     DeviceRoute::from_str("/0").unwrap(),
+//!     DeviceRoute::from_str("/1").unwrap(),
+//! ];
+//! let mut session = Session::new(&proxy, &routes)
+//!     .unwrap()
+//!     .max_skew(Duration::from_millis(20))
+//!     .with_log(LogWriter::create(Path::new("gradiometer.tio"), true).unwrap())
+//!     .unwrap();
+//! session.start(&[1], Duration::from_secs(2)).unwrap();
+//! for _ in 0..1000 {
+//!     for (route, sample) in session.drain().unwrap() {
+//!         println!("{} {:?}", route, session.host_time(&route, &sample));
+//!     }
+//!     std::thread::sleep(Duration::from_millis(10));
+//! }
+//! session.stop().unwrap();
+//! ```
+
+use super::{send_streams_active, wait_streams_active, Device, Sample, StreamsError, StreamsGuard};
+use crate::tio::proto::DeviceRoute;
+use crate::tio::proxy::{self, PortError, RecvError};
+use crate::tio::rawlog::{LogOutput, LogWriter};
+
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Skew allowed by default between the devices when starting streams.
+static DEFAULT_MAX_SKEW: Duration = Duration::from_millis(50);
+
+/// How often the devices are polled for their acknowledgements when
+/// starting streams, which bounds the resolution of the skew measured.
+static SKEW_POLL: Duration = Duration::from_micros(200);
+
+/// Failure of a `Session`.
+#[derive(Debug)]
+pub enum SessionError {
+    /// No port could be opened to the device at this route.
+    Port(DeviceRoute, PortError),
+    /// The streams of the device at this route failed to start or stop.
+    Streams(DeviceRoute, StreamsError),
+    /// The devices acknowledged starting their streams this far apart,
+    /// more than allowed. The streams were stopped again.
+    Skew(Duration),
+    /// The packets could not be logged.
+    Log(io::Error),
+    /// The proxy is gone, for example because it could not reconnect.
+    ProxyDisconnected,
+}
+
+/// Device of a session, with the offset of its clock from the host's.
+struct SessionDevice {
+    route: DeviceRoute,
+    device: Device,
+    clock_offset: Option<f64>,
+}
+
+/// Devices acquiring together. See the module documentation.
+pub struct Session {
+    devices: Vec<SessionDevice>,
+    guards: Vec<StreamsGuard>,
+    /// Ids of the streams started.
+    streams: Vec<u8>,
+    max_skew: Duration,
+    log: Option<(proxy::Port, LogWriter<LogOutput>)>,
+    log_port: proxy::Port,
+}
+
+/// Time at which `sample` was received by the host, in seconds since the
+/// Unix epoch, or now if its packet was not stamped.
+fn host_received(sample: &Sample) -> f64 {
+    let received = sample
+        .received
+        .map_or_else(SystemTime::now, |t| t.system_time);
+    received
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+impl Session {
+    /// Session of the devices at `routes` in the tree of `proxy`.
+    pub fn new(proxy: &proxy::Interface, routes: &[DeviceRoute]) -> Result<Session, SessionError> {
+        let mut devices = vec![];
+        for route in routes {
+            let port = proxy
+                .device_full(route.clone())
+                .map_err(|err| SessionError::Port(route.clone(), err))?;
+            devices.push(SessionDevice {
+                route: route.clone(),
+                device: Device::new(port),
+                clock_offset: None,
+            });
+        }
+        let log_port = proxy
+            .tree_rpc()
+            .map_err(|err| SessionError::Port(DeviceRoute::root(), err))?;
+        Ok(Session {
+            devices,
+            guards: vec![],
+            streams: vec![],
+            max_skew: DEFAULT_MAX_SKEW,
+            log: None,
+            log_port,
+        })
+    }
+
+    /// Largest skew allowed between the devices when starting streams.
+    pub fn max_skew(mut self, max_skew: Duration) -> Session {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Log the packets of all the devices to `log`. They are written when
+    /// the samples are drained.
+    pub fn with_log(mut self, log: LogWriter<LogOutput>) -> Result<Session, SessionError> {
+        // The port is opened from the one kept for this, which is of the
+        // whole tree, so that the logged packets keep their full route.
+        let port = self
+            .log_port
+            .client(&proxy::ClientOptions::new())
+            .map_err(|err| SessionError::Port(DeviceRoute::root(), err))?;
+        self.log = Some((port, log));
+        Ok(self)
+    }
+
+    /// Routes of the devices of the session.
+    pub fn routes(&self) -> Vec<DeviceRoute> {
+        self.devices.iter().map(|dev| dev.route.clone()).collect()
+    }
+
+    /// Device of the session at `route`, for example to call its RPCs.
+    pub fn device(&mut self, route: &DeviceRoute) -> Option<&mut Device> {
+        self.devices
+            .iter_mut()
+            .find(|dev| dev.route == *route)
+            .map(|dev| &mut dev.device)
+    }
+
+    /// Start the streams with these ids on all the devices, and return once
+    /// data of all of them was received from every device, or fail after
+    /// `timeout`. Either the streams are started on all the devices or on
+    /// none: they are stopped again on failure, or if the devices
+    /// acknowledged starting them further apart than the allowed skew. The
+    /// acknowledgements are timed to within a fraction of a millisecond.
+    pub fn start(&mut self, ids: &[u8], timeout: Duration) -> Result<(), SessionError> {
+        self.stop()?;
+        let deadline = Instant::now() + timeout;
+        let mut guards = vec![];
+        for dev in &mut self.devices {
+            let failed = |err| SessionError::Streams(dev.route.clone(), err);
+            guards.push(StreamsGuard {
+                port: dev.device.streams_port().map_err(failed)?,
+                rpcs: dev.device.stream_active_rpcs(ids).map_err(failed)?,
+                timeout,
+            });
+        }
+        // Dropping the guards on failure stops the streams already started.
+        let handles: Vec<_> = guards
+            .iter()
+            .map(|guard| send_streams_active(&guard.port, &guard.rpcs, true, timeout))
+            .collect();
+        let acked = self.wait_acks(ids, handles, deadline)?;
+        let skew = match (acked.iter().min(), acked.iter().max()) {
+            (Some(first), Some(last)) => last.duration_since(*first),
+            _ => Duration::ZERO,
+        };
+        if skew > self.max_skew {
+            return Err(SessionError::Skew(skew));
+        }
+        for dev in &mut self.devices {
+            dev.device
+                .wait_stream_data(ids, deadline)
+                .map_err(|err| SessionError::Streams(dev.route.clone(), err))?;
+        }
+        self.guards = guards;
+        self.streams = ids.to_vec();
+        Ok(())
+    }
+
+    /// Wait for the requests of `send_streams_active` to each device, polling
+    /// all the devices in turn, and return when each device acknowledged all
+    /// of them, or the first failure.
+    fn wait_acks(
+        &self,
+        ids: &[u8],
+        handles: Vec<Vec<proxy::RpcHandle>>,
+        deadline: Instant,
+    ) -> Result<Vec<Instant>, SessionError> {
+        let mut pending: Vec<Vec<(usize, proxy::RpcHandle)>> = handles
+            .into_iter()
+            .map(|handles| handles.into_iter().enumerate().collect())
+            .collect();
+        let mut acked = vec![None; pending.len()];
+        while acked.contains(&None) {
+            for ((dev, handles), acked) in self.devices.iter().zip(&mut pending).zip(&mut acked) {
+                if acked.is_some() {
+                    continue;
+                }
+                let mut failed = None;
+                handles.retain_mut(|(i, handle)| match handle.wait_timeout(Duration::ZERO) {
+                    None => true,
+                    Some(Ok(_)) => false,
+                    Some(Err(err)) => {
+                        failed.get_or_insert(StreamsError::from_rpc(ids[*i], err));
+                        false
+                    }
+                });
+                if let Some(err) = failed {
+                    return Err(SessionError::Streams(dev.route.clone(), err));
+                }
+                if handles.is_empty() {
+                    *acked = Some(Instant::now());
+                } else if Instant::now() > deadline {
+                    let err = StreamsError::Timeout(handles.iter().map(|(i, _)| ids[*i]).collect());
+                    return Err(SessionError::Streams(dev.route.clone(), err));
+                }
+            }
+            if acked.contains(&None) {
+                std::thread::sleep(SKEW_POLL);
+            }
+        }
+        Ok(acked.into_iter().flatten().collect())
+    }
+
+    /// Stop the streams started on all the devices, and return once they
+    /// all acknowledged it. Returns the first failure, after stopping the
+    /// streams of the other devices anyway.
+    pub fn stop(&mut self) -> Result<(), SessionError> {
+        let guards = std::mem::take(&mut self.guards);
+        let ids = std::mem::take(&mut self.streams);
+        let handles: Vec<_> = guards
+            .iter()
+            .map(|guard| send_streams_active(&guard.port, &guard.rpcs, false, guard.timeout))
+            .collect();
+        let mut ret = Ok(());
+        for (dev, handles) in self.devices.iter().zip(handles) {
+            if let (Some((i, err)), Ok(())) = (wait_streams_active(handles), &ret) {
+                let err = StreamsError::from_rpc(ids[i], err);
+                ret = Err(SessionError::Streams(dev.route.clone(), err));
+            }
+        }
+        // Already stopped, or failed to.
+        guards.into_iter().for_each(StreamsGuard::keep_running);
+        if let Some((_, log)) = &mut self.log {
+            log.flush().map_err(SessionError::Log)?;
+        }
+        ret
+    }
+
+    /// Samples received from all the devices since the last call, without
+    /// waiting, with the route of their device. This also logs the packets
+    /// received, so it must be called regularly while logging.
+    pub fn drain(&mut self) -> Result<Vec<(DeviceRoute, Sample)>, SessionError> {
+        if let Some((port, log)) = &mut self.log {
+            loop {
+                match port.try_recv() {
+                    Ok(pkt) => {
+                        if self.devices.iter().any(|dev| dev.route == pkt.routing) {
+                            log.write(&pkt).map_err(SessionError::Log)?;
+                        }
+                    }
+                    Err(RecvError::WouldBlock) => break,
+                    Err(_) => return Err(SessionError::ProxyDisconnected),
+                }
+            }
+        }
+        let mut ret = vec![];
+        for dev in &mut self.devices {
+            for sample in dev.device.drain() {
+                if sample.segment_changed || sample.meta_changed {
+                    dev.clock_offset = None;
+                }
+                let offset = host_received(&sample) - sample.timestamp_end();
+                if dev.clock_offset.is_none_or(|current| offset < current) {
+                    dev.clock_offset = Some(offset);
+                }
+                ret.push((dev.route.clone(), sample));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Offset of the clock of the device at `route` from the host's, in
+    /// seconds, once samples of it were received.
+    pub fn clock_offset(&self, route: &DeviceRoute) -> Option<f64> {
+        self.devices
+            .iter()
+            .find(|dev| dev.route == *route)
+            .and_then(|dev| dev.clock_offset)
+    }
+
+    /// Time at which `sample` of the device at `route` was taken on the
+    /// host clock, in seconds since the Unix epoch.
+    pub fn host_time(&self, route: &DeviceRoute, sample: &Sample) -> Option<f64> {
+        Some(sample.timestamp_begin() + self.clock_offset(route)?)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Failures are ignored, as there is nothing more to do about them.
+        let _ = self.stop();
+    }
+}
@@ -0,0 +1,603 @@
+//! Settings
+//!
+//! Snapshots of the settings of a device, to save them and apply them to a
+//! device again, for example to provision a replacement sensor like the one
+//! it replaces. The settings are the RPCs listed by `rpc.listinfo` which are
+//! both readable and writable, with a numeric or string type.
+//!
+//! Snapshots are saved as a flat TOML document, with one quoted key per
+//! setting:
+//! ```toml
+//! # Settings of VMR (serial 1234)
+//! "data.rate" = 200
+//! "field.filter.cutoff" = 10.5
+//! ```
+//!
+//! Snapshots can be compared with `SettingsSnapshot::diff`, for example to
+//! check that a fleet of sensors is configured consistently.
+//!
+//! Settings changed with RPCs are lost when the device restarts, unless
+//! saved to its flash. A `SettingsTransaction` stages changes, and then
+//! tries them, saves them or goes back to the settings last saved:
+//! ```no_run
+//! # use twinleaf::data::{settings::SettingValue, Device};
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let mut device = Device::new(proxy.device_rpc(DeviceRoute::root()).unwrap());
+//! let mut settings = device.settings_transaction().unwrap();
+//! settings.set("data.rate", SettingValue::UInt(200)).unwrap();
+//! settings.set("field.filter.cutoff", SettingValue::Float(10.5)).unwrap();
+//! settings.commit().unwrap();
+//! ```
+
+use super::Device;
+use crate::tio::proto::RpcErrorCode;
+use crate::tio::proxy::RpcError;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+static RPC_META_READ: u16 = 0x0100;
+static RPC_META_WRITE: u16 = 0x0200;
+static RPC_META_PERSISTENT: u16 = 0x0400;
+
+/// Action saving the current settings to flash.
+static SETTINGS_SAVE_RPC: &str = "dev.conf.save";
+/// Action restoring the settings last saved to flash.
+static SETTINGS_REVERT_RPC: &str = "dev.conf.load";
+
+/// Settings configuring the link to the host, so that changing them can
+/// leave the device unreachable, by prefix.
+static LINK_CRITICAL_SETTINGS: &[&str] = &["dev.port."];
+
+/// True if changing the setting `name` can cut the link to the device, for
+/// example if the port rate changes to one the host does not use.
+pub fn is_link_critical(name: &str) -> bool {
+    LINK_CRITICAL_SETTINGS
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Type of the value of an RPC, as reported by `rpc.listinfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcValueType {
+    UInt(usize),
+    Int(usize),
+    Float(usize),
+    /// String, with a maximum length if not 0.
+    String(usize),
+    Unknown,
+}
+
+/// Description of an RPC, as reported by `rpc.listinfo`.
+#[derive(Debug, Clone)]
+pub struct RpcInfo {
+    pub name: String,
+    pub value_type: RpcValueType,
+    pub readable: bool,
+    pub writable: bool,
+    pub persistent: bool,
+}
+
+impl RpcInfo {
+    pub fn parse(name: &str, meta: u16) -> RpcInfo {
+        let size = ((meta >> 4) & 0xF) as usize;
+        let value_type = match (meta & 0xF, size) {
+            (0, 1 | 2 | 4 | 8) => RpcValueType::UInt(size),
+            (1, 1 | 2 | 4 | 8) => RpcValueType::Int(size),
+            (2, 4 | 8) => RpcValueType::Float(size),
+            (3, _) => RpcValueType::String(size),
+            _ => RpcValueType::Unknown,
+        };
+        RpcInfo {
+            name: name.to_string(),
+            value_type,
+            readable: (meta & RPC_META_READ) != 0,
+            writable: (meta & RPC_META_WRITE) != 0,
+            persistent: (meta & RPC_META_PERSISTENT) != 0,
+        }
+    }
+
+    /// True if this RPC is a setting which can be saved and restored.
+    pub fn is_setting(&self) -> bool {
+        self.readable && self.writable && self.value_type != RpcValueType::Unknown
+    }
+
+    /// Decode a reply of this RPC.
+    pub fn decode(&self, reply: &[u8]) -> Option<SettingValue> {
+        macro_rules! le {
+            ($t:ty) => {
+                <$t>::from_le_bytes(reply.try_into().ok()?)
+            };
+        }
+        let value = match self.value_type {
+            RpcValueType::UInt(1) => SettingValue::UInt(le!(u8).into()),
+            RpcValueType::UInt(2) => SettingValue::UInt(le!(u16).into()),
+            RpcValueType::UInt(4) => SettingValue::UInt(le!(u32).into()),
+            RpcValueType::UInt(8) => SettingValue::UInt(le!(u64)),
+            RpcValueType::Int(1) => SettingValue::Int(le!(i8).into()),
+            RpcValueType::Int(2) => SettingValue::Int(le!(i16).into()),
+            RpcValueType::Int(4) => SettingValue::Int(le!(i32).into()),
+            RpcValueType::Int(8) => SettingValue::Int(le!(i64)),
+            RpcValueType::Float(4) => SettingValue::Float(le!(f32).into()),
+            RpcValueType::Float(8) => SettingValue::Float(le!(f64)),
+            RpcValueType::String(_) => {
+                SettingValue::String(String::from_utf8_lossy(reply).to_string())
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Encode `value` as the argument of this RPC, if it fits its type.
+    pub fn encode(&self, value: &SettingValue) -> Option<Vec<u8>> {
+        let (int, uint, float) = match *value {
+            SettingValue::Int(x) => (Some(x), u64::try_from(x).ok(), Some(x as f64)),
+            SettingValue::UInt(x) => (i64::try_from(x).ok(), Some(x), Some(x as f64)),
+            SettingValue::Float(x) => (None, None, Some(x)),
+            SettingValue::String(ref s) => {
+                return match self.value_type {
+                    RpcValueType::String(max) if max == 0 || s.len() <= max => {
+                        Some(s.as_bytes().to_vec())
+                    }
+                    _ => None,
+                };
+            }
+        };
+        let arg = match self.value_type {
+            RpcValueType::UInt(1) => u8::try_from(uint?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::UInt(2) => u16::try_from(uint?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::UInt(4) => u32::try_from(uint?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::UInt(8) => uint?.to_le_bytes().to_vec(),
+            RpcValueType::Int(1) => i8::try_from(int?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::Int(2) => i16::try_from(int?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::Int(4) => i32::try_from(int?).ok()?.to_le_bytes().to_vec(),
+            RpcValueType::Int(8) => int?.to_le_bytes().to_vec(),
+            RpcValueType::Float(4) => (float? as f32).to_le_bytes().to_vec(),
+            RpcValueType::Float(8) => float?.to_le_bytes().to_vec(),
+            _ => return None,
+        };
+        Some(arg)
+    }
+}
+
+/// Value of a setting.
+#[derive(Debug, Clone)]
+pub enum SettingValue {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+}
+
+/// Numbers compare by value, whatever their type.
+impl PartialEq for SettingValue {
+    fn eq(&self, other: &SettingValue) -> bool {
+        use SettingValue::*;
+        match (self, other) {
+            (String(a), String(b)) => a == b,
+            (Int(a), Int(b)) => a == b,
+            (UInt(a), UInt(b)) => a == b,
+            (Int(a), UInt(b)) | (UInt(b), Int(a)) => u64::try_from(*a) == Ok(*b),
+            (Float(a), Float(b)) => a == b,
+            (Float(a), Int(b)) | (Int(b), Float(a)) => *a == *b as f64,
+            (Float(a), UInt(b)) | (UInt(b), Float(a)) => *a == *b as f64,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for SettingValue {
+    /// Formatted as a TOML value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Int(x) => write!(f, "{}", x),
+            SettingValue::UInt(x) => write!(f, "{}", x),
+            SettingValue::Float(x) if x.is_nan() => write!(f, "nan"),
+            SettingValue::Float(x) if x.is_infinite() => {
+                write!(f, "{}inf", if *x < 0.0 { "-" } else { "" })
+            }
+            SettingValue::Float(x) => write!(f, "{:?}", x),
+            SettingValue::String(s) => write_toml_string(f, s),
+        }
+    }
+}
+
+fn write_toml_string(f: &mut impl fmt::Write, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04X}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Parse a TOML basic string at the start of `s`, returning it and the rest.
+fn parse_toml_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut ret = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((ret, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                '"' => ret.push('"'),
+                '\\' => ret.push('\\'),
+                'n' => ret.push('\n'),
+                'r' => ret.push('\r'),
+                't' => ret.push('\t'),
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next()).map(|c| c.1).collect();
+                    ret.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                _ => return None,
+            },
+            c => ret.push(c),
+        }
+    }
+    None
+}
+
+pub(crate) fn parse_toml_value(s: &str) -> Option<SettingValue> {
+    if s.starts_with('"') {
+        return match parse_toml_string(s)? {
+            (value, "") => Some(SettingValue::String(value)),
+            _ => None,
+        };
+    }
+    let s = s.replace('_', "");
+    if let Ok(x) = s.parse::<u64>() {
+        Some(SettingValue::UInt(x))
+    } else if let Ok(x) = s.parse::<i64>() {
+        Some(SettingValue::Int(x))
+    } else {
+        match s.trim_start_matches(['+', '-']) {
+            "inf" | "nan" => s.parse::<f64>().ok().map(SettingValue::Float),
+            rest if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+                s.parse::<f64>().ok().map(SettingValue::Float)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Failure to save, load or apply settings.
+#[derive(Debug, Clone)]
+pub enum SettingsError {
+    /// An RPC failed.
+    Rpc(String, Box<RpcError>),
+    /// The device has no such setting.
+    UnknownSetting(String),
+    /// The value does not fit the type of the setting.
+    InvalidValue(String, SettingValue),
+    /// Invalid line in a settings document.
+    Parse(usize, String),
+    /// Changing this setting can cut the link to the device, and was not
+    /// allowed, see `SettingsTransaction::allow_link_critical`.
+    LinkCritical(String),
+}
+
+/// Settings of a device, by RPC name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsSnapshot {
+    pub values: BTreeMap<String, SettingValue>,
+    /// Optional description, written as a comment.
+    pub comment: Option<String>,
+}
+
+impl SettingsSnapshot {
+    pub fn new() -> SettingsSnapshot {
+        SettingsSnapshot::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SettingValue> {
+        self.values.get(name)
+    }
+
+    pub fn set(&mut self, name: &str, value: SettingValue) {
+        self.values.insert(name.to_string(), value);
+    }
+
+    pub fn to_toml(&self) -> String {
+        use std::fmt::Write;
+        let mut ret = String::new();
+        if let Some(comment) = &self.comment {
+            for line in comment.lines() {
+                let _ = writeln!(ret, "# {}", line);
+            }
+        }
+        for (name, value) in &self.values {
+            let _ = write_toml_string(&mut ret, name);
+            let _ = writeln!(ret, " = {}", value);
+        }
+        ret
+    }
+
+    /// Settings which differ from `other`, or are only in one of the two
+    /// snapshots, by name.
+    pub fn diff(&self, other: &SettingsSnapshot) -> Vec<SettingDiff> {
+        let mut names: Vec<&String> = self.values.keys().chain(other.values.keys()).collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let (left, right) = (self.values.get(name), other.values.get(name));
+                if left == right {
+                    return None;
+                }
+                Some(SettingDiff {
+                    name: name.clone(),
+                    left: left.cloned(),
+                    right: right.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Parse a document written by `to_toml`. Keys can also be bare, and
+    /// comments are ignored.
+    pub fn from_toml(doc: &str) -> Result<SettingsSnapshot, SettingsError> {
+        let mut ret = SettingsSnapshot::new();
+        for (i, line) in doc.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || SettingsError::Parse(i + 1, line.to_string());
+            let (key, rest) = if line.starts_with('"') {
+                parse_toml_string(line).ok_or_else(invalid)?
+            } else {
+                let end = line.find([' ', '\t', '=']).ok_or_else(invalid)?;
+                (line[..end].to_string(), &line[end..])
+            };
+            let value = rest.trim_start().strip_prefix('=').ok_or_else(invalid)?;
+            // Trailing comments, outside of strings.
+            let value = match value.trim().starts_with('"') {
+                true => value.trim(),
+                false => value.split('#').next().unwrap_or("").trim(),
+            };
+            let value = parse_toml_value(value).ok_or_else(invalid)?;
+            ret.values.insert(key, value);
+        }
+        Ok(ret)
+    }
+}
+
+/// A setting which differs between two snapshots.
+#[derive(Debug, Clone)]
+pub struct SettingDiff {
+    pub name: String,
+    /// Value in the first snapshot, if it has the setting.
+    pub left: Option<SettingValue>,
+    /// Value in the second snapshot, if it has the setting.
+    pub right: Option<SettingValue>,
+}
+
+/// A setting applied to a device by `Device::load_settings`.
+#[derive(Debug, Clone)]
+pub struct SettingUpdate {
+    pub name: String,
+    /// Value before the update.
+    pub current: SettingValue,
+    pub new: SettingValue,
+}
+
+impl Device {
+    /// Description of all the RPCs of the device.
+    pub fn list_rpcs(&mut self) -> Result<Vec<RpcInfo>, SettingsError> {
+        let rpc_error = |err| SettingsError::Rpc("rpc.listinfo".to_string(), Box::new(err));
+        let n_rpcs: u16 = self.get("rpc.listinfo").map_err(rpc_error)?;
+        let mut ret = vec![];
+        for rpc_id in 0..n_rpcs {
+            let (meta, name): (u16, String) =
+                self.rpc("rpc.listinfo", rpc_id).map_err(rpc_error)?;
+            ret.push(RpcInfo::parse(&name, meta));
+        }
+        Ok(ret)
+    }
+
+    fn read_setting(&mut self, info: &RpcInfo) -> Result<SettingValue, SettingsError> {
+        let reply = self
+            .raw_rpc(&info.name, &[])
+            .map_err(|err| SettingsError::Rpc(info.name.clone(), Box::new(err)))?;
+        info.decode(&reply)
+            .ok_or_else(|| SettingsError::Rpc(info.name.clone(), Box::new(RpcError::TypeError)))
+    }
+
+    /// Read the current value of all the settings of the device.
+    pub fn dump_settings(&mut self) -> Result<SettingsSnapshot, SettingsError> {
+        let mut ret = SettingsSnapshot::new();
+        for info in self.list_rpcs()? {
+            if info.is_setting() {
+                let value = self.read_setting(&info)?;
+                ret.values.insert(info.name, value);
+            }
+        }
+        let meta = self.get_metadata();
+        ret.comment = Some(format!(
+            "Settings of {} (serial {})",
+            meta.device.name, meta.device.serial_number
+        ));
+        Ok(ret)
+    }
+
+    /// Apply the settings of `snapshot` which differ from the current ones,
+    /// returning them. With `dry_run`, only compare to the current settings.
+    /// Settings are checked against the device before any is written, so
+    /// nothing is written if any is unknown or has an invalid value.
+    pub fn load_settings(
+        &mut self,
+        snapshot: &SettingsSnapshot,
+        dry_run: bool,
+    ) -> Result<Vec<SettingUpdate>, SettingsError> {
+        let settings = self.list_settings()?;
+        self.apply_settings(&settings, snapshot, dry_run)
+    }
+
+    /// Description of the settings of the device, by name.
+    fn list_settings(&mut self) -> Result<BTreeMap<String, RpcInfo>, SettingsError> {
+        Ok(self
+            .list_rpcs()?
+            .into_iter()
+            .filter(|info| info.is_setting())
+            .map(|info| (info.name.clone(), info))
+            .collect())
+    }
+
+    /// Same as `load_settings`, with the `settings` of the device.
+    fn apply_settings(
+        &mut self,
+        settings: &BTreeMap<String, RpcInfo>,
+        snapshot: &SettingsSnapshot,
+        dry_run: bool,
+    ) -> Result<Vec<SettingUpdate>, SettingsError> {
+        let mut updates = vec![];
+        for (name, value) in &snapshot.values {
+            let info = settings
+                .get(name)
+                .ok_or_else(|| SettingsError::UnknownSetting(name.clone()))?;
+            let arg = info
+                .encode(value)
+                .ok_or_else(|| SettingsError::InvalidValue(name.clone(), value.clone()))?;
+            let current = self.read_setting(info)?;
+            // Compare as the device would store the new value, so that for
+            // example rounding to f32 does not count as a difference.
+            if info.decode(&arg).as_ref() != Some(&current) {
+                updates.push((
+                    arg,
+                    SettingUpdate {
+                        name: name.clone(),
+                        current,
+                        new: value.clone(),
+                    },
+                ));
+            }
+        }
+
+        if !dry_run {
+            for (arg, update) in &updates {
+                match self.raw_rpc(&update.name, arg) {
+                    Ok(_) => {}
+                    Err(RpcError::ExecError(err))
+                        if matches!(err.error, RpcErrorCode::InvalidArgs) =>
+                    {
+                        return Err(SettingsError::InvalidValue(
+                            update.name.clone(),
+                            update.new.clone(),
+                        ));
+                    }
+                    Err(err) => return Err(SettingsError::Rpc(update.name.clone(), Box::new(err))),
+                }
+            }
+        }
+        Ok(updates.into_iter().map(|(_, update)| update).collect())
+    }
+
+    /// Start changing the settings of the device, see `SettingsTransaction`.
+    pub fn settings_transaction(&mut self) -> Result<SettingsTransaction<'_>, SettingsError> {
+        let settings = self.list_settings()?;
+        Ok(SettingsTransaction {
+            device: self,
+            settings,
+            staged: SettingsSnapshot::new(),
+            allow_link_critical: false,
+        })
+    }
+
+    fn settings_action(&mut self, name: &str) -> Result<(), SettingsError> {
+        self.action(name)
+            .map_err(|err| SettingsError::Rpc(name.to_string(), Box::new(err)))
+    }
+}
+
+/// Changes to the settings of a device, staged to be applied together,
+/// and then saved to flash with `commit`, or undone with `revert`. Changes
+/// are checked against the settings of the device as they are staged.
+///
+/// Changing a link-critical setting, see `is_link_critical`, is refused
+/// unless allowed with `allow_link_critical`, as a mistake would leave the
+/// device unreachable to fix it; once saved, even after restarting it.
+pub struct SettingsTransaction<'a> {
+    device: &'a mut Device,
+    settings: BTreeMap<String, RpcInfo>,
+    staged: SettingsSnapshot,
+    allow_link_critical: bool,
+}
+
+impl SettingsTransaction<'_> {
+    /// Stage a new value for the setting `name`.
+    pub fn set(&mut self, name: &str, value: SettingValue) -> Result<(), SettingsError> {
+        let info = self
+            .settings
+            .get(name)
+            .ok_or_else(|| SettingsError::UnknownSetting(name.to_string()))?;
+        if info.encode(&value).is_none() {
+            return Err(SettingsError::InvalidValue(name.to_string(), value));
+        }
+        if is_link_critical(name) {
+            log_warn!("Changing {} can cut the link to the device", name);
+        }
+        self.staged.set(name, value);
+        Ok(())
+    }
+
+    /// Stage all the settings of `snapshot`.
+    pub fn set_all(&mut self, snapshot: &SettingsSnapshot) -> Result<(), SettingsError> {
+        for (name, value) in &snapshot.values {
+            self.set(name, value.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Changes staged and not applied yet.
+    pub fn staged(&self) -> &SettingsSnapshot {
+        &self.staged
+    }
+
+    /// Allow changing link-critical settings.
+    pub fn allow_link_critical(&mut self, allow: bool) {
+        self.allow_link_critical = allow;
+    }
+
+    /// Apply the staged changes to the device, without saving them, and
+    /// return those which changed a setting. They can still be undone with
+    /// `revert`, or by restarting the device.
+    pub fn apply(&mut self) -> Result<Vec<SettingUpdate>, SettingsError> {
+        if !self.allow_link_critical {
+            if let Some(name) = self
+                .staged
+                .values
+                .keys()
+                .find(|name| is_link_critical(name))
+            {
+                return Err(SettingsError::LinkCritical(name.clone()));
+            }
+        }
+        let updates = self
+            .device
+            .apply_settings(&self.settings, &self.staged, false)?;
+        self.staged = SettingsSnapshot::new();
+        Ok(updates)
+    }
+
+    /// Apply the staged changes, and save all the settings to flash, so
+    /// that the device keeps them when it restarts.
+    pub fn commit(mut self) -> Result<Vec<SettingUpdate>, SettingsError> {
+        let updates = self.apply()?;
+        self.device.settings_action(SETTINGS_SAVE_RPC)?;
+        Ok(updates)
+    }
+
+    /// Drop the staged changes, and restore the settings last saved to
+    /// flash, undoing those applied since.
+    pub fn revert(self) -> Result<(), SettingsError> {
+        self.device.settings_action(SETTINGS_REVERT_RPC)
+    }
+}
@@ -0,0 +1,226 @@
+//! Stream statistics
+//!
+//! Rolling statistics of device samples, for quick health checks of the
+//! streams without processing the data any further. `StreamStatistics`
+//! keeps the samples of each stream received over a sliding window of
+//! sample time, and computes on request the mean, RMS, minimum and maximum
+//! of every column, as well as the rate at which samples are coming in.
+//!
+//! Statistics can also be published periodically to any number of
+//! subscribers, as samples are pushed.
+
+use super::Sample;
+use crate::tio::proto::meta::ColumnMetadata;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+
+/// Statistics of one column over the window. Samples where the column has
+/// no numeric value are ignored.
+#[derive(Debug, Clone)]
+pub struct ColumnStats {
+    pub desc: Arc<ColumnMetadata>,
+    /// Number of values in the window.
+    pub count: usize,
+    pub mean: f64,
+    pub rms: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Statistics of one stream over the window.
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    pub stream_id: u8,
+    pub name: String,
+    /// Number of samples in the window.
+    pub samples: usize,
+    /// Samples per second received over the window. Lower than the nominal
+    /// rate of the stream if samples are lost.
+    pub rate: f64,
+    pub columns: Vec<ColumnStats>,
+}
+
+struct StreamWindow {
+    name: String,
+    columns: Vec<Arc<ColumnMetadata>>,
+    /// Timestamp and column values of the samples in the window.
+    samples: VecDeque<(f64, Vec<f64>)>,
+    last_published: Option<Instant>,
+}
+
+impl StreamWindow {
+    fn new(sample: &Sample) -> StreamWindow {
+        StreamWindow {
+            name: sample.stream.name.clone(),
+            columns: sample.columns.iter().map(|col| col.desc.clone()).collect(),
+            samples: VecDeque::new(),
+            last_published: None,
+        }
+    }
+
+    fn stats(&self, stream_id: u8) -> StreamStats {
+        let columns = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, desc)| {
+                let mut stats = ColumnStats {
+                    desc: desc.clone(),
+                    count: 0,
+                    mean: f64::NAN,
+                    rms: f64::NAN,
+                    min: f64::NAN,
+                    max: f64::NAN,
+                };
+                let (mut sum, mut sum_sq) = (0.0, 0.0);
+                for v in self.samples.iter().map(|(_, values)| values[i]) {
+                    if v.is_nan() {
+                        continue;
+                    }
+                    stats.count += 1;
+                    sum += v;
+                    sum_sq += v * v;
+                    stats.min = stats.min.min(v);
+                    stats.max = stats.max.max(v);
+                }
+                if stats.count > 0 {
+                    stats.mean = sum / stats.count as f64;
+                    stats.rms = (sum_sq / stats.count as f64).sqrt();
+                }
+                stats
+            })
+            .collect();
+        let rate = match (self.samples.front(), self.samples.back()) {
+            (Some((first, _)), Some((last, _))) if last > first => {
+                (self.samples.len() - 1) as f64 / (last - first)
+            }
+            _ => 0.0,
+        };
+        StreamStats {
+            stream_id,
+            name: self.name.clone(),
+            samples: self.samples.len(),
+            rate,
+            columns,
+        }
+    }
+}
+
+/// Rolling statistics of the streams of a device.
+pub struct StreamStatistics {
+    window: f64,
+    publish_interval: Duration,
+    streams: BTreeMap<u8, StreamWindow>,
+    subscribers: Vec<channel::Sender<StreamStats>>,
+}
+
+impl StreamStatistics {
+    /// Statistics over the last `window` of sample time.
+    pub fn new(window: Duration) -> StreamStatistics {
+        StreamStatistics {
+            window: window.as_secs_f64(),
+            publish_interval: Duration::from_secs(1),
+            streams: BTreeMap::new(),
+            subscribers: vec![],
+        }
+    }
+
+    /// How often the statistics of each stream are published to subscribers.
+    /// The default is once per second.
+    pub fn with_publish_interval(mut self, interval: Duration) -> StreamStatistics {
+        self.publish_interval = interval;
+        self
+    }
+
+    /// Add a sample to the statistics of its stream.
+    pub fn push(&mut self, sample: &Sample) {
+        let stream_id = sample.stream.stream_id;
+        let t = sample.timestamp_begin();
+        let window = self
+            .streams
+            .entry(stream_id)
+            .or_insert_with(|| StreamWindow::new(sample));
+
+        // Start over if the stream changed, or time went backwards as when
+        // the device restarts.
+        let restarted = window.samples.back().is_some_and(|(last, _)| t < *last);
+        if sample.meta_changed || sample.segment_changed || restarted {
+            let last_published = window.last_published;
+            *window = StreamWindow::new(sample);
+            window.last_published = last_published;
+        }
+
+        let values = sample
+            .columns
+            .iter()
+            .map(|col| col.value.as_f64().unwrap_or(f64::NAN))
+            .collect();
+        window.samples.push_back((t, values));
+        while window
+            .samples
+            .front()
+            .is_some_and(|(first, _)| t - first > self.window)
+        {
+            window.samples.pop_front();
+        }
+
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        if window
+            .last_published
+            .is_some_and(|last| now.duration_since(last) < self.publish_interval)
+        {
+            return;
+        }
+        window.last_published = Some(now);
+        let stats = window.stats(stream_id);
+        // Subscribers which do not keep up miss updates; gone ones are dropped.
+        self.subscribers.retain(|sub| {
+            !matches!(
+                sub.try_send(stats.clone()),
+                Err(channel::TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// Add several samples, as returned by `Device::drain`.
+    pub fn extend<'a>(&mut self, samples: impl IntoIterator<Item = &'a Sample>) {
+        for sample in samples {
+            self.push(sample);
+        }
+    }
+
+    /// Current statistics of stream `stream_id`, if any sample was seen.
+    pub fn stream(&self, stream_id: u8) -> Option<StreamStats> {
+        self.streams
+            .get(&stream_id)
+            .map(|window| window.stats(stream_id))
+    }
+
+    /// Current statistics of every stream seen, by stream id.
+    pub fn streams(&self) -> Vec<StreamStats> {
+        self.streams
+            .iter()
+            .map(|(id, window)| window.stats(*id))
+            .collect()
+    }
+
+    /// Receive the statistics of each stream, at most once per publish
+    /// interval, as samples are pushed.
+    pub fn subscribe(&mut self) -> channel::Receiver<StreamStats> {
+        let (tx, rx) = channel::bounded(64);
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Forget all samples seen.
+    pub fn reset(&mut self) {
+        self.streams.clear();
+    }
+}
@@ -0,0 +1,94 @@
+//! Timebases
+//!
+//! Devices number the samples of each stream, and describe when sample 0
+//! was taken, and how often samples are taken, with a timebase: segment
+//! metadata for current devices, and timebase update packets for legacy
+//! ones. A `Timebase` turns sample numbers into time since the epoch of the
+//! timebase, and into absolute time for timebases referenced to Unix time:
+//! ```no_run
+//! # use twinleaf::data::Device;
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! # let proxy = proxy::Interface::new("tcp://localhost");
+//! # let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! let sample = device.next();
+//! if let Some(time) = sample.system_time() {
+//!     println!("{:?}: {:?}", time, sample.columns[0].value);
+//! }
+//! ```
+//! Devices send a new timebase when it changes, for example when starting
+//! a new segment, and samples always refer to the latest one.
+
+use crate::tio::proto::legacy::{LegacyTimebaseEpoch, LegacyTimebaseInfoPayload};
+use crate::tio::proto::meta::{MetadataEpoch, SegmentMetadata};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Unix time of the GPS epoch, 1980-01-06.
+static GPS_EPOCH_UNIX: f64 = 315_964_800.0;
+/// Leap seconds between GPS time and UTC, since the end of 2016.
+static GPS_LEAP_SECONDS: f64 = 18.0;
+
+/// Time of the samples of a stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timebase {
+    /// What the time is referenced to. GPS time is converted to Unix time.
+    pub epoch: MetadataEpoch,
+    /// Time of sample 0 since the epoch, in seconds.
+    pub start: f64,
+    /// Time between samples, in seconds.
+    pub period: f64,
+}
+
+impl Timebase {
+    /// Timebase of the samples of a segment.
+    pub fn from_segment(segment: &SegmentMetadata) -> Timebase {
+        Timebase {
+            epoch: segment.time_ref_epoch.clone(),
+            start: f64::from(segment.start_time),
+            period: f64::from(segment.decimation) / f64::from(segment.sampling_rate),
+        }
+    }
+
+    /// Timebase announced by a legacy device, whose start time is in
+    /// nanoseconds.
+    pub fn from_legacy(info: &LegacyTimebaseInfoPayload) -> Timebase {
+        let start = info.start_time as f64 * 1e-9;
+        let (epoch, start) = match info.epoch {
+            LegacyTimebaseEpoch::Start => (MetadataEpoch::Zero, start),
+            LegacyTimebaseEpoch::SysTime => (MetadataEpoch::Systime, start),
+            LegacyTimebaseEpoch::Unix => (MetadataEpoch::Unix, start),
+            LegacyTimebaseEpoch::GPS => (
+                MetadataEpoch::Unix,
+                start + GPS_EPOCH_UNIX - GPS_LEAP_SECONDS,
+            ),
+            LegacyTimebaseEpoch::Invalid => (MetadataEpoch::Invalid, start),
+            LegacyTimebaseEpoch::Unknown(x) => (MetadataEpoch::Unknown(x), start),
+        };
+        Timebase {
+            epoch,
+            start,
+            period: info.period(),
+        }
+    }
+
+    /// Time of sample `n` since the epoch, in seconds.
+    pub fn time(&self, n: u32) -> f64 {
+        self.start + self.period * f64::from(n)
+    }
+
+    /// Unix time of sample `n`, in seconds, if the timebase is referenced
+    /// to Unix time.
+    pub fn unix_time(&self, n: u32) -> Option<f64> {
+        match self.epoch {
+            MetadataEpoch::Unix => Some(self.time(n)),
+            _ => None,
+        }
+    }
+
+    /// Absolute time of sample `n`, if the timebase is referenced to Unix
+    /// time.
+    pub fn system_time(&self, n: u32) -> Option<SystemTime> {
+        let time = self.unix_time(n)?;
+        UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(time).ok()?)
+    }
+}
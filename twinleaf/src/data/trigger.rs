@@ -0,0 +1,352 @@
+//! Triggers
+//!
+//! Event-driven capture of samples, for experiments where logging all the
+//! data continuously would be too large. A `Capture` watches the samples of
+//! a stream for a `TriggerCondition`, on a column value or on a setting
+//! published by the device, and records a window of samples around it:
+//! a number of samples before the trigger, and samples after it until an
+//! optional stop condition is met plus a number of samples.
+//!
+//! Completed windows are returned by `Capture::process`, and can be written
+//! out as CSV with `CaptureWindow::write_csv`.
+
+use super::{ColumnData, Sample, SettingChange};
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Default limit on the length of a capture window, in samples.
+static DEFAULT_MAX_WINDOW_SAMPLES: usize = 1 << 20;
+
+/// Condition starting or stopping a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TriggerCondition {
+    /// The column value is above the level.
+    Above { column: String, level: f64 },
+    /// The column value is below the level.
+    Below { column: String, level: f64 },
+    /// The column value crosses the level upwards.
+    Rising { column: String, level: f64 },
+    /// The column value crosses the level downwards.
+    Falling { column: String, level: f64 },
+    /// The device publishes a new value for the setting, see
+    /// `Capture::setting_changed`.
+    Setting { name: String },
+    /// Only triggered explicitly, with `Capture::trigger`.
+    Manual,
+}
+
+impl TriggerCondition {
+    pub fn above(column: &str, level: f64) -> TriggerCondition {
+        TriggerCondition::Above {
+            column: column.to_string(),
+            level,
+        }
+    }
+
+    pub fn below(column: &str, level: f64) -> TriggerCondition {
+        TriggerCondition::Below {
+            column: column.to_string(),
+            level,
+        }
+    }
+
+    pub fn rising(column: &str, level: f64) -> TriggerCondition {
+        TriggerCondition::Rising {
+            column: column.to_string(),
+            level,
+        }
+    }
+
+    pub fn falling(column: &str, level: f64) -> TriggerCondition {
+        TriggerCondition::Falling {
+            column: column.to_string(),
+            level,
+        }
+    }
+
+    pub fn setting(name: &str) -> TriggerCondition {
+        TriggerCondition::Setting {
+            name: name.to_string(),
+        }
+    }
+
+    fn column(&self) -> Option<&str> {
+        match self {
+            TriggerCondition::Above { column, .. }
+            | TriggerCondition::Below { column, .. }
+            | TriggerCondition::Rising { column, .. }
+            | TriggerCondition::Falling { column, .. } => Some(column),
+            _ => None,
+        }
+    }
+
+    /// Evaluate the condition on a column value, given the previous one.
+    fn is_met(&self, previous: Option<f64>, value: f64) -> bool {
+        match *self {
+            TriggerCondition::Above { level, .. } => value > level,
+            TriggerCondition::Below { level, .. } => value < level,
+            TriggerCondition::Rising { level, .. } => {
+                previous.is_some_and(|p| p < level) && value >= level
+            }
+            TriggerCondition::Falling { level, .. } => {
+                previous.is_some_and(|p| p > level) && value <= level
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Tracks the column value a condition applies to across samples.
+#[derive(Debug, Clone)]
+struct ConditionState {
+    condition: TriggerCondition,
+    previous: Option<f64>,
+    /// Set when triggered by a setting or explicitly.
+    forced: bool,
+}
+
+impl ConditionState {
+    fn new(condition: TriggerCondition) -> ConditionState {
+        ConditionState {
+            condition,
+            previous: None,
+            forced: false,
+        }
+    }
+
+    fn update(&mut self, sample: &Sample) -> bool {
+        let value = self
+            .condition
+            .column()
+            .and_then(|name| sample.column(name))
+            .and_then(|col| col.value.as_f64());
+        let met = match value {
+            Some(value) => self.condition.is_met(self.previous, value),
+            None => false,
+        };
+        self.previous = value;
+        met || std::mem::take(&mut self.forced)
+    }
+}
+
+/// Samples recorded around a trigger.
+#[derive(Debug, Clone)]
+pub struct CaptureWindow {
+    /// Index in `samples` of the sample which met the trigger condition.
+    pub trigger_index: usize,
+    pub samples: Vec<Sample>,
+    /// The window was cut short, by reaching the maximum window length or
+    /// by a change in the stream.
+    pub truncated: bool,
+}
+
+impl CaptureWindow {
+    /// The sample which met the trigger condition.
+    pub fn trigger_sample(&self) -> &Sample {
+        &self.samples[self.trigger_index]
+    }
+
+    /// Write the samples as CSV, with a time column followed by the columns
+    /// of the stream.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let Some(first) = self.samples.first() else {
+            return Ok(());
+        };
+        write!(out, "time")?;
+        for col in &first.columns {
+            write!(out, ",{}", col.desc.name)?;
+        }
+        writeln!(out)?;
+        for sample in &self.samples {
+            write!(out, "{:.6}", sample.timestamp_begin())?;
+            for col in &sample.columns {
+                match col.value {
+                    ColumnData::Int(x) => write!(out, ",{}", x)?,
+                    ColumnData::UInt(x) => write!(out, ",{}", x)?,
+                    ColumnData::Float(x) => write!(out, ",{}", x)?,
+                    ColumnData::Unknown => write!(out, ",")?,
+                }
+            }
+            writeln!(out)?;
+        }
+        out.flush()
+    }
+}
+
+struct Recording {
+    window: CaptureWindow,
+    /// Samples left to record, once the stop condition is met.
+    remaining: Option<usize>,
+}
+
+/// Records windows of samples of a stream around trigger events.
+pub struct Capture {
+    stream_id: Option<u8>,
+    start: ConditionState,
+    stop: Option<ConditionState>,
+    pre_trigger: usize,
+    post_trigger: usize,
+    max_samples: usize,
+    /// Capture again after each window, rather than only once.
+    rearm: bool,
+    armed: bool,
+    history: VecDeque<Sample>,
+    recording: Option<Recording>,
+}
+
+impl Capture {
+    /// Capture `pre_trigger` samples before and `post_trigger` samples after
+    /// each sample meeting `condition`.
+    pub fn new(condition: TriggerCondition, pre_trigger: usize, post_trigger: usize) -> Capture {
+        Capture {
+            stream_id: None,
+            start: ConditionState::new(condition),
+            stop: None,
+            pre_trigger,
+            post_trigger,
+            max_samples: DEFAULT_MAX_WINDOW_SAMPLES,
+            rearm: true,
+            armed: true,
+            history: VecDeque::new(),
+            recording: None,
+        }
+    }
+
+    /// Only consider samples of stream `stream_id`. By default, the stream
+    /// of the first sample processed is used.
+    pub fn with_stream(mut self, stream_id: u8) -> Capture {
+        self.stream_id = Some(stream_id);
+        self
+    }
+
+    /// Keep recording after the trigger until `condition` is met, then for
+    /// the post-trigger samples.
+    pub fn with_stop(mut self, condition: TriggerCondition) -> Capture {
+        self.stop = Some(ConditionState::new(condition));
+        self
+    }
+
+    /// Limit the length of capture windows.
+    pub fn with_max_samples(mut self, max_samples: usize) -> Capture {
+        self.max_samples = max_samples.max(1);
+        self
+    }
+
+    /// Only capture the first window, until `arm` is called again.
+    pub fn single_shot(mut self) -> Capture {
+        self.rearm = false;
+        self
+    }
+
+    /// Wait for the trigger again, after a single shot capture.
+    pub fn arm(&mut self) {
+        self.armed = true;
+    }
+
+    /// True while recording a window.
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Trigger with the next sample processed, whatever the condition.
+    pub fn trigger(&mut self) {
+        self.start.forced = true;
+    }
+
+    /// Stop recording with the next sample processed, whatever the stop
+    /// condition.
+    pub fn stop(&mut self) {
+        if let Some(stop) = &mut self.stop {
+            stop.forced = true;
+        }
+    }
+
+    /// Pass settings published by the device, as received from
+    /// `Device::setting_changes`, for `TriggerCondition::Setting`.
+    pub fn setting_changed(&mut self, change: &SettingChange) {
+        let is_named = |state: &ConditionState| matches!(&state.condition, TriggerCondition::Setting { name } if *name == change.name);
+        if is_named(&self.start) {
+            self.start.forced = true;
+        }
+        if let Some(stop) = &mut self.stop {
+            if is_named(stop) {
+                stop.forced = true;
+            }
+        }
+    }
+
+    fn finish(&mut self, truncated: bool) -> Option<CaptureWindow> {
+        let mut window = self.recording.take()?.window;
+        window.truncated |= truncated;
+        if !self.rearm {
+            self.armed = false;
+        }
+        Some(window)
+    }
+
+    /// Process a sample, returning a capture window if it completes one.
+    pub fn process(&mut self, sample: &Sample) -> Option<CaptureWindow> {
+        let stream_id = *self.stream_id.get_or_insert(sample.stream.stream_id);
+        if sample.stream.stream_id != stream_id {
+            return None;
+        }
+
+        // Samples before and after a change in the stream do not go together.
+        let mut ret = None;
+        if sample.meta_changed || sample.segment_changed {
+            self.history.clear();
+            ret = self.finish(true);
+        }
+
+        let started = self.start.update(sample);
+        let stopped = match &mut self.stop {
+            Some(stop) => stop.update(sample),
+            None => true,
+        };
+
+        if let Some(recording) = &mut self.recording {
+            recording.window.samples.push(sample.clone());
+            if recording.remaining.is_none() && stopped {
+                recording.remaining = Some(self.post_trigger);
+            } else if let Some(remaining) = &mut recording.remaining {
+                *remaining = remaining.saturating_sub(1);
+            }
+            if recording.remaining == Some(0) {
+                return self.finish(false);
+            }
+            if recording.window.samples.len() >= self.max_samples {
+                return self.finish(true);
+            }
+            return ret;
+        }
+
+        if started && self.armed {
+            let mut samples: Vec<Sample> = self.history.drain(..).collect();
+            let trigger_index = samples.len();
+            samples.push(sample.clone());
+            let remaining = if self.stop.is_none() {
+                Some(self.post_trigger)
+            } else {
+                None
+            };
+            self.recording = Some(Recording {
+                window: CaptureWindow {
+                    trigger_index,
+                    samples,
+                    truncated: false,
+                },
+                remaining,
+            });
+            if remaining == Some(0) {
+                return self.finish(false);
+            }
+        } else if self.pre_trigger > 0 {
+            if self.history.len() == self.pre_trigger {
+                self.history.pop_front();
+            }
+            self.history.push_back(sample.clone());
+        }
+        ret
+    }
+}
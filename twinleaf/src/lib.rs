@@ -1,2 +1,32 @@
+//! Twinleaf I/O
+//!
+//! Without the default `std` feature, only the protocol core is built, for
+//! `no_std` targets with an allocator: `tio::proto` (packets and routes) and
+//! `tio::util` (`PacketBuilder` and RPC value encodings). This lets firmware
+//! and embedded gateways share the protocol code of the host tools.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "control")]
+pub mod control;
+#[cfg(feature = "std")]
 pub mod data;
+#[cfg(feature = "lsl")]
+pub mod lsl;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "std")]
+pub mod prelude;
+#[cfg(feature = "std")]
+pub mod shutdown;
 pub mod tio;
+#[cfg(feature = "zstd")]
+pub mod zstd;
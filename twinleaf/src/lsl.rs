@@ -0,0 +1,184 @@
+//! Lab Streaming Layer
+//!
+//! Exposes the streams of a device as LSL outlets, for labs synchronizing
+//! their instruments with LSL. Enabled with the `lsl` feature, using the
+//! `lsl` crate, which builds liblsl and needs cmake for it.
+//!
+//! `Outlets` creates an outlet for each stream of the device as its samples
+//! arrive, named after the device and stream, typed with the stream name,
+//! with the column names and units as channel metadata, and with the source
+//! id of the device serial number and stream, so that recorders can pick up
+//! again after restarts:
+//! ```no_run
+//! # use twinleaf::data::Device;
+//! # use twinleaf::lsl::Outlets;
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! let mut outlets = Outlets::new();
+//! loop {
+//!     outlets.push(&device.next()).unwrap();
+//! }
+//! ```
+//!
+//! Samples are timestamped with the LSL clock: from their Unix time if the
+//! device time is referenced to Unix time, and otherwise relative to the
+//! first sample of the segment, taken to be received when it was taken.
+
+use crate::data::Sample;
+
+use lsl::{ChannelFormat, ExPushable, StreamInfo, StreamOutlet};
+use std::collections::HashMap;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds of data buffered by outlets for slow consumers.
+static MAX_BUFFERED: i32 = 360;
+
+/// Error of the `lsl` crate, as an I/O error.
+fn lsl_error(err: lsl::Error) -> io::Error {
+    io::Error::other(format!("LSL error: {}", err))
+}
+
+/// Metadata strings, which cannot contain NUL characters.
+fn clean(s: &str) -> String {
+    s.replace('\0', "")
+}
+
+/// Current LSL clock.
+pub fn local_clock() -> f64 {
+    lsl::local_clock()
+}
+
+/// LSL outlet of a stream of a device.
+pub struct Outlet {
+    outlet: StreamOutlet,
+    n_columns: usize,
+    /// Offset from the sample time to the LSL clock, in seconds.
+    clock_offset: f64,
+    segment_id: u8,
+    /// Buffer for the values of a sample.
+    values: Vec<f64>,
+}
+
+// liblsl outlets can be used from any thread, but the `lsl` crate does not
+// say so.
+unsafe impl Send for Outlet {}
+
+impl Outlet {
+    /// Create an outlet for the stream of this sample, with its metadata.
+    pub fn new(sample: &Sample) -> io::Result<Outlet> {
+        let name = format!("{} {}", sample.device.name, sample.stream.name);
+        let source_id = format!(
+            "{}:{}",
+            sample.device.serial_number, sample.stream.stream_id
+        );
+        let rate = 1.0 / sample.period();
+        let mut info = StreamInfo::new(
+            &clean(&name),
+            &clean(&sample.stream.name),
+            sample.columns.len() as u32,
+            if rate.is_finite() { rate } else { 0.0 },
+            ChannelFormat::Double64,
+            &clean(&source_id),
+        )
+        .map_err(lsl_error)?;
+        let mut desc = info.desc();
+        desc.append_child_value("manufacturer", "Twinleaf");
+        desc.append_child_value("serial_number", &clean(&sample.device.serial_number));
+        desc.append_child_value("firmware", &clean(&sample.device.firmware_hash));
+        let mut channels = desc.append_child("channels");
+        for col in &sample.columns {
+            let mut channel = channels.append_child("channel");
+            channel.append_child_value("label", &clean(&col.desc.name));
+            channel.append_child_value("unit", &clean(&col.desc.units));
+            channel.append_child_value("description", &clean(&col.desc.description));
+        }
+        Ok(Outlet {
+            outlet: StreamOutlet::new(&info, 0, MAX_BUFFERED).map_err(lsl_error)?,
+            n_columns: sample.columns.len(),
+            clock_offset: Outlet::clock_offset(sample),
+            segment_id: sample.segment.segment_id,
+            values: vec![],
+        })
+    }
+
+    /// Offset from the time of the sample, as returned by `sample_time`,
+    /// to the LSL clock.
+    fn clock_offset(sample: &Sample) -> f64 {
+        let now = local_clock();
+        match sample.unix_time() {
+            Some(_) => {
+                let unix_now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0.0, |d| d.as_secs_f64());
+                now - unix_now
+            }
+            None => now - sample.timestamp_begin(),
+        }
+    }
+
+    /// Time of the sample, Unix time if possible.
+    fn sample_time(sample: &Sample) -> f64 {
+        sample
+            .unix_time()
+            .unwrap_or_else(|| sample.timestamp_begin())
+    }
+
+    /// Push a sample of the stream, with its timestamp on the LSL clock.
+    /// Samples with different columns need a new outlet.
+    pub fn push(&mut self, sample: &Sample) -> io::Result<()> {
+        if sample.columns.len() != self.n_columns {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sample does not match the outlet",
+            ));
+        }
+        if sample.segment.segment_id != self.segment_id {
+            // The device time restarts with the segment.
+            self.clock_offset = Outlet::clock_offset(sample);
+            self.segment_id = sample.segment.segment_id;
+        }
+        self.values.clear();
+        self.values.extend(
+            sample
+                .columns
+                .iter()
+                .map(|col| col.value.as_f64().unwrap_or(f64::NAN)),
+        );
+        let timestamp = Outlet::sample_time(sample) + self.clock_offset;
+        self.outlet
+            .push_sample_ex(&self.values, timestamp, true)
+            .map_err(lsl_error)
+    }
+}
+
+/// Outlets of all the streams of a device, created as needed.
+#[derive(Default)]
+pub struct Outlets {
+    outlets: HashMap<u8, Outlet>,
+}
+
+impl Outlets {
+    pub fn new() -> Outlets {
+        Outlets::default()
+    }
+
+    /// Push a sample to the outlet of its stream, creating it for the first
+    /// sample of the stream and again when its metadata changes.
+    pub fn push(&mut self, sample: &Sample) -> io::Result<()> {
+        let stream_id = sample.stream.stream_id;
+        if sample.meta_changed
+            || self
+                .outlets
+                .get(&stream_id)
+                .is_none_or(|outlet| outlet.n_columns != sample.columns.len())
+        {
+            self.outlets.insert(stream_id, Outlet::new(sample)?);
+        }
+        self.outlets
+            .get_mut(&stream_id)
+            .expect("outlet was just created")
+            .push(sample)
+    }
+}
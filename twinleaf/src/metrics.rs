@@ -0,0 +1,232 @@
+//! Metrics
+//!
+//! Exposes proxy and device statistics in the Prometheus text exposition
+//! format, so that acquisition hosts can be monitored with standard
+//! infrastructure. Enabled with the `metrics` feature.
+//!
+//! A `Metrics` registry is cheap to clone and can be shared between threads.
+//! Proxy status events are accounted for with `record_event`, and any other
+//! value (for example housekeeping or pipeline statistics) can be published
+//! with `counter_add` and `gauge_set`. `serve` starts a minimal HTTP server
+//! answering `GET /metrics`.
+
+use crate::tio::proxy::{Event, LinkStatus};
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Maximum size of an HTTP request accepted by the server.
+static MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+struct MetricFamily {
+    kind: MetricKind,
+    help: String,
+    /// Values keyed by their rendered label set.
+    values: BTreeMap<String, f64>,
+}
+
+/// Shared registry of metrics.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    families: Arc<Mutex<BTreeMap<String, MetricFamily>>>,
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return "".to_string();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        help: &str,
+        kind: MetricKind,
+        labels: &[(&str, &str)],
+        f: impl FnOnce(&mut f64),
+    ) {
+        let mut families = self.families.lock().expect("metrics lock poisoned");
+        let family = families
+            .entry(name.to_string())
+            .or_insert_with(|| MetricFamily {
+                kind,
+                help: help.to_string(),
+                values: BTreeMap::new(),
+            });
+        f(family.values.entry(render_labels(labels)).or_insert(0.0));
+    }
+
+    /// Add `value` to a counter.
+    pub fn counter_add(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, help, MetricKind::Counter, labels, |v| *v += value);
+    }
+
+    /// Set the current value of a gauge.
+    pub fn gauge_set(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, help, MetricKind::Gauge, labels, |v| *v = value);
+    }
+
+    fn gauge_add(&self, name: &str, help: &str, value: f64) {
+        self.update(name, help, MetricKind::Gauge, &[], |v| *v += value);
+    }
+
+    /// Account for a status event sent by a proxy.
+    pub fn record_event(&self, event: &Event) {
+        let desc = format!("{:?}", event);
+        let kind = desc.split('(').next().unwrap_or(&desc);
+        self.counter_add(
+            "twinleaf_proxy_events_total",
+            "Proxy status events, by type.",
+            &[("event", kind)],
+            1.0,
+        );
+
+        static CONNECTED: &str = "twinleaf_proxy_sensor_connected";
+        static CONNECTED_HELP: &str = "Whether the proxy is connected to its sensor.";
+        static CLIENTS: &str = "twinleaf_proxy_clients";
+        static CLIENTS_HELP: &str = "Number of clients connected to the proxy.";
+        static SLEEPING: &str = "twinleaf_proxy_sensor_sleeping";
+        static SLEEPING_HELP: &str = "Whether the root device is in low power mode.";
+        static OVERLOADED: &str = "twinleaf_proxy_link_overloaded";
+        static OVERLOADED_HELP: &str =
+            "Whether the sensor sends more data than its link can carry without delays.";
+        match event {
+            Event::SensorConnected | Event::SensorReconnected => {
+                self.gauge_set(CONNECTED, CONNECTED_HELP, &[], 1.0)
+            }
+            Event::SensorDisconnected
+            | Event::FailedToConnect(_)
+            | Event::FailedToReconnect(_)
+            | Event::Exiting => self.gauge_set(CONNECTED, CONNECTED_HELP, &[], 0.0),
+            Event::NewClient(_) => self.gauge_add(CLIENTS, CLIENTS_HELP, 1.0),
+            Event::ClientTerminated(_) => self.gauge_add(CLIENTS, CLIENTS_HELP, -1.0),
+            Event::RootDeviceSleeping => self.gauge_set(SLEEPING, SLEEPING_HELP, &[], 1.0),
+            Event::RootDeviceAwake => self.gauge_set(SLEEPING, SLEEPING_HELP, &[], 0.0),
+            Event::SetRate(rate) => self.gauge_set(
+                "twinleaf_proxy_port_rate_bps",
+                "Current data rate of the sensor port.",
+                &[],
+                *rate as f64,
+            ),
+            Event::LinkOverloaded(..) => self.gauge_set(OVERLOADED, OVERLOADED_HELP, &[], 1.0),
+            Event::LinkLoadNormal(..) => self.gauge_set(OVERLOADED, OVERLOADED_HELP, &[], 0.0),
+            _ => {}
+        }
+    }
+
+    /// Publish the packet and error counters of a proxy's link, as returned
+    /// by `proxy::Interface::link_status`.
+    pub fn record_link_status(&self, status: &LinkStatus) {
+        let counters = &status.counters;
+        let set = |name: &str, help: &str, value: u64| {
+            self.update(name, help, MetricKind::Counter, &[], |v| *v = value as f64)
+        };
+        set(
+            "twinleaf_proxy_link_packets_total",
+            "Packets received intact from the sensor.",
+            counters.packets,
+        );
+        set(
+            "twinleaf_proxy_link_crc_errors_total",
+            "Packets from the sensor which failed their checksum.",
+            counters.crc_errors,
+        );
+        set(
+            "twinleaf_proxy_link_framing_errors_total",
+            "Data from the sensor which could not be decoded as packets.",
+            counters.framing_errors,
+        );
+        set(
+            "twinleaf_proxy_link_resyncs_total",
+            "Times packets were received from the sensor again after errors.",
+            counters.resyncs,
+        );
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().expect("metrics lock poisoned");
+        let mut ret = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            ret += &format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, family.help, name, kind
+            );
+            for (labels, value) in family.values.iter() {
+                ret += &format!("{}{} {}\n", name, labels, value);
+            }
+        }
+        ret
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf)?;
+            if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut words = request.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    /// Serve `/metrics` over HTTP on `addr`, from a background thread.
+    /// Requests are handled one at a time, which is plenty for scraping.
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        let metrics = self.clone();
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Errors only affect the one scrape, so they are ignored.
+                let _ = metrics.handle_connection(stream);
+            }
+        }))
+    }
+}
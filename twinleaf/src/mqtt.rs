@@ -0,0 +1,244 @@
+//! MQTT
+//!
+//! Publishes decoded samples and device events to an MQTT broker, to feed
+//! existing IoT dashboards and alerting. Enabled with the `mqtt` feature.
+//!
+//! A `Publisher` sends each column of each sample to a topic, and optionally
+//! the whole sample as JSON (see `data::jsonl`) and events as text, to
+//! topics given by templates where `{device}`, `{stream}` and `{column}` are
+//! replaced by the serial number of the device, and the names of the stream
+//! and column:
+//! ```no_run
+//! # use twinleaf::data::Device;
+//! # use twinleaf::mqtt::{Publisher, Topics};
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! let mut publisher = Publisher::connect("broker:1883", "tio", Topics::default()).unwrap();
+//! loop {
+//!     publisher.publish_sample(&device.next()).unwrap();
+//! }
+//! ```
+//!
+//! Only the part of MQTT 3.1.1 needed for this is implemented: messages are
+//! published with QoS 0, without authentication or TLS.
+
+use crate::data::{jsonl, ColumnData, Sample};
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Keep alive interval announced to the broker.
+static KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// How long to wait for the broker to accept the connection.
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const RETAIN: u8 = 0x01;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+/// Clean session flag of CONNECT.
+const CLEAN_SESSION: u8 = 0x02;
+
+/// Topic templates of a `Publisher`. Templates which are None are not
+/// published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    /// Topic of the value of each column, as text.
+    pub columns: Option<String>,
+    /// Topic of each sample, as a JSON object.
+    pub samples: Option<String>,
+    /// Topic of device events, as text.
+    pub events: Option<String>,
+}
+
+impl Default for Topics {
+    fn default() -> Topics {
+        Topics {
+            columns: Some("twinleaf/{device}/{stream}/{column}".to_string()),
+            samples: None,
+            events: Some("twinleaf/{device}/events".to_string()),
+        }
+    }
+}
+
+fn topic(template: &str, device: &str, stream: &str, column: &str) -> String {
+    template
+        .replace("{device}", device)
+        .replace("{stream}", stream)
+        .replace("{column}", column)
+}
+
+/// Append an MQTT string, prefixed with its length.
+fn push_string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s);
+}
+
+/// A control packet with the given fixed header byte and body.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut ret = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            ret.push(byte | 0x80);
+        } else {
+            ret.push(byte);
+            break;
+        }
+    }
+    ret.extend(body);
+    ret
+}
+
+/// Connection to an MQTT broker.
+pub struct Client {
+    stream: TcpStream,
+    last_send: Instant,
+}
+
+impl Client {
+    /// Connect to the broker at `addr` as `client_id`, with a clean session.
+    pub fn connect<A: ToSocketAddrs>(addr: A, client_id: &str) -> io::Result<Client> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut body = vec![];
+        push_string(&mut body, b"MQTT");
+        body.push(4); // protocol level 3.1.1
+        body.push(CLEAN_SESSION);
+        body.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        push_string(&mut body, client_id.as_bytes());
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != CONNACK || connack[1] != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected reply to MQTT connect",
+            ));
+        }
+        if connack[3] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("MQTT broker refused the connection ({})", connack[3]),
+            ));
+        }
+        // The broker only sends ping replies from now on, which are drained
+        // by a thread until the connection closes.
+        stream.set_read_timeout(None)?;
+        let mut replies = stream.try_clone()?;
+        thread::spawn(move || io::copy(&mut replies, &mut io::sink()));
+        Ok(Client {
+            stream,
+            last_send: Instant::now(),
+        })
+    }
+
+    fn send(&mut self, pkt: &[u8]) -> io::Result<()> {
+        self.stream.write_all(pkt)?;
+        self.last_send = Instant::now();
+        Ok(())
+    }
+
+    /// Publish `payload` to `topic`, with QoS 0. Retained messages are
+    /// kept by the broker for future subscribers.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = vec![];
+        push_string(&mut body, topic.as_bytes());
+        body.extend(payload);
+        let header = if retain { PUBLISH | RETAIN } else { PUBLISH };
+        self.send(&packet(header, &body))
+    }
+
+    /// Keep the connection alive while nothing is published. Should be
+    /// called regularly, at least every 30 seconds.
+    pub fn poll(&mut self) -> io::Result<()> {
+        if self.last_send.elapsed() > KEEP_ALIVE / 2 {
+            self.send(&packet(PINGREQ, &[]))?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect cleanly from the broker.
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))?;
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+/// Publishes samples and events to the topics configured.
+pub struct Publisher {
+    client: Client,
+    topics: Topics,
+}
+
+impl Publisher {
+    /// Connect to the broker at `addr` as `client_id`.
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        client_id: &str,
+        topics: Topics,
+    ) -> io::Result<Publisher> {
+        Ok(Publisher {
+            client: Client::connect(addr, client_id)?,
+            topics,
+        })
+    }
+
+    /// Publish the columns of the sample, and the whole sample if enabled.
+    /// Placeholder samples filling gaps are skipped.
+    pub fn publish_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        if sample.placeholder {
+            return Ok(());
+        }
+        let device = &sample.device.serial_number;
+        let stream = &sample.stream.name;
+        if let Some(template) = &self.topics.columns {
+            for col in &sample.columns {
+                let value = match col.value {
+                    ColumnData::Int(x) => x.to_string(),
+                    ColumnData::UInt(x) => x.to_string(),
+                    ColumnData::Float(x) => x.to_string(),
+                    ColumnData::Unknown => continue,
+                };
+                let topic = topic(template, device, stream, &col.desc.name);
+                self.client.publish(&topic, value.as_bytes(), false)?;
+            }
+        }
+        if let Some(template) = &self.topics.samples {
+            let topic = topic(template, device, stream, "");
+            let json = jsonl::sample_to_json(sample);
+            self.client.publish(&topic, json.as_bytes(), false)?;
+        }
+        self.client.poll()
+    }
+
+    /// Publish an event of the device with this serial number, if enabled.
+    /// Events are retained, so that new subscribers get the latest one.
+    pub fn publish_event(&mut self, device: &str, event: &str) -> io::Result<()> {
+        if let Some(template) = &self.topics.events {
+            let topic = topic(template, device, "", "");
+            self.client.publish(&topic, event.as_bytes(), true)?;
+        }
+        self.client.poll()
+    }
+
+    /// Keep the connection alive when there is nothing to publish, see
+    /// `Client::poll`.
+    pub fn poll(&mut self) -> io::Result<()> {
+        self.client.poll()
+    }
+
+    /// Disconnect cleanly from the broker.
+    pub fn disconnect(self) -> io::Result<()> {
+        self.client.disconnect()
+    }
+}
@@ -0,0 +1,19 @@
+//! Prelude
+//!
+//! The most commonly used types, for a single glob import:
+//! ```no_run
+//! use twinleaf::prelude::*;
+//!
+//! let proxy = Proxy::builder().url("tcp://localhost").spawn();
+//! let mut device = Device::new(proxy.device_full(DeviceRoute::root()).unwrap());
+//! println!("{:?}", device.next());
+//! ```
+
+pub use crate::data::{ColumnData, Device, Sample};
+pub use crate::shutdown::Shutdown;
+pub use crate::tio::proto::{DeviceRoute, Packet, Payload};
+pub use crate::tio::proxy::{
+    self, Event, ForwardingPolicy, Interface as Proxy, Port, PortBuilder, ProxyBuilder,
+    RetryPolicy, RpcError,
+};
+pub use crate::tio::util::{TioRpcReplyable, TioRpcRequestable};
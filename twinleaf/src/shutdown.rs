@@ -0,0 +1,134 @@
+//! Shutdown
+//!
+//! Coordinated shutdown for long running programs, such as a proxy server or
+//! a recorder. A `Shutdown` is a cheap to clone handle: any part of the
+//! program can request shutdown, poll whether it was requested, or wait for
+//! it in a `crossbeam::select!` alongside its other channels.
+//!
+//! Components register cleanup tasks with `on_shutdown` (flushing files,
+//! finalizing indices, ...). `finish` runs them in reverse registration
+//! order and collects a one line summary from each, which the program can
+//! report before exiting.
+//!
+//! On unix, `install_signal_handlers` requests shutdown on SIGTERM or SIGINT.
+//! A second signal terminates the process immediately.
+
+use crossbeam::channel;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+type CleanupTask = Box<dyn FnOnce() -> Option<String> + Send>;
+
+struct Inner {
+    requested: AtomicBool,
+    /// Dropped when shutdown is requested, which wakes up all receivers.
+    notify: Mutex<Option<channel::Sender<()>>>,
+    waiter: channel::Receiver<()>,
+    tasks: Mutex<Vec<(String, CleanupTask)>>,
+}
+
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (notify, waiter) = channel::bounded(0);
+        Shutdown {
+            inner: Arc::new(Inner {
+                requested: AtomicBool::new(false),
+                notify: Mutex::new(Some(notify)),
+                waiter,
+                tasks: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Request shutdown on SIGTERM and SIGINT. A second signal exits the
+    /// process right away, without running cleanup tasks.
+    #[cfg(unix)]
+    pub fn install_signal_handlers(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = self.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_requested() {
+                    std::process::exit(128 + signal);
+                }
+                log_info!("Shutdown requested by signal {}", signal);
+                shutdown.request();
+            }
+        });
+        Ok(())
+    }
+
+    /// Signal handling is only supported on unix.
+    #[cfg(not(unix))]
+    pub fn install_signal_handlers(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "signal handling not supported on this platform",
+        ))
+    }
+
+    /// Request shutdown. This can be called any number of times.
+    pub fn request(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        drop(
+            self.inner
+                .notify
+                .lock()
+                .expect("shutdown lock poisoned")
+                .take(),
+        );
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// A receiver which becomes ready (disconnected) once shutdown is
+    /// requested. Never delivers any message; intended for `select!`.
+    pub fn receiver(&self) -> &channel::Receiver<()> {
+        &self.inner.waiter
+    }
+
+    /// Block until shutdown is requested.
+    pub fn wait(&self) {
+        let _ = self.inner.waiter.recv();
+    }
+
+    /// Register a cleanup task to run at shutdown. The task can return a
+    /// summary of what it did.
+    pub fn on_shutdown<F: FnOnce() -> Option<String> + Send + 'static>(&self, name: &str, task: F) {
+        self.inner
+            .tasks
+            .lock()
+            .expect("shutdown lock poisoned")
+            .push((name.to_string(), Box::new(task)));
+    }
+
+    /// Request shutdown if not done already, and run all the cleanup tasks
+    /// registered so far, most recent first. Returns their summaries,
+    /// prefixed by the task name.
+    pub fn finish(&self) -> Vec<String> {
+        self.request();
+        let tasks = std::mem::take(&mut *self.inner.tasks.lock().expect("shutdown lock poisoned"));
+        let mut summary = vec![];
+        for (name, task) in tasks.into_iter().rev() {
+            if let Some(line) = task() {
+                summary.push(format!("{}: {}", name, line));
+            }
+        }
+        summary
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
@@ -0,0 +1,144 @@
+//! Memory budget
+//!
+//! Accounting of the memory used by packets buffered between a proxy and
+//! its clients. A `MemoryBudget` can be shared by any number of proxies;
+//! once the total amount of buffered data reaches its limit, the budget's
+//! policy decides what happens to further packets. This way a client which
+//! stops reading degrades predictably instead of growing memory usage until
+//! the process is killed mid-capture.
+
+use super::proto::Packet;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What to do with a packet which does not fit in the budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetPolicy {
+    /// Drop the packet for the client it was destined to. The client
+    /// keeps receiving packets once it has caught up.
+    DropPackets,
+    /// Disconnect the client whose packet would exceed the budget.
+    DropClient,
+}
+
+/// A limit on the total memory used by buffered packets.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    policy: BudgetPolicy,
+    used: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl MemoryBudget {
+    /// Create a budget allowing up to `limit` bytes of buffered packets.
+    pub fn new(limit: usize, policy: BudgetPolicy) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget {
+            limit,
+            policy,
+            used: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        self.policy
+    }
+
+    /// Bytes currently accounted for.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because the budget was exhausted.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Account for `bytes` more, if they fit within the limit.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    /// Return `bytes` previously obtained with `try_reserve`.
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Approximate memory used by a buffered packet.
+pub fn packet_size(pkt: &Packet) -> usize {
+    std::mem::size_of::<Packet>() + pkt.serialize().map(|raw| raw.len()).unwrap_or(0)
+}
+
+/// Per queue accounting against a shared budget. Packets are consumed from
+/// the queue in order, so the reservations of the packets consumed so far
+/// can be released by only looking at how many packets are still queued.
+pub(crate) struct BudgetAccount {
+    budget: Arc<MemoryBudget>,
+    queued: VecDeque<usize>,
+    bytes: usize,
+}
+
+impl BudgetAccount {
+    pub fn new(budget: Arc<MemoryBudget>) -> BudgetAccount {
+        BudgetAccount {
+            budget,
+            queued: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    /// Release the reservations of packets no longer in the queue, given
+    /// the number of packets still queued.
+    pub fn reconcile(&mut self, queue_len: usize) {
+        while self.queued.len() > queue_len {
+            let size = self.queued.pop_front().expect("non empty");
+            self.bytes -= size;
+            self.budget.release(size);
+        }
+    }
+
+    /// Account for a packet about to be queued.
+    pub fn reserve(&mut self, size: usize) -> bool {
+        if !self.budget.try_reserve(size) {
+            return false;
+        }
+        self.queued.push_back(size);
+        self.bytes += size;
+        true
+    }
+
+    /// Forget the reservation of the most recently queued packet, when
+    /// queuing it failed after all.
+    pub fn unreserve_last(&mut self) {
+        if let Some(size) = self.queued.pop_back() {
+            self.bytes -= size;
+            self.budget.release(size);
+        }
+    }
+}
+
+impl Drop for BudgetAccount {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
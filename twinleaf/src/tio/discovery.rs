@@ -0,0 +1,229 @@
+//! Discovery
+//!
+//! Finds TIO proxies and networked sensors on the local network, without
+//! keeping track of their addresses by hand. Devices announce themselves by
+//! broadcasting a small UDP datagram, a beacon, to `DISCOVERY_PORT` every
+//! few seconds. A `Listener` collects the beacons, and reports every device
+//! it finds with the URL to reach it, for `port::Port::new` or
+//! `proxy::Interface::new`:
+//! ```no_run
+//! # use twinleaf::tio::discovery::Listener;
+//! # use std::time::Duration;
+//! for found in Listener::discover(Duration::from_secs(3)).unwrap() {
+//!     println!("{} {} at {}", found.name, found.serial, found.url());
+//! }
+//! ```
+//! A `Beacon` announces a proxy, as `tio-proxy --announce` does.
+//!
+//! A beacon is UTF-8 text: a first line `TIO-ANNOUNCE 1`, followed by
+//! `key=value` lines. The keys are `port`, the TCP port serving TIO
+//! (default 7855), `name` and `serial`, the name and serial number of the
+//! device. Unknown keys are ignored.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+
+/// UDP port beacons are broadcast to.
+pub static DISCOVERY_PORT: u16 = 7856;
+
+/// First line of a beacon.
+static BEACON_MAGIC: &str = "TIO-ANNOUNCE 1";
+
+/// TCP port announced when a beacon does not have one.
+static DEFAULT_TCP_PORT: u16 = 7855;
+
+/// How often the listener thread checks whether it should exit.
+static LISTENER_POLL: Duration = Duration::from_millis(200);
+
+/// What a device tells about itself in its beacons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub name: String,
+    pub serial: String,
+    /// TCP port serving TIO on the device.
+    pub port: u16,
+}
+
+impl Announcement {
+    pub fn new(name: &str, serial: &str, port: u16) -> Announcement {
+        Announcement {
+            name: name.to_string(),
+            serial: serial.to_string(),
+            port,
+        }
+    }
+
+    /// Beacon datagram for this announcement.
+    pub fn to_beacon(&self) -> Vec<u8> {
+        format!(
+            "{}\nport={}\nname={}\nserial={}\n",
+            BEACON_MAGIC,
+            self.port,
+            self.name.replace('\n', " "),
+            self.serial.replace('\n', " ")
+        )
+        .into_bytes()
+    }
+
+    /// Parse a beacon datagram, or `None` if it is not a valid beacon.
+    pub fn from_beacon(data: &[u8]) -> Option<Announcement> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut lines = text.lines();
+        if lines.next()?.trim() != BEACON_MAGIC {
+            return None;
+        }
+        let mut ret = Announcement::new("", "", DEFAULT_TCP_PORT);
+        for line in lines {
+            match line.split_once('=') {
+                Some(("port", port)) => ret.port = port.trim().parse().ok()?,
+                Some(("name", name)) => ret.name = name.to_string(),
+                Some(("serial", serial)) => ret.serial = serial.to_string(),
+                _ => {}
+            }
+        }
+        Some(ret)
+    }
+}
+
+/// A device found on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    /// Address the beacon came from.
+    pub addr: IpAddr,
+    pub name: String,
+    pub serial: String,
+    /// TCP port serving TIO on the device.
+    pub port: u16,
+}
+
+impl Discovered {
+    /// URL to connect to the device.
+    pub fn url(&self) -> String {
+        match self.addr {
+            IpAddr::V4(addr) => format!("tcp://{}:{}", addr, self.port),
+            IpAddr::V6(addr) => format!("tcp://[{}]:{}", addr, self.port),
+        }
+    }
+}
+
+/// Listens for beacons in its own thread, until dropped.
+pub struct Listener {
+    rx: channel::Receiver<Discovered>,
+    /// Dropping this stops the thread.
+    _stop: channel::Sender<()>,
+}
+
+impl Listener {
+    /// Listen for beacons on `DISCOVERY_PORT`.
+    pub fn new() -> io::Result<Listener> {
+        Listener::bind(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            DISCOVERY_PORT,
+        ))
+    }
+
+    /// Listen for beacons sent to `addr`.
+    pub fn bind(addr: SocketAddr) -> io::Result<Listener> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(LISTENER_POLL))?;
+        let (tx, rx) = channel::bounded::<Discovered>(64);
+        let (stop, stopped) = channel::bounded::<()>(0);
+        thread::Builder::new()
+            .name("discovery".to_string())
+            .spawn(move || Listener::listen(socket, tx, stopped))?;
+        Ok(Listener { rx, _stop: stop })
+    }
+
+    fn listen(socket: UdpSocket, tx: channel::Sender<Discovered>, stopped: channel::Receiver<()>) {
+        // Last announcement from each address and port, to only report
+        // devices when they are first found or their announcement changes.
+        let mut known: HashMap<(IpAddr, u16), Announcement> = HashMap::new();
+        let mut buf = [0u8; 1024];
+        while let Err(channel::TryRecvError::Empty) = stopped.try_recv() {
+            let (size, from) = match socket.recv_from(&mut buf) {
+                Ok(ret) => ret,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(err) => {
+                    log_warn!("Discovery listener failed: {:?}", err);
+                    return;
+                }
+            };
+            let Some(announcement) = Announcement::from_beacon(&buf[..size]) else {
+                continue;
+            };
+            let key = (from.ip(), announcement.port);
+            if known.get(&key) == Some(&announcement) {
+                continue;
+            }
+            let found = Discovered {
+                addr: from.ip(),
+                name: announcement.name.clone(),
+                serial: announcement.serial.clone(),
+                port: announcement.port,
+            };
+            known.insert(key, announcement);
+            if tx.send(found).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Devices as they are found.
+    pub fn receiver(&self) -> &channel::Receiver<Discovered> {
+        &self.rx
+    }
+
+    /// Listen for `duration`, and return the devices found.
+    pub fn discover(duration: Duration) -> io::Result<Vec<Discovered>> {
+        let listener = Listener::new()?;
+        let deadline = Instant::now() + duration;
+        let mut ret: Vec<Discovered> = vec![];
+        while let Ok(found) = listener.rx.recv_deadline(deadline) {
+            // Keep the latest announcement of each device.
+            ret.retain(|d| (d.addr, d.port) != (found.addr, found.port));
+            ret.push(found);
+        }
+        Ok(ret)
+    }
+}
+
+/// Broadcasts beacons in its own thread, until dropped.
+pub struct Beacon {
+    /// Dropping this stops the thread.
+    _stop: channel::Sender<()>,
+}
+
+impl Beacon {
+    /// Broadcast `announcement` to `DISCOVERY_PORT` every `interval`.
+    pub fn new(announcement: Announcement, interval: Duration) -> io::Result<Beacon> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+        socket.set_broadcast(true)?;
+        let dest = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT);
+        let beacon = announcement.to_beacon();
+        let (stop, stopped) = channel::bounded::<()>(0);
+        thread::Builder::new()
+            .name("beacon".to_string())
+            .spawn(move || loop {
+                if let Err(err) = socket.send_to(&beacon, dest) {
+                    log_debug!("Failed to send beacon: {:?}", err);
+                }
+                if let Err(channel::RecvTimeoutError::Disconnected) = stopped.recv_timeout(interval)
+                {
+                    break;
+                }
+            })?;
+        Ok(Beacon { _stop: stop })
+    }
+}
@@ -0,0 +1,428 @@
+//! Event log
+//!
+//! Records the status events of a proxy to a file, with the time they
+//! happened, and reads them back. Over a long acquisition, the log tells
+//! after the fact when the sensor disconnected, the port rate was
+//! renegotiated or RPCs timed out, to understand why data degraded:
+//! ```no_run
+//! # use twinleaf::tio::{eventlog::EventRecorder, proxy};
+//! # let (tx, events) = crossbeam::channel::unbounded();
+//! # let proxy = proxy::Interface::builder().status(tx).spawn();
+//! let file = std::fs::File::create("events.log").unwrap();
+//! let mut recorder = EventRecorder::new(std::io::BufWriter::new(file));
+//! for event in events.iter() {
+//!     recorder.record(&event).unwrap();
+//! }
+//! ```
+//! `EventReader` iterates over the events of a log, and `replay` sends them
+//! to a channel with their original timing, to feed them to the same code
+//! which processes live events.
+//!
+//! A log is UTF-8 text, with one event per line: the time in seconds since
+//! the UNIX epoch, a space, the name of the event and its arguments
+//! separated by spaces, e.g. `1700000000.123456 RpcTimeout 3`. Text
+//! arguments are last on the line, with backslashes and line breaks escaped.
+//! The kind of IO errors is not recorded, so they are read back as
+//! `io::ErrorKind::Other` errors with the original message.
+
+use super::port::{ConnectError, RecvError};
+use super::proto::{self, DeviceRoute, RpcErrorCode};
+use super::proxy::{ClientDropReason, Event};
+
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel;
+
+/// An event read from a log.
+#[derive(Debug)]
+pub struct RecordedEvent {
+    pub time: SystemTime,
+    pub event: Event,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some(c) => ret.push(c),
+            None => {}
+        }
+    }
+    ret
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_proto_error(err: &proto::Error) -> String {
+    match err {
+        proto::Error::NeedMore => "NeedMore".to_string(),
+        proto::Error::Text(text) => format!("Text {}", escape(text)),
+        proto::Error::CRC32(data) => format!("CRC32 {}", hex(data)),
+        proto::Error::PacketTooBig(data) => format!("PacketTooBig {}", hex(data)),
+        proto::Error::PacketTooSmall(data) => format!("PacketTooSmall {}", hex(data)),
+        proto::Error::InvalidPacketType(data) => format!("InvalidPacketType {}", hex(data)),
+        proto::Error::PayloadTooBig(data) => format!("PayloadTooBig {}", hex(data)),
+        proto::Error::RoutingTooBig(data) => format!("RoutingTooBig {}", hex(data)),
+        proto::Error::PayloadTooSmall(data) => format!("PayloadTooSmall {}", hex(data)),
+        proto::Error::InvalidPayload(data) => format!("InvalidPayload {}", hex(data)),
+        proto::Error::Framing(data) => format!("Framing {}", hex(data)),
+    }
+}
+
+fn decode_proto_error(text: &str) -> Option<proto::Error> {
+    let (name, arg) = text.split_once(' ').unwrap_or((text, ""));
+    Some(match name {
+        "NeedMore" => proto::Error::NeedMore,
+        "Text" => proto::Error::Text(unescape(arg)),
+        "CRC32" => proto::Error::CRC32(unhex(arg)?),
+        "PacketTooBig" => proto::Error::PacketTooBig(unhex(arg)?),
+        "PacketTooSmall" => proto::Error::PacketTooSmall(unhex(arg)?),
+        "InvalidPacketType" => proto::Error::InvalidPacketType(unhex(arg)?),
+        "PayloadTooBig" => proto::Error::PayloadTooBig(unhex(arg)?),
+        "RoutingTooBig" => proto::Error::RoutingTooBig(unhex(arg)?),
+        "PayloadTooSmall" => proto::Error::PayloadTooSmall(unhex(arg)?),
+        "InvalidPayload" => proto::Error::InvalidPayload(unhex(arg)?),
+        "Framing" => proto::Error::Framing(unhex(arg)?),
+        _ => return None,
+    })
+}
+
+fn encode_recv_error(err: &RecvError) -> String {
+    match err {
+        RecvError::NotReady => "NotReady".to_string(),
+        RecvError::Disconnected => "Disconnected".to_string(),
+        RecvError::Protocol(err) => format!("Protocol {}", encode_proto_error(err)),
+        RecvError::IO(err) => format!("IO {}", escape(&err.to_string())),
+    }
+}
+
+fn decode_recv_error(text: &str) -> Option<RecvError> {
+    let (name, arg) = text.split_once(' ').unwrap_or((text, ""));
+    Some(match name {
+        "NotReady" => RecvError::NotReady,
+        "Disconnected" => RecvError::Disconnected,
+        "Protocol" => RecvError::Protocol(decode_proto_error(arg)?),
+        "IO" => RecvError::IO(io::Error::other(unescape(arg))),
+        _ => return None,
+    })
+}
+
+fn encode_connect_error(err: &ConnectError) -> String {
+    match err {
+        ConnectError::PermissionDenied => "PermissionDenied".to_string(),
+        ConnectError::NotFound => "NotFound".to_string(),
+        ConnectError::Busy(Some(pid)) => format!("Busy {}", pid),
+        ConnectError::Busy(None) => "Busy".to_string(),
+        ConnectError::UnsupportedScheme(scheme) => {
+            format!("UnsupportedScheme {}", escape(scheme))
+        }
+        ConnectError::InvalidUrl(text) => format!("InvalidUrl {}", escape(text)),
+        ConnectError::Handshake(text) => format!("Handshake {}", escape(text)),
+        ConnectError::Other(text) => format!("Other {}", escape(text)),
+    }
+}
+
+/// The reason of a connection failure. Logs written before reasons were
+/// recorded have none, read back as an empty `Other`.
+fn decode_connect_error(text: &str) -> ConnectError {
+    let (name, arg) = text.split_once(' ').unwrap_or((text, ""));
+    match name {
+        "PermissionDenied" => ConnectError::PermissionDenied,
+        "NotFound" => ConnectError::NotFound,
+        "Busy" => ConnectError::Busy(arg.parse().ok()),
+        "UnsupportedScheme" => ConnectError::UnsupportedScheme(unescape(arg)),
+        "InvalidUrl" => ConnectError::InvalidUrl(unescape(arg)),
+        "Handshake" => ConnectError::Handshake(unescape(arg)),
+        "Other" => ConnectError::Other(unescape(arg)),
+        _ => ConnectError::Other(unescape(text)),
+    }
+}
+
+/// Text form of an event, without its time.
+pub fn encode_event(event: &Event) -> String {
+    let code = |code: &RpcErrorCode| u16::from(*code);
+    match event {
+        Event::SensorConnected => "SensorConnected".to_string(),
+        Event::SensorDisconnected => "SensorDisconnected".to_string(),
+        Event::SensorReconnected => "SensorReconnected".to_string(),
+        Event::FailedToConnect(err) => format!("FailedToConnect {}", encode_connect_error(err)),
+        Event::FailedToReconnect(err) => {
+            format!("FailedToReconnect {}", encode_connect_error(err))
+        }
+        Event::PortBusy(Some(pid)) => format!("PortBusy {}", pid),
+        Event::PortBusy(None) => "PortBusy".to_string(),
+        Event::Exiting => "Exiting".to_string(),
+        Event::ProtocolError(err) => format!("ProtocolError {}", encode_proto_error(err)),
+        Event::FatalError(err) => format!("FatalError {}", encode_recv_error(err)),
+        Event::NewClient(client) => format!("NewClient {}", client),
+        Event::RpcRemap((client, id), wire_id) => {
+            format!("RpcRemap {} {} {}", client, id, wire_id)
+        }
+        Event::RpcQueued((client, id)) => format!("RpcQueued {} {}", client, id),
+        Event::RpcThrottled((client, id)) => format!("RpcThrottled {} {}", client, id),
+        Event::RpcRestore(wire_id, (client, id)) => {
+            format!("RpcRestore {} {} {}", wire_id, client, id)
+        }
+        Event::RpcRestoreNotFound(wire_id) => format!("RpcRestoreNotFound {}", wire_id),
+        Event::RpcClientNotFound(client) => format!("RpcClientNotFound {}", client),
+        Event::RpcTimeout(wire_id) => format!("RpcTimeout {}", wire_id),
+        Event::RpcCancel(wire_id) => format!("RpcCancel {}", wire_id),
+        Event::ClientDropped(client, reason) => format!("ClientDropped {} {:?}", client, reason),
+        Event::ClientTerminated(client) => format!("ClientTerminated {}", client),
+        Event::RouteOutOfScope(client, route) => format!("RouteOutOfScope {} {}", client, route),
+        Event::TtlExpired(client, route) => format!("TtlExpired {} {}", client, route),
+        Event::ReconnectRequested(client) => format!("ReconnectRequested {}", client),
+        Event::UrlChanged(url) => format!("UrlChanged {}", url),
+        Event::RootDeviceRestarted => "RootDeviceRestarted".to_string(),
+        Event::RootDeviceSleeping => "RootDeviceSleeping".to_string(),
+        Event::RootDeviceAwake => "RootDeviceAwake".to_string(),
+        Event::DownstreamAttached(prefix) => format!("DownstreamAttached {}", prefix),
+        Event::DownstreamDetached(prefix) => format!("DownstreamDetached {}", prefix),
+        Event::ReconnectRpcFailed(err) => format!("ReconnectRpcFailed {}", code(err)),
+        Event::AutoRateGaveUp => "AutoRateGaveUp".to_string(),
+        Event::AutoRateQueried(rate) => format!("AutoRateQueried {}", rate),
+        Event::AutoRateRpcError(err) => format!("AutoRateRpcError {}", code(err)),
+        Event::AutoRateRpcInvalid(len) => format!("AutoRateRpcInvalid {}", len),
+        Event::AutoRateIncompatible(rate) => format!("AutoRateIncompatible {}", rate),
+        Event::AutoRateCompatible(rate) => format!("AutoRateCompatible {}", rate),
+        Event::AutoRateWait => "AutoRateWait".to_string(),
+        Event::AutoRateSet(rate) => format!("AutoRateSet {}", rate),
+        Event::AutoRateConfirmed(rate) => format!("AutoRateConfirmed {}", rate),
+        Event::RouteAutoRateSet(route, rate) => format!("RouteAutoRateSet {} {}", route, rate),
+        Event::RouteAutoRateIncompatible(route, rate) => {
+            format!("RouteAutoRateIncompatible {} {}", route, rate)
+        }
+        Event::RouteAutoRateRpcError(route, err) => {
+            format!("RouteAutoRateRpcError {} {}", route, code(err))
+        }
+        Event::RouteAutoRateRpcInvalid(route, len) => {
+            format!("RouteAutoRateRpcInvalid {} {}", route, len)
+        }
+        Event::SetRate(rate) => format!("SetRate {}", rate),
+        Event::SetRateFailed => "SetRateFailed".to_string(),
+        Event::NoData => "NoData".to_string(),
+        Event::LinkOverloaded(rx, rate) => format!("LinkOverloaded {} {}", rx, rate),
+        Event::LinkLoadNormal(rx, rate) => format!("LinkLoadNormal {} {}", rx, rate),
+    }
+}
+
+/// Parse the text form of an event, as returned by `encode_event`.
+pub fn decode_event(text: &str) -> Option<Event> {
+    let (name, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let args: Vec<&str> = rest.split(' ').collect();
+    let num = |i: usize| -> Option<u64> { args.get(i)?.parse().ok() };
+    let id = |i: usize| -> Option<u16> { args.get(i)?.parse().ok() };
+    let rate = |i: usize| -> Option<u32> { args.get(i)?.parse().ok() };
+    let code = |i: usize| -> Option<RpcErrorCode> { Some(RpcErrorCode::from(id(i)?)) };
+    let route = |i: usize| -> Option<DeviceRoute> { DeviceRoute::from_str(args.get(i)?).ok() };
+    Some(match name {
+        "SensorConnected" => Event::SensorConnected,
+        "SensorDisconnected" => Event::SensorDisconnected,
+        "SensorReconnected" => Event::SensorReconnected,
+        "FailedToConnect" => Event::FailedToConnect(decode_connect_error(rest)),
+        "FailedToReconnect" => Event::FailedToReconnect(decode_connect_error(rest)),
+        "PortBusy" => Event::PortBusy(args.first().and_then(|pid| pid.parse().ok())),
+        "Exiting" => Event::Exiting,
+        "ProtocolError" => Event::ProtocolError(decode_proto_error(rest)?),
+        "FatalError" => Event::FatalError(decode_recv_error(rest)?),
+        "NewClient" => Event::NewClient(num(0)?),
+        "RpcRemap" => Event::RpcRemap((num(0)?, id(1)?), id(2)?),
+        "RpcQueued" => Event::RpcQueued((num(0)?, id(1)?)),
+        "RpcThrottled" => Event::RpcThrottled((num(0)?, id(1)?)),
+        "RpcRestore" => Event::RpcRestore(id(0)?, (num(1)?, id(2)?)),
+        "RpcRestoreNotFound" => Event::RpcRestoreNotFound(id(0)?),
+        "RpcClientNotFound" => Event::RpcClientNotFound(num(0)?),
+        "RpcTimeout" => Event::RpcTimeout(id(0)?),
+        "RpcCancel" => Event::RpcCancel(id(0)?),
+        "ClientDropped" => Event::ClientDropped(
+            num(0)?,
+            match *args.get(1)? {
+                "Disconnected" => ClientDropReason::Disconnected,
+                "Unresponsive" => ClientDropReason::Unresponsive,
+                "OverBudget" => ClientDropReason::OverBudget,
+                _ => return None,
+            },
+        ),
+        "ClientTerminated" => Event::ClientTerminated(num(0)?),
+        "RouteOutOfScope" => Event::RouteOutOfScope(num(0)?, route(1)?),
+        "TtlExpired" => Event::TtlExpired(num(0)?, route(1)?),
+        "ReconnectRequested" => Event::ReconnectRequested(num(0)?),
+        "UrlChanged" => Event::UrlChanged(rest.to_string()),
+        "RootDeviceRestarted" => Event::RootDeviceRestarted,
+        "RootDeviceSleeping" => Event::RootDeviceSleeping,
+        "RootDeviceAwake" => Event::RootDeviceAwake,
+        "DownstreamAttached" => Event::DownstreamAttached(route(0)?),
+        "DownstreamDetached" => Event::DownstreamDetached(route(0)?),
+        "ReconnectRpcFailed" => Event::ReconnectRpcFailed(code(0)?),
+        "AutoRateGaveUp" => Event::AutoRateGaveUp,
+        "AutoRateQueried" => Event::AutoRateQueried(rate(0)?),
+        "AutoRateRpcError" => Event::AutoRateRpcError(code(0)?),
+        "AutoRateRpcInvalid" => Event::AutoRateRpcInvalid(num(0)? as usize),
+        "AutoRateIncompatible" => Event::AutoRateIncompatible(rate(0)?),
+        "AutoRateCompatible" => Event::AutoRateCompatible(rate(0)?),
+        "AutoRateWait" => Event::AutoRateWait,
+        "AutoRateSet" => Event::AutoRateSet(rate(0)?),
+        "AutoRateConfirmed" => Event::AutoRateConfirmed(rate(0)?),
+        "RouteAutoRateSet" => Event::RouteAutoRateSet(route(0)?, rate(1)?),
+        "RouteAutoRateIncompatible" => Event::RouteAutoRateIncompatible(route(0)?, rate(1)?),
+        "RouteAutoRateRpcError" => Event::RouteAutoRateRpcError(route(0)?, code(1)?),
+        "RouteAutoRateRpcInvalid" => Event::RouteAutoRateRpcInvalid(route(0)?, num(1)? as usize),
+        "SetRate" => Event::SetRate(rate(0)?),
+        "SetRateFailed" => Event::SetRateFailed,
+        "NoData" => Event::NoData,
+        "LinkOverloaded" => Event::LinkOverloaded(rate(0)?, rate(1)?),
+        "LinkLoadNormal" => Event::LinkLoadNormal(rate(0)?, rate(1)?),
+        _ => return None,
+    })
+}
+
+/// Writes events to a log.
+pub struct EventRecorder<W: Write> {
+    out: W,
+    events: u64,
+}
+
+impl<W: Write> EventRecorder<W> {
+    pub fn new(out: W) -> EventRecorder<W> {
+        EventRecorder { out, events: 0 }
+    }
+
+    /// Record an event which just happened.
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        self.record_at(SystemTime::now(), event)
+    }
+
+    /// Record an event which happened at `time`.
+    pub fn record_at(&mut self, time: SystemTime, event: &Event) -> io::Result<()> {
+        let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(
+            self.out,
+            "{}.{:06} {}",
+            t.as_secs(),
+            t.subsec_micros(),
+            encode_event(event)
+        )?;
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Number of events recorded.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads the events of a log, in order.
+pub struct EventReader<R: BufRead> {
+    input: R,
+    line: u64,
+}
+
+impl<R: BufRead> EventReader<R> {
+    pub fn new(input: R) -> EventReader<R> {
+        EventReader { input, line: 0 }
+    }
+
+    fn parse(line: &str) -> Option<RecordedEvent> {
+        let (time, event) = line.split_once(' ')?;
+        let time = time.parse::<f64>().ok()?;
+        if !time.is_finite() || time < 0.0 {
+            return None;
+        }
+        Some(RecordedEvent {
+            time: UNIX_EPOCH + Duration::from_secs_f64(time),
+            event: decode_event(event)?,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for EventReader<R> {
+    type Item = io::Result<RecordedEvent>;
+
+    /// The next event, or an `InvalidData` error for a line which is not a
+    /// valid event. Blank lines are skipped.
+    fn next(&mut self) -> Option<io::Result<RecordedEvent>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.input.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err)),
+            }
+            self.line += 1;
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(EventReader::<R>::parse(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid event on line {}", self.line),
+                )
+            }));
+        }
+    }
+}
+
+/// Send the events of a log to `tx`, spaced out as they originally were,
+/// sped up by `speed`. A `speed` of 0 sends them all at once. Returns the
+/// number of events sent, stopping early if the receiver goes away.
+pub fn replay<R: BufRead>(
+    reader: EventReader<R>,
+    tx: &channel::Sender<Event>,
+    speed: f64,
+) -> io::Result<u64> {
+    let started = std::time::Instant::now();
+    let mut first: Option<SystemTime> = None;
+    let mut sent = 0;
+    for recorded in reader {
+        let recorded = recorded?;
+        if speed > 0.0 {
+            let first = *first.get_or_insert(recorded.time);
+            let offset = recorded.time.duration_since(first).unwrap_or_default();
+            let due = started + offset.div_f64(speed);
+            std::thread::sleep(due.saturating_duration_since(std::time::Instant::now()));
+        }
+        if tx.send(recorded.event).is_err() {
+            break;
+        }
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,296 @@
+//! Proxy manager
+//!
+//! Supervises the proxies of a station with several sensors. A
+//! `ProxyManager` runs one proxy per sensor, follows their events to keep
+//! track of the health of each sensor, and restarts the proxies which exit,
+//! for example because their sensor stayed disconnected for longer than the
+//! reconnection timeout:
+//! ```no_run
+//! # use twinleaf::tio::manager::ProxyManager;
+//! let manager = ProxyManager::new();
+//! manager.add("vmr", "serial:///dev/ttyACM0");
+//! manager.add("gradiometer", "tcp://10.0.0.5");
+//! for health in manager.health() {
+//!     println!("{}: {:?}", health.name, health.state);
+//! }
+//! let port = manager.interface("vmr").unwrap().tree_full();
+//! ```
+//! Ports opened on a proxy stop working when it exits; after a restart,
+//! `interface` returns the new proxy to open ports on again.
+
+use super::proxy::{self, Event, Interface};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+
+/// How often the supervisor thread checks the proxies.
+static SUPERVISOR_POLL: Duration = Duration::from_millis(100);
+
+/// State of a sensor, as seen by its proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorState {
+    /// The proxy is connecting to the sensor.
+    Connecting,
+    Connected,
+    /// The sensor disconnected, and the proxy is trying to reconnect.
+    Reconnecting,
+    /// The proxy exited, and will be restarted.
+    Restarting,
+}
+
+/// Health of a sensor managed by a `ProxyManager`.
+#[derive(Debug, Clone)]
+pub struct SensorHealth {
+    pub name: String,
+    pub url: String,
+    pub state: SensorState,
+    /// Time of the last change of `state`.
+    pub since: Instant,
+    /// Number of times the proxy was restarted.
+    pub restarts: u32,
+    /// Time of the last event from the proxy.
+    pub last_event: Option<Instant>,
+    /// Last problem reported by the proxy.
+    pub last_error: Option<String>,
+}
+
+impl SensorHealth {
+    fn set_state(&mut self, state: SensorState) {
+        if self.state != state {
+            self.state = state;
+            self.since = Instant::now();
+        }
+    }
+}
+
+struct ManagedProxy {
+    interface: Arc<Interface>,
+    events: channel::Receiver<Event>,
+    health: SensorHealth,
+    /// Set while waiting to restart the proxy.
+    restart_at: Option<Instant>,
+}
+
+struct Config {
+    reconnect_timeout: Duration,
+    restart_delay: Duration,
+    events: Option<channel::Sender<(String, Event)>>,
+}
+
+fn spawn_proxy(url: &str, config: &Config) -> (Arc<Interface>, channel::Receiver<Event>) {
+    // The proxy panics if its status queue is full, so do not bound it;
+    // the supervisor drains it regularly.
+    let (tx, rx) = channel::unbounded();
+    let interface = Interface::builder()
+        .url(url)
+        .reconnect(config.reconnect_timeout)
+        .status(tx)
+        .spawn();
+    (Arc::new(interface), rx)
+}
+
+impl ManagedProxy {
+    /// Process the events received from the proxy, and restart it if it
+    /// is due.
+    fn supervise(&mut self, config: &Config) {
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => {
+                    self.update(&event);
+                    if let Some(tx) = &config.events {
+                        let _ = tx.try_send((self.health.name.clone(), event));
+                    }
+                }
+                Err(channel::TryRecvError::Empty) => break,
+                // The proxy thread is gone.
+                Err(channel::TryRecvError::Disconnected) => {
+                    if self.restart_at.is_none() {
+                        self.health.set_state(SensorState::Restarting);
+                        self.restart_at = Some(Instant::now() + config.restart_delay);
+                    }
+                    break;
+                }
+            }
+        }
+        if self.restart_at.is_some_and(|t| Instant::now() >= t) {
+            (self.interface, self.events) = spawn_proxy(&self.health.url, config);
+            self.restart_at = None;
+            self.health.restarts += 1;
+            self.health.set_state(SensorState::Connecting);
+        }
+    }
+
+    fn update(&mut self, event: &Event) {
+        let health = &mut self.health;
+        health.last_event = Some(Instant::now());
+        match event {
+            Event::SensorConnected | Event::SensorReconnected => {
+                health.set_state(SensorState::Connected)
+            }
+            Event::SensorDisconnected => health.set_state(SensorState::Reconnecting),
+            Event::FailedToConnect(_)
+            | Event::FailedToReconnect(_)
+            | Event::FatalError(_)
+            | Event::ProtocolError(_)
+            | Event::NoData
+            | Event::ReconnectRpcFailed(_)
+            | Event::SetRateFailed => health.last_error = Some(format!("{:?}", event)),
+            _ => {}
+        }
+    }
+}
+
+/// Runs and supervises one proxy per sensor, until dropped.
+pub struct ProxyManager {
+    proxies: Arc<Mutex<BTreeMap<String, ManagedProxy>>>,
+    config: Arc<Config>,
+    /// Dropping this stops the supervisor thread.
+    _stop: channel::Sender<()>,
+}
+
+impl Default for ProxyManager {
+    fn default() -> Self {
+        ProxyManager::new()
+    }
+}
+
+/// Configuration of a `ProxyManager`, created with `ProxyManager::builder()`.
+pub struct ProxyManagerBuilder {
+    config: Config,
+}
+
+impl ProxyManagerBuilder {
+    /// How long proxies keep trying to reconnect to a sensor after it
+    /// disconnects, before exiting. Defaults to 30 seconds.
+    pub fn reconnect_timeout(mut self, timeout: Duration) -> ProxyManagerBuilder {
+        self.config.reconnect_timeout = timeout;
+        self
+    }
+
+    /// How long to wait before restarting a proxy which exited. Defaults
+    /// to 5 seconds.
+    pub fn restart_delay(mut self, delay: Duration) -> ProxyManagerBuilder {
+        self.config.restart_delay = delay;
+        self
+    }
+
+    /// Also send the events of all proxies to `events`, with the name of
+    /// their sensor. Events which do not fit in the channel are dropped.
+    pub fn events(mut self, events: channel::Sender<(String, Event)>) -> ProxyManagerBuilder {
+        self.config.events = Some(events);
+        self
+    }
+
+    /// Start the manager, with its supervisor thread.
+    pub fn spawn(self) -> ProxyManager {
+        ProxyManager::spawn(self.config)
+    }
+}
+
+impl ProxyManager {
+    /// Start a manager with the default configuration.
+    pub fn new() -> ProxyManager {
+        ProxyManager::builder().spawn()
+    }
+
+    /// Configure a new manager, see `ProxyManagerBuilder`.
+    pub fn builder() -> ProxyManagerBuilder {
+        ProxyManagerBuilder {
+            config: Config {
+                reconnect_timeout: Duration::from_secs(30),
+                restart_delay: Duration::from_secs(5),
+                events: None,
+            },
+        }
+    }
+
+    fn spawn(config: Config) -> ProxyManager {
+        let proxies = Arc::new(Mutex::new(BTreeMap::<String, ManagedProxy>::new()));
+        let config = Arc::new(config);
+        let (stop, stopped) = channel::bounded::<()>(0);
+        {
+            let proxies = proxies.clone();
+            let config = config.clone();
+            thread::Builder::new()
+                .name("proxy-manager".to_string())
+                .spawn(move || {
+                    while let Err(channel::RecvTimeoutError::Timeout) =
+                        stopped.recv_timeout(SUPERVISOR_POLL)
+                    {
+                        for proxy in proxies.lock().unwrap().values_mut() {
+                            proxy.supervise(&config);
+                        }
+                    }
+                })
+                .expect("Failed to start proxy manager thread");
+        }
+        ProxyManager {
+            proxies,
+            config,
+            _stop: stop,
+        }
+    }
+
+    /// Start a proxy for the sensor at `url`, under `name`. Replaces the
+    /// sensor previously added with the same name, if any.
+    pub fn add(&self, name: &str, url: &str) {
+        let (interface, events) = spawn_proxy(url, &self.config);
+        let now = Instant::now();
+        let proxy = ManagedProxy {
+            interface,
+            events,
+            health: SensorHealth {
+                name: name.to_string(),
+                url: url.to_string(),
+                state: SensorState::Connecting,
+                since: now,
+                restarts: 0,
+                last_event: None,
+                last_error: None,
+            },
+            restart_at: None,
+        };
+        self.proxies.lock().unwrap().insert(name.to_string(), proxy);
+    }
+
+    /// Stop managing the sensor `name`. Its proxy exits once the ports
+    /// opened on it are closed.
+    pub fn remove(&self, name: &str) -> bool {
+        self.proxies.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Names of the sensors managed.
+    pub fn names(&self) -> Vec<String> {
+        self.proxies.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Current proxy of the sensor `name`, to open ports on.
+    pub fn interface(&self, name: &str) -> Option<Arc<proxy::Interface>> {
+        let proxies = self.proxies.lock().unwrap();
+        Some(proxies.get(name)?.interface.clone())
+    }
+
+    /// Health of the sensor `name`.
+    pub fn sensor_health(&self, name: &str) -> Option<SensorHealth> {
+        let proxies = self.proxies.lock().unwrap();
+        Some(proxies.get(name)?.health.clone())
+    }
+
+    /// Health of all the sensors, ordered by name.
+    pub fn health(&self) -> Vec<SensorHealth> {
+        let proxies = self.proxies.lock().unwrap();
+        proxies.values().map(|p| p.health.clone()).collect()
+    }
+
+    /// True if all the sensors are connected.
+    pub fn is_healthy(&self) -> bool {
+        let proxies = self.proxies.lock().unwrap();
+        proxies
+            .values()
+            .all(|p| p.health.state == SensorState::Connected)
+    }
+}
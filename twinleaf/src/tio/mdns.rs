@@ -0,0 +1,685 @@
+//! mDNS
+//!
+//! DNS-SD service advertisement over multicast DNS, so that proxies serving
+//! TIO over TCP can be found with standard zeroconf tools, and connected to
+//! by the name of their device rather than by IP address.
+//!
+//! An `Advertiser` answers queries for the `_tio._tcp.local` service for as
+//! long as it exists, with the name and serial number of the device in the
+//! TXT record. On the client side, `browse` lists the services on the local
+//! network and `resolve` finds the one of a device, which is also what
+//! `mdns://name` URLs do:
+//! ```no_run
+//! # use twinleaf::tio::proxy::Interface;
+//! let proxy = Interface::new("mdns://VMR");
+//! ```
+//!
+//! Only the part of mDNS needed for this is implemented: there is no probing
+//! for name conflicts, and services are only advertised over IPv4.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel;
+
+/// DNS-SD service type of TIO servers.
+pub static SERVICE_TYPE: &str = "_tio._tcp.local";
+
+/// Name listing the service types on the network.
+static SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+
+static MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+static MDNS_PORT: u16 = 5353;
+
+/// Time to live of the advertised records, in seconds.
+static RECORD_TTL: u32 = 120;
+/// Maximum time to live in replies to one-shot queries, as RFC 6762 asks.
+static LEGACY_TTL: u32 = 10;
+
+/// How often the advertiser thread checks whether it should exit.
+static ADVERTISER_POLL: Duration = Duration::from_millis(200);
+/// How often a query is repeated while browsing.
+static QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Class bit asking for a unicast reply in questions, and telling caches to
+/// replace older records in answers.
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+impl RecordData {
+    fn rtype(&self) -> u16 {
+        match self {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Other => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+#[derive(Debug, Clone)]
+struct Question {
+    name: String,
+    qtype: u16,
+    unicast: bool,
+}
+
+impl Question {
+    fn matches(&self, name: &str, rtype: u16) -> bool {
+        self.name.eq_ignore_ascii_case(name) && (self.qtype == rtype || self.qtype == TYPE_ANY)
+    }
+}
+
+/// A DNS message. Records of the answer, authority and additional sections
+/// are all in `answers` when parsed.
+#[derive(Debug, Default)]
+struct Message {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    answers: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// Read a possibly compressed name at `pos`, returning it and the position
+/// after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xC0 == 0xC0 {
+            let ptr = ((len & 0x3F) << 8) | *msg.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = ptr;
+            continue;
+        }
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(msg.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+impl Message {
+    fn parse(msg: &[u8]) -> Option<Message> {
+        let mut ret = Message {
+            id: read_u16(msg, 0)?,
+            response: read_u16(msg, 2)? & 0x8000 != 0,
+            ..Default::default()
+        };
+        let n_questions = read_u16(msg, 4)?;
+        let n_records = (6..12)
+            .step_by(2)
+            .map(|pos| read_u16(msg, pos).map(usize::from))
+            .sum::<Option<usize>>()?;
+        let mut pos = 12;
+        for _ in 0..n_questions {
+            let (name, next) = read_name(msg, pos)?;
+            let class = read_u16(msg, next + 2)?;
+            ret.questions.push(Question {
+                name,
+                qtype: read_u16(msg, next)?,
+                unicast: class & CLASS_TOP_BIT != 0,
+            });
+            pos = next + 4;
+        }
+        for _ in 0..n_records {
+            let (name, next) = read_name(msg, pos)?;
+            let rtype = read_u16(msg, next)?;
+            let ttl = read_u32(msg, next + 4)?;
+            let len = read_u16(msg, next + 8)? as usize;
+            let start = next + 10;
+            let rdata = msg.get(start..start + len)?;
+            let data = match rtype {
+                TYPE_A if len == 4 => {
+                    RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+                }
+                TYPE_PTR => RecordData::Ptr(read_name(msg, start)?.0),
+                TYPE_TXT => {
+                    let mut strings = vec![];
+                    let mut i = 0;
+                    while i < rdata.len() {
+                        let slen = rdata[i] as usize;
+                        let s = rdata.get(i + 1..i + 1 + slen)?;
+                        strings.push(String::from_utf8_lossy(s).to_string());
+                        i += 1 + slen;
+                    }
+                    RecordData::Txt(strings)
+                }
+                TYPE_SRV => RecordData::Srv {
+                    port: read_u16(msg, start + 4)?,
+                    target: read_name(msg, start + 6)?.0,
+                },
+                _ => RecordData::Other,
+            };
+            ret.answers.push(Record { name, ttl, data });
+            pos = start + len;
+        }
+        Some(ret)
+    }
+
+    /// Serialize the message. Records of `unique` types get the cache flush
+    /// bit, which must not be set in replies to one-shot queries.
+    fn serialize(&self, cache_flush: bool) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend(self.id.to_be_bytes());
+        // Authoritative answer
+        out.extend(if self.response { 0x8400u16 } else { 0 }.to_be_bytes());
+        out.extend((self.questions.len() as u16).to_be_bytes());
+        out.extend((self.answers.len() as u16).to_be_bytes());
+        out.extend(0u16.to_be_bytes());
+        out.extend((self.additional.len() as u16).to_be_bytes());
+        for q in &self.questions {
+            write_name(&mut out, &q.name);
+            out.extend(q.qtype.to_be_bytes());
+            let class = CLASS_IN | if q.unicast { CLASS_TOP_BIT } else { 0 };
+            out.extend(class.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(self.additional.iter()) {
+            write_name(&mut out, &record.name);
+            out.extend(record.data.rtype().to_be_bytes());
+            // PTR records are shared between the servers of a service type.
+            let unique = !matches!(record.data, RecordData::Ptr(_));
+            let class = CLASS_IN
+                | if cache_flush && unique {
+                    CLASS_TOP_BIT
+                } else {
+                    0
+                };
+            out.extend(class.to_be_bytes());
+            out.extend(record.ttl.to_be_bytes());
+            let mut rdata = vec![];
+            match &record.data {
+                RecordData::A(addr) => rdata.extend(addr.octets()),
+                RecordData::Ptr(name) => write_name(&mut rdata, name),
+                RecordData::Txt(strings) => {
+                    for s in strings {
+                        let s = &s.as_bytes()[..s.len().min(255)];
+                        rdata.push(s.len() as u8);
+                        rdata.extend_from_slice(s);
+                    }
+                }
+                RecordData::Srv { port, target } => {
+                    rdata.extend(0u16.to_be_bytes());
+                    rdata.extend(0u16.to_be_bytes());
+                    rdata.extend(port.to_be_bytes());
+                    write_name(&mut rdata, target);
+                }
+                RecordData::Other => {}
+            }
+            out.extend((rdata.len() as u16).to_be_bytes());
+            out.extend(rdata);
+        }
+        out
+    }
+}
+
+/// Make a string usable as a single DNS label.
+fn label(s: &str) -> String {
+    s.replace('.', "-")
+}
+
+/// IPv4 address of the interface used for multicast.
+fn local_ipv4() -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(addr) if !addr.is_unspecified() => Ok(addr),
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no IPv4 address to advertise",
+        )),
+    }
+}
+
+/// Bind the mDNS port, sharing it with other responders on the machine.
+#[cfg(unix)]
+fn bind_shared(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The socket closes the descriptor if anything below fails.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let one: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_port = addr.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind_shared(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    UdpSocket::bind(addr)
+}
+
+/// The records advertising a service.
+struct Service {
+    instance: String,
+    host: String,
+    addr: Ipv4Addr,
+    port: u16,
+    txt: Vec<String>,
+}
+
+impl Service {
+    fn ptr(&self, ttl: u32) -> Record {
+        Record {
+            name: SERVICE_TYPE.to_string(),
+            ttl,
+            data: RecordData::Ptr(self.instance.clone()),
+        }
+    }
+
+    fn srv(&self, ttl: u32) -> Record {
+        Record {
+            name: self.instance.clone(),
+            ttl,
+            data: RecordData::Srv {
+                port: self.port,
+                target: self.host.clone(),
+            },
+        }
+    }
+
+    fn txt(&self, ttl: u32) -> Record {
+        Record {
+            name: self.instance.clone(),
+            ttl,
+            data: RecordData::Txt(self.txt.clone()),
+        }
+    }
+
+    fn a(&self, ttl: u32) -> Record {
+        Record {
+            name: self.host.clone(),
+            ttl,
+            data: RecordData::A(self.addr),
+        }
+    }
+
+    /// Unsolicited response announcing the service, or withdrawing it
+    /// with a `ttl` of 0.
+    fn announcement(&self, ttl: u32) -> Message {
+        Message {
+            response: true,
+            answers: vec![self.ptr(ttl), self.srv(ttl), self.txt(ttl), self.a(ttl)],
+            ..Default::default()
+        }
+    }
+
+    /// Response to `query`, if it asks about this service.
+    fn answer(&self, query: &Message, ttl: u32) -> Option<Message> {
+        let mut answers = vec![];
+        let mut additional = vec![];
+        for q in &query.questions {
+            if q.matches(SERVICE_TYPE, TYPE_PTR) {
+                answers.push(self.ptr(ttl));
+                additional.extend([self.srv(ttl), self.txt(ttl), self.a(ttl)]);
+            }
+            if q.matches(SERVICES_NAME, TYPE_PTR) {
+                answers.push(Record {
+                    name: SERVICES_NAME.to_string(),
+                    ttl,
+                    data: RecordData::Ptr(SERVICE_TYPE.to_string()),
+                });
+            }
+            if q.matches(&self.instance, TYPE_SRV) {
+                answers.push(self.srv(ttl));
+                additional.push(self.a(ttl));
+            }
+            if q.matches(&self.instance, TYPE_TXT) {
+                answers.push(self.txt(ttl));
+            }
+            if q.matches(&self.host, TYPE_A) {
+                answers.push(self.a(ttl));
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        Some(Message {
+            id: 0,
+            response: true,
+            questions: vec![],
+            answers,
+            additional,
+        })
+    }
+}
+
+/// Advertises a TIO server over mDNS in its own thread, until dropped.
+pub struct Advertiser {
+    /// Dropping this stops the thread.
+    _stop: channel::Sender<()>,
+}
+
+impl Advertiser {
+    /// Advertise the TCP server on `port` serving the device `name` with
+    /// serial number `serial`. The service instance is named after both,
+    /// like `VMR 1234._tio._tcp.local`.
+    pub fn new(name: &str, serial: &str, port: u16) -> io::Result<Advertiser> {
+        let instance_label = match (name.is_empty(), serial.is_empty()) {
+            (false, false) => format!("{} {}", name, serial),
+            (false, true) => name.to_string(),
+            (true, false) => serial.to_string(),
+            (true, true) => "tio".to_string(),
+        };
+        let host_label = format!("tio-{}-{}", label(&instance_label).replace(' ', "-"), port);
+        let service = Service {
+            instance: format!("{}.{}", label(&instance_label), SERVICE_TYPE),
+            host: format!("{}.local", host_label),
+            addr: local_ipv4()?,
+            port,
+            txt: vec![format!("name={}", name), format!("serial={}", serial)],
+        };
+
+        let socket = bind_shared(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+        socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(ADVERTISER_POLL))?;
+        let (stop, stopped) = channel::bounded::<()>(0);
+        thread::Builder::new()
+            .name("mdns".to_string())
+            .spawn(move || Advertiser::run(socket, service, stopped))?;
+        Ok(Advertiser { _stop: stop })
+    }
+
+    fn run(socket: UdpSocket, service: Service, stopped: channel::Receiver<()>) {
+        let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+        let send = |msg: &Message, cache_flush: bool, dest: SocketAddr| {
+            if let Err(err) = socket.send_to(&msg.serialize(cache_flush), dest) {
+                log_debug!("Failed to send mDNS response: {:?}", err);
+            }
+        };
+        // Announce twice, a second apart, as RFC 6762 recommends.
+        let mut announcements = 2;
+        let mut next_announcement = Instant::now();
+        let mut buf = [0u8; 9000];
+        while let Err(channel::TryRecvError::Empty) = stopped.try_recv() {
+            if announcements > 0 && Instant::now() >= next_announcement {
+                send(&service.announcement(RECORD_TTL), true, group);
+                announcements -= 1;
+                next_announcement += Duration::from_secs(1);
+            }
+            let (size, from) = match socket.recv_from(&mut buf) {
+                Ok(ret) => ret,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(err) => {
+                    log_warn!("mDNS advertiser failed: {:?}", err);
+                    return;
+                }
+            };
+            let Some(query) = Message::parse(&buf[..size]) else {
+                continue;
+            };
+            if query.response {
+                continue;
+            }
+            if from.port() != MDNS_PORT {
+                // One-shot query from a simple resolver, which expects a
+                // plain DNS reply.
+                if let Some(mut reply) = service.answer(&query, LEGACY_TTL) {
+                    reply.id = query.id;
+                    reply.questions = query.questions.clone();
+                    send(&reply, false, from);
+                }
+            } else if let Some(reply) = service.answer(&query, RECORD_TTL) {
+                let unicast = query.questions.iter().all(|q| q.unicast);
+                send(&reply, true, if unicast { from } else { group });
+            }
+        }
+        // Withdraw the service.
+        send(&service.announcement(0), true, group);
+    }
+}
+
+/// A TIO server found over mDNS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// Full name of the service instance, like `VMR 1234._tio._tcp.local`.
+    pub instance: String,
+    /// Name of the device, from the TXT record.
+    pub name: String,
+    /// Serial number of the device, from the TXT record.
+    pub serial: String,
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+impl ServiceInfo {
+    /// URL to connect to the server.
+    pub fn url(&self) -> String {
+        match self.addr {
+            IpAddr::V4(addr) => format!("tcp://{}:{}", addr, self.port),
+            IpAddr::V6(addr) => format!("tcp://[{}]:{}", addr, self.port),
+        }
+    }
+
+    /// True if `name` is the device name, serial number or instance name
+    /// of the service, ignoring case.
+    pub fn is_named(&self, name: &str) -> bool {
+        let instance_label = self
+            .instance
+            .strip_suffix(SERVICE_TYPE)
+            .unwrap_or(&self.instance)
+            .trim_end_matches('.');
+        [self.name.as_str(), self.serial.as_str(), instance_label]
+            .iter()
+            .any(|n| !n.is_empty() && n.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Services described by the records received so far. Addresses default
+/// to the source of the response when there is no A record for the host.
+fn services(records: &[(Record, IpAddr)]) -> Vec<ServiceInfo> {
+    let mut ret: Vec<ServiceInfo> = vec![];
+    for (record, _) in records {
+        let RecordData::Ptr(instance) = &record.data else {
+            continue;
+        };
+        if !record.name.eq_ignore_ascii_case(SERVICE_TYPE)
+            || record.ttl == 0
+            || ret.iter().any(|s| &s.instance == instance)
+        {
+            continue;
+        }
+        let Some((port, target, from)) = records.iter().find_map(|(r, from)| match &r.data {
+            RecordData::Srv { port, target } if r.name.eq_ignore_ascii_case(instance) => {
+                Some((*port, target, *from))
+            }
+            _ => None,
+        }) else {
+            continue;
+        };
+        let addr = records
+            .iter()
+            .find_map(|(r, _)| match r.data {
+                RecordData::A(addr) if r.name.eq_ignore_ascii_case(target) => {
+                    Some(IpAddr::V4(addr))
+                }
+                _ => None,
+            })
+            .unwrap_or(from);
+        let mut info = ServiceInfo {
+            instance: instance.clone(),
+            name: String::new(),
+            serial: String::new(),
+            addr,
+            port,
+        };
+        let txt = records.iter().find_map(|(r, _)| match &r.data {
+            RecordData::Txt(strings) if r.name.eq_ignore_ascii_case(instance) => Some(strings),
+            _ => None,
+        });
+        for entry in txt.into_iter().flatten() {
+            match entry.split_once('=') {
+                Some(("name", name)) => info.name = name.to_string(),
+                Some(("serial", serial)) => info.serial = serial.to_string(),
+                _ => {}
+            }
+        }
+        ret.push(info);
+    }
+    ret
+}
+
+/// Query for TIO services for up to `timeout`, until `done` is true of the
+/// services found so far.
+fn query(timeout: Duration, done: impl Fn(&[ServiceInfo]) -> bool) -> io::Result<Vec<ServiceInfo>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.subsec_nanos() as u16);
+    let query = Message {
+        id,
+        questions: vec![Question {
+            name: SERVICE_TYPE.to_string(),
+            qtype: TYPE_PTR,
+            unicast: true,
+        }],
+        ..Default::default()
+    }
+    .serialize(false);
+
+    let deadline = Instant::now() + timeout;
+    let mut next_query = Instant::now();
+    let mut records: Vec<(Record, IpAddr)> = vec![];
+    let mut found = vec![];
+    let mut buf = [0u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(found);
+        }
+        if now >= next_query {
+            socket.send_to(&query, (MDNS_ADDR, MDNS_PORT))?;
+            next_query = now + QUERY_INTERVAL;
+        }
+        socket.set_read_timeout(Some(deadline.min(next_query) - now))?;
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(ret) => ret,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let Some(reply) = Message::parse(&buf[..size]) else {
+            continue;
+        };
+        if !reply.response {
+            continue;
+        }
+        records.extend(reply.answers.into_iter().map(|r| (r, from.ip())));
+        found = services(&records);
+        if done(&found) {
+            return Ok(found);
+        }
+    }
+}
+
+/// TIO servers on the local network, found by querying for `timeout`.
+pub fn browse(timeout: Duration) -> io::Result<Vec<ServiceInfo>> {
+    query(timeout, |_| false)
+}
+
+/// Find the TIO server of the device with the given name, serial number or
+/// service instance name, waiting for up to `timeout`.
+pub fn resolve(name: &str, timeout: Duration) -> io::Result<ServiceInfo> {
+    let found = query(timeout, |found| found.iter().any(|s| s.is_named(name)))?;
+    found.into_iter().find(|s| s.is_named(name)).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no TIO server named '{}' found over mDNS", name),
+        )
+    })
+}
@@ -1,10 +1,34 @@
+#[cfg(feature = "std")]
+pub mod budget;
+#[cfg(feature = "std")]
+pub mod discovery;
+#[cfg(feature = "std")]
+pub mod eventlog;
+#[cfg(feature = "std")]
+pub mod manager;
+#[cfg(feature = "std")]
+pub mod mdns;
+#[cfg(feature = "std")]
+pub mod pcapng;
+#[cfg(feature = "std")]
 pub mod port;
+#[cfg(feature = "std")]
+pub mod power;
 pub mod proto;
+#[cfg(feature = "std")]
 pub mod proxy;
+#[cfg(feature = "std")]
 mod proxy_core;
+#[cfg(feature = "std")]
+pub mod rawlog;
 pub mod util;
+#[cfg(feature = "std")]
+pub mod watchdog;
 
+#[cfg(feature = "std")]
 pub use port::{RecvError, SendError};
 pub use proto::Packet;
+#[cfg(feature = "std")]
 pub use proxy::Interface as Proxy;
+#[cfg(feature = "std")]
 pub use proxy::Port;
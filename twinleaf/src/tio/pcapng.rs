@@ -0,0 +1,175 @@
+//! pcapng export
+//!
+//! Writes TIO traffic, typically captured with a `proxy::Sniffer`, in the
+//! pcapng format so that captures can be shared and inspected with standard
+//! tools such as Wireshark. Each packet is stored as it appears on the wire,
+//! under the `LINKTYPE_USER0` link type, with its direction recorded in the
+//! packet flags and a comment summarizing the route and payload type.
+
+use super::proto::{Packet, Payload};
+use super::proxy::{Direction, SniffedPacket};
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Link type used for TIO packets (`LINKTYPE_USER0`).
+pub static LINKTYPE_TIO: u16 = 147;
+
+static BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+static BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+static BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+static BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+static OPT_END: u16 = 0;
+static OPT_COMMENT: u16 = 1;
+static OPT_SHB_USERAPPL: u16 = 4;
+static OPT_IF_NAME: u16 = 2;
+static OPT_IF_TSRESOL: u16 = 9;
+static OPT_EPB_FLAGS: u16 = 2;
+
+static EPB_FLAG_INBOUND: u32 = 1;
+static EPB_FLAG_OUTBOUND: u32 = 2;
+
+/// Append an option, padded to 32 bits.
+fn push_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend(code.to_le_bytes());
+    block.extend((value.len() as u16).to_le_bytes());
+    block.extend(value);
+    pad32(block);
+}
+
+fn pad32(block: &mut Vec<u8>) {
+    while !block.len().is_multiple_of(4) {
+        block.push(0);
+    }
+}
+
+/// Name of the payload type, as used in packet comments.
+pub fn payload_type_name(payload: &Payload) -> &'static str {
+    match payload {
+        Payload::LogMessage(_) => "LogMessage",
+        Payload::RpcRequest(_) => "RpcRequest",
+        Payload::RpcReply(_) => "RpcReply",
+        Payload::RpcError(_) => "RpcError",
+        Payload::Heartbeat(_) => "Heartbeat",
+        Payload::LegacyTimebaseUpdate(_) => "LegacyTimebaseUpdate",
+        Payload::LegacySourceUpdate(_) => "LegacySourceUpdate",
+        Payload::LegacyStreamUpdate(_) => "LegacyStreamUpdate",
+        Payload::LegacyStreamData(_) => "LegacyStreamData",
+        Payload::Metadata(_) => "Metadata",
+        Payload::StreamData(_) => "StreamData",
+        Payload::Unknown(_) => "Unknown",
+    }
+}
+
+/// Writer of a pcapng capture with a single TIO interface.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    n_packets: u64,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start a capture, writing the section header and interface blocks.
+    pub fn new(out: W) -> io::Result<PcapngWriter<W>> {
+        let mut writer = PcapngWriter { out, n_packets: 0 };
+
+        let mut shb = vec![];
+        shb.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend(1u16.to_le_bytes());
+        shb.extend(0u16.to_le_bytes());
+        // Section length not specified
+        shb.extend((-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, b"twinleaf");
+        push_option(&mut shb, OPT_END, &[]);
+        writer.write_block(BLOCK_SECTION_HEADER, &shb)?;
+
+        let mut idb = vec![];
+        idb.extend(LINKTYPE_TIO.to_le_bytes());
+        idb.extend(0u16.to_le_bytes());
+        // No snapshot length limit
+        idb.extend(0u32.to_le_bytes());
+        push_option(&mut idb, OPT_IF_NAME, b"tio");
+        // Microsecond timestamps
+        push_option(&mut idb, OPT_IF_TSRESOL, &[6]);
+        push_option(&mut idb, OPT_END, &[]);
+        writer.write_block(BLOCK_INTERFACE_DESCRIPTION, &idb)?;
+
+        Ok(writer)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_len = (12 + body.len()) as u32;
+        self.out.write_all(&block_type.to_le_bytes())?;
+        self.out.write_all(&total_len.to_le_bytes())?;
+        self.out.write_all(body)?;
+        self.out.write_all(&total_len.to_le_bytes())
+    }
+
+    /// Write a packet exchanged with the device at `timestamp`.
+    pub fn write_packet(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        client: Option<u64>,
+        pkt: &Packet,
+    ) -> io::Result<()> {
+        let raw = pkt.serialize().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "packet cannot be serialized")
+        })?;
+        let us = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_micros() as u64)
+            .unwrap_or(0);
+        let (flags, comment) = match (direction, client) {
+            (Direction::ToDevice, Some(client)) => (
+                EPB_FLAG_OUTBOUND,
+                format!("client {} -> device {}", client, pkt.routing),
+            ),
+            (Direction::ToDevice, None) => {
+                (EPB_FLAG_OUTBOUND, format!("-> device {}", pkt.routing))
+            }
+            (Direction::FromDevice, _) => (EPB_FLAG_INBOUND, format!("device {} ->", pkt.routing)),
+        };
+        let comment = format!("{} {}", comment, payload_type_name(&pkt.payload));
+
+        let mut epb = vec![];
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(((us >> 32) as u32).to_le_bytes());
+        epb.extend((us as u32).to_le_bytes());
+        epb.extend((raw.len() as u32).to_le_bytes());
+        epb.extend((raw.len() as u32).to_le_bytes());
+        epb.extend(&raw);
+        pad32(&mut epb);
+        push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+        push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+        self.write_block(BLOCK_ENHANCED_PACKET, &epb)?;
+        self.n_packets += 1;
+        Ok(())
+    }
+
+    /// Write a packet received from a `Sniffer`.
+    pub fn write(&mut self, sniffed: &SniffedPacket) -> io::Result<()> {
+        self.write_packet(
+            sniffed.timestamp,
+            sniffed.direction,
+            sniffed.client,
+            &sniffed.packet,
+        )
+    }
+
+    /// Number of packets written so far.
+    pub fn packets(&self) -> u64 {
+        self.n_packets
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
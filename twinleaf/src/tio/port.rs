@@ -15,15 +15,29 @@
 //!
 //! Note: `Port` sets up a dedicated thread to perform the above.
 
+mod faults;
 mod iobuf;
+mod mock;
+#[cfg(windows)]
+mod pipe;
 mod serial;
+mod sim;
 mod tcp;
 mod udp;
 
+pub use faults::{FaultConfig, FAULTS_ENV_VAR};
+pub use mock::{MockDevice, MockReply, MockStep};
+pub use serial::decode_frame;
+pub use sim::SimConfig;
+
+use super::mdns;
 use super::proto::{self, Packet};
 use super::util;
+use std::collections::VecDeque;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -65,6 +79,101 @@ pub enum RateError {
     Failed,
 }
 
+/// Reason a serial port could not be opened: it is already in use, by the
+/// process `owner` if it could be found (on Linux only). Returned wrapped in
+/// an `io::Error` of kind `ResourceBusy`, see `PortBusy::from_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortBusy {
+    pub owner: Option<u32>,
+}
+
+impl PortBusy {
+    fn error(owner: Option<u32>) -> io::Error {
+        io::Error::new(io::ErrorKind::ResourceBusy, PortBusy { owner })
+    }
+
+    /// The `PortBusy` reason of `err`, if it has one.
+    pub fn from_error(err: &io::Error) -> Option<PortBusy> {
+        err.get_ref()?.downcast_ref::<PortBusy>().copied()
+    }
+}
+
+impl std::fmt::Display for PortBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.owner {
+            Some(pid) => write!(f, "port in use by process {}", pid),
+            None => write!(f, "port in use"),
+        }
+    }
+}
+
+impl std::error::Error for PortBusy {}
+
+/// Reason a device could not be opened, sorted from the error of
+/// `Port::new` by `ConnectError::new`, so that it can be handled or shown
+/// to users without parsing messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// Not allowed to open the device, such as a serial port of a group the
+    /// user is not in.
+    PermissionDenied,
+    /// There is no such device, serial port or host.
+    NotFound,
+    /// The device is in use, see `PortBusy`.
+    Busy(Option<u32>),
+    /// The scheme of the url is unknown, or not supported on this platform.
+    UnsupportedScheme(String),
+    /// The url or its options are invalid.
+    InvalidUrl(String),
+    /// The device was reached but the connection was refused, reset or
+    /// timed out.
+    Handshake(String),
+    Other(String),
+}
+
+impl ConnectError {
+    /// Reason `err`, returned when opening `url`, failed.
+    pub fn new(url: &str, err: &io::Error) -> ConnectError {
+        use io::ErrorKind::*;
+        if let Some(busy) = PortBusy::from_error(err) {
+            return ConnectError::Busy(busy.owner);
+        }
+        match err.kind() {
+            PermissionDenied => ConnectError::PermissionDenied,
+            NotFound | AddrNotAvailable => ConnectError::NotFound,
+            ResourceBusy => ConnectError::Busy(None),
+            Unsupported => ConnectError::UnsupportedScheme(
+                url.split_once("://")
+                    .map_or(url, |(scheme, _)| scheme)
+                    .to_string(),
+            ),
+            InvalidInput => ConnectError::InvalidUrl(err.to_string()),
+            ConnectionRefused | ConnectionReset | ConnectionAborted | TimedOut | UnexpectedEof => {
+                ConnectError::Handshake(err.to_string())
+            }
+            _ => ConnectError::Other(err.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::PermissionDenied => write!(f, "permission denied"),
+            ConnectError::NotFound => write!(f, "device not found"),
+            ConnectError::Busy(owner) => PortBusy { owner: *owner }.fmt(f),
+            ConnectError::UnsupportedScheme(scheme) => {
+                write!(f, "unsupported url scheme {}", scheme)
+            }
+            ConnectError::InvalidUrl(why) => write!(f, "invalid url: {}", why),
+            ConnectError::Handshake(why) => write!(f, "connection failed: {}", why),
+            ConnectError::Other(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
 /// Custom data rate info associated with the port
 #[derive(Clone)]
 pub struct RateInfo {
@@ -75,6 +184,124 @@ pub struct RateInfo {
     pub target_bps: u32,
 }
 
+/// How a port deals with received data which breaks the framing of packets,
+/// see `Port::set_parse_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Make the most of a noisy link: lines of text are reported as
+    /// `proto::Error::Text`, and garbage (stale partial packets, data
+    /// received while the port starts up, bytes which do not start a packet)
+    /// is skipped to resynchronize on the next packet.
+    #[default]
+    Lenient,
+    /// Report any framing violation as a `proto::Error::Framing` with the
+    /// bytes as they were received, rather than recovering from it, for
+    /// validating the protocol implementation of a device.
+    Strict,
+}
+
+/// Counts of what a port received from the device, to quantify the quality
+/// of the link, as returned by `Port::link_counters`. Errors during the
+/// startup holdoff are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkCounters {
+    /// Packets received intact.
+    pub packets: u64,
+    /// Packets which failed their checksum.
+    pub crc_errors: u64,
+    /// Received data which could not be decoded as packets: too short, too
+    /// long, or malformed.
+    pub framing_errors: u64,
+    /// Lines of text received, as devices print when starting up.
+    pub text_lines: u64,
+    /// Times packets were received again after errors.
+    pub resyncs: u64,
+}
+
+/// `LinkCounters` updated by the port thread.
+#[derive(Default)]
+struct SharedLinkCounters {
+    packets: AtomicU64,
+    crc_errors: AtomicU64,
+    framing_errors: AtomicU64,
+    text_lines: AtomicU64,
+    resyncs: AtomicU64,
+}
+
+impl SharedLinkCounters {
+    fn count(&self, result: &Result<Packet, RecvError>, in_error: &mut bool) {
+        let counter = match result {
+            Ok(_) => {
+                if std::mem::take(in_error) {
+                    self.resyncs.fetch_add(1, Ordering::Relaxed);
+                }
+                &self.packets
+            }
+            Err(RecvError::Protocol(proto::Error::Text(_))) => &self.text_lines,
+            Err(RecvError::Protocol(proto::Error::CRC32(_))) => {
+                *in_error = true;
+                &self.crc_errors
+            }
+            Err(RecvError::Protocol(_)) | Err(RecvError::IO(_)) => {
+                *in_error = true;
+                &self.framing_errors
+            }
+            Err(_) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LinkCounters {
+        LinkCounters {
+            packets: self.packets.load(Ordering::Relaxed),
+            crc_errors: self.crc_errors.load(Ordering::Relaxed),
+            framing_errors: self.framing_errors.load(Ordering::Relaxed),
+            text_lines: self.text_lines.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Destination of the raw bytes received by a port, see `Port::set_raw_tap`.
+#[derive(Clone, Default)]
+struct RawTap(Arc<Mutex<Option<crossbeam::channel::Sender<Vec<u8>>>>>);
+
+impl RawTap {
+    /// Pass on bytes just read from the underlying stream. They are dropped
+    /// if the tap is not keeping up, rather than holding up the port.
+    fn feed(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut tap = self.0.lock().unwrap();
+        if let Some(sender) = tap.as_ref() {
+            if let Err(crossbeam::channel::TrySendError::Disconnected(_)) =
+                sender.try_send(data.to_vec())
+            {
+                *tap = None;
+            }
+        }
+    }
+}
+
+/// `ParseMode` of a port, shared with its raw port.
+#[derive(Clone, Default)]
+struct ParseModeFlag(Arc<AtomicBool>);
+
+impl ParseModeFlag {
+    fn get(&self) -> ParseMode {
+        if self.0.load(Ordering::Relaxed) {
+            ParseMode::Strict
+        } else {
+            ParseMode::Lenient
+        }
+    }
+
+    fn is_strict(&self) -> bool {
+        self.get() == ParseMode::Strict
+    }
+}
+
 /// Generic interface for the low level part of a port.
 trait RawPort {
     /// Returns a packet without blocking, or RecvError::NotReady if one is not available.
@@ -87,6 +314,21 @@ trait RawPort {
     /// - for all other errors, the appropriate action is to tear down this port and recreate.
     fn send(&mut self, pkt: &Packet) -> Result<(), SendError>;
 
+    /// Attempts to send several packets, which ports can coalesce into fewer writes.
+    /// Returns how many packets were sent, and the result of the last send, with the
+    /// same meaning as for `send()`: if it is MustDrain, the last packet counted as
+    /// sent must be drained before sending the remaining ones.
+    fn send_batch(&mut self, pkts: &[Packet]) -> (usize, Result<(), SendError>) {
+        for (i, pkt) in pkts.iter().enumerate() {
+            match self.send(pkt) {
+                Ok(()) => {}
+                Err(SendError::MustDrain) => return (i + 1, Err(SendError::MustDrain)),
+                Err(e) => return (i, Err(e)),
+            }
+        }
+        (pkts.len(), Ok(()))
+    }
+
     /// Drain partially written packet. Note: if a send returned MustDrain, no subsequent
     /// packets can be sent without successfully draining first.
     fn drain(&mut self) -> Result<(), SendError> {
@@ -105,6 +347,14 @@ trait RawPort {
         Err(RateError::Unsupported)
     }
 
+    /// Send a copy of the raw bytes received from now on to `tap`, for ports
+    /// reading from a byte stream.
+    fn set_raw_tap(&mut self, _tap: RawTap) {}
+
+    /// Check the framing of received data according to `mode` from now on,
+    /// for ports reading from a byte stream.
+    fn set_parse_mode(&mut self, _mode: ParseModeFlag) {}
+
     /// Get the `RateInfo` for this port. If None, `set_rate()` is unsupported.
     fn rate_info(&self) -> Option<RateInfo> {
         None
@@ -125,6 +375,88 @@ trait RawPort {
     }
 }
 
+/// True if `url` names a serial port, which can be given without `serial://`:
+/// `/dev/...` on unix, `COMn` or `\\.\COMn` in any case on windows.
+fn is_serial_port(url: &str) -> bool {
+    if cfg!(unix) {
+        return url.starts_with("/dev/");
+    }
+    if cfg!(windows) {
+        let name = url.strip_prefix(r"\\.\").unwrap_or(url);
+        return name.get(..3).is_some_and(|p| p.eq_ignore_ascii_case("COM"))
+            && name[3..].starts_with(|c: char| c.is_ascii_digit());
+    }
+    false
+}
+
+/// How long to wait for each reply of a device being identified, for a
+/// `serial-number://` URL.
+static IDENTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serial ports of USB devices, which sensors are connected through, as
+/// `serial://` URLs.
+pub fn usb_serial_ports() -> Vec<String> {
+    let Ok(ports) = mio_serial::available_ports() else {
+        return vec![];
+    };
+    ports
+        .into_iter()
+        .filter(|p| matches!(p.port_type, mio_serial::SerialPortType::UsbPort(_)))
+        // On macOS, every port also shows up as a `tty.` dial-in device.
+        .filter(|p| !(cfg!(target_os = "macos") && p.port_name.starts_with("/dev/tty.")))
+        .map(|p| format!("serial://{}", p.port_name))
+        .collect()
+}
+
+/// Name and serial number of the device at `url`, as returned by its
+/// `dev.name` and `dev.serial` RPCs, waiting up to `timeout` for each.
+pub fn identify(url: &str, timeout: Duration) -> io::Result<(String, String)> {
+    let (rx_send, rx) = Port::rx_channel();
+    let port = Port::new_with_faults(url, Port::rx_to_channel(rx_send), None)?;
+    let call = |id: u16, name: &str| -> io::Result<String> {
+        let req = util::PacketBuilder::make_rpc_request(name, &[], id, proto::DeviceRoute::root());
+        port.send(req)
+            .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let pkt = match rx.recv_deadline(deadline) {
+                Ok(Ok(pkt)) => pkt,
+                Ok(Err(_)) => continue,
+                Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+            };
+            match pkt.payload {
+                proto::Payload::RpcReply(rep) if rep.id == id => {
+                    return Ok(String::from_utf8_lossy(&rep.reply).to_string());
+                }
+                proto::Payload::RpcError(err) if err.id == id => {
+                    return Err(io::Error::other(format!(
+                        "{} failed: {:?}",
+                        name, err.error
+                    )));
+                }
+                _ => {}
+            }
+        }
+    };
+    Ok((call(1, "dev.name")?, call(2, "dev.serial")?))
+}
+
+/// URL of the first of `candidates` with the given serial number or name.
+/// Candidates which cannot be opened or do not reply are skipped.
+pub fn find_device(id: &str, candidates: &[String], timeout: Duration) -> io::Result<String> {
+    for url in candidates {
+        if let Ok((name, serial)) = identify(url, timeout) {
+            if serial == id || name == id {
+                return Ok(url.clone());
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no device {} found", id),
+    ))
+}
+
 /// In special cases where the default that gets picked when resolving an IP address
 /// does not work, this allows to force using either IPv4 or IPv6.
 enum AddrFamilyRestrict {
@@ -136,6 +468,9 @@ enum AddrFamilyRestrict {
 /// Default TCP and UDP port used by the TIO protocol.
 static TIO_DEFAULT_PORT: u16 = 7855;
 
+/// How long to look for the server of an `mdns://` URL.
+static MDNS_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// Resolve a fully specified socket address with address family restrictions.
 /// This will attempt to add the default port
 fn find_addr(addr: &str, family: AddrFamilyRestrict) -> Result<SocketAddr, io::Error> {
@@ -184,10 +519,14 @@ fn find_addr(addr: &str, family: AddrFamilyRestrict) -> Result<SocketAddr, io::E
     ))
 }
 
+/// Most packets dequeued at once to be sent to the raw port together.
+static TX_BATCH_PACKETS: usize = 64;
+
 /// The communication to the `Port` thread occurs over a single
 /// channel. This enum is used to multiplex data and control messages.
+/// Packets are boxed to keep control messages small.
 enum PacketOrControl {
-    Pkt(Packet),
+    Pkt(Box<Packet>),
     SetRate(u32),
 }
 
@@ -206,11 +545,19 @@ pub struct Port {
     waker: mio::Waker,
     ctl_result: crossbeam::channel::Receiver<ControlResult>,
     rates: Option<RateInfo>,
+    counters: Arc<SharedLinkCounters>,
+    raw_tap: RawTap,
+    parse_mode: ParseModeFlag,
+    /// Disconnected when the port thread exits.
+    closed: crossbeam::channel::Receiver<()>,
 }
 
 /// Default size of the rx channel when receiving to a crossbeam channel.
 static DEFAULT_RX_CHANNEL_SIZE: usize = 64;
 
+/// How long dropping a `Port` waits for its thread to close the raw port.
+static CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
 impl Port {
     /// Method running the `Port` thread event loop. It bridges `mio` and
     /// `crossbeam::channel`, and it takes care of tx buffering/draining,
@@ -224,9 +571,14 @@ impl Port {
         rx: RxCallbackT,
         tx: crossbeam::channel::Receiver<PacketOrControl>,
         ctl_result: crossbeam::channel::Sender<ControlResult>,
+        counters: Arc<SharedLinkCounters>,
+        parse_mode: ParseModeFlag,
     ) {
         use crossbeam::channel::TryRecvError;
 
+        // Set after receive errors, to count when packets come again.
+        let mut in_error = false;
+
         let mut events = mio::Events::with_capacity(1);
         let mut needs_draining = false;
 
@@ -234,6 +586,11 @@ impl Port {
         // the port queue being full.
         let mut needs_tx_queue_check = false;
 
+        // Packets and control messages dequeued from tx but not yet processed,
+        // and whether tx was closed after them.
+        let mut tx_pending: VecDeque<PacketOrControl> = VecDeque::new();
+        let mut tx_closed = false;
+
         poll.registry()
             .register(&mut raw_port, mio::Token(1), mio::Interest::READABLE)
             .expect("mio::Poll raw_port registration failure");
@@ -265,7 +622,8 @@ impl Port {
                                     .expect("Writable interest set failed (HB)");
                                 continue;
                             }
-                            Err(_) => {
+                            Err(e) => {
+                                log_debug!("Port heartbeat send failed: {:?}", e);
                                 break 'ioloop;
                             }
                             Ok(_) => {
@@ -322,7 +680,8 @@ impl Port {
                                     Err(SendError::MustDrain) => {
                                         // Must keep trying, do nothing
                                     }
-                                    Err(_) => {
+                                    Err(e) => {
+                                        log_debug!("Port drain failed: {:?}", e);
                                         break 'ioloop;
                                     }
                                 }
@@ -335,12 +694,19 @@ impl Port {
                         }
                         // Packet or error available from the device
                         loop {
-                            match raw_port.recv() {
-                                Ok(pkt) => {
+                            let received = raw_port.recv();
+                            if !startup {
+                                counters.count(&received, &mut in_error);
+                            }
+                            match received {
+                                Ok(mut pkt) => {
+                                    // Stamped as soon as read, before any queueing.
+                                    pkt.received = Some(proto::ReceiveTime::now());
                                     if startup {
                                         // Ignore this packet
-                                    } else if let Err(_) = rx(Ok(pkt)) {
+                                    } else if let Err(e) = rx(Ok(pkt)) {
                                         // RX callback signaled an error, terminate.
+                                        log_debug!("Port receiver closed: {:?}", e);
                                         break 'ioloop;
                                     }
                                 }
@@ -358,14 +724,18 @@ impl Port {
                                     };
                                     // We want to ignore errors in the startup phase, except for
                                     // receiving text, which can happen on sensor initialization
-                                    // and we want to relay back.
+                                    // and we want to relay back, or when parsing strictly.
                                     let ignore =
                                         if let RecvError::Protocol(proto::Error::Text(_)) = e {
                                             false
                                         } else {
-                                            startup
+                                            startup && !parse_mode.is_strict()
                                         };
                                     if (!ignore && rx(Err(e)).is_err()) || disconnect {
+                                        log_debug!(
+                                            "Port receive failed, disconnect: {}",
+                                            disconnect
+                                        );
                                         break 'ioloop;
                                     }
                                 }
@@ -384,11 +754,57 @@ impl Port {
             }
 
             if check_tx_channel {
-                // Dequeue and send to the device port, or break out.
+                // Dequeue and send to the device port in batches, or break out.
                 loop {
-                    match tx.try_recv() {
-                        Ok(PacketOrControl::Pkt(pkt)) => {
-                            match raw_port.send(&pkt) {
+                    while tx_pending.len() < TX_BATCH_PACKETS {
+                        match tx.try_recv() {
+                            Ok(item) => tx_pending.push_back(item),
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => {
+                                tx_closed = true;
+                                break;
+                            }
+                        }
+                    }
+                    match tx_pending.front() {
+                        None => {
+                            if tx_closed {
+                                break 'ioloop;
+                            }
+                            break;
+                        }
+                        Some(PacketOrControl::SetRate(rate)) => {
+                            let rate = *rate;
+                            tx_pending.pop_front();
+                            log_debug!("Setting port rate to {}", rate);
+                            if let Err(_) = ctl_result.send(match raw_port.set_rate(rate) {
+                                Ok(_) => ControlResult::Success,
+                                Err(e) => {
+                                    log_debug!("Failed to set port rate: {:?}", e);
+                                    ControlResult::SetRateError(e)
+                                }
+                            }) {
+                                break 'ioloop;
+                            }
+                        }
+                        Some(PacketOrControl::Pkt(_)) => {
+                            let n_pkts = tx_pending
+                                .iter()
+                                .take_while(|item| matches!(item, PacketOrControl::Pkt(_)))
+                                .count();
+                            let batch: Vec<Packet> = tx_pending
+                                .drain(..n_pkts)
+                                .filter_map(|item| match item {
+                                    PacketOrControl::Pkt(pkt) => Some(*pkt),
+                                    _ => None,
+                                })
+                                .collect();
+                            let (n_sent, res) = raw_port.send_batch(&batch);
+                            let mut unsent = batch.into_iter().skip(n_sent);
+                            if n_sent > 0 {
+                                last_sent = Instant::now();
+                            }
+                            match res {
                                 Err(SendError::MustDrain) => {
                                     needs_draining = true;
                                     poll.registry()
@@ -404,28 +820,23 @@ impl Port {
                                     // return MustDrain before Full, and the code in the
                                     // ioloop will ensure that a port in that state is
                                     // drained successfully before receiving anything on tx.
+                                    log_warn!("Port full when not draining, packet dropped");
+                                    unsent.next();
                                 }
-                                Err(_) => {
+                                Err(e) => {
+                                    log_debug!("Port send failed: {:?}", e);
                                     break 'ioloop;
                                 }
-                                Ok(_) => {
-                                    last_sent = Instant::now();
-                                }
+                                Ok(_) => {}
                             }
-                        }
-                        Ok(PacketOrControl::SetRate(rate)) => {
-                            if let Err(_) = ctl_result.send(match raw_port.set_rate(rate) {
-                                Ok(_) => ControlResult::Success,
-                                Err(e) => ControlResult::SetRateError(e),
-                            }) {
-                                break 'ioloop;
+                            // Put back what was not sent, to send after draining.
+                            for pkt in unsent.rev() {
+                                tx_pending.push_front(PacketOrControl::Pkt(Box::new(pkt)));
+                            }
+                            if needs_draining {
+                                needs_tx_queue_check = true;
+                                break;
                             }
-                        }
-                        Err(TryRecvError::Empty) => {
-                            break;
-                        }
-                        Err(TryRecvError::Disconnected) => {
-                            break 'ioloop;
                         }
                     }
                 }
@@ -433,20 +844,47 @@ impl Port {
         }
     }
 
-    /// Create a `Port` from a `RawPort` and a rx callback.
+    /// Create a `Port` from a `RawPort` and a rx callback, wrapping the raw
+    /// port to inject faults if configured.
     fn from_raw<
         RawPortT: RawPort + mio::event::Source + Send + 'static,
         RxCallbackT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static,
     >(
         raw_port: RawPortT,
         rx: RxCallbackT,
+        faults: Option<FaultConfig>,
     ) -> io::Result<Port> {
+        match faults {
+            Some(config) => {
+                let rx = faults::delay_rx(config.latency, rx);
+                Port::spawn(faults::FaultyPort::new(raw_port, config), rx)
+            }
+            None => Port::spawn(raw_port, rx),
+        }
+    }
+
+    fn spawn<
+        RawPortT: RawPort + mio::event::Source + Send + 'static,
+        RxCallbackT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static,
+    >(
+        mut raw_port: RawPortT,
+        rx: RxCallbackT,
+    ) -> io::Result<Port> {
+        let raw_tap = RawTap::default();
+        raw_port.set_raw_tap(raw_tap.clone());
+        let parse_mode = ParseModeFlag::default();
+        raw_port.set_parse_mode(parse_mode.clone());
+        let thread_parse_mode = parse_mode.clone();
         let rates = raw_port.rate_info();
         let (tx, ttx) = crossbeam::channel::bounded::<PacketOrControl>(32);
         let (ctl_ret_sender, ctl_ret_receiver) = crossbeam::channel::bounded::<ControlResult>(1);
         let poll = mio::Poll::new()?;
         let waker = mio::Waker::new(poll.registry(), mio::Token(0))?;
+        let counters = Arc::new(SharedLinkCounters::default());
+        let thread_counters = counters.clone();
+        let (closed_sender, closed) = crossbeam::channel::bounded::<()>(0);
         thread::spawn(move || {
+            let _closed = closed_sender;
             // REVISIT
             // If anything panics in this thread and it causes unwinding, this
             // closure terminates and the channels are closed.
@@ -460,13 +898,25 @@ impl Port {
             // to the thread method, and retain ownership to manually drop.
             // Since the issue is minor, it is left unaddressed, with the hope that
             // the windows implementation of mio_serial will fix this eventually.
-            Port::poller_thread(raw_port, poll, rx, ttx, ctl_ret_sender);
+            Port::poller_thread(
+                raw_port,
+                poll,
+                rx,
+                ttx,
+                ctl_ret_sender,
+                thread_counters,
+                thread_parse_mode,
+            );
         });
         io::Result::Ok(Port {
             tx: Some(Box::new(tx)),
             ctl_result: ctl_ret_receiver,
             waker: waker,
             rates: rates,
+            counters,
+            raw_tap,
+            parse_mode,
+            closed,
         })
     }
 
@@ -474,63 +924,139 @@ impl Port {
     /// data or errors to `rx`.
     ///
     /// A valid 'url' has one of the following formats:
-    /// - `serial://port[:target_bps[:default_bps]]`. `target_bps` and `default_bps`
+    /// - `serial://port[:target_bps[:default_bps]][?options]`. `target_bps` and `default_bps`
     ///   are optional and default to 115200. Note that it's possible to omit `serial://`
-    ///   if port starts with `COM` on windows or `/dev/` on unix.
+    ///   if port is like `COM12` or `\\.\COM12` on windows, or starts with `/dev/`
+    ///   on unix. `options` can override
+    ///   the serial line parameters, e.g. `serial:///dev/ttyUSB0?baud=921600&flow=rtscts`
+    ///   (see `serial::Port::new` for the full list).
     /// - `tcp://address[:port]`. Note also that it's possible to use `tcp4` or `tcp6`
     ///   to force a specific version of the IP protocol should the default resolution
     ///   fail.
     /// - `udp://address[:port]`. Note as for TCP there are also `udp4` and `udp6`
+    /// - `sim://[name][?options]`, a simulated device running in the process, for
+    ///   testing without hardware (see `SimConfig::parse` for the options).
+    /// - `mock://name`, the `MockDevice` registered as `name`, for deterministic tests.
+    /// - `pipe://name`, the local named pipe `\\.\pipe\name`, on windows only. The
+    ///   pipe path can also be given directly.
+    /// - `mdns://name`, the TIO server advertised over mDNS for the device with this
+    ///   name or serial number (see `mdns::resolve`), connected to over TCP.
+    /// - `serial-number://id[?options]`, the device with this serial number or name
+    ///   among the USB serial ports, found by asking each for its identity (see
+    ///   `find_device`), with the `options` of `serial://`. The search is done again
+    ///   when the proxy reconnects, so the device is found even if the order of the
+    ///   ports changed.
     ///
     /// The RX callback is called from the thread with the result of a `recv` operation
     /// on the underlying raw port. If it returns an `Err()`, the port is closed.
     ///
     /// The most common use for a `Port` is to receive on a channel, see `rx_to_channel_cb`.
+    ///
+    /// The reason the port could not be opened is sorted by `ConnectError::new`.
+    ///
+    /// If the `TIO_FAULTS` environment variable is set, faults are injected on
+    /// the port as configured there, see `new_with_faults`.
     pub fn new<RXT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static>(
         url: &str,
         rx: RXT,
     ) -> io::Result<Port> {
-        // Special case: serial ports can be given directly
-        #[cfg(unix)]
-        if url.starts_with("/dev/") {
-            return Port::from_raw(serial::Port::new(url)?, rx);
+        Port::new_with_faults(url, rx, FaultConfig::from_env()?)
+    }
+
+    /// Same as `new`, but injecting the given faults on the port (if any),
+    /// regardless of the environment. Intended for soak testing against
+    /// degraded links.
+    pub fn new_with_faults<
+        RXT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static,
+    >(
+        url: &str,
+        rx: RXT,
+        faults: Option<FaultConfig>,
+    ) -> io::Result<Port> {
+        // Special case: serial ports and pipes can be given directly
+        if is_serial_port(url) {
+            return Port::from_raw(serial::Port::new(url)?, rx, faults);
         }
         #[cfg(windows)]
-        if url.starts_with("COM") {
-            return Port::from_raw(serial::Port::new(url)?, rx);
+        if url.starts_with(r"\\.\pipe\") {
+            return Port::from_raw(pipe::connect(url)?, rx, faults);
         }
 
         let split_url: Vec<&str> = url.splitn(2, "://").collect();
         match split_url[..] {
-            ["serial", port] => Port::from_raw(serial::Port::new(port)?, rx),
+            ["serial", port] => Port::from_raw(serial::Port::new(port)?, rx, faults),
             ["tcp", addr] => Port::from_raw(
                 tcp::Port::new(&find_addr(addr, AddrFamilyRestrict::Either)?)?,
                 rx,
+                faults,
             ),
             ["udp", addr] => Port::from_raw(
                 udp::Port::new(&find_addr(addr, AddrFamilyRestrict::Either)?)?,
                 rx,
+                faults,
             ),
             ["tcp4", addr] => Port::from_raw(
                 tcp::Port::new(&find_addr(addr, AddrFamilyRestrict::V4)?)?,
                 rx,
+                faults,
             ),
             ["udp4", addr] => Port::from_raw(
                 udp::Port::new(&find_addr(addr, AddrFamilyRestrict::V4)?)?,
                 rx,
+                faults,
             ),
             ["tcp6", addr] => Port::from_raw(
                 tcp::Port::new(&find_addr(addr, AddrFamilyRestrict::V6)?)?,
                 rx,
+                faults,
             ),
             ["udp6", addr] => Port::from_raw(
                 udp::Port::new(&find_addr(addr, AddrFamilyRestrict::V6)?)?,
                 rx,
+                faults,
             ),
+            ["mdns", name] => {
+                let service = mdns::resolve(name, MDNS_RESOLVE_TIMEOUT)?;
+                Port::from_raw(
+                    tcp::Port::new(&SocketAddr::new(service.addr, service.port))?,
+                    rx,
+                    faults,
+                )
+            }
+            ["serial-number", spec] => {
+                let (id, options) = match spec.split_once('?') {
+                    Some((id, options)) => (id, format!("?{}", options)),
+                    None => (spec, String::new()),
+                };
+                let url = find_device(id, &usb_serial_ports(), IDENTIFY_TIMEOUT)?;
+                Port::new_with_faults(&format!("{}{}", url, options), rx, faults)
+            }
+            ["sim", spec] => Port::from_raw(sim::Port::new(SimConfig::parse(spec)?)?, rx, faults),
+            ["mock", name] => Port::from_raw(mock::Port::new(name)?, rx, faults),
+            #[cfg(windows)]
+            ["pipe", name] => Port::from_raw(pipe::connect(name)?, rx, faults),
+            #[cfg(not(windows))]
+            ["pipe", _] => io::Result::Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "named pipes are only supported on windows",
+            )),
+            [scheme, _] => io::Result::Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported url scheme {}", scheme),
+            )),
             _ => io::Result::Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid url")),
         }
     }
 
+    /// Create a new port connected to a simulated device with the given
+    /// configuration. See `new()`.
+    pub fn from_sim<RXT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static>(
+        config: SimConfig,
+        rx: RXT,
+    ) -> io::Result<Port> {
+        Port::from_raw(sim::Port::new(config)?, rx, None)
+    }
+
     /// Create a new port from a `mio::net::TcpStream`. See `new()`.
     pub fn from_mio_stream<
         RXT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static,
@@ -538,7 +1064,7 @@ impl Port {
         stream: mio::net::TcpStream,
         rx: RXT,
     ) -> io::Result<Port> {
-        Port::from_raw(tcp::Port::from_stream(stream)?, rx)
+        Port::from_raw(tcp::Port::from_stream(stream)?, rx, None)
     }
 
     /// Create a new port from a `std::net::TcpStream`. See `new()`.
@@ -553,8 +1079,10 @@ impl Port {
     }
 
     /// Creates a sender/receiver pair to be used with `rx_to_channel`:
-    /// ```
-    /// let (port_rx_send, port_rx) = rx_channels();
+    /// ```no_run
+    /// # use twinleaf::tio::port::Port;
+    /// # let url = "serial:///dev/ttyUSB0";
+    /// let (port_rx_send, port_rx) = Port::rx_channel();
     /// let port = Port::new(url, Port::rx_to_channel(port_rx_send)).unwrap();
     /// ```
     /// In the example, `port.send()` can now be used to send and `port_rx.recv()`
@@ -617,7 +1145,7 @@ impl Port {
     /// block if the port is backed up.
     pub fn send(&self, packet: Packet) -> Result<(), SendError> {
         let tx = self.tx.as_ref().expect("Tx channel invalid");
-        if let Err(_) = tx.send(PacketOrControl::Pkt(packet)) {
+        if let Err(_) = tx.send(PacketOrControl::Pkt(Box::new(packet))) {
             Err(SendError::Disconnected)
         } else if let Err(_) = self.waker.wake() {
             panic!("Wake failed");
@@ -626,11 +1154,37 @@ impl Port {
         }
     }
 
+    /// Sends several TIO packets to this port synchronously, waking up the
+    /// port thread once rather than for every packet, so that it can write
+    /// them out together. This call will block if the port is backed up.
+    pub fn send_all(&self, packets: &[Packet]) -> Result<(), SendError> {
+        use crossbeam::channel::TrySendError;
+        let tx = self.tx.as_ref().expect("Tx channel invalid");
+        for packet in packets {
+            let item = match tx.try_send(PacketOrControl::Pkt(Box::new(packet.clone()))) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(item)) => item,
+                Err(TrySendError::Disconnected(_)) => return Err(SendError::Disconnected),
+            };
+            // Let the port thread catch up before queueing more.
+            if self.waker.wake().is_err() {
+                panic!("Wake failed");
+            }
+            if tx.send(item).is_err() {
+                return Err(SendError::Disconnected);
+            }
+        }
+        if self.waker.wake().is_err() {
+            panic!("Wake failed");
+        }
+        Ok(())
+    }
+
     /// Attempts to send a TIO packet to this port without blocking.
     pub fn try_send(&self, packet: Packet) -> Result<(), SendError> {
         use crossbeam::channel::TrySendError;
         let tx = self.tx.as_ref().expect("Tx channel invalid");
-        match tx.try_send(PacketOrControl::Pkt(packet)) {
+        match tx.try_send(PacketOrControl::Pkt(Box::new(packet))) {
             Ok(()) => {
                 if let Err(_) = self.waker.wake() {
                     panic!("Wake failed");
@@ -643,6 +1197,30 @@ impl Port {
         }
     }
 
+    /// Counts of packets and errors received so far.
+    pub fn link_counters(&self) -> LinkCounters {
+        self.counters.snapshot()
+    }
+
+    /// Send a copy of the bytes received by the port to `tap` as they are
+    /// read, before any framing or checksum, to see exactly what came off
+    /// the wire, for example when diagnosing a rate mismatch. Only ports
+    /// reading from a byte stream, such as serial and TCP, support this.
+    /// Data is dropped if `tap` is full, and the tap is removed when its
+    /// receiver is dropped, or by passing `None`.
+    pub fn set_raw_tap(&self, tap: Option<crossbeam::channel::Sender<Vec<u8>>>) {
+        *self.raw_tap.0.lock().unwrap() = tap;
+    }
+
+    /// Parse the data received from now on leniently (the default) or
+    /// strictly, see `ParseMode`. Only ports reading from a byte stream, such
+    /// as serial and TCP, have framing to check.
+    pub fn set_parse_mode(&self, mode: ParseMode) {
+        self.parse_mode
+            .0
+            .store(mode == ParseMode::Strict, Ordering::Relaxed);
+    }
+
     /// Get data rate information for the underlying raw port (if supported).
     pub fn rate_info(&self) -> Option<RateInfo> {
         self.rates.clone()
@@ -662,3 +1240,16 @@ impl Port {
         }
     }
 }
+
+impl Drop for Port {
+    /// Have the port thread send what is queued and close the raw port,
+    /// waiting for it briefly, so that the device can be opened again
+    /// right away, as when reconnecting. A device which does not accept
+    /// data can keep it open longer.
+    fn drop(&mut self) {
+        self.tx = None;
+        if self.waker.wake().is_ok() {
+            let _ = self.closed.recv_timeout(CLOSE_TIMEOUT);
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! Fault injection
+//!
+//! Degrades a real transport in a controlled way, to soak test applications,
+//! the proxy's error handling, and rate autonegotiation against bad links
+//! without special builds. Faults are applied by a `FaultyPort` wrapping the
+//! raw port, plus a delivery thread for latency. They are configured with a
+//! comma separated list of `key=value` settings, typically given in the
+//! `TIO_FAULTS` environment variable:
+//!
+//! - `loss=<fraction>`: probability of dropping each packet, in both directions.
+//! - `dup=<fraction>`: probability of duplicating each packet, in both directions.
+//! - `corrupt=<fraction>`: probability of corrupting each packet, in both
+//!   directions. Corrupted packets are reported as CRC errors, as a serial
+//!   port would; outgoing ones are lost, as the device would discard them.
+//! - `ber=<fraction>`: probability of flipping each bit of a received packet.
+//! - `latency=<ms>`: delay added to each received packet.
+//! - `stall=<fraction>`: probability of the link stalling before each packet,
+//!   in both directions, holding up all traffic for `stall_ms`.
+//! - `stall_ms=<ms>`: duration of stalls, 100 ms by default.
+//! - `seed=<u64>`: seed for the pseudo random generator, for reproducibility.
+//!
+//! For example `TIO_FAULTS=loss=0.01,latency=50,ber=1e-6,dup=0.001`.
+
+use super::{ParseModeFlag, RateError, RateInfo, RawPort, RawTap, RecvError, SendError};
+use crate::tio::proto::{self, Packet};
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the fault configuration for all new ports.
+pub static FAULTS_ENV_VAR: &str = "TIO_FAULTS";
+
+/// Faults to inject on a port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultConfig {
+    /// Probability of dropping a packet.
+    pub loss: f64,
+    /// Probability of duplicating a packet.
+    pub duplicate: f64,
+    /// Probability of corrupting a packet.
+    pub corrupt: f64,
+    /// Delay added to received packets.
+    pub latency: Duration,
+    /// Bit error rate of received packets.
+    pub bit_error_rate: f64,
+    /// Probability of the link stalling before a packet.
+    pub stall: f64,
+    /// Duration of a stall.
+    pub stall_duration: Duration,
+    /// Seed for the pseudo random generator; derived from the clock if None.
+    pub seed: Option<u64>,
+}
+
+fn invalid_config(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn parse_probability(key: &str, value: &str) -> io::Result<f64> {
+    match value.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(invalid_config(format!("invalid {} '{}'", key, value))),
+    }
+}
+
+fn parse_ms(key: &str, value: &str) -> io::Result<Duration> {
+    match value.parse::<u64>() {
+        Ok(ms) => Ok(Duration::from_millis(ms)),
+        _ => Err(invalid_config(format!("invalid {} '{}'", key, value))),
+    }
+}
+
+impl FaultConfig {
+    /// Parse a configuration such as `loss=0.01,latency=50`.
+    pub fn parse(config: &str) -> io::Result<FaultConfig> {
+        let mut ret = FaultConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            latency: Duration::ZERO,
+            bit_error_rate: 0.0,
+            stall: 0.0,
+            stall_duration: Duration::from_millis(100),
+            seed: None,
+        };
+        for setting in config.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| invalid_config(format!("invalid fault setting '{}'", setting)))?;
+            match key {
+                "loss" => ret.loss = parse_probability(key, value)?,
+                "dup" => ret.duplicate = parse_probability(key, value)?,
+                "corrupt" => ret.corrupt = parse_probability(key, value)?,
+                "ber" => ret.bit_error_rate = parse_probability(key, value)?,
+                "stall" => ret.stall = parse_probability(key, value)?,
+                "latency" => ret.latency = parse_ms(key, value)?,
+                "stall_ms" => ret.stall_duration = parse_ms(key, value)?,
+                "seed" => {
+                    let seed = value
+                        .parse::<u64>()
+                        .map_err(|_| invalid_config(format!("invalid seed '{}'", value)))?;
+                    ret.seed = Some(seed);
+                }
+                _ => {
+                    return Err(invalid_config(format!("unknown fault setting '{}'", key)));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Configuration from the `TIO_FAULTS` environment variable, if set.
+    pub fn from_env() -> io::Result<Option<FaultConfig>> {
+        match std::env::var(FAULTS_ENV_VAR) {
+            Ok(config) => Ok(Some(FaultConfig::parse(&config)?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Small xorshift generator; statistical quality is not a concern here.
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub(super) fn new(seed: Option<u64>) -> Rng {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+        // Zero is a fixed point of xorshift.
+        Rng(seed | 1)
+    }
+
+    pub(super) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1).
+    pub(super) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(super) fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+pub(super) type RxCallback =
+    Box<dyn Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static>;
+
+/// Wraps a `RawPort`, applying the packet faults of a `FaultConfig` to the
+/// traffic going through it. Stalls block the port thread, as a hung link
+/// would.
+pub(super) struct FaultyPort<P: RawPort> {
+    inner: P,
+    config: FaultConfig,
+    rng: Rng,
+    /// Duplicate of the last received packet, returned on the next `recv`.
+    duplicate: Option<Packet>,
+}
+
+impl<P: RawPort> FaultyPort<P> {
+    pub fn new(inner: P, config: FaultConfig) -> FaultyPort<P> {
+        let rng = Rng::new(config.seed);
+        FaultyPort {
+            inner,
+            config,
+            rng,
+            duplicate: None,
+        }
+    }
+
+    fn maybe_stall(&mut self) {
+        if self.rng.chance(self.config.stall) {
+            thread::sleep(self.config.stall_duration);
+        }
+    }
+
+    /// Flip bits of a packet according to the bit error rate, or one random
+    /// bit with the corruption probability. A corrupted packet turns into the
+    /// CRC error the receiver would have seen.
+    fn corrupt(&mut self, pkt: Packet) -> Result<Packet, RecvError> {
+        let ber = self.config.bit_error_rate;
+        let whole = self.rng.chance(self.config.corrupt);
+        if ber <= 0.0 && !whole {
+            return Ok(pkt);
+        }
+        let mut raw = match pkt.serialize() {
+            Ok(raw) => raw,
+            Err(()) => return Ok(pkt),
+        };
+        let n_bits = raw.len() * 8;
+        let mut corrupted = false;
+        if whole {
+            let bit = (self.rng.next_u64() % n_bits as u64) as usize;
+            raw[bit / 8] ^= 1 << (bit % 8);
+            corrupted = true;
+        }
+        // Jump from one error to the next with geometrically distributed
+        // gaps, rather than drawing a number for every bit.
+        let mut bit = 0usize;
+        while ber > 0.0 && bit < n_bits {
+            let gap = if ber >= 1.0 {
+                0.0
+            } else {
+                ((1.0 - self.rng.next_f64()).ln() / (1.0 - ber).ln()).floor()
+            };
+            if gap >= (n_bits - bit) as f64 {
+                break;
+            }
+            bit += gap as usize;
+            raw[bit / 8] ^= 1 << (bit % 8);
+            corrupted = true;
+            bit += 1;
+        }
+        if corrupted {
+            Err(RecvError::Protocol(proto::Error::CRC32(raw)))
+        } else {
+            Ok(pkt)
+        }
+    }
+}
+
+impl<P: RawPort> RawPort for FaultyPort<P> {
+    fn recv(&mut self) -> Result<Packet, RecvError> {
+        if let Some(pkt) = self.duplicate.take() {
+            return Ok(pkt);
+        }
+        loop {
+            let pkt = self.inner.recv()?;
+            self.maybe_stall();
+            if self.rng.chance(self.config.loss) {
+                continue;
+            }
+            let pkt = self.corrupt(pkt)?;
+            if self.rng.chance(self.config.duplicate) {
+                self.duplicate = Some(pkt.clone());
+            }
+            return Ok(pkt);
+        }
+    }
+
+    fn send(&mut self, pkt: &Packet) -> Result<(), SendError> {
+        self.maybe_stall();
+        if self.rng.chance(self.config.loss) || self.rng.chance(self.config.corrupt) {
+            return Ok(());
+        }
+        self.inner.send(pkt)?;
+        if self.rng.chance(self.config.duplicate) {
+            // Only duplicate if it can go out right away.
+            let _ = self.inner.send(pkt);
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), SendError> {
+        self.inner.drain()
+    }
+
+    fn has_data_to_drain(&self) -> bool {
+        self.inner.has_data_to_drain()
+    }
+
+    fn set_rate(&mut self, rate: u32) -> Result<(), RateError> {
+        self.inner.set_rate(rate)
+    }
+
+    fn set_raw_tap(&mut self, tap: RawTap) {
+        self.inner.set_raw_tap(tap);
+    }
+
+    fn set_parse_mode(&mut self, mode: ParseModeFlag) {
+        self.inner.set_parse_mode(mode);
+    }
+
+    fn rate_info(&self) -> Option<RateInfo> {
+        self.inner.rate_info()
+    }
+
+    fn max_send_interval(&self) -> Option<Duration> {
+        self.inner.max_send_interval()
+    }
+
+    fn startup_holdoff(&self) -> bool {
+        self.inner.startup_holdoff()
+    }
+}
+
+impl<P: RawPort + mio::event::Source> mio::event::Source for FaultyPort<P> {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+/// Wrap a port receive callback, delivering results after `latency`.
+pub(super) fn delay_rx<RXT: Fn(Result<Packet, RecvError>) -> io::Result<()> + Send + 'static>(
+    latency: Duration,
+    rx: RXT,
+) -> RxCallback {
+    if latency.is_zero() {
+        return Box::new(rx);
+    }
+
+    // Delayed results are delivered by a separate thread, which owns the
+    // callback. Its failure is reported back on the next packet.
+    let (delay_tx, delay_rx) =
+        crossbeam::channel::unbounded::<(Instant, Result<Packet, RecvError>)>();
+    let failed = Arc::new(AtomicBool::new(false));
+    let delivery_failed = failed.clone();
+    thread::spawn(move || {
+        for (deliver_at, mut res) in delay_rx.iter() {
+            thread::sleep(deliver_at.saturating_duration_since(Instant::now()));
+            // As if the packet had just been read, after the latency.
+            if let Ok(pkt) = &mut res {
+                pkt.received = Some(proto::ReceiveTime::now());
+            }
+            if rx(res).is_err() {
+                delivery_failed.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+    });
+    Box::new(move |res| {
+        if failed.load(Ordering::Relaxed) {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        delay_tx
+            .send((Instant::now() + latency, res))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    })
+}
@@ -4,7 +4,7 @@ use super::{RecvError, SendError};
 use std::io;
 
 /// Size of the internal buffer.
-const IOBUF_SIZE: usize = 4096;
+pub const IOBUF_SIZE: usize = 4096;
 
 /// Buffer used internally by ports with an underlying byte stream
 /// to implement packetization for both reception and transmission.
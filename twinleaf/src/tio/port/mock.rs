@@ -0,0 +1,460 @@
+//! Mock device
+//!
+//! Implements a `RawPort` connected to a device driven by the code using
+//! it, for deterministic tests of the proxy and applications: unlike the
+//! simulated device, it sends nothing on its own. A `MockDevice` answers
+//! RPCs from a table, for any route, so that it can stand in for a tree of
+//! devices, and sends the packets and drops the connection when told to,
+//! directly or by playing a script of `MockStep`s.
+//!
+//! A mock device is registered under a name, and `mock://name` urls open
+//! a new connection to it, over an in-memory socket pair on unix (loopback
+//! TCP elsewhere), so that the proxy reconnects to it as to a real device:
+//! ```no_run
+//! # use twinleaf::tio::{port::{MockDevice, MockStep}, proto::DeviceRoute, proxy};
+//! # use std::time::Duration;
+//! let mock = MockDevice::new("test").rpc(&DeviceRoute::root(), "dev.name", b"test");
+//! let proxy = proxy::Interface::builder()
+//!     .url("mock://test")
+//!     .reconnect(Duration::from_secs(5))
+//!     .spawn();
+//! assert!(mock.wait_connections(1, Duration::from_secs(1)));
+//! mock.play(&[MockStep::Disconnect]).unwrap();
+//! assert!(mock.wait_connections(2, Duration::from_secs(5)));
+//! let name: String = proxy.rpc(DeviceRoute::root(), "dev.name", ()).unwrap();
+//! ```
+
+use super::{tcp, Packet, ParseModeFlag, RateError, RateInfo, RawPort, RecvError, SendError};
+use crate::tio::proto::{self, DeviceRoute, Payload, RpcErrorCode, RpcMethod};
+use crate::tio::util::PacketBuilder;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+
+#[cfg(unix)]
+type Stream = mio::net::UnixStream;
+#[cfg(unix)]
+type DeviceStream = std::os::unix::net::UnixStream;
+#[cfg(not(unix))]
+type Stream = mio::net::TcpStream;
+#[cfg(not(unix))]
+type DeviceStream = std::net::TcpStream;
+
+/// Connected streams for the port and the device.
+#[cfg(unix)]
+fn stream_pair() -> io::Result<(Stream, DeviceStream)> {
+    let (port, device) = DeviceStream::pair()?;
+    port.set_nonblocking(true)?;
+    Ok((Stream::from_std(port), device))
+}
+
+#[cfg(not(unix))]
+fn stream_pair() -> io::Result<(Stream, DeviceStream)> {
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+    let port = DeviceStream::connect(listener.local_addr()?)?;
+    let (device, _) = listener.accept()?;
+    port.set_nonblocking(true)?;
+    Ok((Stream::from_std(port), device))
+}
+
+/// How the mock device answers an RPC.
+#[derive(Debug, Clone)]
+pub enum MockReply {
+    Value(Vec<u8>),
+    Error(RpcErrorCode),
+    /// No answer at all, so that the request times out.
+    Silent,
+}
+
+/// Action of a mock device script, see `MockDevice::play`.
+#[derive(Debug, Clone)]
+pub enum MockStep {
+    /// Send a packet to the port.
+    Send(Packet),
+    Wait(Duration),
+    /// Wait until the device receives an RPC request with this name, or
+    /// fail after this long.
+    WaitRpc(String, Duration),
+    /// Close the connection, as if the device was unplugged and plugged
+    /// back in: the port reports a disconnection, and may connect again.
+    Disconnect,
+    /// Refuse new connections as `NotFound` while unplugged, or accept
+    /// them again.
+    Unplug(bool),
+}
+
+struct MockState {
+    rpcs: BTreeMap<(DeviceRoute, String), MockReply>,
+    unplugged: bool,
+    /// Current connection to the port, if any.
+    writer: Option<DeviceStream>,
+    connections: usize,
+    /// RPC requests received, by name.
+    rpc_counts: BTreeMap<String, usize>,
+    /// Rates the port was set to.
+    rates: Vec<u32>,
+}
+
+struct Shared {
+    name: String,
+    rate_info: Option<RateInfo>,
+    state: Mutex<MockState>,
+    changed: Condvar,
+    received: channel::Sender<Packet>,
+}
+
+static MOCK_DEVICES: Mutex<BTreeMap<String, Arc<Shared>>> = Mutex::new(BTreeMap::new());
+
+/// Device driven by the code holding it, reached with `mock://name` urls
+/// until it is dropped.
+pub struct MockDevice {
+    shared: Arc<Shared>,
+    received: channel::Receiver<Packet>,
+}
+
+impl MockDevice {
+    /// Register a mock device as `name`, replacing any mock of the same name.
+    pub fn new(name: &str) -> MockDevice {
+        MockDevice::with_rates(name, None)
+    }
+
+    /// Same as `new`, for a port which supports setting its rate, like a
+    /// serial port, so that the proxy negotiates the rate with the device
+    /// once the device sends it a session heartbeat. The rate itself is only
+    /// recorded, see `rates`.
+    pub fn with_rates(name: &str, rate_info: Option<RateInfo>) -> MockDevice {
+        let (tx, received) = channel::unbounded();
+        let shared = Arc::new(Shared {
+            name: name.to_string(),
+            rate_info,
+            state: Mutex::new(MockState {
+                rpcs: BTreeMap::new(),
+                unplugged: false,
+                writer: None,
+                connections: 0,
+                rpc_counts: BTreeMap::new(),
+                rates: vec![],
+            }),
+            changed: Condvar::new(),
+            received: tx,
+        });
+        MOCK_DEVICES
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), shared.clone());
+        MockDevice { shared, received }
+    }
+
+    /// Answer the RPC `name` of the device at `route` with `value`.
+    pub fn rpc(self, route: &DeviceRoute, name: &str, value: &[u8]) -> MockDevice {
+        self.set_rpc(route, name, MockReply::Value(value.to_vec()));
+        self
+    }
+
+    /// Change how the RPC `name` of the device at `route` is answered.
+    /// RPCs not in the table get a `NotFound` error.
+    pub fn set_rpc(&self, route: &DeviceRoute, name: &str, reply: MockReply) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.rpcs.insert((route.clone(), name.to_string()), reply);
+    }
+
+    /// Send a packet to the port, if connected.
+    pub fn send(&self, pkt: &Packet) -> io::Result<()> {
+        let raw = pkt
+            .serialize()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid packet"))?;
+        let mut state = self.shared.state.lock().unwrap();
+        match &mut state.writer {
+            Some(writer) => writer.write_all(&raw),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Close the current connection, if any.
+    pub fn disconnect(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(writer) = state.writer.take() {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Refuse new connections, or accept them again.
+    pub fn unplug(&self, unplugged: bool) {
+        self.shared.state.lock().unwrap().unplugged = unplugged;
+    }
+
+    /// Take the actions of a script in turn.
+    pub fn play(&self, steps: &[MockStep]) -> io::Result<()> {
+        for step in steps {
+            match step {
+                MockStep::Send(pkt) => self.send(pkt)?,
+                MockStep::Wait(duration) => thread::sleep(*duration),
+                MockStep::WaitRpc(name, timeout) => {
+                    let seen = self.rpc_count(name);
+                    if !self.wait(*timeout, |state| {
+                        state.rpc_counts.get(name).copied().unwrap_or(0) > seen
+                    }) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("no {} request received", name),
+                        ));
+                    }
+                }
+                MockStep::Disconnect => self.disconnect(),
+                MockStep::Unplug(unplugged) => self.unplug(*unplugged),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until `done` holds for the state, for at most `timeout`.
+    fn wait(&self, timeout: Duration, done: impl Fn(&MockState) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        while !done(&state) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            state = self.shared.changed.wait_timeout(state, left).unwrap().0;
+        }
+        true
+    }
+
+    /// Wait until the device was connected to `n` times in total, for at
+    /// most `timeout`.
+    pub fn wait_connections(&self, n: usize, timeout: Duration) -> bool {
+        self.wait(timeout, |state| state.connections >= n)
+    }
+
+    /// Number of times the device was connected to.
+    pub fn connections(&self) -> usize {
+        self.shared.state.lock().unwrap().connections
+    }
+
+    /// Whether a port is connected to the device.
+    pub fn connected(&self) -> bool {
+        self.shared.state.lock().unwrap().writer.is_some()
+    }
+
+    /// Number of RPC requests named `name` received.
+    pub fn rpc_count(&self, name: &str) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.rpc_counts.get(name).copied().unwrap_or(0)
+    }
+
+    /// Rates the port was set to, in order.
+    pub fn rates(&self) -> Vec<u32> {
+        self.shared.state.lock().unwrap().rates.clone()
+    }
+
+    /// Every packet received by the device, including the RPC requests.
+    pub fn received(&self) -> &channel::Receiver<Packet> {
+        &self.received
+    }
+}
+
+impl Drop for MockDevice {
+    /// Unregister the device and close its connection.
+    fn drop(&mut self) {
+        let mut devices = MOCK_DEVICES.lock().unwrap();
+        if devices
+            .get(&self.shared.name)
+            .is_some_and(|shared| Arc::ptr_eq(shared, &self.shared))
+        {
+            devices.remove(&self.shared.name);
+        }
+        drop(devices);
+        self.disconnect();
+    }
+}
+
+impl Shared {
+    fn reply(&self, pkt: &Packet) -> Option<Packet> {
+        let Payload::RpcRequest(req) = &pkt.payload else {
+            return None;
+        };
+        let mut state = self.state.lock().unwrap();
+        let name = match &req.method {
+            RpcMethod::Name(name) => name.clone(),
+            RpcMethod::Id(id) => format!("#{}", id),
+        };
+        *state.rpc_counts.entry(name.clone()).or_insert(0) += 1;
+        self.changed.notify_all();
+        let reply = match state.rpcs.get(&(pkt.routing.clone(), name)) {
+            Some(MockReply::Value(value)) => value.clone(),
+            Some(MockReply::Error(code)) => {
+                return Some(PacketBuilder::make_rpc_error(
+                    req.id,
+                    *code,
+                    pkt.routing.clone(),
+                ))
+            }
+            Some(MockReply::Silent) => return None,
+            None => {
+                return Some(PacketBuilder::make_rpc_error(
+                    req.id,
+                    RpcErrorCode::NotFound,
+                    pkt.routing.clone(),
+                ))
+            }
+        };
+        Some(Packet {
+            payload: Payload::RpcReply(proto::RpcReplyPayload { id: req.id, reply }),
+            routing: pkt.routing.clone(),
+            ttl: 0,
+            received: None,
+        })
+    }
+
+    /// Receive packets from the port until it disconnects, answering RPCs.
+    /// `connection` is the number of the connection.
+    fn serve(&self, mut stream: DeviceStream, connection: usize) {
+        let mut rxbuf: Vec<u8> = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => rxbuf.extend(&buf[..n]),
+            }
+            loop {
+                match Packet::deserialize(&rxbuf) {
+                    Ok((pkt, size)) => {
+                        rxbuf.drain(..size);
+                        let reply = self.reply(&pkt).and_then(|reply| reply.serialize().ok());
+                        let _ = self.received.send(pkt);
+                        if let Some(raw) = reply {
+                            // Holding the lock, so as not to interleave
+                            // with the packets sent by `MockDevice::send`.
+                            let _state = self.state.lock().unwrap();
+                            if stream.write_all(&raw).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(proto::Error::NeedMore) => break,
+                    Err(_) => {
+                        rxbuf.clear();
+                        break;
+                    }
+                }
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.connections == connection {
+            state.writer = None;
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// RawPort to communicate with a mock device.
+pub struct Port {
+    inner: tcp::Port<Stream>,
+    shared: Arc<Shared>,
+}
+
+impl Port {
+    /// Connect to the mock device registered as `name`.
+    pub fn new(name: &str) -> io::Result<Port> {
+        let shared = MOCK_DEVICES.lock().unwrap().get(name).cloned();
+        let Some(shared) = shared else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no mock device {}", name),
+            ));
+        };
+        let (stream, device) = stream_pair()?;
+        let connection = {
+            let mut state = shared.state.lock().unwrap();
+            if state.unplugged {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("mock device {} unplugged", name),
+                ));
+            }
+            if let Some(old) = state.writer.replace(device.try_clone()?) {
+                let _ = old.shutdown(Shutdown::Both);
+            }
+            state.connections += 1;
+            shared.changed.notify_all();
+            state.connections
+        };
+        let device_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("mock:{}", name))
+            .spawn(move || device_shared.serve(device, connection))?;
+        Ok(Port {
+            inner: tcp::Port::from_stream(stream)?,
+            shared,
+        })
+    }
+}
+
+impl RawPort for Port {
+    fn recv(&mut self) -> Result<Packet, RecvError> {
+        self.inner.recv()
+    }
+
+    fn send(&mut self, pkt: &Packet) -> Result<(), SendError> {
+        self.inner.send(pkt)
+    }
+
+    fn send_batch(&mut self, pkts: &[Packet]) -> (usize, Result<(), SendError>) {
+        self.inner.send_batch(pkts)
+    }
+
+    fn drain(&mut self) -> Result<(), SendError> {
+        self.inner.drain()
+    }
+
+    fn has_data_to_drain(&self) -> bool {
+        self.inner.has_data_to_drain()
+    }
+
+    fn set_parse_mode(&mut self, mode: ParseModeFlag) {
+        self.inner.set_parse_mode(mode);
+    }
+
+    fn set_rate(&mut self, rate: u32) -> Result<(), RateError> {
+        if self.shared.rate_info.is_none() {
+            return Err(RateError::Unsupported);
+        }
+        self.shared.state.lock().unwrap().rates.push(rate);
+        Ok(())
+    }
+
+    fn rate_info(&self) -> Option<RateInfo> {
+        self.shared.rate_info.clone()
+    }
+}
+
+impl mio::event::Source for Port {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
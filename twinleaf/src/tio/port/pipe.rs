@@ -0,0 +1,34 @@
+//! Named Pipe Port
+//!
+//! Implements a `RawPort` for a Windows named pipe, to talk to a local
+//! process serving TIO packets on `\\.\pipe\name` without going through
+//! the network stack. Packets are framed as on a TCP stream.
+
+use super::tcp;
+use mio::windows::NamedPipe;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
+
+/// RawPort to communicate via the client end of a named pipe
+pub type Port = tcp::Port<NamedPipe>;
+
+/// Connects to the named pipe `name`, which is either a full pipe path
+/// like `\\.\pipe\tio` or just the name of a local pipe like `tio`.
+pub fn connect(name: &str) -> io::Result<Port> {
+    let path = if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\pipe\{}", name)
+    };
+    // mio requires the handle to be opened for overlapped I/O.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(FILE_FLAG_OVERLAPPED)
+        .open(path)?;
+    let pipe = unsafe { NamedPipe::from_raw_handle(file.into_raw_handle()) };
+    tcp::Port::from_stream(pipe)
+}
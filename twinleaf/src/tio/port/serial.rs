@@ -7,7 +7,11 @@
 //! delimited, plain text ascii, which is returned as a
 //! `RecvError::Protocol(proto::Error::Text(textual_data))`
 
-use super::{iobuf::IOBuf, proto, Packet, RateError, RateInfo, RawPort, RecvError, SendError};
+use super::iobuf::{IOBuf, IOBUF_SIZE};
+use super::{
+    proto, Packet, ParseModeFlag, PortBusy, RateError, RateInfo, RawPort, RawTap, RecvError,
+    SendError,
+};
 use crc::{Crc, CRC_32_ISO_HDLC};
 use mio_serial::{SerialPort, SerialPortBuilderExt};
 use std::io;
@@ -34,6 +38,13 @@ pub struct Port {
     /// If true, the next data received will be the first data and
     /// should be discarded since it's usually corrupt/stale.
     first_rx: bool,
+    /// Where to send a copy of the received bytes.
+    raw_tap: RawTap,
+    /// How to deal with framing errors.
+    parse_mode: ParseModeFlag,
+    /// Holds the advisory lock on the port while it is open.
+    #[cfg(unix)]
+    _lock: std::fs::File,
 }
 
 /// Default data rate on the serial port.
@@ -42,9 +53,222 @@ static DEFAULT_RATE: u32 = 115200;
 /// Discard anything for this long after the port is opened.
 static HOLDOFF_TIME: Duration = Duration::from_millis(50);
 
+/// True if `err` means that the port went away, as when a USB serial adapter
+/// is unplugged while open. The OS reports this with various errors rather
+/// than the end of the stream.
+fn is_removal(err: &io::Error) -> bool {
+    let codes: &[i32] = if cfg!(target_os = "macos") {
+        // ENXIO
+        &[6]
+    } else if cfg!(windows) {
+        // ERROR_ACCESS_DENIED, ERROR_BAD_COMMAND, ERROR_GEN_FAILURE,
+        // ERROR_OPERATION_ABORTED and ERROR_DEVICE_NOT_CONNECTED
+        &[5, 22, 31, 995, 1167]
+    } else {
+        &[]
+    };
+    err.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Process which has the serial port at `path` open, found by looking
+/// through the open files of the processes we can see.
+#[cfg(target_os = "linux")]
+fn port_owner(path: &str) -> Option<u32> {
+    let path = std::fs::canonicalize(path).ok()?;
+    for proc_entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = proc_entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(proc_entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if std::fs::read_link(fd.path()).is_ok_and(|target| target == path) {
+                return Some(pid);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_owner(_path: &str) -> Option<u32> {
+    None
+}
+
+/// Take an advisory lock on the serial port at `path`, so that two
+/// proxies cannot use the same port at once. Opening ports in exclusive
+/// mode is not enough, as it does not apply to root. The lock is held
+/// until the file returned is closed.
+#[cfg(unix)]
+fn lock_port(path: &str) -> io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(path)
+        .map_err(|err| match err.raw_os_error() {
+            // Opened in exclusive mode by another process.
+            Some(libc::EBUSY) => PortBusy::error(port_owner(path)),
+            _ => err,
+        })?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => PortBusy::error(port_owner(path)),
+            _ => err,
+        });
+    }
+    Ok(file)
+}
+
+/// Serial line parameters, as parsed from a port URL.
+struct SerialOptions {
+    port_name: String,
+    target_rate: u32,
+    default_rate: u32,
+    flow_control: mio_serial::FlowControl,
+    parity: mio_serial::Parity,
+    stop_bits: mio_serial::StopBits,
+    data_bits: mio_serial::DataBits,
+}
+
+fn invalid_option(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Decode a packet from a SLIP frame received on a serial port, once
+/// unescaped and without its end marker: the packet followed by its CRC32.
+///
+/// Whatever the frame, this returns an error rather than panicking, which
+/// makes it suitable as a fuzzing entry point.
+pub fn decode_frame(frame: &[u8]) -> Result<Packet, proto::Error> {
+    if frame.len() < 4 + std::mem::size_of::<u32>() {
+        // A packet must fit at least the header and its final CRC32
+        return Err(proto::Error::PacketTooSmall(frame.to_vec()));
+    }
+    let len = frame.len() - std::mem::size_of::<u32>();
+    let expected_crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&frame[..len]);
+    let received_crc =
+        u32::from_le_bytes([frame[len], frame[len + 1], frame[len + 2], frame[len + 3]]);
+    if received_crc != expected_crc {
+        return Err(proto::Error::CRC32(frame.to_vec()));
+    }
+    // At this point the whole packet should be here, and there should not
+    // be any bytes left over.
+    match Packet::deserialize(&frame[..len]) {
+        Ok((pkt, size)) if size == len => Ok(pkt),
+        Ok(_) => Err(proto::Error::PacketTooBig(frame.to_vec())),
+        Err(proto::Error::NeedMore) => Err(proto::Error::PacketTooSmall(frame.to_vec())),
+        Err(perr) => Err(perr),
+    }
+}
+
+impl SerialOptions {
+    /// Parses `serial_port[:target_rate[:default_rate]][?key=value[&key=value...]]`.
+    /// See `Port::new` for the supported keys.
+    fn parse(url: &str) -> Result<SerialOptions, io::Error> {
+        let (url, query) = match url.split_once('?') {
+            Some((url, query)) => (url, Some(query)),
+            None => (url, None),
+        };
+        let url_tokens: Vec<&str> = url.split(':').collect();
+        if url_tokens.is_empty() || (url_tokens.len() > 3) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut rates = [None, None];
+        for (rate, token) in rates.iter_mut().zip(url_tokens[1..].iter()) {
+            if let Ok(r) = token.parse::<u32>() {
+                *rate = Some(r);
+            } else {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+        }
+        let [mut target_rate, mut default_rate] = rates;
+
+        let mut ret = SerialOptions {
+            port_name: url_tokens[0].to_string(),
+            target_rate: DEFAULT_RATE,
+            default_rate: DEFAULT_RATE,
+            flow_control: mio_serial::FlowControl::None,
+            parity: mio_serial::Parity::None,
+            stop_bits: mio_serial::StopBits::One,
+            data_bits: mio_serial::DataBits::Eight,
+        };
+
+        for option in query.unwrap_or("").split('&').filter(|o| !o.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| invalid_option(format!("malformed serial option '{}'", option)))?;
+            let invalid_value = || {
+                invalid_option(format!(
+                    "invalid value '{}' for serial option '{}'",
+                    value, key
+                ))
+            };
+            match key {
+                "baud" => {
+                    default_rate = Some(value.parse().map_err(|_| invalid_value())?);
+                }
+                "target" => {
+                    target_rate = Some(value.parse().map_err(|_| invalid_value())?);
+                }
+                "flow" => {
+                    ret.flow_control = match value {
+                        "none" => mio_serial::FlowControl::None,
+                        "rtscts" | "hardware" => mio_serial::FlowControl::Hardware,
+                        "xonxoff" | "software" => mio_serial::FlowControl::Software,
+                        _ => return Err(invalid_value()),
+                    };
+                }
+                "parity" => {
+                    ret.parity = match value {
+                        "none" => mio_serial::Parity::None,
+                        "odd" => mio_serial::Parity::Odd,
+                        "even" => mio_serial::Parity::Even,
+                        _ => return Err(invalid_value()),
+                    };
+                }
+                "stop" => {
+                    ret.stop_bits = value
+                        .parse::<u8>()
+                        .ok()
+                        .and_then(|bits| bits.try_into().ok())
+                        .ok_or_else(invalid_value)?;
+                }
+                "data" => {
+                    ret.data_bits = value
+                        .parse::<u8>()
+                        .ok()
+                        .and_then(|bits| bits.try_into().ok())
+                        .ok_or_else(invalid_value)?;
+                }
+                _ => {
+                    return Err(invalid_option(format!("unknown serial option '{}'", key)));
+                }
+            }
+        }
+
+        // serialport opens `COMn` in the `\\.\` device namespace itself, as
+        // needed for ports above COM9, so accept names with or without it.
+        if cfg!(windows) {
+            if let Some(name) = ret.port_name.strip_prefix(r"\\.\") {
+                ret.port_name = name.to_string();
+            }
+        }
+
+        ret.default_rate = default_rate.unwrap_or(DEFAULT_RATE);
+        // Without an explicit target, stay at the rate the port is opened at.
+        ret.target_rate = target_rate.unwrap_or(ret.default_rate);
+        Ok(ret)
+    }
+}
+
 impl Port {
-    /// Returns a new `tcp::Port`. The `url` should look like
-    /// `serial_port[:target_rate[:default_rate]]``. It must start with a serial port,
+    /// Returns a new `serial::Port`. The `url` should look like
+    /// `serial_port[:target_rate[:default_rate]][?options]`. It must start with a serial port,
     /// like `/dev/tty??` or `COMn`. The second parameter is optional, and it
     /// indicates the rate at which tio should try to configure the connected device.
     /// The final parameter is the default rate: this is the data rate that the device
@@ -54,31 +278,48 @@ impl Port {
     /// For example, `COM3:400000:115200` will start off at 115.2k and try to
     /// negotiate 400k. If it fails to do so, or at any point later, it will
     /// fall back to 115.2k.
+    ///
+    /// `options` is a `&`-separated list of `key=value` pairs overriding the
+    /// serial line parameters:
+    /// - `baud`: default rate (same as `default_rate` above)
+    /// - `target`: target rate (same as `target_rate` above)
+    /// - `flow`: `none` (default), `rtscts` or `xonxoff`
+    /// - `parity`: `none` (default), `odd` or `even`
+    /// - `stop`: stop bits, `1` (default) or `2`
+    /// - `data`: data bits, `5` to `8` (default)
+    ///
+    /// For example, `/dev/ttyUSB0?baud=921600&flow=rtscts` opens a radio
+    /// adapter at 921.6k with hardware flow control, and does not attempt
+    /// to negotiate a different rate.
     pub fn new(url: &str) -> Result<Port, io::Error> {
-        let url_tokens: Vec<&str> = url.split(':').collect();
-        if (url_tokens.len() < 1) || (url_tokens.len() > 3) {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
-        }
-        let port_name = url_tokens[0];
-        let target_rate = if url_tokens.len() > 1 {
-            if let Ok(rate) = url_tokens[1].parse::<u32>() {
-                rate
-            } else {
-                return Err(io::Error::from(io::ErrorKind::InvalidInput));
-            }
-        } else {
-            DEFAULT_RATE
-        };
-        let default_rate = if url_tokens.len() > 2 {
-            if let Ok(rate) = url_tokens[2].parse::<u32>() {
-                rate
-            } else {
-                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        let opts = SerialOptions::parse(url)?;
+        #[cfg(unix)]
+        let lock = lock_port(&opts.port_name)?;
+        let mio_port = match mio_serial::new(&opts.port_name, opts.default_rate)
+            .flow_control(opts.flow_control)
+            .parity(opts.parity)
+            .stop_bits(opts.stop_bits)
+            .data_bits(opts.data_bits)
+            .open_native_async()
+        {
+            Ok(port) => port,
+            Err(err) => {
+                // Windows only lets one process open a port, but this is
+                // reported like a missing port. Open it again to tell.
+                #[cfg(windows)]
+                if let Err(err) = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&opts.port_name)
+                {
+                    // ERROR_ACCESS_DENIED or ERROR_SHARING_VIOLATION
+                    if matches!(err.raw_os_error(), Some(5) | Some(32)) {
+                        return Err(PortBusy::error(None));
+                    }
+                }
+                return Err(err.into());
             }
-        } else {
-            DEFAULT_RATE
         };
-        let mio_port = mio_serial::new(port_name, default_rate).open_native_async()?;
         #[cfg(windows)]
         {
             // Windows requires some custom settings to replicate the unix behavior.
@@ -100,17 +341,74 @@ impl Port {
         Ok(Port {
             port: mio_port,
             rates: RateInfo {
-                default_bps: default_rate,
-                target_bps: target_rate,
+                default_bps: opts.default_rate,
+                target_bps: opts.target_rate,
             },
             rxbuf: IOBuf::new(),
             last_rx: Instant::now(),
             txbuf: IOBuf::new(),
             startup_time: Instant::now(),
             first_rx: true,
+            raw_tap: RawTap::default(),
+            parse_mode: ParseModeFlag::default(),
+            #[cfg(unix)]
+            _lock: lock,
         })
     }
 
+    /// Serialize a packet and encode it with SLIP framing and a trailing CRC32.
+    fn encode(pkt: &Packet) -> Result<Vec<u8>, SendError> {
+        let raw = if let Ok(raw) = pkt.serialize() {
+            raw
+        } else {
+            return Err(SendError::Serialization);
+        };
+        let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let mut encoded = vec![0xC0u8];
+        for byte in [&raw, &crc32.checksum(&raw).to_le_bytes()[..]].concat() {
+            match byte {
+                0xC0 => {
+                    encoded.push(0xDB);
+                    encoded.push(0xDC);
+                }
+                0xDB => {
+                    encoded.push(0xDB);
+                    encoded.push(0xDD);
+                }
+                any => {
+                    encoded.push(any);
+                }
+            }
+        }
+        encoded.push(0xC0);
+        Ok(encoded)
+    }
+
+    /// Write encoded packets, buffering what does not get written right away.
+    /// `encoded` must fit in txbuf, which must be empty.
+    fn write_buffered(&mut self, encoded: &[u8]) -> Result<(), SendError> {
+        match self.port.write(encoded) {
+            Ok(size) => {
+                if size == encoded.len() {
+                    Ok(())
+                } else {
+                    self.txbuf
+                        .add_data(&encoded[size..])
+                        .expect("No fit in IOBuf");
+                    Err(SendError::MustDrain)
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // This can happen if we happen to send with the OS buffer completely full.
+                // Maintain the same semantics and buffer the whole thing in txbuf.
+                self.txbuf.add_data(encoded).expect("No fit in IOBuf");
+                Err(SendError::MustDrain)
+            }
+            Err(e) if is_removal(&e) => Err(SendError::Disconnected),
+            Err(e) => Err(SendError::IO(e)),
+        }
+    }
+
     /// Attempts to receive a packet only from the data currently present
     /// in the incoming buffer.
     fn recv_buffered(&mut self) -> Result<Packet, RecvError> {
@@ -119,6 +417,7 @@ impl Port {
         let mut text = true;
         let mut offset = 0;
         let mut consume_to = 0;
+        let strict = self.parse_mode.is_strict();
         let data = &self.rxbuf.data();
         while offset < data.len() {
             // Avoid packets that are too long, since we know they are invalid.
@@ -134,7 +433,12 @@ impl Port {
                 // Newline character preceded by valid text characters (possibly none).
                 // By the way the tio wire protocol over serial is designed, this can
                 // only be a text packet.
-                if pkt.len() > 0 {
+                if strict {
+                    // Text is not part of the protocol
+                    let raw = data[..=offset].to_vec();
+                    self.rxbuf.consume(offset + 1);
+                    return Err(RecvError::Protocol(proto::Error::Framing(raw)));
+                } else if pkt.len() > 0 {
                     self.rxbuf.consume(offset + 1);
                     return Err(RecvError::Protocol(proto::Error::Text(
                         String::from_utf8_lossy(&pkt).to_string(),
@@ -146,33 +450,14 @@ impl Port {
                 // This denotes the end of a SLIP packet. no matter what, we'll return
                 // from here, either successfully with a packet, or with an error,
                 // so consume the data so far.
-                self.rxbuf.consume(offset + 1);
-                if pkt.len() < 4 + std::mem::size_of::<u32>() {
-                    // A packet must fit at least the header and its final CRC32
-                    return Err(RecvError::Protocol(proto::Error::PacketTooSmall(pkt)));
-                }
-                let len = pkt.len() - std::mem::size_of::<u32>();
-                let expected_crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&pkt[..len]);
-                // This will always succeed, because the vec slice must be 4 bytes
-                let received_crc = u32::from_le_bytes(pkt[len..].try_into().expect("array size"));
-                if received_crc != expected_crc {
-                    return Err(RecvError::Protocol(proto::Error::CRC32(pkt)));
+                if strict && esc {
+                    // The end of the packet cannot be escaped
+                    let raw = data[..=offset].to_vec();
+                    self.rxbuf.consume(offset + 1);
+                    return Err(RecvError::Protocol(proto::Error::Framing(raw)));
                 }
-                // At this point the whole packet should be here, and there should not
-                // be any bytes left over.
-                return match Packet::deserialize(&pkt[..len]) {
-                    Ok((tio_pkt, size)) => {
-                        if size != len {
-                            Err(RecvError::IO(io::Error::from(io::ErrorKind::InvalidData)))
-                        } else {
-                            Ok(tio_pkt)
-                        }
-                    }
-                    Err(proto::Error::NeedMore) => {
-                        Err(RecvError::Protocol(proto::Error::PacketTooSmall(pkt)))
-                    }
-                    Err(perr) => Err(RecvError::Protocol(perr)),
-                };
+                self.rxbuf.consume(offset + 1);
+                return decode_frame(&pkt).map_err(RecvError::Protocol);
             } else {
                 if !c.is_ascii_graphic() && (c != ' ') && (c != '\t') {
                     text = false;
@@ -180,6 +465,11 @@ impl Port {
                 if esc {
                     if data[offset] == 0xDC {
                         pkt.push(0xC0);
+                    } else if strict && (data[offset] != 0xDD) {
+                        // Only the end and escape bytes can be escaped
+                        let raw = data[..=offset].to_vec();
+                        self.rxbuf.consume(offset + 1);
+                        return Err(RecvError::Protocol(proto::Error::Framing(raw)));
                     } else {
                         pkt.push(0xDB);
                     }
@@ -207,19 +497,25 @@ impl RawPort for Port {
             // This could happen e.g. reprogramming a board mid-packet.
             let now = Instant::now();
             if now.duration_since(self.last_rx) > Duration::from_millis(200) {
+                if self.parse_mode.is_strict() && !self.rxbuf.empty() {
+                    let stale = self.rxbuf.data().to_vec();
+                    self.rxbuf.flush();
+                    return Err(RecvError::Protocol(proto::Error::Framing(stale)));
+                }
                 self.rxbuf.flush();
             }
+            let buffered = self.rxbuf.size();
             if let Err(e) = self.rxbuf.refill(&mut self.port) {
-                #[cfg(target_os = "macos")]
-                // On macos, disconnecting a serial port while connected will
-                // generate this error, so translate it.
+                // Translate the errors from unplugging the port, so that the
+                // proxy treats them as a disconnection and tries to reconnect.
                 if let RecvError::IO(ioerr) = &e {
-                    if Some(6) == ioerr.raw_os_error() {
+                    if is_removal(ioerr) {
                         return Err(RecvError::Disconnected);
                     }
                 }
                 return Err(e);
             }
+            self.raw_tap.feed(&self.rxbuf.data()[buffered..]);
             // If this is the very first data we receive, discard it if received
             // before the startup holdoff. Likely it's a combination of stale
             // data and possibly corrupted initial data from the driver, so it's
@@ -227,7 +523,7 @@ impl RawPort for Port {
             // waits for a large amount of data before declaring it invalid.
             if self.first_rx && !self.rxbuf.empty() {
                 self.first_rx = false;
-                if self.startup_holdoff() {
+                if self.startup_holdoff() && !self.parse_mode.is_strict() {
                     self.rxbuf.flush();
                     return Err(RecvError::NotReady);
                 }
@@ -242,52 +538,31 @@ impl RawPort for Port {
         if self.has_data_to_drain() {
             return Err(SendError::Full);
         }
+        let encoded = Port::encode(pkt)?;
+        self.write_buffered(&encoded)
+    }
 
-        let raw = if let Ok(raw) = pkt.serialize() {
-            raw
-        } else {
-            return Err(SendError::Serialization);
-        };
-        let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        let mut encoded = vec![0xC0u8];
-        for byte in [&raw, &crc32.checksum(&raw).to_le_bytes()[..]].concat() {
-            match byte {
-                0xC0 => {
-                    encoded.push(0xDB);
-                    encoded.push(0xDC);
-                }
-                0xDB => {
-                    encoded.push(0xDB);
-                    encoded.push(0xDD);
-                }
-                any => {
-                    encoded.push(any);
-                }
-            }
+    fn send_batch(&mut self, pkts: &[Packet]) -> (usize, Result<(), SendError>) {
+        if self.has_data_to_drain() {
+            return (0, Err(SendError::Full));
         }
-        encoded.push(0xC0);
-
-        match self.port.write(&encoded) {
-            Ok(size) => {
-                if size == encoded.len() {
-                    Ok(())
-                } else {
-                    // IOBuf sized such that it can always store at least a full encoded packet.
-                    self.txbuf
-                        .add_data(&encoded[size..])
-                        .expect("No fit in IOBuf");
-                    Err(SendError::MustDrain)
-                }
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // This can happen if we happen to send with the OS buffer completely full.
-                // Maintain the same semantics and buffer the whole thing in txbuf.
-                // IOBuf sized such that it can always store at least a full encoded packet.
-                self.txbuf.add_data(&encoded[..]).expect("No fit in IOBuf");
-                Err(SendError::MustDrain)
+        // Encode as many packets as fit in txbuf, so that a partial write
+        // can always be buffered.
+        let mut encoded = vec![];
+        let mut count = 0;
+        for pkt in pkts {
+            let pkt_encoded = match Port::encode(pkt) {
+                Ok(e) => e,
+                Err(e) if count == 0 => return (0, Err(e)),
+                Err(_) => break,
+            };
+            if encoded.len() + pkt_encoded.len() > IOBUF_SIZE {
+                break;
             }
-            Err(e) => Err(SendError::IO(e)),
+            encoded.extend(pkt_encoded);
+            count += 1;
         }
+        (count, self.write_buffered(&encoded))
     }
 
     fn drain(&mut self) -> Result<(), SendError> {
@@ -308,6 +583,14 @@ impl RawPort for Port {
         }
     }
 
+    fn set_raw_tap(&mut self, tap: RawTap) {
+        self.raw_tap = tap;
+    }
+
+    fn set_parse_mode(&mut self, mode: ParseModeFlag) {
+        self.parse_mode = mode;
+    }
+
     fn rate_info(&self) -> Option<RateInfo> {
         Some(self.rates.clone())
     }
@@ -0,0 +1,593 @@
+//! Simulated device
+//!
+//! Implements a `RawPort` connected to a software sensor, for testing
+//! applications and the proxy without hardware. The simulated device runs
+//! in its own thread and talks to the port over a loopback TCP connection,
+//! using the same framing as `tcp::Port`. It:
+//! - answers RPCs from a table of values, which can be set with RPCs as
+//!   well, saved with `dev.conf.save` and restored with `dev.conf.load` or
+//!   by restarting, plus `dev.metadata` and the `sim.restart` action;
+//! - streams sine waves on stream 1, with a given number of columns and
+//!   sampling rate, unless `sim.data.active` is set to 0, and sends
+//!   metadata and session heartbeats periodically;
+//! - can drop packets, send garbage bytes, and restart on its own.
+
+use super::faults::Rng;
+use super::{tcp, Packet, ParseModeFlag, RawPort, RecvError, SendError};
+use crate::tio::proto::meta::{
+    ColumnMetadata, DeviceMetadata, MetadataEpoch, MetadataFilter, MetadataType, SegmentMetadata,
+    StreamMetadata,
+};
+use crate::tio::proto::{
+    self, DataType, DeviceRoute, HeartbeatPayload, Payload, RpcErrorCode, RpcMethod,
+    StreamDataPayload,
+};
+use crate::tio::util::PacketBuilder;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Configuration of a simulated device.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub name: String,
+    pub serial_number: String,
+    /// Sampling rate of the data stream, in Hz.
+    pub rate: u32,
+    pub columns: usize,
+    /// Probability of dropping each packet sent by the device.
+    pub drop: f64,
+    /// Probability of sending garbage bytes before each packet.
+    pub garbage: f64,
+    /// Restart the device periodically.
+    pub restart_every: Option<Duration>,
+    pub seed: Option<u64>,
+    /// Values returned by RPCs, by name.
+    pub rpcs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            name: "sim".to_string(),
+            serial_number: "SIM0001".to_string(),
+            rate: 100,
+            columns: 3,
+            drop: 0.0,
+            garbage: 0.0,
+            restart_every: None,
+            seed: None,
+            rpcs: BTreeMap::new(),
+        }
+    }
+}
+
+fn invalid_option(option: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid simulator option '{}'", option),
+    )
+}
+
+impl SimConfig {
+    /// Parse the part of a `sim://` url after the scheme, `[name][?options]`,
+    /// with options separated by `&`: `rate=<Hz>`, `columns=<n>`,
+    /// `drop=<probability>`, `garbage=<probability>`, `restart=<seconds>`
+    /// and `seed=<n>`. For example `vm4?rate=200&drop=0.01&restart=60`.
+    pub fn parse(spec: &str) -> io::Result<SimConfig> {
+        let mut config = SimConfig::default();
+        let (name, options) = spec.split_once('?').unwrap_or((spec, ""));
+        if !name.is_empty() {
+            config.name = name.to_string();
+        }
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| invalid_option(option))?;
+            let err = |_| invalid_option(option);
+            let ferr = |_| invalid_option(option);
+            match key {
+                "rate" => config.rate = value.parse().map_err(err)?,
+                "columns" => config.columns = value.parse().map_err(err)?,
+                "drop" => config.drop = value.parse().map_err(ferr)?,
+                "garbage" => config.garbage = value.parse().map_err(ferr)?,
+                "restart" => {
+                    config.restart_every =
+                        Some(Duration::from_secs_f64(value.parse().map_err(ferr)?))
+                }
+                "seed" => config.seed = Some(value.parse().map_err(err)?),
+                _ => return Err(invalid_option(option)),
+            }
+        }
+        if config.rate == 0 || config.columns == 0 || config.columns > 100 {
+            return Err(invalid_option(spec));
+        }
+        Ok(config)
+    }
+
+    /// Add an RPC to the table, returning `value`.
+    pub fn rpc(mut self, name: &str, value: &[u8]) -> SimConfig {
+        self.rpcs.insert(name.to_string(), value.to_vec());
+        self
+    }
+}
+
+/// RawPort to communicate with a simulated device.
+pub struct Port {
+    inner: tcp::Port,
+}
+
+impl Port {
+    /// Start a simulated device and return a port connected to it.
+    pub fn new(config: SimConfig) -> io::Result<Port> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        thread::Builder::new()
+            .name(format!("sim:{}", config.name))
+            .spawn(move || {
+                if let Ok((stream, _)) = listener.accept() {
+                    drop(listener);
+                    // Send replies right away, like a device would.
+                    let _ = stream.set_nodelay(true);
+                    SimDevice::new(config, stream).run();
+                }
+            })?;
+        Ok(Port {
+            inner: tcp::Port::new(&addr)?,
+        })
+    }
+}
+
+impl RawPort for Port {
+    fn recv(&mut self) -> Result<Packet, RecvError> {
+        self.inner.recv()
+    }
+
+    fn send(&mut self, pkt: &Packet) -> Result<(), SendError> {
+        self.inner.send(pkt)
+    }
+
+    fn send_batch(&mut self, pkts: &[Packet]) -> (usize, Result<(), SendError>) {
+        self.inner.send_batch(pkts)
+    }
+
+    fn drain(&mut self) -> Result<(), SendError> {
+        self.inner.drain()
+    }
+
+    fn has_data_to_drain(&self) -> bool {
+        self.inner.has_data_to_drain()
+    }
+
+    fn set_parse_mode(&mut self, mode: ParseModeFlag) {
+        self.inner.set_parse_mode(mode);
+    }
+}
+
+impl mio::event::Source for Port {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+static SIM_STREAM_ID: u8 = 1;
+/// Setting enabling the stream, see `Device::start_streams`.
+static SIM_ACTIVE_RPC: &str = "sim.data.active";
+static SIM_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+static SIM_METADATA_INTERVAL: Duration = Duration::from_secs(5);
+/// Limit on the samples sent at once after falling behind.
+static SIM_MAX_CATCHUP: u32 = 100;
+static SIM_MAX_METADATA_REPLY: usize = 400;
+
+/// RPC type and permission flags reported by `rpc.listinfo` and `rpc.info`.
+/// Device information is a read-only string; other values are readable and
+/// writable, unsigned integers if they have an integer size and strings
+/// otherwise.
+fn sim_rpc_meta(name: &str, value: &[u8]) -> u16 {
+    static READ: u16 = 0x0100;
+    static WRITE: u16 = 0x0200;
+    static TYPE_STRING: u16 = 3;
+    if name.starts_with("dev.") {
+        READ | TYPE_STRING
+    } else if let 1 | 2 | 4 | 8 = value.len() {
+        READ | WRITE | ((value.len() as u16) << 4)
+    } else {
+        READ | WRITE | TYPE_STRING
+    }
+}
+
+struct SimDevice {
+    config: SimConfig,
+    stream: TcpStream,
+    rng: Rng,
+    session_id: u32,
+    start_time: u32,
+    started: Instant,
+    n_samples: u32,
+    /// RPC values as last saved, restored on restart.
+    saved_rpcs: BTreeMap<String, Vec<u8>>,
+}
+
+impl SimDevice {
+    fn new(config: SimConfig, stream: TcpStream) -> SimDevice {
+        let mut rng = Rng::new(config.seed);
+        let session_id = rng.next_u64() as u32;
+        let mut config = config;
+        let defaults = [
+            ("dev.name", config.name.as_bytes().to_vec()),
+            ("dev.serial", config.serial_number.as_bytes().to_vec()),
+            ("dev.firmware.hash", b"simulated".to_vec()),
+            ("data.rate", config.rate.to_le_bytes().to_vec()),
+            (SIM_ACTIVE_RPC, vec![1]),
+        ];
+        for (name, value) in defaults {
+            config.rpcs.entry(name.to_string()).or_insert(value);
+        }
+        SimDevice {
+            saved_rpcs: config.rpcs.clone(),
+            config,
+            stream,
+            rng,
+            session_id,
+            start_time: 0,
+            started: Instant::now(),
+            n_samples: 0,
+        }
+    }
+
+    /// Start over as after a power cycle: new session, samples counted
+    /// from zero, and the saved RPC values.
+    fn restart(&mut self) {
+        self.config.rpcs = self.saved_rpcs.clone();
+        self.session_id = self.rng.next_u64() as u32;
+        self.started = Instant::now();
+        self.start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs() as u32)
+            .unwrap_or(0);
+        self.n_samples = 0;
+    }
+
+    fn device_metadata(&self) -> DeviceMetadata {
+        DeviceMetadata {
+            serial_number: self.config.serial_number.clone(),
+            firmware_hash: "simulated".to_string(),
+            n_streams: 1,
+            session_id: self.session_id,
+            name: self.config.name.clone(),
+        }
+    }
+
+    fn stream_metadata(&self) -> StreamMetadata {
+        StreamMetadata {
+            stream_id: SIM_STREAM_ID,
+            name: "sim".to_string(),
+            n_columns: self.config.columns,
+            n_segments: 1,
+            sample_size: self.config.columns * 4,
+            buf_samples: 0,
+        }
+    }
+
+    fn segment_metadata(&self) -> SegmentMetadata {
+        SegmentMetadata {
+            stream_id: SIM_STREAM_ID,
+            segment_id: 0,
+            // Valid and active
+            flags: 0x03,
+            time_ref_epoch: MetadataEpoch::Unix,
+            time_ref_serial: self.config.serial_number.clone(),
+            time_ref_session_id: self.session_id,
+            start_time: self.start_time,
+            sampling_rate: self.config.rate,
+            decimation: 1,
+            filter_cutoff: self.config.rate as f32 / 2.0,
+            filter_type: MetadataFilter::Unfiltered,
+        }
+    }
+
+    fn column_metadata(&self, index: usize) -> ColumnMetadata {
+        ColumnMetadata {
+            stream_id: SIM_STREAM_ID,
+            index,
+            data_type: DataType::Float32,
+            name: format!("ch{}", index),
+            units: "V".to_string(),
+            description: format!("Simulated {} Hz sine wave", index + 1),
+        }
+    }
+
+    fn metadata_updates(&self) -> Vec<Packet> {
+        let mut ret = vec![
+            self.device_metadata().make_update(),
+            self.stream_metadata().make_update(),
+            self.segment_metadata().make_update(),
+        ];
+        for i in 0..self.config.columns {
+            ret.push(self.column_metadata(i).make_update());
+        }
+        ret
+    }
+
+    /// Reply to `dev.metadata`. The argument lists the requested items as
+    /// (type, stream, index) triples; without it, as much of the metadata as
+    /// fits is returned.
+    fn metadata_reply(&self, arg: &[u8]) -> Vec<u8> {
+        let mut items = vec![];
+        if arg.is_empty() {
+            items.push((MetadataType::Device, 0));
+            items.push((MetadataType::Stream, 0));
+            items.push((MetadataType::Segment, 0));
+            for i in 0..self.config.columns {
+                items.push((MetadataType::Column, i));
+            }
+        } else {
+            for req in arg.chunks_exact(3) {
+                items.push((MetadataType::from(req[0]), usize::from(req[2])));
+            }
+        }
+        let mut reply = vec![];
+        for (mtype, index) in items {
+            let serialized = match mtype {
+                MetadataType::Device => self.device_metadata().serialize(&[], &[]),
+                MetadataType::Stream => self.stream_metadata().serialize(&[], &[]),
+                MetadataType::Segment => self.segment_metadata().serialize(&[], &[]),
+                MetadataType::Column if index < self.config.columns => {
+                    self.column_metadata(index).serialize(&[], &[])
+                }
+                _ => continue,
+            };
+            let (mut fixed, varlen) = match serialized {
+                Ok(s) => s,
+                Err(()) => continue,
+            };
+            fixed.extend(varlen);
+            if reply.len() + fixed.len() + 2 > SIM_MAX_METADATA_REPLY {
+                break;
+            }
+            reply.push(mtype.into());
+            reply.push(fixed.len() as u8);
+            reply.extend(fixed);
+        }
+        reply
+    }
+
+    fn handle_rpc(&mut self, req: &proto::RpcRequestPayload) -> Packet {
+        let name = match &req.method {
+            RpcMethod::Name(name) => name.clone(),
+            RpcMethod::Id(_) => {
+                return PacketBuilder::make_rpc_error(
+                    req.id,
+                    RpcErrorCode::NotFound,
+                    DeviceRoute::root(),
+                )
+            }
+        };
+        let reply = match name.as_str() {
+            "dev.metadata" => self.metadata_reply(&req.arg),
+            "sim.restart" => {
+                self.restart();
+                vec![]
+            }
+            "dev.conf.save" => {
+                self.saved_rpcs = self.config.rpcs.clone();
+                vec![]
+            }
+            "dev.conf.load" => {
+                self.config.rpcs = self.saved_rpcs.clone();
+                vec![]
+            }
+            "rpc.listinfo" if req.arg.is_empty() => {
+                (self.config.rpcs.len() as u16).to_le_bytes().to_vec()
+            }
+            "rpc.listinfo" => {
+                let listed = <[u8; 2]>::try_from(&req.arg[..])
+                    .ok()
+                    .and_then(|id| self.config.rpcs.iter().nth(u16::from_le_bytes(id).into()));
+                match listed {
+                    Some((name, value)) => {
+                        let mut reply = sim_rpc_meta(name, value).to_le_bytes().to_vec();
+                        reply.extend(name.as_bytes());
+                        reply
+                    }
+                    None => {
+                        return PacketBuilder::make_rpc_error(
+                            req.id,
+                            RpcErrorCode::InvalidArgs,
+                            DeviceRoute::root(),
+                        )
+                    }
+                }
+            }
+            "rpc.info" => {
+                let name = String::from_utf8_lossy(&req.arg);
+                match self.config.rpcs.get(name.as_ref()) {
+                    Some(value) => sim_rpc_meta(&name, value).to_le_bytes().to_vec(),
+                    None => {
+                        return PacketBuilder::make_rpc_error(
+                            req.id,
+                            RpcErrorCode::NotFound,
+                            DeviceRoute::root(),
+                        )
+                    }
+                }
+            }
+            _ => match self.config.rpcs.get_mut(&name) {
+                Some(value) => {
+                    if !req.arg.is_empty() {
+                        *value = req.arg.clone();
+                    }
+                    value.clone()
+                }
+                None => {
+                    return PacketBuilder::make_rpc_error(
+                        req.id,
+                        RpcErrorCode::NotFound,
+                        DeviceRoute::root(),
+                    )
+                }
+            },
+        };
+        Packet {
+            payload: Payload::RpcReply(proto::RpcReplyPayload { id: req.id, reply }),
+            routing: DeviceRoute::root(),
+            ttl: 0,
+            received: None,
+        }
+    }
+
+    fn sample(&mut self) -> Packet {
+        let t = f64::from(self.n_samples) / f64::from(self.config.rate);
+        let mut data = vec![];
+        for i in 0..self.config.columns {
+            let noise = (self.rng.next_f64() - 0.5) * 1e-3;
+            let value = (2.0 * std::f64::consts::PI * (i + 1) as f64 * t).sin() + noise;
+            data.extend((value as f32).to_le_bytes());
+        }
+        let pkt = Packet {
+            payload: Payload::StreamData(StreamDataPayload {
+                stream_id: SIM_STREAM_ID,
+                first_sample_n: self.n_samples & 0xFFFFFF,
+                segment_id: 0,
+                data: data.into(),
+            }),
+            routing: DeviceRoute::root(),
+            ttl: 0,
+            received: None,
+        };
+        self.n_samples += 1;
+        pkt
+    }
+
+    /// Send a packet, subject to the configured faults.
+    fn send(&mut self, pkt: Packet) -> io::Result<()> {
+        if self.rng.chance(self.config.drop) {
+            return Ok(());
+        }
+        if self.rng.chance(self.config.garbage) {
+            let n = 1 + (self.rng.next_u64() % 16) as usize;
+            let garbage: Vec<u8> = (0..n).map(|_| self.rng.next_u64() as u8).collect();
+            self.stream.write_all(&garbage)?;
+        }
+        if let Ok(raw) = pkt.serialize() {
+            self.stream.write_all(&raw)?;
+        }
+        Ok(())
+    }
+
+    fn run(mut self) {
+        self.restart();
+        let _ = self.run_loop();
+    }
+
+    fn run_loop(&mut self) -> io::Result<()> {
+        let mut rxbuf: Vec<u8> = vec![];
+        let mut buf = [0u8; 1024];
+        let period = Duration::from_secs_f64(1.0 / f64::from(self.config.rate));
+        let mut next_heartbeat = Instant::now();
+        let mut next_metadata = Instant::now();
+        let mut next_restart = self.config.restart_every.map(|d| Instant::now() + d);
+        loop {
+            let now = Instant::now();
+            if now >= next_heartbeat {
+                let session = self.session_id;
+                self.send(Packet {
+                    payload: Payload::Heartbeat(HeartbeatPayload::Session(session)),
+                    routing: DeviceRoute::root(),
+                    ttl: 0,
+                    received: None,
+                })?;
+                next_heartbeat = now + SIM_HEARTBEAT_INTERVAL;
+            }
+            if now >= next_metadata {
+                for pkt in self.metadata_updates() {
+                    self.send(pkt)?;
+                }
+                next_metadata = now + SIM_METADATA_INTERVAL;
+            }
+            if let Some(restart) = next_restart {
+                if now >= restart {
+                    self.restart();
+                    next_heartbeat = now;
+                    next_metadata = now;
+                    next_restart = self.config.restart_every.map(|d| now + d);
+                }
+            }
+            let mut n_due = 0;
+            while self.started + period * (self.n_samples + 1) <= now && n_due < SIM_MAX_CATCHUP {
+                // Samples are still counted while the stream is stopped.
+                let pkt = self.sample();
+                if self.config.rpcs.get(SIM_ACTIVE_RPC) != Some(&vec![0]) {
+                    self.send(pkt)?;
+                }
+                n_due += 1;
+            }
+            if n_due == SIM_MAX_CATCHUP {
+                // Too far behind; skip ahead rather than flooding.
+                let elapsed = now.duration_since(self.started).as_secs_f64();
+                self.n_samples = (elapsed * f64::from(self.config.rate)) as u32;
+            }
+
+            let next_sample = self.started + period * (self.n_samples + 1);
+            let wait = [next_heartbeat, next_metadata, next_sample]
+                .into_iter()
+                .min()
+                .expect("non empty")
+                .saturating_duration_since(Instant::now());
+            self.stream
+                .set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => rxbuf.extend(&buf[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+            loop {
+                match Packet::deserialize(&rxbuf) {
+                    Ok((pkt, size)) => {
+                        rxbuf.drain(..size);
+                        if let Payload::RpcRequest(req) = &pkt.payload {
+                            if pkt.routing.is_empty() {
+                                let reply = self.handle_rpc(req);
+                                self.send(reply)?;
+                            }
+                        }
+                    }
+                    Err(proto::Error::NeedMore) => break,
+                    Err(_) => {
+                        rxbuf.clear();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
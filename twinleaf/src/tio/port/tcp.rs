@@ -4,39 +4,83 @@
 //! TIO packets are sent unmodified to the TCP stream. The TIO protocol
 //! packets have a header that allows for figuring out the total size
 //! of a packet, so it can be split up again at the receiving end.
+//! The same framing is used over other byte streams, such as named pipes.
 
-use super::{iobuf::IOBuf, proto, Packet, RawPort, RecvError, SendError};
+use super::iobuf::{IOBuf, IOBUF_SIZE};
+use super::{proto, Packet, ParseModeFlag, RawPort, RawTap, RecvError, SendError};
 use mio::net::TcpStream;
 use std::io;
-use std::io::Write;
 use std::net::SocketAddr;
 
-/// RawPort to communicate via TCP
-pub struct Port {
+/// RawPort to communicate via TCP, or another byte stream `S`
+pub struct Port<S = TcpStream> {
     /// Underlying stream
-    stream: TcpStream,
+    stream: S,
     /// Incoming buffer, used to buffer partial packets.
     rxbuf: IOBuf,
     /// Outgoing buffer, used for all-or-none sends of packets
     /// when the TCP buffer fills up.
     txbuf: IOBuf,
+    /// Where to send a copy of the received bytes.
+    raw_tap: RawTap,
+    /// How to deal with framing errors.
+    parse_mode: ParseModeFlag,
 }
 
-impl Port {
-    /// Takes ownership of a MIO `TcpStream` and constructs a `Port` over it.
-    pub fn from_stream(stream: TcpStream) -> Result<Port, io::Error> {
+impl<S> Port<S> {
+    /// Takes ownership of a MIO stream, such as a `TcpStream`, and constructs
+    /// a `Port` over it.
+    pub fn from_stream(stream: S) -> Result<Port<S>, io::Error> {
         Ok(Port {
             stream: stream,
             rxbuf: IOBuf::new(),
             txbuf: IOBuf::new(),
+            raw_tap: RawTap::default(),
+            parse_mode: ParseModeFlag::default(),
         })
     }
+}
 
+impl Port<TcpStream> {
     /// Returns a new `tcp::Port` for communication with the given `address`.
     pub fn new(address: &SocketAddr) -> Result<Port, io::Error> {
         let stream = TcpStream::connect(*address)?;
         Port::from_stream(stream)
     }
+}
+
+impl<S: io::Read + io::Write> Port<S> {
+    /// Write serialized packets, buffering what does not get written right
+    /// away. `raw` must fit in txbuf, which must be empty.
+    fn write_buffered(&mut self, raw: &[u8]) -> Result<(), SendError> {
+        match self.stream.write(raw) {
+            Ok(size) => {
+                if size == raw.len() {
+                    // The entire packet was written out
+                    Ok(())
+                } else {
+                    // Partial write, the TCP buffer is full. To guarantee packetization
+                    // we must send the remaining data, so add it to the outgoing buffer.
+                    self.txbuf.add_data(&raw[size..]).expect("No fit in IOBuf");
+                    Err(SendError::MustDrain)
+                }
+            }
+            Err(err) => {
+                match err.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::NotConnected => {
+                        // These errors can occur when a packet is sent right after the
+                        // nonblocking connection is initiated and before the handshake
+                        // completes. WouldBlock can also occur if we happen to send with
+                        // the TCP buffer completely full.
+                        // Maintain the same semantics and buffer the whole thing in txbuf.
+                        self.txbuf.add_data(raw).expect("No fit in IOBuf");
+                        Err(SendError::MustDrain)
+                    }
+                    _ => Err(SendError::IO(err)),
+                }
+            }
+        }
+    }
 
     /// Attempts to receive a packet only from the data currently present
     /// in the incoming buffer.
@@ -47,23 +91,44 @@ impl Port {
                 Ok(pkt)
             }
             Err(proto::Error::NeedMore) => Err(RecvError::NotReady),
-            Err(perr) => Err(RecvError::Protocol(perr)),
+            Err(perr) => {
+                if self.parse_mode.is_strict() {
+                    // The error has all the data received, which cannot be
+                    // trusted to start a packet anywhere.
+                    self.rxbuf.flush();
+                } else {
+                    // Skip a byte to look for the next packet, rather than
+                    // failing on the same one forever.
+                    self.rxbuf.consume(1);
+                }
+                Err(RecvError::Protocol(perr))
+            }
         }
     }
 }
 
-impl RawPort for Port {
+impl<S: io::Read + io::Write> RawPort for Port<S> {
     fn recv(&mut self) -> Result<Packet, RecvError> {
         let mut res = self.recv_buffered();
         if let Err(RecvError::NotReady) = res {
+            let buffered = self.rxbuf.size();
             if let Err(e) = self.rxbuf.refill(&mut self.stream) {
                 return Err(e);
             }
+            self.raw_tap.feed(&self.rxbuf.data()[buffered..]);
             res = self.recv_buffered();
         }
         res
     }
 
+    fn set_raw_tap(&mut self, tap: RawTap) {
+        self.raw_tap = tap;
+    }
+
+    fn set_parse_mode(&mut self, mode: ParseModeFlag) {
+        self.parse_mode = mode;
+    }
+
     fn send(&mut self, pkt: &Packet) -> Result<(), SendError> {
         if self.has_data_to_drain() {
             return Err(SendError::Full);
@@ -74,36 +139,30 @@ impl RawPort for Port {
         } else {
             return Err(SendError::Serialization);
         };
-        match self.stream.write(&raw) {
-            Ok(size) => {
-                if size == raw.len() {
-                    // The entire packet was written out
-                    Ok(())
-                } else {
-                    // Partial write, the TCP buffer is full. To guarantee packetization
-                    // we must send the remaining data, so add it to the outgoing buffer.
-                    // IOBuf sized such that it can always store at least a full packet,
-                    // so this should never happen.
-                    self.txbuf.add_data(&raw[size..]).expect("No fit in IOBuf");
-                    Err(SendError::MustDrain)
-                }
-            }
-            Err(err) => {
-                match err.kind() {
-                    io::ErrorKind::WouldBlock | io::ErrorKind::NotConnected => {
-                        // These errors can occur when a packet is sent right after the
-                        // nonblocking connection is initiated and before the handshake
-                        // completes. WouldBlock can also occur if we happen to send with
-                        // the TCP buffer completely full.
-                        // Maintain the same semantics and buffer the whole thing in txbuf.
-                        // IOBuf sized such that it can always store at least a full packet.
-                        self.txbuf.add_data(&raw[..]).expect("No fit in IOBuf");
-                        Err(SendError::MustDrain)
-                    }
-                    _ => Err(SendError::IO(err)),
-                }
+        self.write_buffered(&raw)
+    }
+
+    fn send_batch(&mut self, pkts: &[Packet]) -> (usize, Result<(), SendError>) {
+        if self.has_data_to_drain() {
+            return (0, Err(SendError::Full));
+        }
+        // Coalesce as many packets as fit in txbuf, so that a partial write
+        // can always be buffered.
+        let mut raw = vec![];
+        let mut count = 0;
+        for pkt in pkts {
+            let pkt_raw = match pkt.serialize() {
+                Ok(r) => r,
+                Err(()) if count == 0 => return (0, Err(SendError::Serialization)),
+                Err(()) => break,
+            };
+            if raw.len() + pkt_raw.len() > IOBUF_SIZE {
+                break;
             }
+            raw.extend(pkt_raw);
+            count += 1;
         }
+        (count, self.write_buffered(&raw))
     }
 
     fn drain(&mut self) -> Result<(), SendError> {
@@ -115,7 +174,7 @@ impl RawPort for Port {
     }
 }
 
-impl mio::event::Source for Port {
+impl<S: mio::event::Source> mio::event::Source for Port<S> {
     fn register(
         &mut self,
         registry: &mio::Registry,
@@ -0,0 +1,46 @@
+//! Power
+//!
+//! Helpers for the device power management RPCs. These are mostly useful
+//! for battery powered field stations, which spend most of their time in
+//! a low power state and only wake up periodically to acquire data.
+//!
+//! A device in low power mode stops streaming data. The proxy recognizes
+//! the requests below as they are forwarded to its root device, and does
+//! not treat the resulting silence as a failure until data is seen again.
+
+use super::proto::RpcMethod;
+use super::proxy::{Port, RpcError};
+
+use std::time::Duration;
+
+/// Enter low power mode until explicitly woken up.
+pub static SLEEP_RPC: &str = "dev.power.sleep";
+/// Enter low power mode, waking up automatically after a number of seconds.
+pub static SLEEP_TIMED_RPC: &str = "dev.power.sleep.timed";
+/// Leave low power mode.
+pub static WAKE_RPC: &str = "dev.power.wake";
+
+/// Put the device behind `port` into low power mode.
+pub fn sleep(port: &Port) -> Result<(), Box<RpcError>> {
+    Ok(port.action(SLEEP_RPC)?)
+}
+
+/// Put the device behind `port` into low power mode, and schedule it to
+/// wake up after `duration`, with a resolution of one second.
+pub fn sleep_for(port: &Port, duration: Duration) -> Result<(), Box<RpcError>> {
+    let secs = u32::try_from(duration.as_secs()).unwrap_or(u32::MAX);
+    Ok(port.rpc(SLEEP_TIMED_RPC, secs)?)
+}
+
+/// Wake up the device behind `port` from low power mode.
+pub fn wake(port: &Port) -> Result<(), Box<RpcError>> {
+    Ok(port.action(WAKE_RPC)?)
+}
+
+/// True if `method` puts a device into low power mode.
+pub(crate) fn is_sleep_request(method: &RpcMethod) -> bool {
+    match method {
+        RpcMethod::Name(name) => name == SLEEP_RPC || name == SLEEP_TIMED_RPC,
+        RpcMethod::Id(_) => false,
+    }
+}
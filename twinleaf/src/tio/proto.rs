@@ -3,6 +3,7 @@ pub mod meta;
 pub mod route;
 pub mod rpc;
 pub mod vararg;
+pub mod version;
 
 pub use legacy::{
     LegacySourceInfoPayload, LegacyStreamDataPayload, LegacyStreamInfoPayload,
@@ -12,16 +13,24 @@ pub use meta::{MetadataPayload, MetadataType};
 use num_enum::{FromPrimitive, IntoPrimitive};
 pub use route::DeviceRoute;
 pub use rpc::{RpcErrorCode, RpcErrorPayload, RpcMethod, RpcReplyPayload, RpcRequestPayload};
+pub use version::ProtocolVersion;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GenericPayload {
     pub packet_type: u8,
-    pub payload: Vec<u8>,
+    pub payload: Arc<[u8]>,
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LogLevel {
     Critical = 0,
     Error = 1,
@@ -33,6 +42,7 @@ pub enum LogLevel {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LogMessagePayload {
     pub data: u32,
     pub level: LogLevel,
@@ -40,6 +50,7 @@ pub struct LogMessagePayload {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HeartbeatPayload {
     Session(u32),
     Any(Vec<u8>),
@@ -48,6 +59,7 @@ pub enum HeartbeatPayload {
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataType {
     UInt8 = 0x10,
     Int8 = 0x11,
@@ -73,14 +85,17 @@ impl DataType {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StreamDataPayload {
     pub stream_id: u8,
     pub first_sample_n: u32,
     pub segment_id: u8,
-    pub data: Vec<u8>,
+    /// Shared, since sample data is broadcast to every client of a proxy.
+    pub data: Arc<[u8]>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Payload {
     LogMessage(LogMessagePayload),
     RpcRequest(RpcRequestPayload),
@@ -97,10 +112,65 @@ pub enum Payload {
 }
 
 #[derive(Debug, Clone)]
+/// A TIO packet.
+///
+/// With the `serde` feature, packets can be serialized with any serde format.
+/// The shape is stable across versions: the payload is externally tagged by
+/// its variant name, the route is its string form, and binary data is a
+/// sequence of bytes. In JSON, an RPC request looks like:
+/// ```json
+/// {
+///   "payload": {"RpcRequest": {"id": 7, "method": {"Name": "dev.name"}, "arg": []}},
+///   "routing": "/0",
+///   "ttl": 0
+/// }
+/// ```
+/// Enumerations are tagged by name as well, with values not known to this
+/// version as `{"Unknown": <raw value>}`.
+///
+/// `ttl` limits how many more times the packet can be forwarded, up to
+/// `TIO_PACKET_MAX_TTL`. A TTL of 0 means it was not set and there is no
+/// limit; otherwise each hop decrements it, and the packet is dropped
+/// instead of being forwarded with a TTL of 1.
+///
+/// `received` is when the packet was received from the device, stamped by
+/// the port which read it and kept as it goes through the proxy, so that it
+/// does not depend on when a client got to it. It is not serialized.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     pub payload: Payload,
     pub routing: DeviceRoute,
     pub ttl: usize,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub received: Option<ReceiveTime>,
+}
+
+/// Time at which a packet was received by the host.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReceiveTime {
+    /// Monotonic time, to measure latencies.
+    pub instant: std::time::Instant,
+    /// Wall clock time, to align with other hosts.
+    pub system_time: std::time::SystemTime,
+}
+
+#[cfg(feature = "std")]
+impl ReceiveTime {
+    pub fn now() -> ReceiveTime {
+        ReceiveTime {
+            instant: std::time::Instant::now(),
+            system_time: std::time::SystemTime::now(),
+        }
+    }
+}
+
+/// Time at which a packet was received by the host. Without the `std`
+/// feature there is no clock, and packets are never stamped.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReceiveTime {
+    _private: (),
 }
 
 #[derive(Debug)]
@@ -115,6 +185,7 @@ pub enum Error {
     RoutingTooBig(Vec<u8>),
     PayloadTooSmall(Vec<u8>),
     InvalidPayload(Vec<u8>),
+    Framing(Vec<u8>),
 }
 
 #[repr(u8)]
@@ -148,8 +219,9 @@ struct TioPktHdr {
 }
 
 static TIO_PACKET_HEADER_SIZE: usize = 4;
-static TIO_PACKET_MAX_ROUTING_SIZE: usize = 8;
+pub(crate) static TIO_PACKET_MAX_ROUTING_SIZE: usize = 8;
 pub static TIO_PACKET_MAX_TOTAL_SIZE: usize = 512;
+pub static TIO_PACKET_MAX_TTL: usize = 15;
 static TIO_PACKET_MAX_PAYLOAD_SIZE: usize =
     TIO_PACKET_MAX_TOTAL_SIZE - TIO_PACKET_HEADER_SIZE - TIO_PACKET_MAX_ROUTING_SIZE;
 
@@ -174,7 +246,7 @@ impl TioPktHdr {
         }
 
         // If the packet type appears valid, wait to have a full header
-        if raw.len() < std::mem::size_of::<TioPktHdr>() {
+        if raw.len() < core::mem::size_of::<TioPktHdr>() {
             return Err(Error::NeedMore);
         }
         let pkt_hdr = TioPktHdr {
@@ -233,7 +305,7 @@ impl TioPktHdr {
     }
 
     fn payload_offset(&self) -> usize {
-        std::mem::size_of::<TioPktHdr>()
+        core::mem::size_of::<TioPktHdr>()
     }
 
     fn payload_size(&self) -> usize {
@@ -309,7 +381,7 @@ impl StreamDataPayload {
             stream_id: full_data[0] - TIO_PTYPE_STREAM0,
             first_sample_n: u32::from_le_bytes([raw[0], raw[1], raw[2], 0u8]),
             segment_id: raw[3],
-            data: raw[4..].to_vec(),
+            data: raw[4..].into(),
         })
     }
     fn serialize(&self) -> Result<Vec<u8>, ()> {
@@ -330,7 +402,7 @@ impl StreamDataPayload {
             payload_size as u16,
         );
         ret.extend([sample_ser[0], sample_ser[1], sample_ser[2], self.segment_id]);
-        ret.extend(&self.data);
+        ret.extend_from_slice(&self.data);
         Ok(ret)
     }
 }
@@ -339,7 +411,7 @@ impl GenericPayload {
     fn deserialize(raw: &[u8], full_data: &[u8]) -> Result<GenericPayload, Error> {
         Ok(GenericPayload {
             packet_type: full_data[0],
-            payload: raw.to_vec(),
+            payload: raw.into(),
         })
     }
     fn serialize(&self) -> Result<Vec<u8>, ()> {
@@ -347,12 +419,23 @@ impl GenericPayload {
             return Err(());
         }
         let mut ret = TioPktHdr::serialize_new_raw(self.packet_type, 0, self.payload.len() as u16);
-        ret.extend(&self.payload);
+        ret.extend_from_slice(&self.payload);
         Ok(ret)
     }
 }
 
 impl Payload {
+    /// Size of the serialized payload, with its header.
+    fn serialized_size(&self) -> usize {
+        match self {
+            Payload::StreamData(p) => TIO_PACKET_HEADER_SIZE + 4 + p.data.len(),
+            Payload::RpcReply(p) => TIO_PACKET_HEADER_SIZE + 2 + p.reply.len(),
+            Payload::Unknown(p) => TIO_PACKET_HEADER_SIZE + p.payload.len(),
+            // Rare enough to be sized by serializing them.
+            _ => self.serialize().map_or(0, |raw| raw.len()),
+        }
+    }
+
     fn serialize(&self) -> Result<Vec<u8>, ()> {
         match self {
             Payload::LogMessage(p) => p.serialize(),
@@ -361,6 +444,7 @@ impl Payload {
             Payload::RpcError(p) => p.serialize(),
             Payload::Heartbeat(p) => p.serialize(),
             Payload::Metadata(p) => p.serialize(),
+            Payload::LegacyTimebaseUpdate(p) => Ok(p.serialize()),
             Payload::LegacyStreamData(p) => p.serialize(),
             Payload::StreamData(p) => p.serialize(),
             Payload::Unknown(p) => p.serialize(),
@@ -371,7 +455,14 @@ impl Payload {
         hdr: &TioPktHdr,
         raw_payload: &[u8],
         full_data: &[u8],
+        version: ProtocolVersion,
     ) -> Result<Payload, Error> {
+        if !version.decodes(&hdr.ptype()) {
+            return Ok(Payload::Unknown(GenericPayload::deserialize(
+                raw_payload,
+                full_data,
+            )?));
+        }
         match hdr.ptype() {
             TioPktType::Invalid
             | TioPktType::Reserved0
@@ -402,9 +493,10 @@ impl Payload {
                 raw_payload,
                 full_data,
             )?)),
-            TioPktType::LegacyTimebaseUpdate
-            | TioPktType::LegacySourceUpdate
-            | TioPktType::LegacyStreamUpdate => {
+            TioPktType::LegacyTimebaseUpdate => Ok(Payload::LegacyTimebaseUpdate(
+                LegacyTimebaseInfoPayload::deserialize(raw_payload, full_data)?,
+            )),
+            TioPktType::LegacySourceUpdate | TioPktType::LegacyStreamUpdate => {
                 // For now we deserialize these just into generic payloads, so they can
                 // be sent around by the proxy. TODO: full ser/sed for legacy types,
                 // which would also let us get rid of TioPktHdr::serialize_new_raw,
@@ -439,26 +531,77 @@ impl Payload {
 }
 
 impl Packet {
+    /// Deserialize a packet in the current protocol version. Returns the
+    /// packet and the number of bytes it took in `raw`.
     pub fn deserialize(raw: &[u8]) -> Result<(Packet, usize), Error> {
+        Packet::deserialize_version(raw, ProtocolVersion::CURRENT)
+    }
+
+    /// Deserialize a packet as sent by a device speaking `version`.
+    pub fn deserialize_version(
+        raw: &[u8],
+        version: ProtocolVersion,
+    ) -> Result<(Packet, usize), Error> {
         let pkt_hdr = TioPktHdr::deserialize(raw)?;
         let pkt_len = pkt_hdr.packet_size();
         let payload_raw = &raw[pkt_hdr.payload_offset()..pkt_hdr.routing_offset()];
         let routing_raw = &raw[pkt_hdr.routing_offset()..pkt_len];
-        let payload = Payload::deserialize(&pkt_hdr, payload_raw, raw)?;
+        let payload = Payload::deserialize(&pkt_hdr, payload_raw, raw, version)?;
+        let routing = DeviceRoute::from_bytes(routing_raw)
+            .map_err(|_| Error::RoutingTooBig(raw[..pkt_len].to_vec()))?;
 
         Ok((
             Packet {
                 payload: payload,
-                routing: DeviceRoute::from_bytes(routing_raw)
-                    .expect("routing should have been validated in header deserialization"),
+                routing,
                 ttl: pkt_hdr.ttl(),
+                received: None,
             },
             pkt_len,
         ))
     }
 
+    /// Deserialize all the packets in `raw`, as read from a stream of bytes
+    /// sent by a device speaking `version`. After an invalid packet, one byte
+    /// is skipped to look for the next one; a partial packet at the end is
+    /// reported as `Error::NeedMore`.
+    ///
+    /// Whatever the input, this returns errors rather than panicking, which
+    /// makes it suitable as a fuzzing entry point.
+    pub fn deserialize_all(raw: &[u8], version: ProtocolVersion) -> Vec<Result<Packet, Error>> {
+        let mut ret = vec![];
+        let mut rest = raw;
+        while !rest.is_empty() {
+            match Packet::deserialize_version(rest, version) {
+                Ok((pkt, size)) => {
+                    ret.push(Ok(pkt));
+                    rest = &rest[size..];
+                }
+                Err(Error::NeedMore) => {
+                    ret.push(Err(Error::NeedMore));
+                    break;
+                }
+                Err(err) => {
+                    ret.push(Err(err));
+                    rest = &rest[1..];
+                }
+            }
+        }
+        ret
+    }
+
+    /// Size of the serialized packet, without serializing the data of
+    /// stream packets.
+    pub fn serialized_size(&self) -> usize {
+        self.payload.serialized_size() + self.routing.len()
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, ()> {
-        let ret = self.payload.serialize()?;
+        if self.ttl > TIO_PACKET_MAX_TTL {
+            return Err(());
+        }
+        let mut ret = self.payload.serialize()?;
+        ret[1] |= (self.ttl as u8) << 4;
         self.routing.serialize(ret)
     }
 }
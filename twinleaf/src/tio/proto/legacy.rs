@@ -1,9 +1,12 @@
 use super::{too_small, DataType, Error, TioPktHdr, TioPktType, TIO_PACKET_MAX_PAYLOAD_SIZE};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LegacyTimebaseSource {
     Invalid = 0,
     Local = 1,
@@ -15,6 +18,7 @@ pub enum LegacyTimebaseSource {
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LegacyTimebaseEpoch {
     Invalid = 0,
     Start = 1,
@@ -26,6 +30,7 @@ pub enum LegacyTimebaseEpoch {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LegacyTimebaseInfoPayload {
     pub id: u16,
     pub source: LegacyTimebaseSource,
@@ -38,7 +43,54 @@ pub struct LegacyTimebaseInfoPayload {
     pub source_id: [u8; 16],
 }
 
+impl LegacyTimebaseInfoPayload {
+    /// Size of the payload on the wire.
+    const SIZE: usize = 44;
+
+    pub fn deserialize(raw: &[u8], full_data: &[u8]) -> Result<LegacyTimebaseInfoPayload, Error> {
+        if raw.len() < Self::SIZE {
+            return Err(too_small(full_data));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        Ok(LegacyTimebaseInfoPayload {
+            id: u16::from_le_bytes([raw[0], raw[1]]),
+            source: LegacyTimebaseSource::from(raw[2]),
+            epoch: LegacyTimebaseEpoch::from(raw[3]),
+            start_time: u64::from_le_bytes(raw[4..12].try_into().unwrap()),
+            period_numerator_us: u32_at(12),
+            period_denominator_us: u32_at(16),
+            flags: u32_at(20),
+            stability: f32::from_le_bytes(raw[24..28].try_into().unwrap()),
+            source_id: raw[28..44].try_into().unwrap(),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret =
+            TioPktHdr::serialize_new(TioPktType::LegacyTimebaseUpdate, 0, Self::SIZE as u16);
+        ret.extend(self.id.to_le_bytes());
+        ret.push(self.source.into());
+        ret.push(self.epoch.into());
+        ret.extend(self.start_time.to_le_bytes());
+        ret.extend(self.period_numerator_us.to_le_bytes());
+        ret.extend(self.period_denominator_us.to_le_bytes());
+        ret.extend(self.flags.to_le_bytes());
+        ret.extend(self.stability.to_le_bytes());
+        ret.extend_from_slice(&self.source_id);
+        ret
+    }
+
+    /// Time between samples of the timebase, in seconds.
+    pub fn period(&self) -> f64 {
+        if self.period_denominator_us == 0 {
+            return 0.0;
+        }
+        f64::from(self.period_numerator_us) / f64::from(self.period_denominator_us) * 1e-6
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LegacySourceInfoPayload {
     pub id: u16,
     pub timebase_id: u16,
@@ -51,6 +103,7 @@ pub struct LegacySourceInfoPayload {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LegacyStreamComponentInfo {
     pub source_id: u16,
     pub flags: u16,
@@ -59,6 +112,7 @@ pub struct LegacyStreamComponentInfo {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LegacyStreamInfoPayload {
     pub id: u16,
     pub timebase_id: u16,
@@ -70,9 +124,10 @@ pub struct LegacyStreamInfoPayload {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LegacyStreamDataPayload {
     pub sample_n: u32,
-    pub data: Vec<u8>,
+    pub data: Arc<[u8]>,
 }
 
 impl LegacyStreamDataPayload {
@@ -82,7 +137,7 @@ impl LegacyStreamDataPayload {
         }
         Ok(LegacyStreamDataPayload {
             sample_n: u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
-            data: raw[4..].to_vec(),
+            data: raw[4..].into(),
         })
     }
     pub fn serialize(&self) -> Result<Vec<u8>, ()> {
@@ -93,7 +148,7 @@ impl LegacyStreamDataPayload {
         let mut ret =
             TioPktHdr::serialize_new(TioPktType::LegacyStreamData, 0, payload_size as u16);
         ret.extend(self.sample_n.to_le_bytes());
-        ret.extend(&self.data);
+        ret.extend_from_slice(&self.data);
         Ok(ret)
     }
 }
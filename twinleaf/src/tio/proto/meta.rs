@@ -2,9 +2,13 @@ use super::{
     too_small, vararg, DataType, Error, TioPktHdr, TioPktType, TIO_PACKET_MAX_PAYLOAD_SIZE,
 };
 use super::{DeviceRoute, Packet, Payload};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceMetadata {
     pub serial_number: String,
     pub firmware_hash: String,
@@ -14,6 +18,7 @@ pub struct DeviceMetadata {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StreamMetadata {
     pub stream_id: u8,
     pub name: String,
@@ -26,6 +31,7 @@ pub struct StreamMetadata {
 #[derive(Debug, Clone, PartialEq)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MetadataEpoch {
     Invalid = 0,
     Zero = 1,
@@ -38,6 +44,7 @@ pub enum MetadataEpoch {
 #[derive(Debug, Clone, PartialEq)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MetadataFilter {
     Unfiltered = 0,
     FirstOrderCascade1 = 1,
@@ -50,6 +57,7 @@ static TL_METADATA_SEGMENT_VALID: u8 = 0x01;
 static TL_METADATA_SEGMENT_ACTIVE: u8 = 0x02;
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SegmentMetadata {
     pub stream_id: u8,
     pub segment_id: u8,
@@ -65,6 +73,7 @@ pub struct SegmentMetadata {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ColumnMetadata {
     pub stream_id: u8,
     pub index: usize,
@@ -75,6 +84,7 @@ pub struct ColumnMetadata {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MetadataContent {
     Device(DeviceMetadata),
     Stream(StreamMetadata),
@@ -86,6 +96,7 @@ pub enum MetadataContent {
 #[derive(Debug, Clone)]
 #[repr(u8)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MetadataType {
     Device = 1,
     Stream = 2,
@@ -100,6 +111,7 @@ static TL_METADATA_UPDATE: u8 = 0x02;
 static TL_METADATA_LAST: u8 = 0x04;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MetadataPayload {
     pub content: MetadataContent,
     pub flags: u8,
@@ -162,6 +174,7 @@ impl DeviceMetadata {
             }),
             routing: DeviceRoute::root(),
             ttl: 0,
+            received: None,
         }
     }
 }
@@ -219,6 +232,7 @@ impl StreamMetadata {
             }),
             routing: DeviceRoute::root(),
             ttl: 0,
+            received: None,
         }
     }
 }
@@ -292,6 +306,7 @@ impl SegmentMetadata {
             }),
             routing: DeviceRoute::root(),
             ttl: 0,
+            received: None,
         }
     }
 }
@@ -351,6 +366,7 @@ impl ColumnMetadata {
             }),
             routing: DeviceRoute::root(),
             ttl: 0,
+            received: None,
         }
     }
 }
@@ -1,7 +1,14 @@
 use super::TioPktHdr;
 use super::TIO_PACKET_MAX_ROUTING_SIZE;
+use alloc::vec;
+use alloc::vec::Vec;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Route to a device in the tree of devices, as the hops from the root,
+/// written as `/1/3` (the root device is `/`).
+///
+/// Routes are ordered as the tree is walked depth first, so that a device
+/// comes right before the devices behind it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct DeviceRoute {
     route: Vec<u8>,
 }
@@ -46,13 +53,40 @@ impl DeviceRoute {
         self.route.len()
     }
 
-    pub fn iter(&self) -> std::slice::Iter<u8> {
+    /// True for the route of the root device.
+    pub fn is_empty(&self) -> bool {
+        self.route.is_empty()
+    }
+
+    /// Hops of the route, from the root.
+    pub fn iter(&self) -> core::slice::Iter<'_, u8> {
         self.route.iter()
     }
 
+    /// Route of the hub this device is behind, or None for the root device.
+    pub fn parent(&self) -> Option<DeviceRoute> {
+        let (_, parent) = self.route.split_last()?;
+        Some(DeviceRoute {
+            route: parent.to_vec(),
+        })
+    }
+
+    /// Route of the device behind this one at `hop`.
+    pub fn child(&self, hop: u8) -> DeviceRoute {
+        let mut route = self.route.clone();
+        route.push(hop);
+        DeviceRoute { route }
+    }
+
+    /// True if `other` is this device or one of the hubs it is behind,
+    /// so that this route is in the subtree rooted at `other`.
+    pub fn starts_with(&self, other: &DeviceRoute) -> bool {
+        self.route.starts_with(&other.route)
+    }
+
     pub fn serialize(&self, mut rest_of_packet: Vec<u8>) -> Result<Vec<u8>, ()> {
         if (self.route.len() > TIO_PACKET_MAX_ROUTING_SIZE)
-            || (rest_of_packet.len() < std::mem::size_of::<TioPktHdr>())
+            || (rest_of_packet.len() < core::mem::size_of::<TioPktHdr>())
         {
             Err(())
         } else {
@@ -83,10 +117,27 @@ impl DeviceRoute {
     }
 }
 
-use std::fmt::{Display, Formatter};
+impl<'a> IntoIterator for &'a DeviceRoute {
+    type Item = &'a u8;
+    type IntoIter = core::slice::Iter<'a, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl core::str::FromStr for DeviceRoute {
+    type Err = ();
+
+    fn from_str(route_str: &str) -> Result<DeviceRoute, ()> {
+        DeviceRoute::from_str(route_str)
+    }
+}
+
+use core::fmt::{Display, Formatter};
 
 impl Display for DeviceRoute {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         if self.route.len() == 0 {
             write!(f, "/")?;
         } else {
@@ -97,3 +148,20 @@ impl Display for DeviceRoute {
         Ok(())
     }
 }
+
+/// Routes are serialized as their string form, e.g. `"/0/1"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for DeviceRoute {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DeviceRoute {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<DeviceRoute, D::Error> {
+        let route = alloc::string::String::deserialize(deserializer)?;
+        DeviceRoute::from_str(&route)
+            .map_err(|_| serde::de::Error::custom(alloc::format!("invalid route '{}'", route)))
+    }
+}
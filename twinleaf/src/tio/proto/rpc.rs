@@ -1,13 +1,17 @@
 use super::{too_small, Error, TioPktHdr, TioPktType, TIO_PACKET_MAX_PAYLOAD_SIZE};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RpcMethod {
     Id(u16),
     Name(String),
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RpcRequestPayload {
     pub id: u16,
     pub method: RpcMethod,
@@ -15,6 +19,7 @@ pub struct RpcRequestPayload {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RpcReplyPayload {
     pub id: u16,
     pub reply: Vec<u8>,
@@ -23,6 +28,7 @@ pub struct RpcReplyPayload {
 #[derive(Debug, Clone, Copy)]
 #[repr(u16)]
 #[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RpcErrorCode {
     NoError = 0,
     Undefined = 1,
@@ -47,6 +53,7 @@ pub enum RpcErrorCode {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RpcErrorPayload {
     pub id: u16,
     pub error: RpcErrorCode,
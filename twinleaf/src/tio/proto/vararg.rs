@@ -1,4 +1,6 @@
 use super::{too_small, Error};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 // Split a varlen message into fixed and variable length parts
 pub fn split<'a>(raw: &'a [u8], full_data: &[u8]) -> Result<(&'a [u8], &'a [u8]), Error> {
@@ -0,0 +1,58 @@
+//! Protocol versions
+//!
+//! The TIO wire format evolved along with the device firmware. All the
+//! differences in how packets are decoded across versions are captured here,
+//! so that supporting a new firmware revision means adding a version rather
+//! than changing how packets from older devices are understood.
+//!
+//! - `Legacy` devices describe their data with the timebase, source and
+//!   stream update packets, and send all data as legacy stream data.
+//! - `Metadata` devices describe their data with metadata packets, and send
+//!   each stream as its own packet type. Legacy packets are still understood.
+
+use super::TioPktType;
+use num_enum::{FromPrimitive, IntoPrimitive};
+
+/// RPC reporting the protocol version implemented by a device. Devices
+/// which do not implement it predate versioning, and speak `Metadata`.
+pub static PROTOCOL_VERSION_RPC: &str = "dev.protocol";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+#[derive(FromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProtocolVersion {
+    Legacy = 1,
+    Metadata = 2,
+    #[num_enum(catch_all)]
+    Unknown(u8),
+}
+
+impl ProtocolVersion {
+    /// Most recent version understood by this library.
+    pub const CURRENT: ProtocolVersion = ProtocolVersion::Metadata;
+
+    /// True if this library can decode the packets of this version.
+    pub fn is_supported(&self) -> bool {
+        !matches!(self, ProtocolVersion::Unknown(_))
+    }
+
+    /// Whether packets of type `ptype` are decoded into their payload type.
+    /// Packet types which are not part of a version are still accepted, as
+    /// generic payloads, so they can be forwarded as they are.
+    pub(super) fn decodes(&self, ptype: &TioPktType) -> bool {
+        match self {
+            ProtocolVersion::Legacy => {
+                !matches!(ptype, TioPktType::Metadata | TioPktType::UnknownOrStream(_))
+            }
+            // Decode as much as possible of newer versions
+            ProtocolVersion::Metadata | ProtocolVersion::Unknown(_) => true,
+        }
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> ProtocolVersion {
+        ProtocolVersion::CURRENT
+    }
+}
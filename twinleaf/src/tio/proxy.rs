@@ -8,63 +8,631 @@
 //!
 //! Note: the proxy runs in a dedicated thread.
 
+use super::budget::MemoryBudget;
 use super::port;
 use super::proto::{self, DeviceRoute, Packet};
-use super::proxy_core::{ProxyClient, ProxyCore};
+use super::proxy_core::{Downstream, DownstreamRequest, ProxyClient, ProxyCore};
 use super::util;
 use super::util::{TioRpcReplyable, TioRpcRequestable};
 
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use crossbeam::channel;
 
+/// Client id of the proxy itself, in events such as `Event::RpcRemap`, for
+/// the RPCs it sends to devices to negotiate the port rate or to restore
+/// settings after a reconnection. Ports get ids starting from 1.
+pub static INTERNAL_CLIENT_ID: u64 = 0;
+
+/// Wire ids reserved for the RPCs the proxy sends itself. Requests from
+/// ports are remapped to other wire ids, whatever their own ids, so that
+/// busy ports cannot delay or disrupt the proxy's requests.
+pub static INTERNAL_RPC_WIRE_IDS: RangeInclusive<u16> = 0xFF00..=0xFFFF;
+
+/// Range of RPC timeouts accepted by the proxy.
+static MIN_RPC_TIMEOUT: Duration = Duration::from_millis(100);
+static MAX_RPC_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// Status event that ProxyCore sent back to an optional user specified channel
 #[derive(Debug)]
 pub enum Event {
     SensorConnected,
     SensorDisconnected,
     SensorReconnected,
-    FailedToConnect,
-    FailedToReconnect,
+    /// The device could not be opened when the proxy started, for this
+    /// reason.
+    FailedToConnect(port::ConnectError),
+    /// The proxy gave up reopening the device, which last failed for this
+    /// reason.
+    FailedToReconnect(port::ConnectError),
+    /// The port of the device is in use, by the process with this id if
+    /// known, so it could not be opened. Reported once until it is opened.
+    PortBusy(Option<u32>),
     Exiting,
     ProtocolError(proto::Error),
     FatalError(port::RecvError),
     NewClient(u64),
     RpcRemap((u64, u16), u16),
+    /// All wire RPC ids are in use, so a request from a client was queued
+    /// until one becomes available.
+    RpcQueued((u64, u16)),
+    /// A client sent RPC requests to a device faster than allowed by the
+    /// proxy's `RpcRateLimit`. The request was answered with a `Busy` error.
+    RpcThrottled((u64, u16)),
     RpcRestore(u16, (u64, u16)),
     RpcRestoreNotFound(u16),
     RpcClientNotFound(u64),
     RpcTimeout(u16),
     RpcCancel(u16),
-    ClientSendFailed(u64),
+    /// The proxy dropped a client it could not send packets to.
+    ClientDropped(u64, ClientDropReason),
     ClientTerminated(u64),
+    /// A client sent a packet addressed outside of its scope. The packet
+    /// was not forwarded, and an RPC error was returned for requests.
+    RouteOutOfScope(u64, DeviceRoute),
+    /// A client sent a packet for this route whose TTL ran out, see
+    /// `proto::Packet`. The packet was not forwarded, and an RPC error was
+    /// returned for requests.
+    TtlExpired(u64, DeviceRoute),
+    /// A client asked to close the link to the root device and open it
+    /// again, see `Port::reconnect`.
+    ReconnectRequested(u64),
+    /// A client changed the url of the device, see `Port::set_url`. The
+    /// link is reopened at the new url.
+    UrlChanged(String),
+    /// The session announced by the root device changed, so it restarted.
+    /// Pending RPCs were cancelled, and clients were sent the heartbeat
+    /// with the new session.
     RootDeviceRestarted,
+    /// The root device was asked to enter low power mode, so it is
+    /// expected to stop sending data.
+    RootDeviceSleeping,
+    /// Data was received from the root device after it entered low power mode.
+    RootDeviceAwake,
+    /// The device tree of another proxy was attached under this route,
+    /// see `Interface::attach`.
+    DownstreamAttached(DeviceRoute),
+    /// The proxy attached under this route shut down, so its devices were
+    /// removed from the tree and their pending RPCs cancelled.
+    DownstreamDetached(DeviceRoute),
+    /// An RPC registered by a port with `PortBuilder::on_reconnect` failed
+    /// when replayed after the device reconnected.
+    ReconnectRpcFailed(proto::RpcErrorCode),
     AutoRateGaveUp,
     AutoRateQueried(u32),
     AutoRateRpcError(proto::RpcErrorCode),
-    AutoRateRpcInvalid,
+    /// The reply to the rate query RPC had this unexpected size, so the
+    /// rate of the link was left unchanged.
+    AutoRateRpcInvalid(usize),
     AutoRateIncompatible(u32),
     AutoRateCompatible(u32),
     AutoRateWait,
     AutoRateSet(u32),
+    /// Data was received after switching the link to the rate negotiated,
+    /// so it works at that rate. Acquisition can start once this arrives.
+    AutoRateConfirmed(u32),
+    /// The link of the device at this route to its hub was set to this
+    /// rate, see `ProxyBuilder::autorate_route`.
+    RouteAutoRateSet(DeviceRoute, u32),
+    /// The device at this route cannot run its link at the requested rate;
+    /// the closest it supports is given.
+    RouteAutoRateIncompatible(DeviceRoute, u32),
+    /// Negotiating the rate of the device at this route failed.
+    RouteAutoRateRpcError(DeviceRoute, proto::RpcErrorCode),
+    /// The reply of the device at this route to the rate query RPC had this
+    /// unexpected size, so the rate of its link was left unchanged.
+    RouteAutoRateRpcInvalid(DeviceRoute, usize),
     SetRate(u32),
     SetRateFailed,
     NoData,
+    /// The device sends data at close to the rate the link can carry, in
+    /// bits per second, so packets get delayed and eventually lost. The
+    /// device streams should be slowed down, or the link rate raised.
+    LinkOverloaded(u32, u32),
+    /// The data sent by the device fits in the link again.
+    LinkLoadNormal(u32, u32),
+}
+
+/// Why the proxy dropped a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientDropReason {
+    /// The client went away.
+    Disconnected,
+    /// The client did not keep up: its queue was still full after many
+    /// packets in a row were dropped.
+    Unresponsive,
+    /// The client went over the memory budget, with `BudgetPolicy::DropClient`.
+    OverBudget,
+}
+
+/// Change in the connection to the device, sent to ports created with
+/// `PortBuilder::link_events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// The device disconnected. Pending RPCs were cancelled.
+    Disconnected,
+    /// The device is connected again.
+    Reconnected,
+}
+
+/// Outcome of the rate autonegotiation of the link to the root device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoRateStatus {
+    /// The link has a fixed rate, or autonegotiation is disabled.
+    Unavailable,
+    /// The proxy is negotiating a higher rate with the device.
+    Negotiating,
+    /// The link was switched to the target rate, and the proxy waits for
+    /// data at that rate.
+    Confirming,
+    /// The link runs at the target rate, and data was received at it, see
+    /// `Event::AutoRateConfirmed`.
+    Negotiated,
+    /// Negotiation failed, or the link stopped working at the target rate,
+    /// so it runs at the default rate.
+    FellBack,
+}
+
+/// Names of the RPCs the proxy negotiates link rates with, which differ
+/// between firmwares. Both take a rate in bits per second as a `u32`, and
+/// reply with a rate as an unsigned integer of 2, 4 or 8 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoRateRpcs {
+    /// Returns the supported rate nearest to the one given.
+    pub query: String,
+    /// Switches the link to the rate given.
+    pub set: String,
+}
+
+impl AutoRateRpcs {
+    pub fn new(query: &str, set: &str) -> AutoRateRpcs {
+        AutoRateRpcs {
+            query: query.to_string(),
+            set: set.to_string(),
+        }
+    }
+}
+
+impl Default for AutoRateRpcs {
+    /// The RPCs of current firmwares.
+    fn default() -> AutoRateRpcs {
+        AutoRateRpcs::new("dev.port.rate.near", "dev.port.rate")
+    }
+}
+
+/// State of the link between the proxy and the root device, as returned
+/// by `Port::link_status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkStatus {
+    pub connected: bool,
+    /// Rate the link runs at, for links with a settable rate which are
+    /// connected.
+    pub rate_bps: Option<u32>,
+    /// Rate the link starts at, and falls back to.
+    pub default_bps: Option<u32>,
+    /// Rate the proxy tries to bring the link to.
+    pub target_bps: Option<u32>,
+    pub autorate: AutoRateStatus,
+    /// Estimate of the bits per second the device sent over the last
+    /// second, for links with a settable rate.
+    pub rx_bps: Option<u32>,
+    /// The device sends more than the link can carry without delays, see
+    /// `Event::LinkOverloaded`.
+    pub overloaded: bool,
+    /// Packets and errors received over the link since the proxy started,
+    /// across reconnections.
+    pub counters: port::LinkCounters,
+}
+
+impl LinkStatus {
+    pub(crate) fn new() -> LinkStatus {
+        LinkStatus {
+            connected: false,
+            rate_bps: None,
+            default_bps: None,
+            target_bps: None,
+            autorate: AutoRateStatus::Unavailable,
+            rx_bps: None,
+            overloaded: false,
+            counters: port::LinkCounters::default(),
+        }
+    }
+
+    /// Fraction of the data received which was corrupted.
+    pub fn error_rate(&self) -> f64 {
+        let errors = self.counters.crc_errors + self.counters.framing_errors;
+        let total = self.counters.packets + errors;
+        if total == 0 {
+            0.0
+        } else {
+            errors as f64 / total as f64
+        }
+    }
+
+    /// Fraction of the link rate used by the data the device sends.
+    pub fn load(&self) -> Option<f64> {
+        match (self.rx_bps, self.rate_bps) {
+            (Some(rx), Some(rate)) if rate > 0 => Some(rx as f64 / rate as f64),
+            _ => None,
+        }
+    }
+
+    /// True if the link runs slower than it could, after autonegotiation
+    /// failed.
+    pub fn is_fallback(&self) -> bool {
+        self.autorate == AutoRateStatus::FellBack
+    }
+}
+
+/// Activity of a device the proxy received packets from, as returned by
+/// `Interface::topology`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteActivity {
+    /// Packets received from the device since the proxy started.
+    pub packets: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+impl RouteActivity {
+    /// True if the device sent a packet within `timeout`. Devices send
+    /// heartbeats even when idle, so a few seconds tell live devices from
+    /// ones which went away.
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.last_seen.elapsed() <= timeout
+    }
+}
+
+/// Devices the proxy received packets from, by route.
+pub type Topology = BTreeMap<DeviceRoute, RouteActivity>;
+
+/// Changes to an existing port, sent by the port to the proxy.
+#[derive(Debug, Clone)]
+pub(crate) enum ClientControl {
+    Scope {
+        scope: DeviceRoute,
+        depth: usize,
+    },
+    Forwarding(ForwardingPolicy),
+    /// Timeout of the next RPC request with this id.
+    RpcTimeout {
+        id: u16,
+        timeout: Duration,
+    },
+    /// The client is no longer interested in the reply to the RPC request
+    /// in flight with this id.
+    RpcCancel {
+        id: u16,
+    },
+    /// Close the link to the root device and open it again.
+    Reconnect,
+    /// Close the link to the root device and open this url instead.
+    SetUrl(String),
+}
+
+/// Limit on the rate of RPC requests each client may send to each device,
+/// so that a client cannot monopolize a device. Requests over the limit are
+/// answered with an `RpcErrorCode::Busy` error without reaching the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RpcRateLimit {
+    /// Sustained requests per second.
+    pub rate: f64,
+    /// Requests which can be sent at once after a pause.
+    pub burst: u32,
+}
+
+impl RpcRateLimit {
+    pub fn new(rate: f64, burst: u32) -> RpcRateLimit {
+        RpcRateLimit {
+            rate,
+            burst: burst.max(1),
+        }
+    }
+}
+
+/// Which packets from the device tree a port receives, besides the replies to
+/// its own RPCs. RPC requests sent by devices are always forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardingPolicy {
+    /// Sample data.
+    pub data: bool,
+    pub heartbeats: bool,
+    /// Text log messages.
+    pub text: bool,
+    /// Metadata and legacy timebase, source and stream updates.
+    pub timebase: bool,
+    /// Any other packet, such as unknown payload types.
+    pub other: bool,
+    /// Only forward one of every `decimation` sample data packets of each
+    /// stream, which is one of every `decimation` samples for devices sending
+    /// a sample per packet. 0 and 1 forward all sample data.
+    pub decimation: u32,
+}
+
+impl ForwardingPolicy {
+    /// Forward everything.
+    pub fn all() -> ForwardingPolicy {
+        ForwardingPolicy {
+            data: true,
+            heartbeats: true,
+            text: true,
+            timebase: true,
+            other: true,
+            decimation: 1,
+        }
+    }
+
+    /// Only forward RPCs.
+    pub fn rpc_only() -> ForwardingPolicy {
+        ForwardingPolicy {
+            data: false,
+            heartbeats: false,
+            text: false,
+            timebase: false,
+            other: false,
+            decimation: 1,
+        }
+    }
+
+    /// Same as setting `heartbeats`, `text`, `timebase` and `other`.
+    pub fn with_nonrpc(mut self, forward: bool) -> ForwardingPolicy {
+        self.heartbeats = forward;
+        self.text = forward;
+        self.timebase = forward;
+        self.other = forward;
+        self
+    }
+
+    /// Same as setting `decimation`.
+    pub fn with_decimation(mut self, decimation: u32) -> ForwardingPolicy {
+        self.decimation = decimation;
+        self
+    }
+
+    /// True if packets with this payload should be forwarded. Does not
+    /// account for `decimation`, which depends on the packets seen before.
+    pub fn forwards(&self, payload: &proto::Payload) -> bool {
+        use proto::Payload;
+        match payload {
+            Payload::RpcRequest(_) | Payload::RpcReply(_) | Payload::RpcError(_) => true,
+            Payload::LegacyStreamData(_) | Payload::StreamData(_) => self.data,
+            Payload::Heartbeat(_) => self.heartbeats,
+            Payload::LogMessage(_) => self.text,
+            Payload::Metadata(_)
+            | Payload::LegacyTimebaseUpdate(_)
+            | Payload::LegacySourceUpdate(_)
+            | Payload::LegacyStreamUpdate(_) => self.timebase,
+            Payload::Unknown(_) => self.other,
+        }
+    }
+}
+
+impl Default for ForwardingPolicy {
+    fn default() -> ForwardingPolicy {
+        ForwardingPolicy::all()
+    }
 }
 
 /// A port which communicates with a proxy via `crossbeam::channel`s
 pub struct Port {
     tx: channel::Sender<Packet>,
     rx: channel::Receiver<Packet>,
+    /// RPC replies and errors, which have priority over packets in `rx`.
+    rpc_rx: channel::Receiver<Packet>,
     depth: usize,
+    /// Absolute route of the root of this port's subtree.
+    scope: DeviceRoute,
+    rpc_timeout: Duration,
+    forwarding: ForwardingPolicy,
+    /// Changes to the scope and forwarding of the port, applied by the
+    /// proxy before any packet sent afterwards.
+    control: channel::Sender<ClientControl>,
+    /// Absolute route and depth of the subtree the port was opened for,
+    /// within which `set_scope` can move it.
+    bounds: (DeviceRoute, usize),
+    link_rx: Option<channel::Receiver<LinkEvent>>,
+    /// Kept up to date by the proxy.
+    link_status: Arc<Mutex<LinkStatus>>,
+    topology: Arc<Mutex<Topology>>,
+    /// Used to create ports for subtrees of this one. This is weak so
+    /// that the proxy still shuts down when its `Interface` is dropped.
+    clients: Weak<ClientQueue>,
+    /// RPCs in flight with an `RpcHandle`.
+    rpcs: Mutex<PendingRpcs>,
+}
+
+/// How long a thread waiting on an `RpcHandle` waits on the port before
+/// checking for replies received by other threads.
+static RPC_HANDLE_POLL: Duration = Duration::from_millis(10);
+
+/// How long `RpcHandle::wait` waits past the timeout of the RPC for the
+/// proxy to time it out, before giving up on it itself.
+static RPC_HANDLE_GRACE: Duration = Duration::from_secs(1);
+
+/// RPCs of a port in flight with an `RpcHandle`, and the replies received
+/// while waiting for another one.
+#[derive(Default)]
+struct PendingRpcs {
+    last_id: u16,
+    in_flight: HashSet<u16>,
+    replies: HashMap<u16, Packet>,
+    /// RPCs cancelled, whose replies might still be received.
+    cancelled: HashSet<u16>,
+    /// Replies to requests sent directly with `Port::send`, received while
+    /// waiting for an RPC, returned by `Port::recv` and `Port::try_recv`.
+    unclaimed: VecDeque<Packet>,
+}
+
+impl PendingRpcs {
+    /// Id for a new request, not in flight. Id 0 is left for requests sent
+    /// directly with `Port::send`.
+    fn alloc(&mut self) -> u16 {
+        loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            if self.last_id != 0 && self.in_flight.insert(self.last_id) {
+                self.cancelled.remove(&self.last_id);
+                return self.last_id;
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u16) {
+        self.in_flight.remove(&id);
+        self.replies.remove(&id);
+    }
+}
+
+/// RPC in flight, returned by `Port::start_rpc`, so that several RPCs can
+/// be in flight at once from one thread. Dropping the handle before the
+/// RPC completes cancels it: the proxy drops its reply.
+/// ```no_run
+/// # use twinleaf::tio::{proto::DeviceRoute, proxy};
+/// let proxy = proxy::Interface::new("tcp://localhost");
+/// let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+/// let name = port.start_rpc("dev.name", &[]);
+/// let serial = port.start_rpc("dev.serial", &[]);
+/// println!("{:?} {:?}", name.wait_as::<String>(), serial.wait_as::<String>());
+/// ```
+/// Handles receive all the RPC replies of their port. Replies to requests
+/// sent directly with `Port::send` meanwhile are kept for `Port::recv` and
+/// `Port::try_recv`, but not seen on `Port::rpc_receiver`, so requests should
+/// not be sent both ways on a port waited on with `select!`.
+pub struct RpcHandle<'a> {
+    port: &'a Port,
+    id: u16,
+    /// Failure to send the request, returned when waiting.
+    send_error: Option<SendError>,
+    /// When `wait` gives up, if the proxy did not time the RPC out.
+    deadline: Instant,
+    done: bool,
+}
+
+impl RpcHandle<'_> {
+    /// Id of the request on the port.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Wait for the RPC to complete, and return its reply. The RPC fails
+    /// with `RpcErrorCode::Timeout` if the proxy did not time it out shortly
+    /// after its timeout.
+    pub fn wait(mut self) -> Result<Vec<u8>, RpcError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        match self.wait_timeout(remaining) {
+            Some(ret) => ret,
+            None => Err(RpcError::ExecError(proto::RpcErrorPayload {
+                id: self.id,
+                error: proto::RpcErrorCode::Timeout,
+                extra: vec![],
+            })),
+        }
+    }
+
+    /// Same as `wait`, parsing the reply as a `T`.
+    pub fn wait_as<T: TioRpcReplyable<T>>(self) -> Result<T, RpcError> {
+        let ret = self.wait()?;
+        T::from_reply(&ret).map_err(|_| RpcError::TypeError)
+    }
+
+    /// Wait at most `timeout` for the RPC to complete. Returns None if it
+    /// did not complete in time, in which case it is still in flight, or if
+    /// its outcome was already returned.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<Result<Vec<u8>, RpcError>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(ret) = self.poll(remaining.min(RPC_HANDLE_POLL)) {
+                return Some(ret);
+            }
+            if remaining.is_zero() {
+                return None;
+            }
+        }
+    }
+
+    /// Cancel the RPC, which the device might still execute. Same as
+    /// dropping the handle.
+    pub fn cancel(self) {}
+
+    /// Check for the reply, waiting at most `wait` for packets.
+    fn poll(&mut self, wait: Duration) -> Option<Result<Vec<u8>, RpcError>> {
+        if self.done {
+            return None;
+        }
+        if let Some(err) = self.send_error.take() {
+            self.done = true;
+            return Some(Err(RpcError::SendFailed(err)));
+        }
+        let stored = self.port.rpcs.lock().unwrap().replies.remove(&self.id);
+        if let Some(pkt) = stored {
+            return self.complete(pkt);
+        }
+        match self.port.rpc_rx.recv_timeout(wait) {
+            Ok(pkt) => {
+                let id = match &pkt.payload {
+                    proto::Payload::RpcReply(rep) => rep.id,
+                    proto::Payload::RpcError(err) => err.id,
+                    _ => return None,
+                };
+                if id == self.id {
+                    return self.complete(pkt);
+                }
+                // Keep replies of other handles and of requests sent
+                // directly, drop those of cancelled RPCs.
+                let mut rpcs = self.port.rpcs.lock().unwrap();
+                if rpcs.in_flight.contains(&id) {
+                    rpcs.replies.insert(id, pkt);
+                } else if !rpcs.cancelled.remove(&id) {
+                    rpcs.unclaimed.push_back(pkt);
+                }
+                None
+            }
+            Err(channel::RecvTimeoutError::Timeout) => None,
+            Err(channel::RecvTimeoutError::Disconnected) => {
+                self.done = true;
+                self.port.rpcs.lock().unwrap().remove(self.id);
+                Some(Err(RpcError::RecvFailed(RecvError::ProxyDisconnected)))
+            }
+        }
+    }
+
+    /// Outcome of the RPC, from its reply or error packet.
+    fn complete(&mut self, pkt: Packet) -> Option<Result<Vec<u8>, RpcError>> {
+        self.done = true;
+        self.port.rpcs.lock().unwrap().remove(self.id);
+        Some(match pkt.payload {
+            proto::Payload::RpcError(err) => Err(RpcError::ExecError(err)),
+            proto::Payload::RpcReply(rep) => Ok(rep.reply),
+            _ => Err(RpcError::TypeError),
+        })
+    }
+}
+
+impl Drop for RpcHandle<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut rpcs = self.port.rpcs.lock().unwrap();
+        rpcs.remove(self.id);
+        if self.send_error.is_none() {
+            rpcs.cancelled.insert(self.id);
+            let _ = self.port.cancel_rpc(self.id);
+        }
+    }
 }
 
+/// Failure to send a packet, which is given back. It is boxed to keep
+/// this and `RpcError` small.
 #[derive(Debug, Clone)]
 pub enum SendError {
-    WouldBlock(Packet),
-    ProxyDisconnected(Packet),
-    InvalidRoute(Packet),
+    WouldBlock(Box<Packet>),
+    ProxyDisconnected(Box<Packet>),
+    InvalidRoute(Box<Packet>),
 }
 
 #[derive(Debug, Clone)]
@@ -79,6 +647,83 @@ pub enum RpcError {
     ExecError(proto::RpcErrorPayload),
     RecvFailed(RecvError),
     TypeError,
+    /// No port could be opened for the RPC, see `Interface::rpc`.
+    PortFailed(PortError),
+}
+
+/// Why `Port::ping` got no reply from the device.
+#[derive(Debug, Clone)]
+pub enum PingError {
+    /// The proxy is gone.
+    ProxyDisconnected,
+    /// No reply within the timeout.
+    Timeout,
+    /// The request failed with this error, from the proxy or the device.
+    Failed(proto::RpcErrorCode),
+}
+
+/// RPC sent by `Port::ping`, cheap and implemented by all devices.
+static PING_RPC: &str = "dev.name";
+
+/// How `Port::rpc_with_retry` handles RPCs which time out. Only idempotent
+/// RPCs, which have the same effect when executed more than once, are retried:
+/// a request might time out after the device executed it, if the reply was lost.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each retry after it.
+    pub backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+    /// Whether the RPC can safely be retried.
+    pub idempotent: bool,
+}
+
+impl RetryPolicy {
+    /// Never retry, for RPCs which are not idempotent.
+    pub fn once() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            idempotent: false,
+        }
+    }
+
+    /// Retry an idempotent RPC up to `attempts` times in total.
+    pub fn idempotent(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            idempotent: true,
+            ..RetryPolicy::once()
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> RetryPolicy {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    fn retries(&self) -> u32 {
+        if self.idempotent {
+            self.attempts.saturating_sub(1)
+        } else {
+            0
+        }
+    }
+}
+
+/// Failure of `Port::rpc_with_retry`.
+#[derive(Debug, Clone)]
+pub enum RpcRetryError {
+    /// No reply from the device, after this many attempts.
+    Timeout(u32),
+    /// The device replied with an error.
+    Device(proto::RpcErrorPayload),
+    /// Communication with the proxy failed.
+    Transport(RpcError),
 }
 
 impl Port {
@@ -86,24 +731,26 @@ impl Port {
     /// block if the port is backed up.
     pub fn send(&self, packet: Packet) -> Result<(), SendError> {
         if packet.routing.len() > self.depth {
-            return Err(SendError::InvalidRoute(packet));
+            return Err(SendError::InvalidRoute(Box::new(packet)));
         }
         match self.tx.send(packet) {
             Ok(()) => Ok(()),
-            Err(se) => Err(SendError::ProxyDisconnected(se.into_inner())),
+            Err(se) => Err(SendError::ProxyDisconnected(Box::new(se.into_inner()))),
         }
     }
 
     /// Attempts to send a TIO packet to this port without blocking.
     pub fn try_send(&self, packet: Packet) -> Result<(), SendError> {
         if packet.routing.len() > self.depth {
-            return Err(SendError::InvalidRoute(packet));
+            return Err(SendError::InvalidRoute(Box::new(packet)));
         }
         match self.tx.try_send(packet) {
             Ok(()) => Ok(()),
-            Err(crossbeam::channel::TrySendError::Full(pkt)) => Err(SendError::WouldBlock(pkt)),
+            Err(crossbeam::channel::TrySendError::Full(pkt)) => {
+                Err(SendError::WouldBlock(Box::new(pkt)))
+            }
             Err(crossbeam::channel::TrySendError::Disconnected(pkt)) => {
-                Err(SendError::ProxyDisconnected(pkt))
+                Err(SendError::ProxyDisconnected(Box::new(pkt)))
             }
         }
     }
@@ -113,16 +760,42 @@ impl Port {
         sel.send(&self.tx)
     }
 
-    /// Waits for a packet to be available, and returns it.
+    /// Waits for a packet to be available, and returns it. RPC replies and
+    /// errors are returned ahead of other packets.
     pub fn recv(&self) -> Result<Packet, RecvError> {
-        match self.rx.recv() {
+        if let Some(pkt) = self.unclaimed_reply() {
+            return Ok(pkt);
+        }
+        if let Ok(pkt) = self.rpc_rx.try_recv() {
+            return Ok(pkt);
+        }
+        crossbeam::select! {
+            recv(self.rpc_rx) -> pkt => {
+                if let Ok(pkt) = pkt {
+                    return Ok(pkt);
+                }
+            }
+            recv(self.rx) -> pkt => {
+                if let Ok(pkt) = pkt {
+                    return Ok(pkt);
+                }
+            }
+        }
+        // One of the lanes is disconnected: deliver what is left on the other.
+        match self.try_recv() {
             Ok(pkt) => Ok(pkt),
-            Err(crossbeam::channel::RecvError) => Err(RecvError::ProxyDisconnected),
+            Err(_) => Err(RecvError::ProxyDisconnected),
         }
     }
 
     /// Returns a packet if available, otherwise it doesn't stop.
     pub fn try_recv(&self) -> Result<Packet, RecvError> {
+        if let Some(pkt) = self.unclaimed_reply() {
+            return Ok(pkt);
+        }
+        if let Ok(pkt) = self.rpc_rx.try_recv() {
+            return Ok(pkt);
+        }
         match self.rx.try_recv() {
             Ok(pkt) => Ok(pkt),
             Err(crossbeam::channel::TryRecvError::Empty) => Err(RecvError::WouldBlock),
@@ -132,47 +805,211 @@ impl Port {
         }
     }
 
-    /// `Select` the rx channel
+    /// Reply to a request sent with `send`, received by an `RpcHandle`.
+    fn unclaimed_reply(&self) -> Option<Packet> {
+        self.rpcs.lock().unwrap().unclaimed.pop_front()
+    }
+
+    /// Waits for a packet to be available, and returns it together with the
+    /// packets already queued after it, up to `max` packets in total. At high
+    /// data rates this amortizes the cost of waiting over many packets.
+    pub fn recv_batch(&self, max: usize) -> Result<Vec<Packet>, RecvError> {
+        let mut ret = vec![self.recv()?];
+        while ret.len() < max {
+            match self.try_recv() {
+                Ok(pkt) => ret.push(pkt),
+                Err(_) => break,
+            }
+        }
+        Ok(ret)
+    }
+
+    /// `Select` the rx channel. Note that RPC replies and errors are not
+    /// received there, see `select_recv_rpc`.
     pub fn select_recv<'a>(&'a self, sel: &mut crossbeam::channel::Select<'a>) -> usize {
         sel.recv(&self.rx)
     }
 
-    /// To use `crossbeam::channel::select!`.
+    /// `Select` the channel receiving RPC replies and errors.
+    pub fn select_recv_rpc<'a>(&'a self, sel: &mut crossbeam::channel::Select<'a>) -> usize {
+        sel.recv(&self.rpc_rx)
+    }
+
+    /// To use `crossbeam::channel::select!`. Note that RPC replies and errors
+    /// are not received there, see `rpc_receiver`.
     pub fn receiver<'a>(&'a self) -> &'a crossbeam::channel::Receiver<Packet> {
         &self.rx
     }
 
+    /// Changes in the connection to the device, if requested with
+    /// `PortBuilder::link_events`.
+    pub fn link_events(&self) -> Option<&crossbeam::channel::Receiver<LinkEvent>> {
+        self.link_rx.as_ref()
+    }
+
+    /// Receiver of RPC replies and errors, to use `crossbeam::channel::select!`.
+    pub fn rpc_receiver(&self) -> &crossbeam::channel::Receiver<Packet> {
+        &self.rpc_rx
+    }
+
     /// Iterate over packets (until disconnect or break out).
-    pub fn iter(&self) -> crossbeam::channel::Iter<'_, Packet> {
-        self.rx.iter()
+    pub fn iter(&self) -> impl Iterator<Item = Packet> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
     }
 
     /// Iterate over packets (until disconnect, break out, or empty channel).
-    pub fn try_iter(&self) -> crossbeam::channel::TryIter<'_, Packet> {
-        self.rx.try_iter()
+    pub fn try_iter(&self) -> impl Iterator<Item = Packet> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    /// Time out the next RPC request sent with `id` after `timeout` rather
+    /// than the timeout of the port, for RPCs which take unusually long or
+    /// should fail fast. The timeout is clamped to the range accepted by
+    /// `PortBuilder::rpc_timeout`.
+    pub fn set_rpc_timeout(&self, id: u16, timeout: Duration) -> Result<(), PortError> {
+        let timeout = timeout.clamp(MIN_RPC_TIMEOUT, MAX_RPC_TIMEOUT);
+        self.control
+            .send(ClientControl::RpcTimeout { id, timeout })
+            .map_err(|_| PortError::ProxyDisconnected)
+    }
+
+    /// Abandon the RPC request in flight with `id`: the proxy frees its
+    /// resources right away, and drops the reply if it comes. The device
+    /// might still execute the request. See also `RpcHandle`.
+    pub fn cancel_rpc(&self, id: u16) -> Result<(), PortError> {
+        self.control
+            .send(ClientControl::RpcCancel { id })
+            .map_err(|_| PortError::ProxyDisconnected)
     }
 
     /// Generic any sized input/output RPC, blocking
     pub fn raw_rpc(&self, name: &str, arg: &[u8]) -> Result<Vec<u8>, RpcError> {
-        if let Err(err) = self.send(util::PacketBuilder::make_rpc_request(
-            name,
-            arg,
-            0,
-            DeviceRoute::root(),
-        )) {
-            return Err(RpcError::SendFailed(err));
+        self.call_rpc(name, arg, DeviceRoute::root(), None)
+    }
+
+    /// Same as `raw_rpc`, timing out after `timeout` rather than the
+    /// timeout of the port, see `set_rpc_timeout`.
+    pub fn raw_rpc_with_timeout(
+        &self,
+        name: &str,
+        arg: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError> {
+        self.call_rpc(name, arg, DeviceRoute::root(), Some(timeout))
+    }
+
+    /// Check that the device at `route`, relative to the port, answers
+    /// RPCs within `timeout`, and return the round trip time. Cheap enough
+    /// to be used as a liveness probe.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use twinleaf::tio::{proto::DeviceRoute, proxy};
+    /// let proxy = proxy::Interface::new("tcp://localhost");
+    /// let port = proxy.tree_rpc().unwrap();
+    /// match port.ping(DeviceRoute::root(), Duration::from_secs(1)) {
+    ///     Ok(rtt) => println!("alive, {:?}", rtt),
+    ///     Err(err) => println!("not responding: {:?}", err),
+    /// }
+    /// ```
+    pub fn ping(&self, route: DeviceRoute, timeout: Duration) -> Result<Duration, PingError> {
+        let start = Instant::now();
+        match self.call_rpc(PING_RPC, &[], route, Some(timeout)) {
+            Ok(_) => Ok(start.elapsed()),
+            Err(RpcError::ExecError(err)) => match err.error {
+                proto::RpcErrorCode::Timeout => Err(PingError::Timeout),
+                code => Err(PingError::Failed(code)),
+            },
+            Err(_) => Err(PingError::ProxyDisconnected),
+        }
+    }
+
+    /// Send an RPC request without waiting for its reply, which is received
+    /// with the handle returned. See `RpcHandle`.
+    pub fn start_rpc(&self, name: &str, arg: &[u8]) -> RpcHandle<'_> {
+        self.start_rpc_to(name, arg, DeviceRoute::root(), None)
+    }
+
+    /// Same as `start_rpc`, timing out after `timeout` rather than the
+    /// timeout of the port.
+    pub fn start_rpc_with_timeout(
+        &self,
+        name: &str,
+        arg: &[u8],
+        timeout: Duration,
+    ) -> RpcHandle<'_> {
+        self.start_rpc_to(name, arg, DeviceRoute::root(), Some(timeout))
+    }
+
+    fn start_rpc_to(
+        &self,
+        name: &str,
+        arg: &[u8],
+        route: DeviceRoute,
+        timeout: Option<Duration>,
+    ) -> RpcHandle<'_> {
+        let id = self.rpcs.lock().unwrap().alloc();
+        let req = util::PacketBuilder::make_rpc_request(name, arg, id, route);
+        let deadline = Instant::now()
+            + timeout.map_or(self.rpc_timeout, |t| {
+                t.clamp(MIN_RPC_TIMEOUT, MAX_RPC_TIMEOUT)
+            })
+            + RPC_HANDLE_GRACE;
+        let timeout_set = match timeout {
+            Some(timeout) => self.set_rpc_timeout(id, timeout).is_ok(),
+            None => true,
+        };
+        let send_error = if timeout_set {
+            self.send(req).err()
+        } else {
+            Some(SendError::ProxyDisconnected(Box::new(req)))
+        };
+        if send_error.is_some() {
+            self.rpcs.lock().unwrap().remove(id);
+        }
+        RpcHandle {
+            port: self,
+            id,
+            send_error,
+            deadline,
+            done: false,
         }
+    }
+
+    fn call_rpc(
+        &self,
+        name: &str,
+        arg: &[u8],
+        route: DeviceRoute,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, RpcError> {
+        self.start_rpc_to(name, arg, route, timeout).wait()
+    }
+
+    /// Same as `raw_rpc`, retrying RPCs which time out according to `policy`.
+    pub fn rpc_with_retry(
+        &self,
+        name: &str,
+        arg: &[u8],
+        policy: &RetryPolicy,
+    ) -> Result<Vec<u8>, RpcRetryError> {
+        let mut backoff = policy.backoff;
+        let mut attempt = 1;
         loop {
-            match self.recv() {
-                Ok(pkt) => match pkt.payload {
-                    proto::Payload::RpcReply(rep) => return Ok(rep.reply),
-                    proto::Payload::RpcError(err) => return Err(RpcError::ExecError(err)),
-                    _ => continue,
-                },
-                Err(err) => {
-                    return Err(RpcError::RecvFailed(err));
+            match self.raw_rpc(name, arg) {
+                Ok(reply) => return Ok(reply),
+                Err(RpcError::ExecError(err)) => {
+                    if !matches!(err.error, proto::RpcErrorCode::Timeout) {
+                        return Err(RpcRetryError::Device(err));
+                    }
                 }
+                Err(err) => return Err(RpcRetryError::Transport(err)),
             }
+            if attempt > policy.retries() {
+                return Err(RpcRetryError::Timeout(attempt));
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(policy.max_backoff);
+            attempt += 1;
         }
     }
 
@@ -189,6 +1026,18 @@ impl Port {
         }
     }
 
+    /// Same as `rpc`, timing out after `timeout` rather than the timeout of
+    /// the port, see `set_rpc_timeout`.
+    pub fn rpc_with_timeout<ReqT: TioRpcRequestable<ReqT>, RepT: TioRpcReplyable<RepT>>(
+        &self,
+        name: &str,
+        arg: ReqT,
+        timeout: Duration,
+    ) -> Result<RepT, RpcError> {
+        let ret = self.raw_rpc_with_timeout(name, &arg.to_request(), timeout)?;
+        RepT::from_reply(&ret).map_err(|_| RpcError::TypeError)
+    }
+
     /// Action: rpc with no argument which returns nothing
     pub fn action(&self, name: &str) -> Result<(), RpcError> {
         self.rpc(name, ())
@@ -197,6 +1046,232 @@ impl Port {
     pub fn get<T: TioRpcReplyable<T>>(&self, name: &str) -> Result<T, RpcError> {
         self.rpc(name, ())
     }
+
+    /// Protocol version reported by the device at the root of this port.
+    pub fn protocol_version(&self) -> Result<proto::ProtocolVersion, RpcError> {
+        match self.get::<u8>(proto::version::PROTOCOL_VERSION_RPC) {
+            Ok(version) => Ok(proto::ProtocolVersion::from(version)),
+            Err(RpcError::ExecError(err)) if matches!(err.error, proto::RpcErrorCode::NotFound) => {
+                Ok(proto::ProtocolVersion::Metadata)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Current state and rate of the link between the proxy and the root
+    /// device, to show the health of the link or warn when it runs at its
+    /// fallback rate.
+    pub fn link_status(&self) -> LinkStatus {
+        self.link_status.lock().unwrap().clone()
+    }
+
+    /// Devices of the subtree of this port which the proxy received packets
+    /// from, by route relative to the port, without polling them. Devices
+    /// stay listed after they go away, see `RouteActivity::is_alive`.
+    pub fn topology(&self) -> Topology {
+        let topology = self.topology.lock().unwrap();
+        topology
+            .iter()
+            .filter_map(|(route, activity)| {
+                let relative = self.scope.relative_route(route).ok()?;
+                (relative.len() <= self.depth).then_some((relative, *activity))
+            })
+            .collect()
+    }
+
+    /// Absolute route of the root of the subtree this port has access to.
+    pub fn scope(&self) -> &DeviceRoute {
+        &self.scope
+    }
+
+    /// Move the port to the subtree at `route` with the given `depth`, without
+    /// creating a new port. `route` is relative to the subtree the port was
+    /// opened for, which the port cannot leave: a port opened at `/1` can
+    /// move to `/1/2` and back to `/1`, but not to `/2`. The depth is limited
+    /// to the bottom of that subtree.
+    ///
+    /// Packets sent afterwards use routes relative to the new scope. Packets
+    /// already queued to the port keep routes relative to the old one, and
+    /// replies to RPCs pending outside the new scope are lost.
+    pub fn set_scope(&mut self, route: DeviceRoute, depth: usize) -> Result<(), PortError> {
+        let (root, max_depth) = &self.bounds;
+        if route.len() > *max_depth {
+            return Err(PortError::InvalidRoute);
+        }
+        let scope = root.absolute_route(&route);
+        let depth = depth.min(max_depth - route.len());
+        self.control
+            .send(ClientControl::Scope {
+                scope: scope.clone(),
+                depth,
+            })
+            .map_err(|_| PortError::ProxyDisconnected)?;
+        self.scope = scope;
+        self.depth = depth;
+        Ok(())
+    }
+
+    /// Close the link of the proxy to the root device and open it again, as
+    /// if the device disconnected, to recover a link which stopped
+    /// delivering data. Affects all the ports of the proxy. The proxy gives
+    /// up if the device cannot be opened again within its reconnection
+    /// timeout, as for any disconnection.
+    pub fn reconnect(&self) -> Result<(), PortError> {
+        self.control
+            .send(ClientControl::Reconnect)
+            .map_err(|_| PortError::ProxyDisconnected)
+    }
+
+    /// Change the url of the device of the proxy, as when it moved to
+    /// another serial port after being plugged again, and open the link at
+    /// the new url. Affects all the ports of the proxy, which keep their
+    /// scopes and channels, as over a reconnection. The proxy gives up if
+    /// the new url cannot be opened within its reconnection timeout.
+    pub fn set_url(&self, url: &str) -> Result<(), PortError> {
+        self.control
+            .send(ClientControl::SetUrl(url.to_string()))
+            .map_err(|_| PortError::ProxyDisconnected)
+    }
+
+    /// Which packets the port receives.
+    pub fn forwarding(&self) -> ForwardingPolicy {
+        self.forwarding
+    }
+
+    /// Change which packets the port receives, without creating a new port.
+    pub fn set_forwarding(&mut self, forwarding: ForwardingPolicy) -> Result<(), PortError> {
+        self.control
+            .send(ClientControl::Forwarding(forwarding))
+            .map_err(|_| PortError::ProxyDisconnected)?;
+        self.forwarding = forwarding;
+        Ok(())
+    }
+
+    /// Create a new port for the subtree at `route`, relative to this
+    /// port's scope, with the same parameters as this port. Routes on the
+    /// new port are relative to `route`, and the proxy rejects anything
+    /// addressed outside of it.
+    pub fn device_port(&self, route: DeviceRoute) -> Result<Port, PortError> {
+        if route.len() > self.depth {
+            return Err(PortError::InvalidRoute);
+        }
+        let clients = self
+            .clients
+            .upgrade()
+            .ok_or(PortError::FailedNewClientSetup)?;
+        clients.new_port(
+            self.rpc_timeout,
+            self.scope.absolute_route(&route),
+            self.depth - route.len(),
+            self.forwarding,
+            PortOptions::default(),
+        )
+    }
+
+    /// Open a port with these options within the subtree of this port: the
+    /// scope of the options is relative to this port, and the new port
+    /// reaches no deeper than this one. RPCs time out like on this port,
+    /// unless the options set a timeout.
+    pub fn client(&self, options: &ClientOptions) -> Result<Port, PortError> {
+        if options.scope.len() > self.depth {
+            return Err(PortError::InvalidRoute);
+        }
+        let rpc_timeout = check_rpc_timeout(options.rpc_timeout.unwrap_or(self.rpc_timeout))?;
+        let clients = self
+            .clients
+            .upgrade()
+            .ok_or(PortError::FailedNewClientSetup)?;
+        clients.new_port(
+            rpc_timeout,
+            self.scope.absolute_route(&options.scope),
+            options.depth.min(self.depth - options.scope.len()),
+            options.forwarding,
+            options.options.clone(),
+        )
+    }
+}
+
+/// Direction of a packet seen by a `Sniffer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    ToDevice,
+    FromDevice,
+}
+
+/// A copy of a packet exchanged between the proxy and the device.
+#[derive(Debug, Clone)]
+pub struct SniffedPacket {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    /// For packets sent to the device, the client that sent it, which is
+    /// `INTERNAL_CLIENT_ID` for the proxy's own RPCs.
+    pub client: Option<u64>,
+    /// The packet as seen on the wire: routes are absolute, and RPC ids are
+    /// the ones assigned by the proxy.
+    pub packet: Packet,
+}
+
+/// A debugging client which receives a copy of every packet exchanged with
+/// the device, in both directions, including the proxy's own traffic.
+pub struct Sniffer {
+    rx: channel::Receiver<SniffedPacket>,
+    /// Never used to send; the proxy drops the sniffer when this goes away.
+    _tx: channel::Sender<Packet>,
+}
+
+impl Sniffer {
+    pub fn recv(&self) -> Result<SniffedPacket, RecvError> {
+        self.rx.recv().map_err(|_| RecvError::ProxyDisconnected)
+    }
+
+    pub fn try_recv(&self) -> Result<SniffedPacket, RecvError> {
+        match self.rx.try_recv() {
+            Ok(pkt) => Ok(pkt),
+            Err(channel::TryRecvError::Empty) => Err(RecvError::WouldBlock),
+            Err(channel::TryRecvError::Disconnected) => Err(RecvError::ProxyDisconnected),
+        }
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<SniffedPacket> {
+        &self.rx
+    }
+
+    /// Iterate over packets until the proxy goes away.
+    pub fn iter(&self) -> channel::Iter<'_, SniffedPacket> {
+        self.rx.iter()
+    }
+}
+
+/// A client which receives the text output of a device, one line at a time.
+/// This includes the device's log messages, as well as any plain text
+/// printed on a serial port by the root device.
+pub struct Console {
+    rx: channel::Receiver<String>,
+    /// Never used to send; the proxy drops the console when this goes away.
+    _tx: channel::Sender<Packet>,
+}
+
+impl Console {
+    pub fn recv(&self) -> Result<String, RecvError> {
+        self.rx.recv().map_err(|_| RecvError::ProxyDisconnected)
+    }
+
+    pub fn try_recv(&self) -> Result<String, RecvError> {
+        match self.rx.try_recv() {
+            Ok(line) => Ok(line),
+            Err(channel::TryRecvError::Empty) => Err(RecvError::WouldBlock),
+            Err(channel::TryRecvError::Disconnected) => Err(RecvError::ProxyDisconnected),
+        }
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<String> {
+        &self.rx
+    }
+
+    /// Iterate over lines until the proxy goes away.
+    pub fn iter(&self) -> channel::Iter<'_, String> {
+        self.rx.iter()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -204,12 +1279,545 @@ pub enum PortError {
     RpcTimeoutTooShort,
     RpcTimeoutTooLong,
     FailedNewClientSetup,
+    InvalidRoute,
+    ProxyDisconnected,
+    /// The proxy could not open the device, for this reason.
+    FailedToConnect(port::ConnectError),
+    /// The proxy did not connect to the device in time.
+    ConnectTimeout,
 }
 
-/// Interface to a port proxy. Can create new ports.
-pub struct Interface {
+/// Outcome of the first connection of the proxy to the device, set once by
+/// the proxy thread, see `Interface::wait_ready`.
+pub(crate) struct Readiness {
+    outcome: Mutex<Option<Result<(), PortError>>>,
+    changed: Condvar,
+}
+
+impl Readiness {
+    pub(crate) fn new() -> Readiness {
+        Readiness {
+            outcome: Mutex::new(None),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Record the outcome, unless already known.
+    pub(crate) fn set(&self, outcome: Result<(), PortError>) {
+        let mut current = self.outcome.lock().unwrap();
+        if current.is_none() {
+            *current = Some(outcome);
+            self.changed.notify_all();
+        }
+    }
+
+    fn wait(&self, timeout: Duration) -> Result<(), PortError> {
+        let current = self.outcome.lock().unwrap();
+        let (current, _) = self
+            .changed
+            .wait_timeout_while(current, timeout, |outcome| outcome.is_none())
+            .unwrap();
+        current.clone().unwrap_or(Err(PortError::ConnectTimeout))
+    }
+}
+
+/// Packets queued from the proxy to a port, by default, for data and RPC
+/// replies separately.
+static DEFAULT_RECV_CAPACITY: usize = 256;
+/// Packets queued from a port to the proxy, by default.
+static DEFAULT_SEND_CAPACITY: usize = 32;
+
+/// Options of a new port beyond those of `Interface::new_port`.
+#[derive(Clone)]
+struct PortOptions {
+    link_events: bool,
+    /// RPC requests sent again to the device after it reconnects, with
+    /// routes relative to the port scope.
+    reconnect_rpcs: Vec<Packet>,
+    recv_capacity: usize,
+    send_capacity: usize,
+}
+
+impl Default for PortOptions {
+    fn default() -> PortOptions {
+        PortOptions {
+            link_events: false,
+            reconnect_rpcs: vec![],
+            recv_capacity: DEFAULT_RECV_CAPACITY,
+            send_capacity: DEFAULT_SEND_CAPACITY,
+        }
+    }
+}
+
+/// Handle to register new clients with a running `ProxyCore`.
+struct ClientQueue {
     new_client_queue: channel::Sender<ProxyClient>,
     new_client_confirm: Option<channel::Receiver<Event>>,
+    downstream_queue: channel::Sender<DownstreamRequest>,
+    budget: Option<Arc<MemoryBudget>>,
+    link_status: Arc<Mutex<LinkStatus>>,
+    topology: Arc<Mutex<Topology>>,
+    readiness: Arc<Readiness>,
+}
+
+impl ClientQueue {
+    fn new_port(
+        self: &Arc<Self>,
+        rpc_timeout: Duration,
+        scope: DeviceRoute,
+        depth: usize,
+        forwarding: ForwardingPolicy,
+        options: PortOptions,
+    ) -> Result<Port, PortError> {
+        let (client_to_proxy_sender, proxy_from_client_receiver) =
+            channel::bounded::<Packet>(options.send_capacity);
+        let (proxy_to_client_sender, client_from_proxy_receiver) =
+            channel::bounded::<Packet>(options.recv_capacity);
+        let (rpc_sender, rpc_receiver) = channel::bounded::<Packet>(options.recv_capacity);
+        let (control_sender, control_receiver) = channel::bounded::<ClientControl>(16);
+        let mut client = ProxyClient::new(
+            proxy_to_client_sender,
+            proxy_from_client_receiver,
+            rpc_timeout,
+            scope.clone(),
+            depth,
+            forwarding,
+        )
+        .with_rpc_lane(rpc_sender)
+        .with_control(control_receiver)
+        .with_budget(self.budget.clone())
+        .with_reconnect_rpcs(options.reconnect_rpcs);
+        let link_rx = if options.link_events {
+            let (link_sender, link_receiver) = channel::bounded::<LinkEvent>(16);
+            client = client.with_link_events(link_sender);
+            Some(link_receiver)
+        } else {
+            None
+        };
+        if self.new_client_queue.send(client).is_err() {
+            return Err(PortError::FailedNewClientSetup);
+        }
+        if let Some(confirm) = &self.new_client_confirm {
+            if confirm.recv().is_err() {
+                return Err(PortError::FailedNewClientSetup);
+            }
+        }
+        Ok(Port {
+            tx: client_to_proxy_sender,
+            rx: client_from_proxy_receiver,
+            rpc_rx: rpc_receiver,
+            depth,
+            scope: scope.clone(),
+            rpc_timeout,
+            forwarding,
+            control: control_sender,
+            bounds: (scope, depth),
+            link_rx,
+            link_status: self.link_status.clone(),
+            topology: self.topology.clone(),
+            clients: Arc::downgrade(self),
+            rpcs: Mutex::new(PendingRpcs::default()),
+        })
+    }
+
+    fn new_sniffer(&self) -> Result<Sniffer, PortError> {
+        let (keepalive_sender, keepalive_receiver) = channel::bounded::<Packet>(1);
+        let (sniff_sender, sniff_receiver) = channel::bounded::<SniffedPacket>(1024);
+        if self
+            .new_client_queue
+            .send(ProxyClient::sniffer(keepalive_receiver, sniff_sender))
+            .is_err()
+        {
+            return Err(PortError::FailedNewClientSetup);
+        }
+        if let Some(confirm) = &self.new_client_confirm {
+            if confirm.recv().is_err() {
+                return Err(PortError::FailedNewClientSetup);
+            }
+        }
+        Ok(Sniffer {
+            rx: sniff_receiver,
+            _tx: keepalive_sender,
+        })
+    }
+
+    fn new_console(&self, route: DeviceRoute) -> Result<Console, PortError> {
+        let (keepalive_sender, keepalive_receiver) = channel::bounded::<Packet>(1);
+        let (line_sender, line_receiver) = channel::bounded::<String>(256);
+        if self
+            .new_client_queue
+            .send(ProxyClient::console(keepalive_receiver, route, line_sender))
+            .is_err()
+        {
+            return Err(PortError::FailedNewClientSetup);
+        }
+        if let Some(confirm) = &self.new_client_confirm {
+            if confirm.recv().is_err() {
+                return Err(PortError::FailedNewClientSetup);
+            }
+        }
+        Ok(Console {
+            rx: line_receiver,
+            _tx: keepalive_sender,
+        })
+    }
+}
+
+/// Options for a new proxy, created with `Interface::builder()`:
+/// ```no_run
+/// # use twinleaf::tio::proxy::Interface;
+/// # use std::time::Duration;
+/// let proxy = Interface::builder()
+///     .url("serial:///dev/ttyUSB0")
+///     .reconnect(Duration::from_secs(30))
+///     .autorate(false)
+///     .spawn();
+/// ```
+pub struct ProxyBuilder {
+    url: String,
+    reconnect_timeout: Option<Duration>,
+    status_queue: Option<channel::Sender<Event>>,
+    budget: Option<Arc<MemoryBudget>>,
+    autorate: bool,
+    route_autorate: Vec<(DeviceRoute, u32)>,
+    rpc_rate_limit: Option<RpcRateLimit>,
+    metadata_cache: bool,
+    raw_tap: Option<channel::Sender<Vec<u8>>>,
+    parse_mode: port::ParseMode,
+    default_ttl: usize,
+    autorate_rpcs: Vec<AutoRateRpcs>,
+}
+
+impl ProxyBuilder {
+    /// Url of the sensor, see `port::Port::new`. Defaults to a tio-proxy
+    /// running on this machine.
+    pub fn url(mut self, url: &str) -> ProxyBuilder {
+        self.url = url.to_string();
+        self
+    }
+
+    /// Keep trying to reconnect to the sensor for up to `timeout` after it
+    /// gets disconnected. By default the proxy exits on disconnection.
+    pub fn reconnect(mut self, timeout: Duration) -> ProxyBuilder {
+        self.reconnect_timeout = Some(timeout);
+        self
+    }
+
+    /// Send status events to `status_queue`.
+    pub fn status(mut self, status_queue: channel::Sender<Event>) -> ProxyBuilder {
+        self.status_queue = Some(status_queue);
+        self
+    }
+
+    /// Account for packets queued to ports against `budget`.
+    pub fn budget(mut self, budget: Option<Arc<MemoryBudget>>) -> ProxyBuilder {
+        self.budget = budget;
+        self
+    }
+
+    /// Negotiate a higher serial port rate with the sensor, if the url
+    /// asks for one. Enabled by default.
+    pub fn autorate(mut self, autorate: bool) -> ProxyBuilder {
+        self.autorate = autorate;
+        self
+    }
+
+    /// Also negotiate a rate of `target_bps` for the link between the device
+    /// at `route`, behind a hub, and its hub, as well as for the links of
+    /// the hubs on the way which are not configured otherwise. Links are
+    /// negotiated one at a time from the root outwards, after the link to
+    /// the root device, by calling the `autorate_rpcs` on each device, which
+    /// switches the link together with its hub.
+    pub fn autorate_route(mut self, route: DeviceRoute, target_bps: u32) -> ProxyBuilder {
+        self.route_autorate.push((route, target_bps));
+        self
+    }
+
+    /// RPCs to negotiate rates with, for firmwares which do not have the
+    /// default ones. Each device is queried with the candidates in order,
+    /// until one is found, and the rate is set with the same candidate.
+    pub fn autorate_rpcs(mut self, rpcs: Vec<AutoRateRpcs>) -> ProxyBuilder {
+        self.autorate_rpcs = rpcs;
+        self
+    }
+
+    /// Limit the rate of RPC requests of each port to each device. There is
+    /// no limit by default.
+    pub fn rpc_rate_limit(mut self, limit: Option<RpcRateLimit>) -> ProxyBuilder {
+        self.rpc_rate_limit = limit;
+        self
+    }
+
+    /// Answer `dev.metadata` requests from the replies to the same requests
+    /// of earlier ports, until the device restarts with a new session or
+    /// announces changed metadata, so that ports opened later do not query
+    /// the device again. Enabled by default.
+    pub fn metadata_cache(mut self, enable: bool) -> ProxyBuilder {
+        self.metadata_cache = enable;
+        self
+    }
+
+    /// Send a copy of the raw bytes received from the sensor to `tap`, as
+    /// they come off the wire before any framing, to diagnose links which
+    /// deliver garbage. Only supported by byte stream links, such as serial
+    /// ports, see `port::Port::set_raw_tap`.
+    pub fn raw_tap(mut self, tap: channel::Sender<Vec<u8>>) -> ProxyBuilder {
+        self.raw_tap = Some(tap);
+        self
+    }
+
+    /// Report any data from the sensor which breaks the framing of packets
+    /// as a `ProtocolError` instead of recovering from it, to validate the
+    /// firmware of a device. Lenient by default, see `port::ParseMode`.
+    pub fn parse_mode(mut self, mode: port::ParseMode) -> ProxyBuilder {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// TTL given to the packets sent to the sensor without one, such as
+    /// those of ports and of the proxy itself, to limit how far they are
+    /// forwarded past the sensor. Packets with a TTL, for example from
+    /// another proxy, have it decremented instead. Defaults to 0, no limit.
+    pub fn default_ttl(mut self, ttl: usize) -> ProxyBuilder {
+        self.default_ttl = ttl.min(proto::TIO_PACKET_MAX_TTL);
+        self
+    }
+
+    /// Start the proxy in its own thread.
+    pub fn spawn(self) -> Interface {
+        Interface::spawn(self)
+    }
+}
+
+/// Parameters of a new port: by default it has access to the whole device
+/// tree and receives all packets. Options can be kept and reused, to open
+/// ports with `Interface::client`, or within the subtree of an existing
+/// port with `Port::client`:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use twinleaf::tio::{proto::DeviceRoute, proxy};
+/// let proxy = proxy::Interface::new("tcp://localhost");
+/// let control = proxy::ClientOptions::new()
+///     .rpc_only()
+///     .rpc_timeout(Duration::from_millis(500))
+///     .recv_capacity(8);
+/// let port = proxy.client(&control).unwrap();
+/// let hub_port = port.client(&control.clone().device(DeviceRoute::from_str("/0").unwrap()));
+/// ```
+#[derive(Clone)]
+pub struct ClientOptions {
+    rpc_timeout: Option<Duration>,
+    scope: DeviceRoute,
+    depth: usize,
+    forwarding: ForwardingPolicy,
+    options: PortOptions,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            rpc_timeout: None,
+            scope: DeviceRoute::root(),
+            depth: usize::MAX,
+            forwarding: ForwardingPolicy::all(),
+            options: PortOptions::default(),
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    /// Timeout for RPCs sent through the port.
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = Some(timeout);
+        self
+    }
+
+    /// Restrict the port to the subtree rooted at `route`.
+    pub fn subtree(mut self, route: DeviceRoute) -> Self {
+        self.scope = route;
+        self.depth = usize::MAX;
+        self
+    }
+
+    /// Restrict the port to the device at `route`.
+    pub fn device(mut self, route: DeviceRoute) -> Self {
+        self.scope = route;
+        self.depth = 0;
+        self
+    }
+
+    /// Limit how deep under its root the port reaches.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Only receive RPC replies, not sample data nor other packets.
+    pub fn rpc_only(mut self) -> Self {
+        self.forwarding = ForwardingPolicy::rpc_only();
+        self
+    }
+
+    /// Which packets to receive.
+    pub fn forwarding(mut self, forwarding: ForwardingPolicy) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    /// Whether to receive sample data.
+    pub fn data(mut self, forward: bool) -> Self {
+        self.forwarding.data = forward;
+        self
+    }
+
+    /// Whether to receive packets which are neither sample data nor RPCs.
+    pub fn nonrpc(mut self, forward: bool) -> Self {
+        self.forwarding = self.forwarding.with_nonrpc(forward);
+        self
+    }
+
+    /// Only receive one of every `n` sample data packets of each stream, to
+    /// follow a device at a reduced rate, for example to plot its data.
+    pub fn decimate(mut self, n: u32) -> Self {
+        self.forwarding.decimation = n;
+        self
+    }
+
+    /// Number of packets the proxy queues to the port before dropping them
+    /// (default 256), for data and for RPC replies. High rate streams need
+    /// more to ride out pauses of the reader, while RPC only ports can use
+    /// fewer to bound the latency of what they receive. See also
+    /// `Event::ClientDropped`.
+    pub fn recv_capacity(mut self, packets: usize) -> Self {
+        self.options.recv_capacity = packets.max(1);
+        self
+    }
+
+    /// Number of packets the port queues to the proxy before `Port::send`
+    /// blocks (default 32).
+    pub fn send_capacity(mut self, packets: usize) -> Self {
+        self.options.send_capacity = packets.max(1);
+        self
+    }
+
+    /// Receive changes in the connection to the device, see `Port::link_events`.
+    pub fn link_events(mut self) -> Self {
+        self.options.link_events = true;
+        self
+    }
+
+    /// Have the proxy call the RPC `name` with `arg` on the root device of
+    /// the port whenever the device reconnects, to restore a setting the
+    /// port depends on. Replies are not sent to the port, and failures are
+    /// reported as `Event::ReconnectRpcFailed`.
+    pub fn on_reconnect(mut self, name: &str, arg: &[u8]) -> Self {
+        self.options
+            .reconnect_rpcs
+            .push(util::PacketBuilder::make_rpc_request(
+                name,
+                arg,
+                0,
+                DeviceRoute::root(),
+            ));
+        self
+    }
+}
+
+/// Options for a new port, created with `Interface::port()`, see
+/// `ClientOptions` for the details.
+pub struct PortBuilder<'a> {
+    proxy: &'a Interface,
+    options: ClientOptions,
+}
+
+impl PortBuilder<'_> {
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.options = self.options.rpc_timeout(timeout);
+        self
+    }
+
+    pub fn subtree(mut self, route: DeviceRoute) -> Self {
+        self.options = self.options.subtree(route);
+        self
+    }
+
+    pub fn device(mut self, route: DeviceRoute) -> Self {
+        self.options = self.options.device(route);
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.options = self.options.depth(depth);
+        self
+    }
+
+    pub fn rpc_only(mut self) -> Self {
+        self.options = self.options.rpc_only();
+        self
+    }
+
+    pub fn forwarding(mut self, forwarding: ForwardingPolicy) -> Self {
+        self.options = self.options.forwarding(forwarding);
+        self
+    }
+
+    pub fn data(mut self, forward: bool) -> Self {
+        self.options = self.options.data(forward);
+        self
+    }
+
+    pub fn nonrpc(mut self, forward: bool) -> Self {
+        self.options = self.options.nonrpc(forward);
+        self
+    }
+
+    pub fn decimate(mut self, n: u32) -> Self {
+        self.options = self.options.decimate(n);
+        self
+    }
+
+    pub fn recv_capacity(mut self, packets: usize) -> Self {
+        self.options = self.options.recv_capacity(packets);
+        self
+    }
+
+    pub fn send_capacity(mut self, packets: usize) -> Self {
+        self.options = self.options.send_capacity(packets);
+        self
+    }
+
+    pub fn link_events(mut self) -> Self {
+        self.options = self.options.link_events();
+        self
+    }
+
+    pub fn on_reconnect(mut self, name: &str, arg: &[u8]) -> Self {
+        self.options = self.options.on_reconnect(name, arg);
+        self
+    }
+
+    pub fn open(self) -> Result<Port, PortError> {
+        self.proxy.client(&self.options)
+    }
+}
+
+fn check_rpc_timeout(rpc_timeout: Duration) -> Result<Duration, PortError> {
+    if rpc_timeout < MIN_RPC_TIMEOUT {
+        Err(PortError::RpcTimeoutTooShort)
+    } else if rpc_timeout > MAX_RPC_TIMEOUT {
+        Err(PortError::RpcTimeoutTooLong)
+    } else {
+        Ok(rpc_timeout)
+    }
+}
+
+/// Interface to a port proxy. Can create new ports.
+pub struct Interface {
+    clients: Arc<ClientQueue>,
 }
 
 impl Interface {
@@ -219,7 +1827,58 @@ impl Interface {
         reconnect_timeout: Option<Duration>,
         status_queue: Option<channel::Sender<Event>>,
     ) -> Interface {
+        Self::new_proxy_with_budget(url, reconnect_timeout, status_queue, None)
+    }
+
+    /// Like `new_proxy`, but packets queued to this proxy's ports are
+    /// accounted against `budget`, which can be shared with other proxies.
+    pub fn new_proxy_with_budget(
+        url: &str,
+        reconnect_timeout: Option<Duration>,
+        status_queue: Option<channel::Sender<Event>>,
+        budget: Option<Arc<MemoryBudget>>,
+    ) -> Interface {
+        let mut builder = Interface::builder().url(url).budget(budget);
+        builder.reconnect_timeout = reconnect_timeout;
+        builder.status_queue = status_queue;
+        builder.spawn()
+    }
+
+    /// Configure a new proxy, see `ProxyBuilder`.
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder {
+            url: util::default_proxy_url().to_string(),
+            reconnect_timeout: None,
+            status_queue: None,
+            budget: None,
+            autorate: true,
+            route_autorate: vec![],
+            rpc_rate_limit: None,
+            metadata_cache: true,
+            raw_tap: None,
+            parse_mode: port::ParseMode::Lenient,
+            default_ttl: 0,
+            autorate_rpcs: vec![AutoRateRpcs::default()],
+        }
+    }
+
+    fn spawn(builder: ProxyBuilder) -> Interface {
+        let ProxyBuilder {
+            url,
+            reconnect_timeout,
+            status_queue,
+            budget,
+            autorate,
+            route_autorate,
+            rpc_rate_limit,
+            metadata_cache,
+            raw_tap,
+            parse_mode,
+            default_ttl,
+            autorate_rpcs,
+        } = builder;
         let (client_sender, client_receiver) = channel::bounded::<ProxyClient>(5);
+        let (downstream_sender, downstream_receiver) = channel::bounded::<DownstreamRequest>(1);
         let (status_sender, status_receiver, only_clients) = {
             if let Some(status_sender) = status_queue {
                 (status_sender, None, false)
@@ -228,20 +1887,44 @@ impl Interface {
                 (s, Some(r), true)
             }
         };
-        let url_string = url.to_string();
+        let link_status = Arc::new(Mutex::new(LinkStatus::new()));
+        let core_link_status = link_status.clone();
+        let topology = Arc::new(Mutex::new(Topology::new()));
+        let core_topology = topology.clone();
+        let readiness = Arc::new(Readiness::new());
+        let core_readiness = readiness.clone();
         thread::spawn(move || {
             let mut proxy = ProxyCore::new(
-                url_string,
+                url,
                 reconnect_timeout,
                 client_receiver,
                 status_sender,
                 only_clients,
-            );
+            )
+            .with_autorate(autorate)
+            .with_autorate_rpcs(autorate_rpcs)
+            .with_route_autorate(route_autorate)
+            .with_rpc_rate_limit(rpc_rate_limit)
+            .with_metadata_cache(metadata_cache)
+            .with_raw_tap(raw_tap)
+            .with_parse_mode(parse_mode)
+            .with_default_ttl(default_ttl)
+            .with_link_status(core_link_status)
+            .with_topology(core_topology)
+            .with_readiness(core_readiness)
+            .with_downstream_queue(downstream_receiver);
             proxy.run();
         });
         Interface {
-            new_client_queue: client_sender,
-            new_client_confirm: status_receiver,
+            clients: Arc::new(ClientQueue {
+                new_client_queue: client_sender,
+                new_client_confirm: status_receiver,
+                downstream_queue: downstream_sender,
+                budget,
+                link_status,
+                topology,
+                readiness,
+            }),
         }
     }
 
@@ -250,6 +1933,31 @@ impl Interface {
         Self::new_proxy(url, None, None)
     }
 
+    /// Create a new proxy like `new`, and wait for it to connect to the
+    /// device, so that failures are reported right away rather than by
+    /// ports timing out.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use twinleaf::tio::proxy;
+    /// match proxy::Interface::connect("/dev/ttyACM0", Duration::from_secs(5)) {
+    ///     Ok(proxy) => println!("{:?}", proxy.link_status()),
+    ///     Err(err) => eprintln!("Failed to connect: {:?}", err),
+    /// }
+    /// ```
+    pub fn connect(url: &str, timeout: Duration) -> Result<Interface, PortError> {
+        let proxy = Self::new(url);
+        proxy.wait_ready(timeout)?;
+        Ok(proxy)
+    }
+
+    /// Wait until the proxy connected to the device for the first time, or
+    /// failed to. Returns `PortError::FailedToConnect` if the device could
+    /// not be opened, and `PortError::ConnectTimeout` if it did not connect
+    /// within `timeout`. Returns immediately once the outcome is known.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), PortError> {
+        self.clients.readiness.wait(timeout)
+    }
+
     /// Create a new proxy which connects to a standalone tio-proxy process at
     /// the default address.
     pub fn default() -> Interface {
@@ -262,51 +1970,86 @@ impl Interface {
         rpc_timeout: Option<Duration>,
         scope: DeviceRoute,
         depth: usize,
-        forward_data: bool,
-        forward_nonrpc: bool,
+        forwarding: ForwardingPolicy,
     ) -> Result<Port, PortError> {
-        let default_rpc_timeout = Duration::from_millis(3000);
-        let rpc_timeout = rpc_timeout.unwrap_or(default_rpc_timeout);
-        if rpc_timeout < Duration::from_millis(100) {
-            return Err(PortError::RpcTimeoutTooShort);
-        }
-        if rpc_timeout > Duration::from_secs(60) {
-            return Err(PortError::RpcTimeoutTooLong);
-        }
-
-        let (client_to_proxy_sender, proxy_from_client_receiver) = channel::bounded::<Packet>(32);
-        let (proxy_to_client_sender, client_from_proxy_receiver) = channel::bounded::<Packet>(256);
-        if let Err(_) = self.new_client_queue.send(ProxyClient::new(
-            proxy_to_client_sender,
-            proxy_from_client_receiver,
+        self.open_port(
             rpc_timeout,
             scope,
             depth,
-            forward_data,
-            forward_nonrpc,
-        )) {
-            return Err(PortError::FailedNewClientSetup);
-        }
-        if let Some(confirm) = &self.new_client_confirm {
-            if let Err(_) = confirm.recv() {
-                return Err(PortError::FailedNewClientSetup);
-            }
+            forwarding,
+            PortOptions::default(),
+        )
+    }
+
+    fn open_port(
+        &self,
+        rpc_timeout: Option<Duration>,
+        scope: DeviceRoute,
+        depth: usize,
+        forwarding: ForwardingPolicy,
+        options: PortOptions,
+    ) -> Result<Port, PortError> {
+        let default_rpc_timeout = Duration::from_millis(3000);
+        let rpc_timeout = check_rpc_timeout(rpc_timeout.unwrap_or(default_rpc_timeout))?;
+        self.clients
+            .new_port(rpc_timeout, scope, depth, forwarding, options)
+    }
+
+    /// Current state and rate of the link to the root device, see
+    /// `Port::link_status`.
+    pub fn link_status(&self) -> LinkStatus {
+        self.clients.link_status.lock().unwrap().clone()
+    }
+
+    /// Outcome of the rate autonegotiation of the link to the root device,
+    /// to wait for it to complete before starting acquisition.
+    pub fn autorate_status(&self) -> AutoRateStatus {
+        self.link_status().autorate
+    }
+
+    /// Devices of the tree which the proxy received packets from, by route,
+    /// to show which are alive without polling them. See `Port::topology`.
+    pub fn topology(&self) -> Topology {
+        self.clients.topology.lock().unwrap().clone()
+    }
+
+    /// Create a sniffer, receiving a copy of all traffic with the device.
+    pub fn sniffer(&self) -> Result<Sniffer, PortError> {
+        self.clients.new_sniffer()
+    }
+
+    /// Receive the text output of the device at `address`.
+    pub fn console(&self, address: DeviceRoute) -> Result<Console, PortError> {
+        self.clients.new_console(address)
+    }
+
+    /// Configure a new port, see `PortBuilder`.
+    pub fn port(&self) -> PortBuilder<'_> {
+        PortBuilder {
+            proxy: self,
+            options: ClientOptions::default(),
         }
-        Ok(Port {
-            tx: client_to_proxy_sender,
-            rx: client_from_proxy_receiver,
-            depth: depth,
-        })
+    }
+
+    /// Open a port with these options.
+    pub fn client(&self, options: &ClientOptions) -> Result<Port, PortError> {
+        self.open_port(
+            options.rpc_timeout,
+            options.scope.clone(),
+            options.depth,
+            options.forwarding,
+            options.options.clone(),
+        )
     }
 
     /// New port with default parameters for a subtree, receiving all packets.
     pub fn subtree_full(&self, subtree_root: DeviceRoute) -> Result<Port, PortError> {
-        self.new_port(None, subtree_root, usize::MAX, true, true)
+        self.new_port(None, subtree_root, usize::MAX, ForwardingPolicy::all())
     }
 
     /// New port with default parameters for a subtree, receiving only RPCs.
     pub fn subtree_rpc(&self, subtree_root: DeviceRoute) -> Result<Port, PortError> {
-        self.new_port(None, subtree_root, usize::MAX, false, false)
+        self.new_port(None, subtree_root, usize::MAX, ForwardingPolicy::rpc_only())
     }
 
     /// New port with default parameters for the full device tree, receiving all packets.
@@ -321,12 +2064,77 @@ impl Interface {
 
     /// New port with default parameters for a specific device, receiving all packets.
     pub fn device_full(&self, address: DeviceRoute) -> Result<Port, PortError> {
-        self.new_port(None, address, 0, true, true)
+        self.new_port(None, address, 0, ForwardingPolicy::all())
     }
 
     /// New port with default parameters for a specific device, receiving only RPCs.
     pub fn device_rpc(&self, address: DeviceRoute) -> Result<Port, PortError> {
-        self.new_port(None, address, 0, false, false)
+        self.new_port(None, address, 0, ForwardingPolicy::rpc_only())
+    }
+
+    /// Call the RPC `name` of the device at `route` with `arg`, without
+    /// keeping a port open: one is opened for the call, which times out
+    /// like the RPCs of ports by default, and closed afterwards. For one off
+    /// commands, such as rebooting a device.
+    /// ```no_run
+    /// # use twinleaf::tio::{proto::DeviceRoute, proxy};
+    /// let proxy = proxy::Interface::new("tcp://localhost");
+    /// let name: String = proxy.rpc(DeviceRoute::root(), "dev.name", ()).unwrap();
+    /// proxy.rpc::<(), ()>(DeviceRoute::root(), "dev.reboot", ()).unwrap();
+    /// ```
+    pub fn rpc<ReqT: TioRpcRequestable<ReqT>, RepT: TioRpcReplyable<RepT>>(
+        &self,
+        route: DeviceRoute,
+        name: &str,
+        arg: ReqT,
+    ) -> Result<RepT, RpcError> {
+        let port = self.device_rpc(route).map_err(RpcError::PortFailed)?;
+        port.rpc(name, arg)
+    }
+
+    /// Change the url of the device and reopen the link, without a port
+    /// open, see `Port::set_url`.
+    /// ```no_run
+    /// # use twinleaf::tio::proxy;
+    /// let proxy = proxy::Interface::new("serial:///dev/ttyUSB0");
+    /// // The device was plugged again, and came back on another port.
+    /// proxy.set_url("serial:///dev/ttyUSB1").unwrap();
+    /// ```
+    pub fn set_url(&self, url: &str) -> Result<(), PortError> {
+        self.tree_rpc()?.set_url(url)
+    }
+
+    /// Attach the device tree of another proxy, reached through `port`,
+    /// under `prefix`: clients of this proxy find its devices at their
+    /// route prefixed with `prefix`, and the packets they send there go
+    /// through `port`. This presents the devices of several acquisition
+    /// machines as a single tree, e.g. those of a field laptop from a lab
+    /// server. The prefix cannot be the root or overlap the one of another
+    /// attached tree, and should not be a route of the device of this
+    /// proxy. Devices whose prefixed route is over 8 hops are unreachable.
+    ///
+    /// The other proxy must be kept running by keeping its `Interface`;
+    /// when it shuts down, its tree is detached.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use twinleaf::tio::{proto::DeviceRoute, proxy};
+    /// let lab = proxy::Interface::new("serial:///dev/ttyUSB0");
+    /// let field = proxy::Interface::builder()
+    ///     .url("tcp://field-laptop")
+    ///     .reconnect(Duration::from_secs(30))
+    ///     .spawn();
+    /// let prefix = DeviceRoute::from_str("/7").unwrap();
+    /// lab.attach(prefix.clone(), field.tree_full().unwrap()).unwrap();
+    /// // The root device of the field laptop, through the lab proxy.
+    /// let name: String = lab.rpc(prefix, "dev.name", ()).unwrap();
+    /// ```
+    pub fn attach(&self, prefix: DeviceRoute, port: Port) -> Result<(), PortError> {
+        let (confirm, confirmed) = channel::bounded(1);
+        self.clients
+            .downstream_queue
+            .send((Downstream::new(prefix, port), confirm))
+            .map_err(|_| PortError::ProxyDisconnected)?;
+        confirmed.recv().map_err(|_| PortError::ProxyDisconnected)?
     }
 
     /// New port with default parameters for the root device, receiving all packets.
@@ -1,14 +1,24 @@
+use super::budget::{self, BudgetAccount, BudgetPolicy, MemoryBudget};
 use super::port;
 use super::port::Port as HardwarePort;
 use super::port::RecvError;
+use super::power;
 use super::proto::{self, DeviceRoute, Packet};
-use super::proxy::Event;
+use super::proxy::{
+    AutoRateRpcs, AutoRateStatus, ClientControl, ClientDropReason, Direction, Event,
+    ForwardingPolicy, LinkEvent, LinkStatus, Port as ProxyPort, PortError, Readiness,
+    RecvError as ProxyRecvError, RouteActivity, RpcRateLimit, SniffedPacket, Topology,
+    INTERNAL_CLIENT_ID, INTERNAL_RPC_WIRE_IDS,
+};
 use super::util;
 use super::util::TioRpcReplyable;
 
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
 
 use crossbeam::channel;
 
@@ -19,6 +29,30 @@ struct StatusQueue {
 
 impl StatusQueue {
     fn send(&self, event: Event) {
+        match &event {
+            Event::RpcRemap(..) | Event::RpcRestore(..) => log_trace!("{:?}", event),
+            Event::SensorConnected
+            | Event::SensorDisconnected
+            | Event::SensorReconnected
+            | Event::FailedToConnect(_)
+            | Event::FailedToReconnect(_)
+            | Event::PortBusy(_)
+            | Event::Exiting
+            | Event::FatalError(_)
+            | Event::ReconnectRequested(_)
+            | Event::UrlChanged(_)
+            | Event::RootDeviceRestarted
+            | Event::RootDeviceSleeping
+            | Event::RootDeviceAwake
+            | Event::DownstreamAttached(_)
+            | Event::DownstreamDetached(_)
+            | Event::AutoRateGaveUp
+            | Event::SetRate(_)
+            | Event::SetRateFailed
+            | Event::LinkLoadNormal(..) => log_info!("{:?}", event),
+            Event::LinkOverloaded(..) => log_warn!("{:?}", event),
+            _ => log_debug!("{:?}", event),
+        }
         if match &event {
             Event::NewClient(_) => true,
             _ => !self.only_new_client,
@@ -35,12 +69,22 @@ pub struct ProxyClient {
     /// Used to send packets to the client
     tx: channel::Sender<Packet>,
 
+    /// If set, RPC replies and errors are sent to the client here, so that
+    /// they do not wait behind stream data queued in `tx`.
+    rpc_tx: Option<channel::Sender<Packet>>,
+
     /// Used to receive packets from the client
     rx: channel::Receiver<Packet>,
 
     /// Configurable (per-client) timeout for RPCs
     rpc_timeout: Duration,
 
+    /// Timeouts overriding `rpc_timeout` for the next request with each id.
+    rpc_timeout_hints: HashMap<u16, Duration>,
+
+    /// Ids of the RPC requests in flight the client cancelled, to process.
+    rpc_cancels: Vec<u16>,
+
     /// Restrict traffic to devices in the device tree at or under this node.
     /// Addresses are stripped of this common prefix on receive, and augmented
     /// with it on transmit.
@@ -49,11 +93,218 @@ pub struct ProxyClient {
     /// Restrict traffic to devices at most this deep under the scope root.
     depth: usize,
 
-    /// Forward sample data.
-    forward_data: bool,
+    /// Which packets to forward besides RPCs.
+    forwarding: ForwardingPolicy,
+
+    /// Sample data packets seen per device and stream, to decimate them.
+    decimation: RefCell<HashMap<(DeviceRoute, u8), u32>>,
+
+    /// Accounting of the packets queued to the client, if memory is budgeted.
+    account: Option<RefCell<BudgetAccount>>,
+
+    /// Packets dropped in a row because the client's queue was full.
+    send_failures: Cell<u32>,
+
+    /// Changes to the scope and forwarding requested by the client.
+    control: Option<channel::Receiver<ClientControl>>,
+
+    /// The client asked to reopen the link to the device, at a new url if
+    /// set.
+    reconnect: bool,
+    new_url: Option<String>,
+
+    /// If set, changes in the connection to the device are sent here.
+    link: Option<channel::Sender<LinkEvent>>,
+
+    /// RPC requests to send again after the device reconnects, relative to
+    /// the scope.
+    reconnect_rpcs: Vec<Packet>,
+
+    /// RPC rate limiting state for each device the client sends requests to.
+    rpc_buckets: HashMap<DeviceRoute, TokenBucket>,
+
+    /// Set for sniffer clients, which get a copy of all traffic to and from
+    /// the device here instead of regular packets.
+    sniff: Option<channel::Sender<SniffedPacket>>,
+
+    /// Set for console clients, which get the text output of the device at
+    /// `scope` here instead of regular packets.
+    console: Option<RefCell<ConsoleLines>>,
+}
+
+/// Replies to `dev.metadata` requests, shared by all clients. Replies are
+/// kept until the session of their device changes, when it restarts, until
+/// the device announces changed metadata, as it does when starting a new
+/// segment, or until the link to the device is lost or reopened.
+struct MetadataCache {
+    /// Last session seen in the heartbeats of each device.
+    sessions: HashMap<DeviceRoute, u32>,
+    /// Reply to each request argument, by device and session.
+    replies: HashMap<(DeviceRoute, u32), HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MetadataCache {
+    fn new() -> MetadataCache {
+        MetadataCache {
+            sessions: HashMap::new(),
+            replies: HashMap::new(),
+        }
+    }
+
+    /// True for requests whose replies can be cached.
+    fn is_metadata_request(req: &proto::RpcRequestPayload) -> bool {
+        matches!(&req.method, proto::RpcMethod::Name(name) if name == "dev.metadata")
+    }
+
+    fn get(&self, route: &DeviceRoute, arg: &[u8]) -> Option<&Vec<u8>> {
+        let session = self.sessions.get(route)?;
+        self.replies.get(&(route.clone(), *session))?.get(arg)
+    }
+
+    fn insert(&mut self, route: &DeviceRoute, arg: Vec<u8>, reply: Vec<u8>) {
+        let Some(session) = self.sessions.get(route) else {
+            return;
+        };
+        if MetadataCache::cacheable(&reply) {
+            self.replies
+                .entry((route.clone(), *session))
+                .or_default()
+                .insert(arg, reply);
+        }
+    }
+
+    /// True if a reply is a well formed list of metadata.
+    fn cacheable(reply: &[u8]) -> bool {
+        let mut offset = 0;
+        while offset + 2 <= reply.len() {
+            offset += 2 + usize::from(reply[offset + 1]);
+        }
+        offset == reply.len() && offset > 0
+    }
+
+    /// Process a session heartbeat, forgetting the replies of the device
+    /// if it restarted.
+    fn session(&mut self, route: &DeviceRoute, session: u32) {
+        if let Some(old) = self.sessions.insert(route.clone(), session) {
+            if old != session {
+                log_debug!("Metadata of {} invalidated by new session", route);
+                self.replies.remove(&(route.clone(), old));
+            }
+        }
+    }
+
+    /// Forget the replies of a device, which announced changed metadata.
+    fn invalidate(&mut self, route: &DeviceRoute) {
+        self.replies.retain(|(r, _), _| r != route);
+    }
+
+    /// Forget everything about the devices not in these subtrees, after the
+    /// link to them was lost: another device can be found there once it
+    /// reconnects, before any of its heartbeats.
+    fn invalidate_except(&mut self, prefixes: &[DeviceRoute]) {
+        let kept = |route: &DeviceRoute| prefixes.iter().any(|prefix| route.starts_with(prefix));
+        self.replies.retain(|(r, _), _| kept(r));
+        self.sessions.retain(|r, _| kept(r));
+    }
+
+    /// Forget everything about the devices of a subtree, which is gone.
+    fn invalidate_subtree(&mut self, root: &DeviceRoute) {
+        self.replies.retain(|(r, _), _| !r.starts_with(root));
+        self.sessions.retain(|r, _| !r.starts_with(root));
+    }
+}
+
+/// Device tree of another proxy, reached through one of its ports and
+/// attached under `prefix`, see `Interface::attach`.
+pub(crate) struct Downstream {
+    prefix: DeviceRoute,
+    port: ProxyPort,
+}
+
+/// A downstream proxy to attach, and where to tell whether it was.
+pub(crate) type DownstreamRequest = (Downstream, channel::Sender<Result<(), PortError>>);
+
+impl Downstream {
+    pub(crate) fn new(prefix: DeviceRoute, port: ProxyPort) -> Downstream {
+        Downstream { prefix, port }
+    }
+
+    /// True if the packets for `route` go to this tree.
+    fn routes(&self, route: &DeviceRoute) -> bool {
+        route.starts_with(&self.prefix)
+    }
+
+    /// Send a packet with an absolute route to the other proxy. RPC
+    /// requests time out there at `timeout` as well, so that no late reply
+    /// comes back after this proxy gave up on them.
+    fn send(&self, mut pkt: Packet, timeout: Instant) -> bool {
+        pkt.routing = match self.prefix.relative_route(&pkt.routing) {
+            Ok(route) => route,
+            Err(()) => return false,
+        };
+        if let proto::Payload::RpcRequest(req) = &pkt.payload {
+            let remaining = timeout.saturating_duration_since(Instant::now());
+            if self.port.set_rpc_timeout(req.id, remaining).is_err() {
+                return false;
+            }
+        }
+        self.port.try_send(pkt).is_ok()
+    }
+}
+
+/// Token bucket rate limiter.
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RpcRateLimit) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available, after refilling the bucket for
+    /// the time elapsed since the last call.
+    fn take(&mut self, limit: &RpcRateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Assembles the text output of a device into lines.
+struct ConsoleLines {
+    tx: channel::Sender<String>,
+    partial: String,
+}
+
+impl ConsoleLines {
+    /// Add text to the current line, sending every completed line. Lines
+    /// which do not fit in the channel are dropped.
+    fn push(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' | '\r' => self.end_line(),
+                c => self.partial.push(c),
+            }
+        }
+    }
 
-    /// Forward packets that are not sample data nor RPC-related.
-    forward_nonrpc: bool,
+    /// Complete the current line, if any.
+    fn end_line(&mut self) {
+        if !self.partial.is_empty() {
+            let _ = self.tx.try_send(std::mem::take(&mut self.partial));
+        }
+    }
 }
 
 impl ProxyClient {
@@ -63,21 +314,174 @@ impl ProxyClient {
         rpc_timeout: Duration,
         scope: DeviceRoute,
         depth: usize,
-        forward_data: bool,
-        forward_nonrpc: bool,
+        forwarding: ForwardingPolicy,
     ) -> ProxyClient {
         ProxyClient {
             tx,
+            rpc_tx: None,
             rx,
             rpc_timeout,
+            rpc_timeout_hints: HashMap::new(),
+            rpc_cancels: vec![],
+            reconnect: false,
+            new_url: None,
             scope,
             depth,
-            forward_data,
-            forward_nonrpc,
+            forwarding,
+            decimation: RefCell::new(HashMap::new()),
+            account: None,
+            send_failures: Cell::new(0),
+            control: None,
+            link: None,
+            reconnect_rpcs: vec![],
+            rpc_buckets: HashMap::new(),
+            sniff: None,
+            console: None,
         }
     }
 
-    fn send(&self, pkt: &Packet) -> Result<(), channel::TrySendError<Packet>> {
+    /// Create a sniffer client. `rx` is only used to detect when the
+    /// sniffer goes away.
+    pub fn sniffer(
+        rx: channel::Receiver<Packet>,
+        sniff: channel::Sender<SniffedPacket>,
+    ) -> ProxyClient {
+        let (tx, _) = channel::bounded(0);
+        let mut ret = ProxyClient::new(
+            tx,
+            rx,
+            Duration::from_secs(1),
+            DeviceRoute::root(),
+            0,
+            ForwardingPolicy::rpc_only(),
+        );
+        ret.sniff = Some(sniff);
+        ret
+    }
+
+    /// Create a console client for the device at `route`. `rx` is only
+    /// used to detect when the console goes away.
+    pub fn console(
+        rx: channel::Receiver<Packet>,
+        route: DeviceRoute,
+        lines: channel::Sender<String>,
+    ) -> ProxyClient {
+        let (tx, _) = channel::bounded(0);
+        let mut ret = ProxyClient::new(
+            tx,
+            rx,
+            Duration::from_secs(1),
+            route,
+            0,
+            ForwardingPolicy::rpc_only(),
+        );
+        ret.console = Some(RefCell::new(ConsoleLines {
+            tx: lines,
+            partial: String::new(),
+        }));
+        ret
+    }
+
+    /// Send RPC replies and errors on a separate channel, to be delivered
+    /// ahead of other packets.
+    pub fn with_rpc_lane(mut self, rpc_tx: channel::Sender<Packet>) -> ProxyClient {
+        self.rpc_tx = Some(rpc_tx);
+        self
+    }
+
+    /// Let the client change its scope and forwarding through `control`.
+    pub fn with_control(mut self, control: channel::Receiver<ClientControl>) -> ProxyClient {
+        self.control = Some(control);
+        self
+    }
+
+    /// Notify the client of changes in the connection to the device.
+    pub fn with_link_events(mut self, link: channel::Sender<LinkEvent>) -> ProxyClient {
+        self.link = Some(link);
+        self
+    }
+
+    /// RPC requests, relative to the client scope, to send again on behalf
+    /// of the client after the device reconnects.
+    pub fn with_reconnect_rpcs(mut self, rpcs: Vec<Packet>) -> ProxyClient {
+        self.reconnect_rpcs = rpcs;
+        self
+    }
+
+    /// Account for the packets queued to this client against `budget`.
+    pub fn with_budget(mut self, budget: Option<Arc<MemoryBudget>>) -> ProxyClient {
+        self.account = budget.map(|b| RefCell::new(BudgetAccount::new(b)));
+        self
+    }
+
+    /// Queue a packet to the client, within the memory budget if any.
+    /// RPC replies go to the RPC lane if there is one, outside of the budget
+    /// as they are few and small. Fails if the client should be dropped.
+    fn queue(&self, pkt: Packet) -> Result<(), ClientDropReason> {
+        if self.sniff.is_some() || self.console.is_some() {
+            return Ok(());
+        }
+        if let Some(rpc_tx) = &self.rpc_tx {
+            if let proto::Payload::RpcReply(_) | proto::Payload::RpcError(_) = pkt.payload {
+                return self.check_sent(rpc_tx.try_send(pkt));
+            }
+        }
+        let mut account = match &self.account {
+            Some(account) => account.borrow_mut(),
+            None => return self.check_sent(self.tx.try_send(pkt)),
+        };
+        account.reconcile(self.tx.len());
+        if !account.reserve(budget::packet_size(&pkt)) {
+            account.budget().record_drop();
+            return match account.budget().policy() {
+                BudgetPolicy::DropPackets => Ok(()),
+                BudgetPolicy::DropClient => Err(ClientDropReason::OverBudget),
+            };
+        }
+        let ret = self.tx.try_send(pkt);
+        if ret.is_err() {
+            account.unreserve_last();
+        }
+        self.check_sent(ret)
+    }
+
+    /// Packets which do not fit in the client's queue are dropped, unless
+    /// it stays full for `MAX_SEND_FAILURES` packets in a row, in which
+    /// case the client is not keeping up and should be dropped instead.
+    fn check_sent(
+        &self,
+        result: Result<(), channel::TrySendError<Packet>>,
+    ) -> Result<(), ClientDropReason> {
+        match result {
+            Ok(()) => {
+                self.send_failures.set(0);
+                Ok(())
+            }
+            Err(channel::TrySendError::Disconnected(_)) => Err(ClientDropReason::Disconnected),
+            Err(channel::TrySendError::Full(_)) => {
+                let failures = self.send_failures.get() + 1;
+                self.send_failures.set(failures);
+                if failures >= MAX_SEND_FAILURES {
+                    Err(ClientDropReason::Unresponsive)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn send(&self, pkt: &Packet) -> Result<(), ClientDropReason> {
+        if let Some(console) = &self.console {
+            if let proto::Payload::LogMessage(log) = &pkt.payload {
+                if pkt.routing == self.scope {
+                    // Each log message is a line of its own.
+                    let mut console = console.borrow_mut();
+                    console.push(&log.message);
+                    console.end_line();
+                }
+            }
+            return Ok(());
+        }
         let scoped_route = if let Ok(r) = self.scope.relative_route(&pkt.routing) {
             if r.len() <= self.depth {
                 r
@@ -87,33 +491,121 @@ impl ProxyClient {
         } else {
             return Ok(());
         };
-        if !match pkt.payload {
-            proto::Payload::RpcRequest(_)
-            | proto::Payload::RpcReply(_)
-            | proto::Payload::RpcError(_) => true,
-            proto::Payload::LegacyStreamData(_) | proto::Payload::StreamData(_) => {
-                self.forward_data
-            }
-            _ => self.forward_nonrpc,
-        } {
+        if !self.forwarding.forwards(&pkt.payload) || self.decimated(pkt) {
             return Ok(());
         }
-        self.tx.try_send(Packet {
+        self.queue(Packet {
             payload: pkt.payload.clone(),
             routing: scoped_route,
             ttl: pkt.ttl,
+            received: pkt.received,
         })
     }
 
-    fn recv(&self) -> Result<Packet, channel::TryRecvError> {
+    /// True if this packet should be skipped to decimate sample data.
+    fn decimated(&self, pkt: &Packet) -> bool {
+        if self.forwarding.decimation <= 1 {
+            return false;
+        }
+        let stream_id = match &pkt.payload {
+            proto::Payload::StreamData(data) => data.stream_id,
+            proto::Payload::LegacyStreamData(_) => 0,
+            _ => return false,
+        };
+        let mut decimation = self.decimation.borrow_mut();
+        let count = decimation
+            .entry((pkt.routing.clone(), stream_id))
+            .or_insert(0);
+        let skip = *count != 0;
+        *count = (*count + 1) % self.forwarding.decimation;
+        skip
+    }
+
+    /// Send the heartbeat announcing a new session of the root device. Clients
+    /// which can see the root device get it even if they do not forward
+    /// heartbeats otherwise.
+    fn send_restart(&self, pkt: &Packet) -> Result<(), ClientDropReason> {
+        if self.scope.is_empty() {
+            self.queue(pkt.clone())
+        } else {
+            self.send(pkt)
+        }
+    }
+
+    /// Notify the client of a change in the connection, if it asked for it.
+    /// Events which do not fit in the channel are dropped.
+    fn send_link(&self, event: LinkEvent) {
+        if let Some(link) = &self.link {
+            let _ = link.try_send(event);
+        }
+    }
+
+    /// Text printed on the port by the root device, which a serial port
+    /// delivers one line at a time.
+    fn send_text(&self, text: &str) {
+        if let Some(console) = &self.console {
+            if self.scope.is_empty() {
+                let mut console = console.borrow_mut();
+                console.push(text);
+                console.end_line();
+            }
+        }
+    }
+
+    /// Apply the changes requested by the client so far.
+    fn apply_control(&mut self) {
+        while let Some(control) = &self.control {
+            match control.try_recv() {
+                Ok(ClientControl::Scope { scope, depth }) => {
+                    self.scope = scope;
+                    self.depth = depth;
+                    self.decimation.borrow_mut().clear();
+                }
+                Ok(ClientControl::Forwarding(forwarding)) => {
+                    self.forwarding = forwarding;
+                    self.decimation.borrow_mut().clear();
+                }
+                Ok(ClientControl::RpcTimeout { id, timeout }) => {
+                    self.rpc_timeout_hints.insert(id, timeout);
+                }
+                Ok(ClientControl::RpcCancel { id }) => {
+                    self.rpc_cancels.push(id);
+                }
+                Ok(ClientControl::Reconnect) => {
+                    self.reconnect = true;
+                }
+                Ok(ClientControl::SetUrl(url)) => {
+                    self.reconnect = true;
+                    self.new_url = Some(url);
+                }
+                Err(channel::TryRecvError::Empty) => break,
+                // The port is gone, which is detected on its packet channel.
+                Err(channel::TryRecvError::Disconnected) => self.control = None,
+            }
+        }
+    }
+
+    /// Receive a packet from the client, translating its route from relative
+    /// to the client scope to absolute. Packets addressed outside of the scope
+    /// are returned untranslated as `Err`.
+    fn recv(&self) -> Result<Result<Packet, Packet>, channel::TryRecvError> {
         let mut pkt = self.rx.try_recv()?;
+        if pkt.routing.len() > self.depth {
+            return Ok(Err(pkt));
+        }
         pkt.routing = self.scope.absolute_route(&pkt.routing);
-        Ok(pkt)
+        Ok(Ok(pkt))
+    }
+
+    /// Send a packet whose route is already relative to the client scope,
+    /// bypassing all forwarding restrictions.
+    fn send_scoped(&self, pkt: Packet) -> Result<(), ClientDropReason> {
+        self.queue(pkt)
     }
 }
 
 /// States for the rate autonegotiation state machine
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum RateChange {
     DoNothing,
     WaitingForSession,
@@ -121,20 +613,139 @@ enum RateChange {
     WaitingDeviceRate,
     SetDeviceRate,
     WaitingNewRate,
+    /// The port was switched to the new rate, waiting for data.
     RateChanged,
+    /// Data was received at the new rate.
+    RateConfirmed,
+    GaveUp,
+}
+
+/// States for the rate negotiation of the link of a device behind a hub.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RouteRateState {
+    Pending,
+    Querying,
+    /// The device supports this rate, close enough to the target.
+    Compatible(u32),
+    Setting(u32),
+    Done,
     GaveUp,
 }
 
+/// Rate negotiation of the link between the device at `route` and its hub.
+#[derive(Debug, Clone)]
+struct RouteRate {
+    route: DeviceRoute,
+    target_bps: u32,
+    state: RouteRateState,
+    /// Candidate of `ProxyCore::autorate_rpcs` in use.
+    rpcs: usize,
+}
+
+/// True if a rate `value` offered by a device is close enough to `target`.
+fn rate_compatible(target: u32, value: u32) -> bool {
+    value != 0 && (((target as f64) - (value as f64)) / (value as f64)).abs() <= 0.015
+}
+
+/// Rate in a reply to the rate RPCs, which firmwares give as integers of
+/// different sizes.
+fn rate_from_reply(reply: &[u8]) -> Option<u32> {
+    match reply.len() {
+        2 => u16::from_reply(reply).ok().map(u32::from),
+        4 => u32::from_reply(reply).ok(),
+        8 => u64::from_reply(reply)
+            .ok()
+            .and_then(|x| u32::try_from(x).ok()),
+        _ => None,
+    }
+}
+
+/// Window over which the load of the link is estimated.
+static LOAD_WINDOW: Duration = Duration::from_secs(1);
+/// Fraction of the link rate above which the link is overloaded, and the
+/// fraction below which it is not anymore.
+static LOAD_OVERLOADED: f64 = 0.9;
+static LOAD_NORMAL: f64 = 0.75;
+
+/// Estimates the bandwidth used by the data the device sends, to warn when
+/// it gets close to what the link can carry: the device then buffers the
+/// data it cannot send, which arrives increasingly late.
+struct LinkLoad {
+    window_start: Instant,
+    bits: u64,
+    /// Estimate over the last complete window.
+    rx_bps: Option<u32>,
+    overloaded: bool,
+}
+
+impl LinkLoad {
+    fn new() -> LinkLoad {
+        LinkLoad {
+            window_start: Instant::now(),
+            bits: 0,
+            rx_bps: None,
+            overloaded: false,
+        }
+    }
+
+    fn add(&mut self, pkt: &Packet) {
+        // Serial framing: start and stop bits around each byte, and the
+        // packet delimiters. Escaped bytes are not accounted for.
+        let bytes = pkt.serialized_size() + 2;
+        self.bits += 10 * bytes as u64;
+    }
+
+    /// Close the current window if it is over, and check the load against
+    /// the link rate `rate_bps`.
+    fn update(&mut self, rate_bps: u32, status_queue: &StatusQueue) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < LOAD_WINDOW {
+            return;
+        }
+        let rx_bps = (self.bits as f64 / elapsed.as_secs_f64()) as u32;
+        self.window_start = Instant::now();
+        self.bits = 0;
+        self.rx_bps = Some(rx_bps);
+        let load = rx_bps as f64 / rate_bps as f64;
+        if !self.overloaded && load >= LOAD_OVERLOADED {
+            self.overloaded = true;
+            status_queue.send(Event::LinkOverloaded(rx_bps, rate_bps));
+        } else if self.overloaded && load < LOAD_NORMAL {
+            self.overloaded = false;
+            status_queue.send(Event::LinkLoadNormal(rx_bps, rate_bps));
+        }
+    }
+}
+
 struct ProxyDevice {
     tio_port: HardwarePort,
     rx_channel: channel::Receiver<Result<Packet, RecvError>>,
     rate_change_state: RateChange,
     last_rx: Instant,
     last_session: Option<u32>,
-    restarted: bool,
+    /// The device was put into low power mode, so a lack of data is expected.
+    sleeping: bool,
+    /// Links behind hubs to negotiate the rate of, in order.
+    route_rates: Vec<RouteRate>,
+    /// Candidate of `ProxyCore::autorate_rpcs` in use for the root device.
+    autorate_rpcs: usize,
+    /// Load of links with a settable rate.
+    load: Option<LinkLoad>,
 }
 
 impl ProxyDevice {
+    /// Move the rate autonegotiation state machine to `next`.
+    fn set_rate_state(&mut self, next: RateChange) {
+        if self.rate_change_state != next {
+            log_debug!(
+                "Rate negotiation: {:?} -> {:?}",
+                self.rate_change_state,
+                next
+            );
+            self.rate_change_state = next;
+        }
+    }
+
     /// True if this device does not have a settable data rate.
     fn has_static_rate(&self) -> bool {
         match self.rate_change_state {
@@ -149,18 +760,83 @@ impl ProxyDevice {
     /// by without seeing data.
     fn needs_autonegotiation(&self) -> bool {
         match self.rate_change_state {
-            RateChange::DoNothing | RateChange::GaveUp => false,
+            RateChange::DoNothing | RateChange::GaveUp => self.route_rates_pending(),
             _ => true,
         }
     }
 
+    /// True if the negotiation of the link to the root device is over, one
+    /// way or another, so that links behind hubs can be negotiated.
+    fn root_rate_settled(&self) -> bool {
+        matches!(
+            self.rate_change_state,
+            RateChange::DoNothing
+                | RateChange::GaveUp
+                | RateChange::RateChanged
+                | RateChange::RateConfirmed
+        )
+    }
+
+    /// True if some links behind hubs are still to negotiate.
+    fn route_rates_pending(&self) -> bool {
+        self.route_rates
+            .iter()
+            .any(|r| !matches!(r.state, RouteRateState::Done | RouteRateState::GaveUp))
+    }
+
+    /// The link being negotiated, waiting for a reply in `state`.
+    fn route_rate_waiting(&mut self, state: fn(&RouteRateState) -> bool) -> Option<&mut RouteRate> {
+        self.route_rates.iter_mut().find(|r| state(&r.state))
+    }
+
     /// True if it's safe to forward packets to the device due to rate
     /// negotiation concerns. Specifically, packets might be lost around
     /// when the rate transitions, so we hold back on forwarding traffic then.
     fn safe_to_forward(&self) -> bool {
         match self.rate_change_state {
             RateChange::SetDeviceRate | RateChange::WaitingNewRate => false,
-            _ => true,
+            _ => !self
+                .route_rates
+                .iter()
+                .any(|r| matches!(r.state, RouteRateState::Setting(_))),
+        }
+    }
+
+    /// State of the link to this device.
+    fn link_status(&self) -> LinkStatus {
+        let rates = self.tio_port.rate_info();
+        let autorate = match self.rate_change_state {
+            RateChange::DoNothing => AutoRateStatus::Unavailable,
+            RateChange::RateChanged => AutoRateStatus::Confirming,
+            RateChange::RateConfirmed => AutoRateStatus::Negotiated,
+            RateChange::GaveUp => AutoRateStatus::FellBack,
+            _ => AutoRateStatus::Negotiating,
+        };
+        LinkStatus {
+            connected: true,
+            rate_bps: self.rate_bps(),
+            default_bps: rates.as_ref().map(|r| r.default_bps),
+            target_bps: rates.as_ref().map(|r| r.target_bps),
+            autorate,
+            rx_bps: self.load.as_ref().and_then(|l| l.rx_bps),
+            overloaded: self.load.as_ref().is_some_and(|l| l.overloaded),
+            counters: self.tio_port.link_counters(),
+        }
+    }
+
+    /// Rate the link runs at, if it has a settable rate.
+    fn rate_bps(&self) -> Option<u32> {
+        let rates = self.tio_port.rate_info()?;
+        Some(match self.rate_change_state {
+            RateChange::RateChanged | RateChange::RateConfirmed => rates.target_bps,
+            _ => rates.default_bps,
+        })
+    }
+
+    /// Check the load of the link, once per window.
+    fn update_load(&mut self, status_queue: &StatusQueue) {
+        if let (Some(rate_bps), Some(load)) = (self.rate_bps(), self.load.as_mut()) {
+            load.update(rate_bps, status_queue);
         }
     }
 
@@ -177,58 +853,174 @@ impl ProxyDevice {
         &mut self,
         status_queue: &StatusQueue,
     ) -> Result<Result<Packet, RecvError>, crossbeam::channel::TryRecvError> {
-        if self.has_static_rate() {
-            self.rx_channel.try_recv()
-        } else {
-            match self.rx_channel.try_recv() {
-                Ok(res) => {
-                    self.last_rx = match &res {
-                        Ok(pkt) => {
-                            if let proto::Payload::Heartbeat(proto::HeartbeatPayload::Session(
-                                session,
-                            )) = pkt.payload
-                            {
-                                if pkt.routing.len() == 0 {
-                                    // This is a heartbeat for the root sensor
-                                    let old_session = self.last_session.replace(session);
-                                    if let RateChange::WaitingForSession = self.rate_change_state {
-                                        self.rate_change_state = RateChange::QueryDeviceRate;
-                                    } else if (self.last_session != old_session)
-                                        && old_session.is_some()
-                                    {
-                                        status_queue.send(Event::RootDeviceRestarted);
-                                        // It has restarted, restart autonegotiation if needed.
-                                        self.rate_change_state = match self.rate_change_state {
-                                            RateChange::DoNothing => RateChange::DoNothing,
-                                            RateChange::WaitingForSession => {
-                                                RateChange::WaitingForSession
-                                            } // never happens
-                                            _ => RateChange::QueryDeviceRate,
-                                        };
-                                        self.restarted = true;
-                                    }
-                                }
-                            }
-                            Instant::now()
-                        }
-                        // Text means we are still getting data. Other protocol errors could mean we are getting
-                        // garbled bytes from running at the wrong rate
-                        Err(RecvError::Protocol(proto::Error::Text(_))) => Instant::now(),
-                        _ => self.last_rx,
-                    };
-                    Ok(res)
+        let ret = self.rx_channel.try_recv();
+        if !self.has_static_rate() {
+            match &ret {
+                // Text means we are still getting data. Other protocol errors could mean we are getting
+                // garbled bytes from running at the wrong rate
+                Ok(Ok(_)) | Ok(Err(RecvError::Protocol(proto::Error::Text(_)))) => {
+                    self.last_rx = Instant::now();
                 }
-                err => err,
+                _ => {}
+            }
+            // Only a valid packet shows that the link works at the new rate.
+            if let (Ok(Ok(_)), RateChange::RateChanged) = (&ret, &self.rate_change_state) {
+                self.set_rate_state(RateChange::RateConfirmed);
+                status_queue.send(Event::AutoRateConfirmed(self.rates().target_bps));
+            }
+        }
+        if let (Ok(Ok(pkt)), Some(load)) = (&ret, self.load.as_mut()) {
+            load.add(pkt);
+        }
+        if self.sleeping {
+            if let Ok(Ok(_)) = &ret {
+                self.sleeping = false;
+                status_queue.send(Event::RootDeviceAwake);
+            }
+        }
+        ret
+    }
+
+    /// Track the session of the root device, as announced by its heartbeats.
+    /// Returns true if the session changed, meaning that the device restarted.
+    fn update_session(&mut self, session: u32) -> bool {
+        let old_session = self.last_session.replace(session);
+        if let RateChange::WaitingForSession = self.rate_change_state {
+            self.set_rate_state(RateChange::QueryDeviceRate);
+            false
+        } else if old_session.is_some() && (old_session != Some(session)) {
+            // It has restarted, restart autonegotiation if needed.
+            let next_state = match self.rate_change_state {
+                RateChange::DoNothing => RateChange::DoNothing,
+                _ => RateChange::QueryDeviceRate,
+            };
+            self.set_rate_state(next_state);
+            // Hubs come back at their default rates too.
+            for route_rate in &mut self.route_rates {
+                route_rate.state = RouteRateState::Pending;
             }
+            true
+        } else {
+            false
         }
     }
 }
 
+/// Allocator for the RPC ids used on the wire. Ids are handed out in
+/// increasing order, wrapping around, skipping those still in use, so
+/// that all the ids can be used concurrently. `INTERNAL_RPC_WIRE_IDS` are
+/// only handed out by `alloc_internal`.
+struct RpcIdAllocator {
+    used: Vec<u64>,
+    next: u16,
+    count: usize,
+    internal: HashSet<u16>,
+}
+
+impl RpcIdAllocator {
+    fn new() -> RpcIdAllocator {
+        let mut ret = RpcIdAllocator {
+            used: vec![0; 65536 / 64],
+            next: 0,
+            count: 0,
+            internal: HashSet::new(),
+        };
+        // Reserved ids are permanently in use as far as `alloc` is concerned.
+        for id in INTERNAL_RPC_WIRE_IDS.clone() {
+            ret.used[usize::from(id) / 64] |= 1 << (id % 64);
+            ret.count += 1;
+        }
+        ret
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == 65536
+    }
+
+    fn alloc(&mut self) -> Option<u16> {
+        if self.is_full() {
+            return None;
+        }
+        let start = usize::from(self.next);
+        // Scan a word at a time, starting from the word containing `next`
+        // with the bits below it masked off, and wrap around to check them last.
+        for i in 0..=self.used.len() {
+            let word = (start / 64 + i) % self.used.len();
+            let mut bits = self.used[word];
+            if i == 0 {
+                bits |= (1u64 << (start % 64)) - 1;
+            }
+            if bits != u64::MAX {
+                let id = (word * 64 + bits.trailing_ones() as usize) as u16;
+                self.used[word] |= 1 << (id % 64);
+                self.count += 1;
+                self.next = id.wrapping_add(1);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Allocate one of the ids reserved for internal RPCs.
+    fn alloc_internal(&mut self) -> Option<u16> {
+        let id = INTERNAL_RPC_WIRE_IDS
+            .clone()
+            .find(|id| !self.internal.contains(id))?;
+        self.internal.insert(id);
+        Some(id)
+    }
+
+    fn free(&mut self, id: u16) {
+        if INTERNAL_RPC_WIRE_IDS.contains(&id) {
+            self.internal.remove(&id);
+            return;
+        }
+        let (word, bit) = (usize::from(id) / 64, id % 64);
+        if self.used[word] & (1 << bit) != 0 {
+            self.used[word] &= !(1 << bit);
+            self.count -= 1;
+        }
+    }
+}
+
+/// Receivers of the clients, in the order the main loop selects on them:
+/// the packets of every client, then the changes of those which have a
+/// control channel. Gathered again only when clients come and go, rather
+/// than on every iteration, which is costly with many clients at high
+/// packet rates.
+#[derive(Default)]
+struct ClientReceivers {
+    data: Vec<(u64, channel::Receiver<Packet>)>,
+    control: Vec<(u64, channel::Receiver<ClientControl>)>,
+}
+
+impl ClientReceivers {
+    fn new(clients: &HashMap<u64, ProxyClient>) -> ClientReceivers {
+        let mut ret = ClientReceivers::default();
+        for (id, client) in clients.iter() {
+            ret.data.push((*id, client.rx.clone()));
+            if let Some(control) = &client.control {
+                ret.control.push((*id, control.clone()));
+            }
+        }
+        ret
+    }
+}
+
+/// RPC request waiting for a wire id to become available.
+struct QueuedRpc {
+    pkt: Packet,
+    client: u64,
+    timeout: Instant,
+}
+
 struct RpcMapEntry {
     id: u16,
     client: u64,
     route: DeviceRoute,
     timeout: Instant,
+    /// Argument of metadata requests, to cache their reply.
+    metadata_arg: Option<Vec<u8>>,
 }
 
 pub struct ProxyCore {
@@ -239,19 +1031,77 @@ pub struct ProxyCore {
 
     device: Option<ProxyDevice>,
 
+    /// Proxies to attach under a route prefix.
+    downstream_queue: channel::Receiver<DownstreamRequest>,
+    /// Proxies attached, whose device trees are merged with the one of
+    /// the device.
+    downstreams: Vec<Downstream>,
+
     /// Id to assign to the next client, 64 bits.
     /// It is realistic to assume that it will never wrap around.
     next_client_id: u64,
     clients: HashMap<u64, ProxyClient>,
     clients_to_drop: HashSet<u64>,
 
-    next_rpc_id: u16,
+    rpc_ids: RpcIdAllocator,
+    /// Wire ids of requests cancelled by their client, which were freed
+    /// right away, until they would have timed out, to drop late replies.
+    cancelled_rpcs: HashMap<u16, Instant>,
     rpc_map: HashMap<u16, RpcMapEntry>,
+    /// RPC requests received while all wire ids were in use.
+    rpc_queue: VecDeque<QueuedRpc>,
     rpc_timeouts: BTreeMap<Instant, HashSet<u16>>,
+    /// RPC requests of clients to send again once the reconnected device
+    /// is ready, with absolute routes.
+    reconnect_rpcs: Vec<Packet>,
+
+    /// Negotiate the port rate with the device when possible.
+    autorate: bool,
+    /// RPCs to negotiate rates with, tried in order.
+    autorate_rpcs: Vec<AutoRateRpcs>,
+
+    /// Links behind hubs to negotiate the rate of, with their target rate.
+    route_autorate: Vec<(DeviceRoute, u32)>,
+
+    /// Limit on the RPC request rate of each client to each device.
+    rpc_rate_limit: Option<RpcRateLimit>,
+
+    /// Metadata replies shared by the clients, if enabled.
+    metadata_cache: Option<MetadataCache>,
+
+    /// State of the link shared with the ports, and the last value
+    /// published to it.
+    link_status: Arc<Mutex<LinkStatus>>,
+    last_link_status: LinkStatus,
+    /// Link counters of the previous connections.
+    past_counters: port::LinkCounters,
+    /// Devices packets were received from, shared with the ports.
+    topology: Arc<Mutex<Topology>>,
+    /// Outcome of the first connection, shared with the interface.
+    readiness: Arc<Readiness>,
+
+    /// Where to send a copy of the raw bytes received from the device.
+    raw_tap: Option<channel::Sender<Vec<u8>>>,
+    /// How strictly the framing of the data from the device is checked.
+    parse_mode: port::ParseMode,
+
+    /// TTL given to packets sent to the device without one.
+    default_ttl: usize,
+
+    /// The port of the device was found in use, and this was reported,
+    /// since it was last opened.
+    port_busy: bool,
 }
 
 static QUERY_RATE_RPC_ID: u16 = 0x101;
 static SET_RATE_RPC_ID: u16 = 0x102;
+static RECONNECT_RPC_ID: u16 = 0x103;
+static ROUTE_QUERY_RATE_RPC_ID: u16 = 0x104;
+static ROUTE_SET_RATE_RPC_ID: u16 = 0x105;
+
+/// Packets dropped in a row because a client's queue is full, after which
+/// the client is dropped.
+static MAX_SEND_FAILURES: u32 = 256;
 
 impl ProxyCore {
     pub fn new(
@@ -270,44 +1120,256 @@ impl ProxyCore {
                 only_new_client: notify_new_client_only,
             },
             device: None,
+            downstream_queue: channel::never(),
+            downstreams: vec![],
             // Start from client 1, as 0 is reserved for internal RPCs.
-            next_client_id: 1,
+            next_client_id: INTERNAL_CLIENT_ID + 1,
             clients: HashMap::new(),
             clients_to_drop: HashSet::new(),
-            next_rpc_id: 0,
+            rpc_ids: RpcIdAllocator::new(),
+            cancelled_rpcs: HashMap::new(),
             rpc_map: HashMap::new(),
+            rpc_queue: VecDeque::new(),
             rpc_timeouts: BTreeMap::new(),
+            reconnect_rpcs: vec![],
+            autorate: true,
+            autorate_rpcs: vec![AutoRateRpcs::default()],
+            route_autorate: vec![],
+            rpc_rate_limit: None,
+            metadata_cache: Some(MetadataCache::new()),
+            link_status: Arc::new(Mutex::new(LinkStatus::new())),
+            last_link_status: LinkStatus::new(),
+            past_counters: port::LinkCounters::default(),
+            topology: Arc::new(Mutex::new(Topology::new())),
+            readiness: Arc::new(Readiness::new()),
+            raw_tap: None,
+            parse_mode: port::ParseMode::Lenient,
+            default_ttl: 0,
+            port_busy: false,
+        }
+    }
+
+    /// Enable or disable port rate autonegotiation.
+    pub fn with_autorate(mut self, autorate: bool) -> ProxyCore {
+        self.autorate = autorate;
+        self
+    }
+
+    /// Negotiate rates with the first of these RPCs found on each device.
+    pub fn with_autorate_rpcs(mut self, rpcs: Vec<AutoRateRpcs>) -> ProxyCore {
+        self.autorate_rpcs = rpcs;
+        self
+    }
+
+    /// Negotiate the rate of the links of the devices at these routes, and
+    /// of the hubs on the way, with their target rate.
+    pub fn with_route_autorate(mut self, routes: Vec<(DeviceRoute, u32)>) -> ProxyCore {
+        self.route_autorate = routes;
+        self
+    }
+
+    /// Links to negotiate, walking each hop to the configured routes from
+    /// the root outwards. Hops not configured themselves take the target
+    /// rate of the first route configured through them.
+    fn route_rates(&self) -> Vec<RouteRate> {
+        let mut ret: Vec<RouteRate> = vec![];
+        let mut add = |route: DeviceRoute, target_bps: u32, explicit: bool| match ret
+            .iter_mut()
+            .find(|r| r.route == route)
+        {
+            Some(existing) if explicit => existing.target_bps = target_bps,
+            Some(_) => {}
+            None => ret.push(RouteRate {
+                route,
+                target_bps,
+                state: RouteRateState::Pending,
+                rpcs: 0,
+            }),
+        };
+        for (route, target_bps) in &self.route_autorate {
+            add(route.clone(), *target_bps, true);
+        }
+        for (route, target_bps) in &self.route_autorate {
+            let mut hop = route.parent();
+            while let Some(hub) = hop {
+                hop = hub.parent();
+                add(hub, *target_bps, false);
+            }
+        }
+        ret.retain(|r| !r.route.is_empty());
+        ret.sort_by_key(|r| r.route.len());
+        ret
+    }
+
+    /// Enable or disable answering metadata requests of clients from
+    /// the replies to earlier requests.
+    pub fn with_metadata_cache(mut self, enable: bool) -> ProxyCore {
+        self.metadata_cache = enable.then(MetadataCache::new);
+        self
+    }
+
+    /// Send a copy of the raw bytes received from the device to `tap`,
+    /// across reconnections, see `port::Port::set_raw_tap`.
+    pub fn with_raw_tap(mut self, tap: Option<channel::Sender<Vec<u8>>>) -> ProxyCore {
+        self.raw_tap = tap;
+        self
+    }
+
+    /// Check the framing of the data from the device leniently or strictly,
+    /// across reconnections, see `port::Port::set_parse_mode`.
+    pub fn with_parse_mode(mut self, mode: port::ParseMode) -> ProxyCore {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Set the TTL of packets sent to the device without one.
+    pub fn with_default_ttl(mut self, ttl: usize) -> ProxyCore {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Keep `link_status` up to date with the state of the link.
+    pub fn with_link_status(mut self, link_status: Arc<Mutex<LinkStatus>>) -> ProxyCore {
+        self.link_status = link_status;
+        self
+    }
+
+    /// Keep `topology` up to date with the devices packets are received from.
+    pub fn with_topology(mut self, topology: Arc<Mutex<Topology>>) -> ProxyCore {
+        self.topology = topology;
+        self
+    }
+
+    pub(crate) fn with_readiness(mut self, readiness: Arc<Readiness>) -> ProxyCore {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Attach the proxies received on `queue`, see `Interface::attach`.
+    pub(crate) fn with_downstream_queue(
+        mut self,
+        queue: channel::Receiver<DownstreamRequest>,
+    ) -> ProxyCore {
+        self.downstream_queue = queue;
+        self
+    }
+
+    fn record_activity(&self, route: &DeviceRoute) {
+        let now = Instant::now();
+        let mut topology = self.topology.lock().unwrap();
+        let activity = topology
+            .entry(route.clone())
+            .or_insert_with(|| RouteActivity {
+                packets: 0,
+                first_seen: now,
+                last_seen: now,
+            });
+        activity.packets += 1;
+        activity.last_seen = now;
+    }
+
+    /// Update the shared link status, if it changed. Rates of a link
+    /// which disconnected are kept, as it usually comes back the same.
+    fn publish_link_status(&mut self) {
+        if let Some(dev) = self.device.as_mut() {
+            dev.update_load(&self.status_queue);
+        }
+        let status = match &self.device {
+            Some(dev) => {
+                let mut status = dev.link_status();
+                let (past, now) = (&self.past_counters, &mut status.counters);
+                now.packets += past.packets;
+                now.crc_errors += past.crc_errors;
+                now.framing_errors += past.framing_errors;
+                now.text_lines += past.text_lines;
+                now.resyncs += past.resyncs;
+                status
+            }
+            None => {
+                self.past_counters = self.last_link_status.counters;
+                LinkStatus {
+                    connected: false,
+                    rate_bps: None,
+                    rx_bps: None,
+                    overloaded: false,
+                    ..self.last_link_status.clone()
+                }
+            }
+        };
+        if status != self.last_link_status {
+            *self.link_status.lock().unwrap() = status.clone();
+            self.last_link_status = status;
         }
     }
 
-    fn try_setup_device(&mut self) -> bool {
+    /// Limit the RPC request rate of each client to each device.
+    pub fn with_rpc_rate_limit(mut self, limit: Option<RpcRateLimit>) -> ProxyCore {
+        self.rpc_rate_limit = limit;
+        self
+    }
+
+    fn try_setup_device(&mut self) -> io::Result<()> {
         if self.device.is_some() {
-            return true;
+            return Ok(());
         }
         let (port_rx_send, port_rx) = HardwarePort::rx_channel();
         let port = match HardwarePort::new(&self.url, HardwarePort::rx_to_channel(port_rx_send)) {
             Ok(p) => p,
-            Err(_) => {
-                return false;
+            Err(err) => {
+                log_debug!("Failed to open {}: {:?}", self.url, err);
+                if let Some(busy) = port::PortBusy::from_error(&err) {
+                    if !self.port_busy {
+                        self.status_queue.send(Event::PortBusy(busy.owner));
+                    }
+                    self.port_busy = true;
+                }
+                return Err(err);
             }
         };
+        self.port_busy = false;
+        if self.raw_tap.is_some() {
+            port.set_raw_tap(self.raw_tap.clone());
+        }
+        port.set_parse_mode(self.parse_mode);
         // Kickstart rate autonegotiation only if the port supports
         // changing rates and the target rate differs from the default.
         let mut rate_change_state = RateChange::DoNothing;
-        if let Some(rates) = port.rate_info() {
+        if let (true, Some(rates)) = (self.autorate, port.rate_info()) {
             if rates.target_bps != rates.default_bps {
                 rate_change_state = RateChange::WaitingForSession;
             }
         }
+        let load = port.rate_info().map(|_| LinkLoad::new());
         self.device = Some(ProxyDevice {
             tio_port: port,
             rx_channel: port_rx,
             rate_change_state: rate_change_state,
             last_rx: Instant::now(),
             last_session: None,
-            restarted: false,
+            sleeping: false,
+            route_rates: self.route_rates(),
+            autorate_rpcs: 0,
+            load,
         });
-        true
+        Ok(())
+    }
+
+    /// Send a copy of a packet to all sniffer clients. Sniffers which do
+    /// not keep up miss packets rather than slowing down the proxy.
+    fn sniff(&self, direction: Direction, client: Option<u64>, pkt: &Packet) {
+        // Packets from the device were stamped when their port read them.
+        let timestamp = match (direction, pkt.received) {
+            (Direction::FromDevice, Some(received)) => received.system_time,
+            _ => SystemTime::now(),
+        };
+        for sniffer in self.clients.values().filter_map(|c| c.sniff.as_ref()) {
+            let _ = sniffer.try_send(SniffedPacket {
+                timestamp,
+                direction,
+                client,
+                packet: pkt.clone(),
+            });
+        }
     }
 
     /// Clients get dropped as part of the main loop. This function adds a
@@ -319,6 +1381,42 @@ impl ProxyCore {
         }
     }
 
+    /// Drop a client which packets could not be sent to.
+    fn drop_dead_client(&mut self, client_id: u64, reason: ClientDropReason) {
+        self.status_queue
+            .send(Event::ClientDropped(client_id, reason));
+        self.drop_client(client_id);
+    }
+
+    /// Process the RPC requests the client cancelled: those still queued are
+    /// dropped, and those in flight free their wire id right away, and their
+    /// reply will be dropped.
+    fn cancel_client_rpcs(&mut self, client_id: u64) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        for id in std::mem::take(&mut client.rpc_cancels) {
+            let queued = self.rpc_queue.iter().position(|q| {
+                q.client == client_id
+                    && matches!(&q.pkt.payload, proto::Payload::RpcRequest(req) if req.id == id)
+            });
+            if let Some(index) = queued {
+                self.rpc_queue.remove(index);
+                continue;
+            }
+            let in_flight = self
+                .rpc_map
+                .iter()
+                .find(|(_, r)| r.client == client_id && r.id == id)
+                .map(|(wire_id, r)| (*wire_id, r.route.clone(), r.timeout));
+            if let Some((wire_id, route, timeout)) = in_flight {
+                self.rpc_restore(wire_id, &route);
+                self.cancelled_rpcs.insert(wire_id, timeout);
+                self.status_queue.send(Event::RpcCancel(wire_id));
+            }
+        }
+    }
+
     fn rpc_restore(&mut self, wire_id: u16, route: &DeviceRoute) -> Option<(u64, u16)> {
         let remap = match self.rpc_map.remove(&wire_id) {
             None => {
@@ -330,40 +1428,163 @@ impl ProxyCore {
             self.rpc_map.insert(wire_id, remap);
             return None;
         }
+        self.rpc_ids.free(wire_id);
         if let Some(ids) = self.rpc_timeouts.get_mut(&remap.timeout) {
             ids.remove(&wire_id);
             if ids.len() == 0 {
                 self.rpc_timeouts.remove(&remap.timeout);
             }
         } else {
-            #[cfg(debug_assertions)]
-            eprintln!("Failed to find RPC timeout in map");
+            log_warn!("Failed to find RPC timeout in map");
         }
         Some((remap.client, remap.id))
     }
 
     // Ok: successful. Err: packet should be sent back to client
-    fn forward_to_device(&mut self, mut pkt: Packet, client_id: u64) -> Result<(), Packet> {
-        let mut rpc_mapped_id: Option<u16> = None;
-        let mut timeout = Instant::now();
-        if let proto::Payload::RpcRequest(req) = &mut pkt.payload {
-            let wire_id = self.next_rpc_id;
-            // Always increment even if it fails, on the slim chance it hits an open spot
-            // next time.
-            self.next_rpc_id = self.next_rpc_id.wrapping_add(1);
-            if self.rpc_map.contains_key(&wire_id) {
-                return Err(util::PacketBuilder::new(pkt.routing)
-                    .rpc_error(req.id, proto::RpcErrorCode::OutOfMemory));
-            }
-            timeout += if client_id != 0 {
-                self.clients
-                    .get(&client_id)
-                    .expect("Invalid client when forwarding RPC")
-                    .rpc_timeout
+    fn forward_to_device(&mut self, mut pkt: Packet, client_id: u64) -> Result<(), Box<Packet>> {
+        // Forwarding to the device uses up a hop of the packet's TTL, unless
+        // it has none, in which case it gets the default one.
+        match pkt.ttl {
+            0 => pkt.ttl = self.default_ttl,
+            1 => {
+                self.status_queue
+                    .send(Event::TtlExpired(client_id, pkt.routing.clone()));
+                return match &pkt.payload {
+                    proto::Payload::RpcRequest(req) => {
+                        Err(Box::new(util::PacketBuilder::make_rpc_error(
+                            req.id,
+                            proto::RpcErrorCode::NotFound,
+                            pkt.routing,
+                        )))
+                    }
+                    _ => Ok(()),
+                };
+            }
+            _ => pkt.ttl -= 1,
+        }
+        let req_id = match &pkt.payload {
+            proto::Payload::RpcRequest(req) => {
+                // Internal RPCs are meant for the device itself.
+                let cached = self.metadata_cache.as_ref().and_then(|cache| {
+                    if client_id != INTERNAL_CLIENT_ID && MetadataCache::is_metadata_request(req) {
+                        cache.get(&pkt.routing, &req.arg)
+                    } else {
+                        None
+                    }
+                });
+                if let Some(reply) = cached {
+                    return Err(Box::new(util::PacketBuilder::make_rpc_reply(
+                        req.id,
+                        reply.clone(),
+                        pkt.routing,
+                    )));
+                }
+                req.id
+            }
+            _ => return self.send_to_device(pkt, client_id, Instant::now()),
+        };
+        let now = Instant::now();
+        let timeout = now
+            + if client_id != INTERNAL_CLIENT_ID {
+                let client = self
+                    .clients
+                    .get_mut(&client_id)
+                    .expect("Invalid client when forwarding RPC");
+                if let Some(limit) = &self.rpc_rate_limit {
+                    let bucket = client
+                        .rpc_buckets
+                        .entry(pkt.routing.clone())
+                        .or_insert_with(|| TokenBucket::new(limit));
+                    if !bucket.take(limit, now) {
+                        self.status_queue
+                            .send(Event::RpcThrottled((client_id, req_id)));
+                        return Err(Box::new(util::PacketBuilder::make_rpc_error(
+                            req_id,
+                            proto::RpcErrorCode::Busy,
+                            pkt.routing,
+                        )));
+                    }
+                }
+                client
+                    .rpc_timeout_hints
+                    .remove(&req_id)
+                    .unwrap_or(client.rpc_timeout)
             } else {
                 // Timeout internal RPCs after 1 second
                 Duration::from_secs(1)
             };
+        // If all the wire ids are in use, hold on to the request until one
+        // frees up. Keep requests in order once any is queued. Internal
+        // requests have ids of their own, so they never wait.
+        if client_id != INTERNAL_CLIENT_ID && (self.rpc_ids.is_full() || !self.rpc_queue.is_empty())
+        {
+            self.status_queue
+                .send(Event::RpcQueued((client_id, req_id)));
+            self.rpc_queue.push_back(QueuedRpc {
+                pkt,
+                client: client_id,
+                timeout,
+            });
+            return Ok(());
+        }
+        self.send_to_device(pkt, client_id, timeout)
+    }
+
+    /// Forward requests queued waiting for a wire id, as long as ids are available.
+    fn forward_queued_rpcs(&mut self) {
+        while !self.rpc_ids.is_full() {
+            let queued = if let Some(q) = self.rpc_queue.pop_front() {
+                q
+            } else {
+                break;
+            };
+            if let Err(rpkt) = self.send_to_device(queued.pkt, queued.client, queued.timeout) {
+                self.send_generated_error(queued.client, *rpkt);
+            }
+        }
+    }
+
+    /// Send a packet generated by the proxy to a client, dropping the client
+    /// if that fails. Internal RPC errors are processed immediately.
+    fn send_generated_error(&mut self, client_id: u64, pkt: Packet) {
+        if client_id == INTERNAL_CLIENT_ID {
+            if let proto::Payload::RpcError(err) = &pkt.payload {
+                self.internal_rpc_error(err);
+            }
+        } else if let Some(client) = self.clients.get(&client_id) {
+            if let Err(reason) = client.send(&pkt) {
+                self.drop_dead_client(client_id, reason);
+            }
+        }
+    }
+
+    /// Send a packet to the device, remapping the id of RPC requests to a
+    /// free wire id, which expires at `timeout`.
+    fn send_to_device(
+        &mut self,
+        mut pkt: Packet,
+        client_id: u64,
+        timeout: Instant,
+    ) -> Result<(), Box<Packet>> {
+        let mut rpc_mapped_id: Option<u16> = None;
+        if let proto::Payload::RpcRequest(req) = &mut pkt.payload {
+            let wire_id = if client_id == INTERNAL_CLIENT_ID {
+                self.rpc_ids.alloc_internal()
+            } else {
+                self.rpc_ids.alloc()
+            };
+            let wire_id = if let Some(id) = wire_id {
+                // Replies to a previous request with this id are not told
+                // apart anymore, but the ids are only reused after all the
+                // others were.
+                self.cancelled_rpcs.remove(&id);
+                id
+            } else {
+                return Err(Box::new(
+                    util::PacketBuilder::new(pkt.routing)
+                        .rpc_error(req.id, proto::RpcErrorCode::OutOfMemory),
+                ));
+            };
             self.rpc_map.insert(
                 wire_id,
                 RpcMapEntry {
@@ -371,6 +1592,10 @@ impl ProxyCore {
                     client: client_id,
                     route: pkt.routing.clone(),
                     timeout: timeout,
+                    metadata_arg: match &self.metadata_cache {
+                        Some(_) if MetadataCache::is_metadata_request(req) => Some(req.arg.clone()),
+                        _ => None,
+                    },
                 },
             );
             self.status_queue
@@ -378,20 +1603,43 @@ impl ProxyCore {
             req.id = wire_id;
             rpc_mapped_id = Some(wire_id);
         }
-        if let Some(dev) = &self.device {
-            if let Ok(()) = dev.tio_port.send(pkt) {
-                if let Some(rpc_id) = rpc_mapped_id {
-                    if !self.rpc_timeouts.contains_key(&timeout) {
-                        self.rpc_timeouts.insert(timeout, HashSet::new());
+        let sent =
+            if let Some(downstream) = self.downstreams.iter().find(|ds| ds.routes(&pkt.routing)) {
+                self.sniff(Direction::ToDevice, Some(client_id), &pkt);
+                downstream.send(pkt, timeout)
+            } else {
+                let sleep_request = match &pkt.payload {
+                    proto::Payload::RpcRequest(req) => {
+                        pkt.routing.is_empty() && power::is_sleep_request(&req.method)
                     }
-                    let timeout_ids = self
-                        .rpc_timeouts
-                        .get_mut(&timeout)
-                        .expect("Unexpected missing timeout set");
-                    timeout_ids.insert(rpc_id);
+                    _ => false,
+                };
+                if self.device.is_some() {
+                    self.sniff(Direction::ToDevice, Some(client_id), &pkt);
                 }
-                return Ok(());
+                match &mut self.device {
+                    Some(dev) if dev.tio_port.send(pkt).is_ok() => {
+                        if sleep_request && !dev.sleeping {
+                            dev.sleeping = true;
+                            self.status_queue.send(Event::RootDeviceSleeping);
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            };
+        if sent {
+            if let Some(rpc_id) = rpc_mapped_id {
+                if !self.rpc_timeouts.contains_key(&timeout) {
+                    self.rpc_timeouts.insert(timeout, HashSet::new());
+                }
+                let timeout_ids = self
+                    .rpc_timeouts
+                    .get_mut(&timeout)
+                    .expect("Unexpected missing timeout set");
+                timeout_ids.insert(rpc_id);
             }
+            return Ok(());
         }
         // If we got here, the packet was not sent. avoid erroring out since if
         // there is something wrong with the device we'll notice in the main
@@ -402,28 +1650,52 @@ impl ProxyCore {
                 .rpc_map
                 .remove(&rpc_id)
                 .expect("Unexpected missing timeout set");
-            return Err(util::PacketBuilder::new(remap.route)
-                .rpc_error(remap.id, proto::RpcErrorCode::Undefined));
+            self.rpc_ids.free(rpc_id);
+            return Err(Box::new(
+                util::PacketBuilder::new(remap.route)
+                    .rpc_error(remap.id, proto::RpcErrorCode::Undefined),
+            ));
         } else {
             Ok(())
         }
     }
 
     /// Synthesize an RPC error packet with the given code and send it back to
-    /// all clients that have an RPC with timeout < `until` (all RPCs if None).
+    /// all clients that have an RPC with timeout < `until` (all RPCs if None)
+    /// to a route for which `only` is true.
     /// Used to generate RPC timeouts, or to notify a client that it will never
     /// get a reply when the device disconnects or restarts.
-    fn dispatch_rpc_errors(&mut self, error: proto::RpcErrorCode, until: Option<Instant>) {
+    fn dispatch_rpc_errors(
+        &mut self,
+        error: proto::RpcErrorCode,
+        until: Option<Instant>,
+        only: impl Fn(&DeviceRoute) -> bool,
+    ) {
         let mut to_remove = Vec::new();
         let mut to_drop = Vec::new();
-        for (timeout, rpc_ids) in self.rpc_timeouts.iter() {
+        let mut internal = Vec::new();
+        for (timeout, rpc_ids) in self.rpc_timeouts.iter_mut() {
             if let Some(timeout_bound) = until {
                 if *timeout >= timeout_bound {
                     break;
                 }
             }
-            to_remove.push(*timeout);
-            for rpc_id in rpc_ids {
+            let selected: Vec<u16> = rpc_ids
+                .iter()
+                .copied()
+                .filter(|rpc_id| {
+                    let remap = self
+                        .rpc_map
+                        .get(rpc_id)
+                        .expect("RPC ID from timeout missing in main map");
+                    only(&remap.route)
+                })
+                .collect();
+            rpc_ids.retain(|rpc_id| !selected.contains(rpc_id));
+            if rpc_ids.is_empty() {
+                to_remove.push(*timeout);
+            }
+            for rpc_id in &selected {
                 self.status_queue
                     .send(if let proto::RpcErrorCode::Timeout = error {
                         Event::RpcTimeout(*rpc_id)
@@ -434,23 +1706,31 @@ impl ProxyCore {
                     .rpc_map
                     .remove(&rpc_id)
                     .expect("RPC ID from timeout missing in main map");
+                self.rpc_ids.free(*rpc_id);
+                if remap.client == INTERNAL_CLIENT_ID {
+                    internal.push(proto::RpcErrorPayload {
+                        id: remap.id,
+                        error,
+                        extra: vec![],
+                    });
+                    continue;
+                }
                 let client = if let Some(c) = self.clients.get(&remap.client) {
                     c
                 } else {
                     // Client is gone.
                     continue;
                 };
-                if let Err(_) = client.send(&util::PacketBuilder::make_rpc_error(
+                if let Err(reason) = client.send(&util::PacketBuilder::make_rpc_error(
                     remap.id,
                     error.clone(),
                     remap.route,
                 )) {
-                    to_drop.push(remap.client);
+                    to_drop.push((remap.client, reason));
                     // This can happen without a problem per se, if e.g. a client
                     // issues an RPC which will time out, and disconnects before
                     // said timeout occurs, so only say something in debug mode.
-                    #[cfg(debug_assertions)]
-                    eprintln!(
+                    log_warn!(
                         "Failed to send generated RPC error to client {:?}",
                         remap.client
                     );
@@ -460,15 +1740,41 @@ impl ProxyCore {
         for timeout in to_remove {
             self.rpc_timeouts.remove(&timeout);
         }
-        for client_id in to_drop {
-            self.drop_client(client_id);
+        for (client_id, reason) in to_drop {
+            self.drop_dead_client(client_id, reason);
+        }
+        for err in internal {
+            self.internal_rpc_error(&err);
+        }
+
+        // Requests still waiting for a wire id never made it to the device.
+        let (expired, queued): (Vec<QueuedRpc>, Vec<QueuedRpc>) =
+            self.rpc_queue.drain(..).partition(|q| {
+                only(&q.pkt.routing)
+                    && match until {
+                        Some(timeout_bound) => q.timeout < timeout_bound,
+                        None => true,
+                    }
+            });
+        self.rpc_queue = queued.into();
+        for q in expired {
+            if let proto::Payload::RpcRequest(req) = q.pkt.payload {
+                let err = util::PacketBuilder::make_rpc_error(req.id, error, q.pkt.routing);
+                self.send_generated_error(q.client, err);
+            }
         }
     }
 
     fn process_rpc_timeouts(&mut self) -> Duration {
         let now = Instant::now();
-        self.dispatch_rpc_errors(proto::RpcErrorCode::Timeout, Some(now));
-        if let Some(timeout) = self.rpc_timeouts.keys().next() {
+        self.dispatch_rpc_errors(proto::RpcErrorCode::Timeout, Some(now), |_| true);
+        self.cancelled_rpcs.retain(|_, timeout| *timeout > now);
+        let next_queued = self.rpc_queue.iter().map(|q| q.timeout).min();
+        let next_timeout = match (self.rpc_timeouts.keys().next().copied(), next_queued) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(timeout) = next_timeout {
             timeout.saturating_duration_since(now) + Duration::from_millis(1)
         } else {
             Duration::from_secs(60)
@@ -476,17 +1782,75 @@ impl ProxyCore {
     }
 
     fn send_internal_rpc(&mut self, pkt: Packet) -> Result<(), proto::RpcErrorCode> {
-        if let Err(epkt) = self.forward_to_device(pkt, 0) {
-            if let proto::Payload::RpcError(rpc_err) = epkt.payload {
-                Err(rpc_err.error)
-            } else {
-                panic!(
-                    "Unexpected error packet from sending interal RPC: {:?}",
-                    epkt
-                );
+        match self.forward_to_device(pkt, INTERNAL_CLIENT_ID) {
+            Err(epkt) => match epkt.payload {
+                proto::Payload::RpcError(rpc_err) => Err(rpc_err.error),
+                _ => Ok(()),
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Notify clients of a change in the connection to the device. On
+    /// reconnection, also queue the RPCs they registered to send again.
+    fn link_changed(&mut self, event: LinkEvent) {
+        self.reconnect_rpcs.clear();
+        for client in self.clients.values() {
+            client.send_link(event);
+            if event == LinkEvent::Reconnected {
+                for rpc in &client.reconnect_rpcs {
+                    let mut pkt = rpc.clone();
+                    pkt.routing = client.scope.absolute_route(&pkt.routing);
+                    if let proto::Payload::RpcRequest(req) = &mut pkt.payload {
+                        req.id = RECONNECT_RPC_ID;
+                    }
+                    self.reconnect_rpcs.push(pkt);
+                }
+            }
+        }
+    }
+
+    /// Whether the client asked to reopen the link to the device since
+    /// last checked. A new url requested is used from now on.
+    fn reconnect_requested(&mut self, client_id: u64) -> bool {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        if !std::mem::take(&mut client.reconnect) {
+            return false;
+        }
+        if let Some(url) = client.new_url.take() {
+            self.url = url.clone();
+            self.status_queue.send(Event::UrlChanged(url));
+        }
+        self.status_queue.send(Event::ReconnectRequested(client_id));
+        self.device.is_some()
+    }
+
+    /// Forget the device after its link was lost or closed on request, to
+    /// open it again. Returns when to give up reopening it.
+    fn disconnect_device(&mut self) -> Instant {
+        self.device = None;
+        if let Some(cache) = self.metadata_cache.as_mut() {
+            // Downstream proxies keep their links.
+            let prefixes: Vec<DeviceRoute> = self
+                .downstreams
+                .iter()
+                .map(|ds| ds.prefix.clone())
+                .collect();
+            cache.invalidate_except(&prefixes);
+        }
+        self.status_queue.send(Event::SensorDisconnected);
+        self.link_changed(LinkEvent::Disconnected);
+        Instant::now() + self.reconnect_timeout.unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Send the RPCs queued by `link_changed`.
+    fn send_reconnect_rpcs(&mut self) {
+        for pkt in std::mem::take(&mut self.reconnect_rpcs) {
+            if let Err(error) = self.send_internal_rpc(pkt) {
+                self.status_queue.send(Event::ReconnectRpcFailed(error));
             }
-        } else {
-            Ok(())
         }
     }
 
@@ -504,16 +1868,20 @@ impl ProxyCore {
             }
         }
 
-        if rep.id == QUERY_RATE_RPC_ID {
+        if rep.id == RECONNECT_RPC_ID {
+            return;
+        } else if rep.id == ROUTE_QUERY_RATE_RPC_ID || rep.id == ROUTE_SET_RATE_RPC_ID {
+            self.route_rate_reply(rep);
+            return;
+        } else if rep.id == QUERY_RATE_RPC_ID {
             if let Some((RateChange::WaitingDeviceRate, target)) = get_rate_vars(self) {
-                let next_state = if let Ok(value) = u32::from_reply(&rep.reply) {
+                let next_state = if let Some(value) = rate_from_reply(&rep.reply) {
                     if value == 0 {
                         self.status_queue.send(Event::AutoRateIncompatible(0));
                         self.status_queue.send(Event::AutoRateGaveUp);
                         RateChange::GaveUp
                     } else {
-                        let error = (((target as f64) - (value as f64)) / (value as f64)).abs();
-                        if error > 0.015 {
+                        if !rate_compatible(target, value) {
                             self.status_queue.send(Event::AutoRateIncompatible(value));
                             self.status_queue.send(Event::AutoRateGaveUp);
                             RateChange::GaveUp
@@ -523,10 +1891,17 @@ impl ProxyCore {
                         }
                     }
                 } else {
-                    self.status_queue.send(Event::AutoRateRpcInvalid);
+                    log_warn!(
+                        "Rate query reply of unexpected size {}: {:?}",
+                        rep.reply.len(),
+                        rep.reply
+                    );
+                    self.status_queue
+                        .send(Event::AutoRateRpcInvalid(rep.reply.len()));
+                    self.status_queue.send(Event::AutoRateGaveUp);
                     RateChange::GaveUp
                 };
-                self.device.as_mut().expect("").rate_change_state = next_state;
+                self.device.as_mut().expect("").set_rate_state(next_state);
                 return;
             }
         } else if rep.id == SET_RATE_RPC_ID {
@@ -539,18 +1914,19 @@ impl ProxyCore {
                         RateChange::GaveUp
                     }
                 };
-                self.device.as_mut().expect("").rate_change_state = next_state;
+                self.device.as_mut().expect("").set_rate_state(next_state);
                 return;
             }
         } else {
             // Note: internal RPCs still get remapped with all other RPCs,
             // so this ID does not come from the device itself, but from the
             // proxy remapping, and it should never be an unexpected value.
-            panic!("Unexpected reply ID to internal RPC: {}", rep.id)
+            // Ignore it anyway rather than taking the proxy down.
+            log_warn!("Unexpected reply ID to internal RPC: {}", rep.id);
+            return;
         }
 
-        #[cfg(debug_assertions)]
-        eprintln!(
+        log_warn!(
             "Unexpected internal rpc reply 0x{:x} in state {:?}",
             rep.id,
             get_rate_vars(self)
@@ -558,15 +1934,166 @@ impl ProxyCore {
     }
 
     fn internal_rpc_error(&mut self, err: &proto::RpcErrorPayload) {
+        if err.id == RECONNECT_RPC_ID {
+            self.status_queue.send(Event::ReconnectRpcFailed(err.error));
+            return;
+        }
+        let n_candidates = self.autorate_rpcs.len();
+        let not_found = matches!(err.error, proto::RpcErrorCode::NotFound);
+        if err.id == ROUTE_QUERY_RATE_RPC_ID && not_found {
+            // Try the next candidate RPCs, if any.
+            let querying = self
+                .device
+                .as_mut()
+                .and_then(|dev| dev.route_rate_waiting(|s| matches!(s, RouteRateState::Querying)));
+            if let Some(route_rate) = querying.filter(|r| r.rpcs + 1 < n_candidates) {
+                route_rate.rpcs += 1;
+                route_rate.state = RouteRateState::Pending;
+                return;
+            }
+        }
+        if err.id == QUERY_RATE_RPC_ID && not_found {
+            if let Some(dev) = self.device.as_mut() {
+                if dev.rate_change_state == RateChange::WaitingDeviceRate
+                    && dev.autorate_rpcs + 1 < n_candidates
+                {
+                    dev.autorate_rpcs += 1;
+                    dev.set_rate_state(RateChange::QueryDeviceRate);
+                    return;
+                }
+            }
+        }
+        if err.id == ROUTE_QUERY_RATE_RPC_ID || err.id == ROUTE_SET_RATE_RPC_ID {
+            let waiting = self.device.as_mut().and_then(|dev| {
+                dev.route_rate_waiting(|s| {
+                    matches!(s, RouteRateState::Querying | RouteRateState::Setting(_))
+                })
+            });
+            if let Some(route_rate) = waiting {
+                route_rate.state = RouteRateState::GaveUp;
+                let route = route_rate.route.clone();
+                self.status_queue
+                    .send(Event::RouteAutoRateRpcError(route, err.error));
+            }
+            return;
+        }
         // We could handle this better, but just keep the device to the default speed until the port is reset
         self.status_queue
             .send(Event::AutoRateRpcError(err.error.clone()));
         if let Some(dev) = self.device.as_mut() {
-            dev.rate_change_state = RateChange::GaveUp;
+            dev.set_rate_state(RateChange::GaveUp);
             self.status_queue.send(Event::AutoRateGaveUp);
         }
     }
 
+    /// Process the reply to an RPC negotiating the rate of a link behind a hub.
+    fn route_rate_reply(&mut self, rep: &proto::RpcReplyPayload) {
+        let Some(dev) = self.device.as_mut() else {
+            return;
+        };
+        if rep.id == ROUTE_QUERY_RATE_RPC_ID {
+            let Some(route_rate) =
+                dev.route_rate_waiting(|s| matches!(s, RouteRateState::Querying))
+            else {
+                return;
+            };
+            match rate_from_reply(&rep.reply) {
+                Some(value) if rate_compatible(route_rate.target_bps, value) => {
+                    route_rate.state = RouteRateState::Compatible(value);
+                }
+                Some(value) => {
+                    route_rate.state = RouteRateState::GaveUp;
+                    let route = route_rate.route.clone();
+                    self.status_queue
+                        .send(Event::RouteAutoRateIncompatible(route, value));
+                }
+                None => {
+                    route_rate.state = RouteRateState::GaveUp;
+                    let route = route_rate.route.clone();
+                    log_warn!(
+                        "Rate query reply of unexpected size {} from {}: {:?}",
+                        rep.reply.len(),
+                        route,
+                        rep.reply
+                    );
+                    self.status_queue
+                        .send(Event::RouteAutoRateRpcInvalid(route, rep.reply.len()));
+                }
+            }
+        } else {
+            let Some(route_rate) =
+                dev.route_rate_waiting(|s| matches!(s, RouteRateState::Setting(_)))
+            else {
+                return;
+            };
+            if let RouteRateState::Setting(rate) = route_rate.state {
+                route_rate.state = RouteRateState::Done;
+                let route = route_rate.route.clone();
+                self.status_queue.send(Event::RouteAutoRateSet(route, rate));
+            }
+        }
+    }
+
+    /// Negotiate the rate of the next link behind a hub, one at a time.
+    fn route_autonegotiation(&mut self) {
+        let in_flight = self.rpc_map.len();
+        let Some(dev) = self.device.as_mut() else {
+            return;
+        };
+        let Some(next) = dev
+            .route_rates
+            .iter()
+            .position(|r| !matches!(r.state, RouteRateState::Done | RouteRateState::GaveUp))
+        else {
+            return;
+        };
+        let RouteRate {
+            route,
+            target_bps,
+            state,
+            rpcs,
+        } = dev.route_rates[next].clone();
+        let Some(rpcs) = self.autorate_rpcs.get(rpcs) else {
+            dev.route_rates[next].state = RouteRateState::GaveUp;
+            self.status_queue.send(Event::RouteAutoRateRpcError(
+                route,
+                proto::RpcErrorCode::NotFound,
+            ));
+            return;
+        };
+        let (request, next_state) = match state {
+            RouteRateState::Pending => (
+                util::PacketBuilder::make_rpc_request(
+                    &rpcs.query,
+                    &target_bps.to_le_bytes(),
+                    ROUTE_QUERY_RATE_RPC_ID,
+                    route.clone(),
+                ),
+                RouteRateState::Querying,
+            ),
+            // As for the root device, wait for traffic in flight to complete
+            // before switching, as it could be lost.
+            RouteRateState::Compatible(rate) if in_flight == 0 => (
+                util::PacketBuilder::make_rpc_request(
+                    &rpcs.set,
+                    &rate.to_le_bytes(),
+                    ROUTE_SET_RATE_RPC_ID,
+                    route.clone(),
+                ),
+                RouteRateState::Setting(rate),
+            ),
+            _ => return,
+        };
+        dev.route_rates[next].state = next_state;
+        if let Err(error) = self.send_internal_rpc(request) {
+            if let Some(dev) = self.device.as_mut() {
+                dev.route_rates[next].state = RouteRateState::GaveUp;
+            }
+            self.status_queue
+                .send(Event::RouteAutoRateRpcError(route, error));
+        }
+    }
+
     fn autonegotiation(&mut self) {
         // When this is called, device will be Some, and it does not change
         // from any of the called methods
@@ -576,12 +2103,20 @@ impl ProxyCore {
                 .as_mut()
                 .expect("No device but in autonegotiation")
         }
+        let candidate = device(self).autorate_rpcs;
+        let Some(rpcs) = self.autorate_rpcs.get(candidate).cloned() else {
+            self.status_queue
+                .send(Event::AutoRateRpcError(proto::RpcErrorCode::NotFound));
+            self.status_queue.send(Event::AutoRateGaveUp);
+            device(self).set_rate_state(RateChange::GaveUp);
+            return;
+        };
         let next_state = match device(self).rate_change_state.clone() {
             RateChange::QueryDeviceRate => {
                 let target = device(self).rates().target_bps;
                 if let Err(rpc_error) =
                     self.send_internal_rpc(util::PacketBuilder::make_rpc_request(
-                        "dev.port.rate.near",
+                        &rpcs.query,
                         &target.to_le_bytes(),
                         QUERY_RATE_RPC_ID,
                         DeviceRoute::root(),
@@ -599,7 +2134,7 @@ impl ProxyCore {
                     let target = device(self).rates().target_bps;
                     if let Err(rpc_error) =
                         self.send_internal_rpc(util::PacketBuilder::make_rpc_request(
-                            "dev.port.rate",
+                            &rpcs.set,
                             &target.to_le_bytes(),
                             SET_RATE_RPC_ID,
                             DeviceRoute::root(),
@@ -616,9 +2151,9 @@ impl ProxyCore {
                     RateChange::SetDeviceRate
                 }
             }
-            RateChange::RateChanged => {
+            state @ (RateChange::RateChanged | RateChange::RateConfirmed) => {
                 let last_rx_delta = device(self).last_rx.elapsed();
-                if last_rx_delta > Duration::from_millis(1000) {
+                if last_rx_delta > Duration::from_millis(1000) && !device(self).sleeping {
                     self.status_queue.send(Event::NoData);
                     let dev = device(self);
                     let default_bps = dev.rates().default_bps;
@@ -628,91 +2163,303 @@ impl ProxyCore {
                     self.status_queue.send(Event::SetRate(default_bps));
                     RateChange::GaveUp
                 } else {
-                    RateChange::RateChanged
+                    state
                 }
             }
             // In any other case, do nothing
             current_state => current_state,
         };
-        device(self).rate_change_state = next_state;
+        device(self).set_rate_state(next_state);
+        if device(self).root_rate_settled() {
+            self.route_autonegotiation();
+        }
     }
 
+    /// Cancel the RPCs in flight to the device, which will never be answered
+    /// as it disconnected or restarted. Those to downstream proxies are
+    /// not affected.
     fn cancel_active_rpcs(&mut self) {
-        self.dispatch_rpc_errors(proto::RpcErrorCode::Undefined, None);
+        let prefixes: Vec<DeviceRoute> = self
+            .downstreams
+            .iter()
+            .map(|ds| ds.prefix.clone())
+            .collect();
+        self.dispatch_rpc_errors(proto::RpcErrorCode::Undefined, None, |route| {
+            !prefixes.iter().any(|prefix| route.starts_with(prefix))
+        });
+    }
+
+    /// Attach a downstream proxy, unless its prefix is the root or overlaps
+    /// the one of another.
+    fn attach_downstream(&mut self, (downstream, confirm): DownstreamRequest) {
+        let overlaps = downstream.prefix.is_empty()
+            || self
+                .downstreams
+                .iter()
+                .any(|ds| ds.routes(&downstream.prefix) || downstream.routes(&ds.prefix));
+        if overlaps {
+            let _ = confirm.send(Err(PortError::InvalidRoute));
+            return;
+        }
+        self.status_queue
+            .send(Event::DownstreamAttached(downstream.prefix.clone()));
+        self.downstreams.push(downstream);
+        let _ = confirm.send(Ok(()));
+    }
+
+    /// Process the packets received from the downstream proxy `n` like
+    /// those of the device, and detach it if it is gone.
+    fn recv_downstream(&mut self, n: usize) {
+        loop {
+            let downstream = &self.downstreams[n];
+            match downstream.port.try_recv() {
+                Ok(mut pkt) => {
+                    pkt.routing = downstream.prefix.absolute_route(&pkt.routing);
+                    // Devices too deep to be addressed through the prefix
+                    // are left out.
+                    if pkt.routing.len() <= proto::TIO_PACKET_MAX_ROUTING_SIZE {
+                        self.process_device_packet(pkt);
+                    }
+                }
+                Err(ProxyRecvError::WouldBlock) => break,
+                Err(ProxyRecvError::ProxyDisconnected) => {
+                    let prefix = self.downstreams.remove(n).prefix;
+                    self.dispatch_rpc_errors(proto::RpcErrorCode::Undefined, None, |route| {
+                        route.starts_with(&prefix)
+                    });
+                    if let Some(cache) = self.metadata_cache.as_mut() {
+                        cache.invalidate_subtree(&prefix);
+                    }
+                    self.status_queue.send(Event::DownstreamDetached(prefix));
+                    break;
+                }
+            }
+        }
+    }
+
+    /// The root device restarted, as announced by the heartbeat `pkt` with
+    /// its new session. Requests in flight will never be answered, so they
+    /// are cancelled, and all clients are sent the heartbeat regardless of
+    /// their forwarding settings, so they can drop what they know about the
+    /// device state.
+    fn root_device_restarted(&mut self, pkt: &Packet) {
+        self.status_queue.send(Event::RootDeviceRestarted);
+        self.cancel_active_rpcs();
+        let mut to_drop = vec![];
+        for (client_id, client) in self.clients.iter() {
+            if let Err(reason) = client.send_restart(pkt) {
+                to_drop.push((*client_id, reason));
+            }
+        }
+        for (client_id, reason) in to_drop {
+            self.drop_dead_client(client_id, reason);
+        }
+    }
+
+    /// Process a packet from the device tree, with an absolute route:
+    /// update the proxy state, and forward it to the clients, or RPC
+    /// replies to the one which sent the request.
+    fn process_device_packet(&mut self, mut pkt: Packet) {
+        self.sniff(Direction::FromDevice, None, &pkt);
+        self.record_activity(&pkt.routing);
+        if let Some(cache) = self.metadata_cache.as_mut() {
+            match &pkt.payload {
+                proto::Payload::Heartbeat(proto::HeartbeatPayload::Session(session)) => {
+                    cache.session(&pkt.routing, *session)
+                }
+                proto::Payload::Metadata(mp) if mp.update() => cache.invalidate(&pkt.routing),
+                proto::Payload::RpcReply(rep) => {
+                    let arg = self
+                        .rpc_map
+                        .get(&rep.id)
+                        .filter(|entry| entry.route == pkt.routing)
+                        .and_then(|entry| entry.metadata_arg.clone());
+                    if let Some(arg) = arg {
+                        cache.insert(&pkt.routing, arg, rep.reply.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let proto::Payload::Heartbeat(proto::HeartbeatPayload::Session(session)) = pkt.payload {
+            // This is a heartbeat for the root sensor
+            let restarted = pkt.routing.is_empty()
+                && self
+                    .device
+                    .as_mut()
+                    .is_some_and(|dev| dev.update_session(session));
+            if restarted {
+                self.root_device_restarted(&pkt);
+                return;
+            }
+        }
+        // In general, packets get forwarded to all clients,
+        // except for RPCs which are directed only to the
+        // client which placed the request.
+        if let Some(wire_id) = match &pkt.payload {
+            proto::Payload::RpcReply(rep) => Some(rep.id),
+            proto::Payload::RpcError(err) => Some(err.id),
+            _ => None,
+        } {
+            // Replies to cancelled requests are dropped.
+            if !self.rpc_map.contains_key(&wire_id)
+                && self.cancelled_rpcs.remove(&wire_id).is_some()
+            {
+                return;
+            }
+            // Remap RPC reply or error ID to client + ID
+            let (client, client_id, original_id) =
+                if let Some((client_id, rpc_id)) = self.rpc_restore(wire_id, &pkt.routing) {
+                    if client_id == INTERNAL_CLIENT_ID {
+                        // internal reply
+                        (None, INTERNAL_CLIENT_ID, rpc_id)
+                    } else if let Some(client) = self.clients.get(&client_id) {
+                        self.status_queue
+                            .send(Event::RpcRestore(wire_id, (client_id, rpc_id)));
+                        (Some(client), client_id, rpc_id)
+                    } else {
+                        // If we cannot find the client which originally sent the
+                        // request, just drop the packet and send an event.
+                        self.status_queue.send(Event::RpcClientNotFound(client_id));
+                        return;
+                    }
+                } else {
+                    self.status_queue.send(Event::RpcRestoreNotFound(wire_id));
+                    return;
+                };
+            // Restore original ID, and process internal RPCs.
+            match &mut pkt.payload {
+                proto::Payload::RpcReply(rep) => {
+                    rep.id = original_id;
+                    if client_id == INTERNAL_CLIENT_ID {
+                        self.internal_rpc_reply(rep);
+                        return;
+                    }
+                }
+                proto::Payload::RpcError(err) => {
+                    err.id = original_id;
+                    if client_id == INTERNAL_CLIENT_ID {
+                        self.internal_rpc_error(err);
+                        return;
+                    }
+                }
+                _ => {
+                    // should never happen due to outer match
+                    panic!("unexpected payload")
+                }
+            }
+            // Forward with correct request id to the requestor
+            if let Err(reason) = client.expect("unexpected client").send(&pkt) {
+                self.drop_dead_client(client_id, reason);
+            }
+        } else {
+            let mut to_drop = vec![];
+            for (client_id, client) in self.clients.iter() {
+                if let Err(reason) = client.send(&pkt) {
+                    to_drop.push((*client_id, reason));
+                }
+            }
+            for (client_id, reason) in to_drop {
+                self.drop_dead_client(client_id, reason);
+            }
+        }
     }
 
     pub fn run(&mut self) {
         use channel::TryRecvError;
 
-        if !self.try_setup_device() {
-            self.status_queue.send(Event::FailedToConnect);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("proxy", url = %self.url).entered();
+
+        if let Err(err) = self.try_setup_device() {
+            let err = port::ConnectError::new(&self.url, &err);
+            self.readiness
+                .set(Err(PortError::FailedToConnect(err.clone())));
+            self.status_queue.send(Event::FailedToConnect(err));
             return;
         } else {
+            self.readiness.set(Ok(()));
             self.status_queue.send(Event::SensorConnected);
         }
         let mut device_timeout = Instant::now();
+        let mut receivers = ClientReceivers::default();
+        let mut clients_changed = true;
 
         'mainloop: loop {
             let mut timeout = self.process_rpc_timeouts();
 
             if self.device.is_none() {
                 self.cancel_active_rpcs();
-                if !self.try_setup_device() {
+                if let Err(err) = self.try_setup_device() {
                     if Instant::now() > device_timeout {
-                        self.status_queue.send(Event::FailedToReconnect);
+                        let err = port::ConnectError::new(&self.url, &err);
+                        self.status_queue.send(Event::FailedToReconnect(err));
                         break;
                     }
                     timeout = std::cmp::min(timeout, Duration::from_secs(1));
                 } else {
                     self.status_queue.send(Event::SensorReconnected);
+                    self.link_changed(LinkEvent::Reconnected);
                 }
             }
 
-            let (safe_to_forward, needs_autonegotiation, restarted) =
-                if let Some(dev) = &mut self.device {
-                    (
-                        dev.safe_to_forward(),
-                        if dev.needs_autonegotiation() {
-                            timeout = std::cmp::min(timeout, Duration::from_millis(200));
-                            true
-                        } else {
-                            false
-                        },
-                        if dev.restarted {
-                            dev.restarted = false;
-                            true
-                        } else {
-                            false
-                        },
-                    )
-                } else {
-                    // If no device, forwarding will send RPC errors, which we want.
-                    (true, false, false)
-                };
+            let (safe_to_forward, needs_autonegotiation) = if let Some(dev) = &mut self.device {
+                (
+                    dev.safe_to_forward(),
+                    if dev.needs_autonegotiation() {
+                        timeout = std::cmp::min(timeout, Duration::from_millis(200));
+                        true
+                    } else {
+                        false
+                    },
+                )
+            } else {
+                // If no device, forwarding will send RPC errors, which we want.
+                (true, false)
+            };
 
             if needs_autonegotiation {
                 self.autonegotiation();
             }
-            if restarted {
-                self.cancel_active_rpcs();
+            self.publish_link_status();
+            if safe_to_forward {
+                self.forward_queued_rpcs();
+                if self.device.is_some() && !self.reconnect_rpcs.is_empty() {
+                    self.send_reconnect_rpcs();
+                    timeout = std::cmp::min(timeout, self.process_rpc_timeouts());
+                }
             }
             // Drop dead clients right before populating the Select object.
             for client_id in self.clients_to_drop.drain() {
                 drop(self.clients.remove(&client_id));
+                clients_changed = true;
+            }
+            if clients_changed {
+                receivers = ClientReceivers::new(&self.clients);
+                clients_changed = false;
             }
             let mut sel = channel::Select::new();
-            let mut ids: Vec<u64> = Vec::new();
-            if safe_to_forward {
-                // Ignore data from clients if in the process of autonegotiation,
-                // as the packet might get lost. Once the process finishes, we
-                // their queue will be processed.
-                for (id, client) in self.clients.iter() {
-                    sel.recv(&client.rx);
-                    ids.push(*id);
-                }
+            // Ignore data from clients if in the process of autonegotiation,
+            // as the packet might get lost. Once the process finishes, we
+            // their queue will be processed.
+            let n_data = if safe_to_forward {
+                receivers.data.len()
+            } else {
+                0
+            };
+            for (_, rx) in &receivers.data[..n_data] {
+                sel.recv(rx);
+            }
+            for (_, control) in &receivers.control {
+                sel.recv(control);
             }
 
             sel.recv(&self.new_client_queue);
+            sel.recv(&self.downstream_queue);
+            for downstream in &self.downstreams {
+                downstream.port.select_recv(&mut sel);
+                downstream.port.select_recv_rpc(&mut sel);
+            }
             if let Some(device) = &self.device {
                 sel.recv(&device.rx_channel);
             }
@@ -722,23 +2469,29 @@ impl ProxyCore {
                 Err(channel::ReadyTimeoutError) => continue,
             };
 
-            if index < ids.len() {
+            if index < n_data {
                 // data from a client to send to the port
-                let client_id = ids[index];
+                let client_id = receivers.data[index].0;
                 let mut packets = vec![];
+                let mut rejected = vec![];
                 {
                     let client = self
                         .clients
-                        .get(&client_id)
+                        .get_mut(&client_id)
                         .expect("invalid client from Select");
+                    // Changes requested before the packets apply to them.
+                    client.apply_control();
                     loop {
                         // Looking up the client for every packet is not very efficient,
                         // but the packet rate client->device is very low that in
                         // practice this will rarely loop more than once
                         match client.recv() {
-                            Ok(pkt) => {
+                            Ok(Ok(pkt)) => {
                                 packets.push(pkt);
                             }
+                            Ok(Err(pkt)) => {
+                                rejected.push(pkt);
+                            }
                             Err(TryRecvError::Empty) => {
                                 break;
                             }
@@ -754,6 +2507,30 @@ impl ProxyCore {
                     }
                 }
 
+                // Refuse packets addressed outside of the client scope. Those are
+                // not forwarded, and RPC requests get an error back so the client
+                // does not have to wait for a timeout.
+                for pkt in rejected {
+                    self.status_queue
+                        .send(Event::RouteOutOfScope(client_id, pkt.routing.clone()));
+                    if let proto::Payload::RpcRequest(req) = &pkt.payload {
+                        let client = self
+                            .clients
+                            .get(&client_id)
+                            .expect("invalid client from Select");
+                        if let Err(reason) =
+                            client.send_scoped(util::PacketBuilder::make_rpc_error(
+                                req.id,
+                                proto::RpcErrorCode::NotFound,
+                                pkt.routing,
+                            ))
+                        {
+                            self.drop_dead_client(client_id, reason);
+                            break;
+                        }
+                    }
+                }
+
                 // Forward all packets from clients to the device. If there are
                 // RPC requests which cannot be sent, a synthetic RPC error
                 // will be returned to send back.
@@ -773,19 +2550,33 @@ impl ProxyCore {
                         .clients
                         .get(&client_id)
                         .expect("invalid client from Select");
-                    let mut failed = false;
+                    let mut failed = None;
                     for pkt in rpc_errors {
-                        if let Err(_) = client.send(&pkt) {
-                            failed = true;
+                        if let Err(reason) = client.send(&pkt) {
+                            failed = Some(reason);
                             break;
                         }
                     }
-                    if failed {
-                        self.status_queue.send(Event::ClientSendFailed(client_id));
-                        self.drop_client(client_id);
+                    if let Some(reason) = failed {
+                        self.drop_dead_client(client_id, reason);
                     }
                 }
-            } else if index == ids.len() {
+                // Cancellations apply to the requests just forwarded.
+                self.cancel_client_rpcs(client_id);
+                if self.reconnect_requested(client_id) {
+                    device_timeout = self.disconnect_device();
+                }
+            } else if index < n_data + receivers.control.len() {
+                // change to a client
+                let client_id = receivers.control[index - n_data].0;
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.apply_control();
+                }
+                self.cancel_client_rpcs(client_id);
+                if self.reconnect_requested(client_id) {
+                    device_timeout = self.disconnect_device();
+                }
+            } else if index == n_data + receivers.control.len() {
                 // new proxy client
                 loop {
                     match self.new_client_queue.try_recv() {
@@ -793,6 +2584,7 @@ impl ProxyCore {
                             self.status_queue
                                 .send(Event::NewClient(self.next_client_id));
                             self.clients.insert(self.next_client_id, client);
+                            clients_changed = true;
                             self.next_client_id += 1;
                         }
                         Err(TryRecvError::Empty) => {
@@ -804,6 +2596,14 @@ impl ProxyCore {
                         }
                     }
                 }
+            } else if index == n_data + receivers.control.len() + 1 {
+                // new downstream proxy
+                while let Ok(request) = self.downstream_queue.try_recv() {
+                    self.attach_downstream(request);
+                }
+            } else if index < n_data + receivers.control.len() + 2 + 2 * self.downstreams.len() {
+                // data from a downstream proxy, on either of its lanes
+                self.recv_downstream((index - n_data - receivers.control.len() - 2) / 2);
             } else {
                 // data from the device
                 loop {
@@ -814,83 +2614,18 @@ impl ProxyCore {
                         break;
                     };
                     match device.try_recv(&self.status_queue) {
-                        Ok(Ok(mut pkt)) => {
-                            // In general, packets get forwarded to all clients,
-                            // except for RPCs which are directed only to the
-                            // client which placed the request.
-                            if let Some(wire_id) = match &pkt.payload {
-                                proto::Payload::RpcReply(rep) => Some(rep.id),
-                                proto::Payload::RpcError(err) => Some(err.id),
-                                _ => None,
-                            } {
-                                // Remap RPC reply or error ID to client + ID
-                                let (client, client_id, original_id) =
-                                    if let Some((client_id, rpc_id)) =
-                                        self.rpc_restore(wire_id, &pkt.routing)
-                                    {
-                                        if client_id == 0 {
-                                            // internal reply
-                                            (None, 0, rpc_id)
-                                        } else if let Some(client) = self.clients.get(&client_id) {
-                                            self.status_queue.send(Event::RpcRestore(
-                                                wire_id,
-                                                (client_id, rpc_id),
-                                            ));
-                                            (Some(client), client_id, rpc_id)
-                                        } else {
-                                            // If we cannot find the client which originally sent the
-                                            // request, just drop the packet and send an event.
-                                            self.status_queue
-                                                .send(Event::RpcClientNotFound(client_id));
-                                            continue;
-                                        }
-                                    } else {
-                                        self.status_queue.send(Event::RpcRestoreNotFound(wire_id));
-                                        continue;
-                                    };
-                                // Restore original ID, and process internal RPCs.
-                                match &mut pkt.payload {
-                                    proto::Payload::RpcReply(rep) => {
-                                        rep.id = original_id;
-                                        if client_id == 0 {
-                                            self.internal_rpc_reply(rep);
-                                            continue;
-                                        }
-                                    }
-                                    proto::Payload::RpcError(err) => {
-                                        err.id = original_id;
-                                        if client_id == 0 {
-                                            self.internal_rpc_error(err);
-                                            continue;
-                                        }
-                                    }
-                                    _ => {
-                                        // should never happen due to outer match
-                                        panic!("unexpected payload")
-                                    }
-                                }
-                                // Forward with correct request id to the requestor
-                                if let Err(_) = client.expect("unexpected client").send(&pkt) {
-                                    self.status_queue.send(Event::ClientSendFailed(client_id));
-                                    self.drop_client(client_id);
-                                }
-                            } else {
-                                let mut to_drop = vec![];
-                                for (client_id, client) in self.clients.iter() {
-                                    if let Err(_) = client.send(&pkt) {
-                                        self.status_queue.send(Event::ClientSendFailed(*client_id));
-                                        to_drop.push(*client_id);
-                                    }
-                                }
-                                for client_id in to_drop {
-                                    self.drop_client(client_id);
-                                }
-                            }
+                        Ok(Ok(pkt)) => {
+                            self.process_device_packet(pkt);
                         }
                         // Got a RecvError
                         Ok(Err(err)) => {
                             match err {
                                 RecvError::Protocol(perror) => {
+                                    if let proto::Error::Text(text) = &perror {
+                                        for client in self.clients.values() {
+                                            client.send_text(text);
+                                        }
+                                    }
                                     self.status_queue.send(Event::ProtocolError(perror));
                                 }
                                 // All other errors are treated as fatal.
@@ -904,18 +2639,14 @@ impl ProxyCore {
                             break;
                         }
                         Err(TryRecvError::Disconnected) => {
-                            self.device = None;
-                            device_timeout = Instant::now()
-                                + match self.reconnect_timeout {
-                                    Some(t) => t,
-                                    None => Duration::from_secs(0),
-                                };
-                            self.status_queue.send(Event::SensorDisconnected);
+                            device_timeout = self.disconnect_device();
                             break;
                         }
                     }
                 }
             }
         }
+        self.device = None;
+        self.publish_link_status();
     }
 }
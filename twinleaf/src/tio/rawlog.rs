@@ -0,0 +1,739 @@
+//! Raw packet logs
+//!
+//! Writes the raw TIO packets received from a device to a log, as
+//! `tio-tool log` does, and reads them back. A log is just the packets
+//! serialized one after the other, so finding a time range in a long log
+//! normally means reading it from the start. To avoid that, `LogWriter` can
+//! write a sidecar index alongside the log, mapping the time packets were
+//! logged to their offset in the log, and marking where the session of a
+//! device changed, e.g. after it rebooted:
+//! ```no_run
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy, rawlog::LogWriter};
+//! # use std::path::Path;
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let port = proxy.device_full(DeviceRoute::root()).unwrap();
+//! let mut log = LogWriter::create(Path::new("run.tio"), true).unwrap();
+//! for pkt in port.iter() {
+//!     log.write(&pkt).unwrap();
+//! }
+//! ```
+//! `LogReader` then reads the packets logged over a time range, seeking
+//! directly to it with the index:
+//! ```no_run
+//! # use twinleaf::tio::rawlog::{LogIndex, LogReader};
+//! # use std::path::Path;
+//! # use std::time::{Duration, SystemTime};
+//! let path = Path::new("run.tio");
+//! let index = LogIndex::open(path).unwrap();
+//! let mut reader = LogReader::open(path).unwrap();
+//! let to = SystemTime::now();
+//! reader.seek_range(&index, to - Duration::from_secs(60), to).unwrap();
+//! for pkt in reader {
+//!     println!("{:?}", pkt.unwrap());
+//! }
+//! ```
+//!
+//! The index is UTF-8 text, in a file named after the log with `.idx`
+//! appended, with one entry per line: the time in seconds since the UNIX
+//! epoch, a space and the offset in the log of the first packet logged at
+//! or after that time, e.g. `1700000000.123456 1048576`. Entries are
+//! written at most every `index_interval`, so seeking is only that precise.
+//! Entries for session changes are followed by `session`, the route of the
+//! device and its new session id, e.g. `1700000000.123456 0 session /0 42`.
+//!
+//! Analysts often only want the data of one sensor out of a capture of
+//! several. `LogSplitter` demultiplexes packets into a log per device
+//! route and/or per session, while logging or from an existing log, named
+//! after the original as given by `split_path`. It can also embed a
+//! snapshot of the metadata of a device at the start of each log and after
+//! each session change, so that logs can be decoded without the device (see
+//! `LogSplitter::with_metadata`).
+//!
+//! Logs whose path ends with `.zst` are compressed with zstd, if the `zstd`
+//! feature is enabled, and decompressed transparently when read. Their
+//! index is not compressed, and offsets are in the decompressed log, so
+//! seeking in a compressed log still decompresses it up to the offset, but
+//! without parsing the packets.
+
+use super::proto::meta::MetadataContent;
+use super::proto::{self, DeviceRoute, HeartbeatPayload, Packet, Payload};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default interval between index entries.
+pub static DEFAULT_INDEX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Magic number starting zstd frames, as stored.
+static ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Size of the reads from the log.
+static READ_CHUNK: usize = 64 * 1024;
+
+/// Path of the index of the log at `log`.
+pub fn index_path(log: &Path) -> PathBuf {
+    let mut path = log.as_os_str().to_os_string();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// Time a packet was received by its port, or now if it was not stamped.
+fn received_time(pkt: &Packet) -> SystemTime {
+    pkt.received.map_or_else(SystemTime::now, |t| t.system_time)
+}
+
+/// Session id announced by a packet, if any.
+fn packet_session(pkt: &Packet) -> Option<u32> {
+    match &pkt.payload {
+        Payload::Heartbeat(HeartbeatPayload::Session(session)) => Some(*session),
+        Payload::Metadata(meta) => match &meta.content {
+            MetadataContent::Device(device) => Some(device.session_id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Writes packets to a log, and optionally its index.
+pub struct LogWriter<W: Write> {
+    out: W,
+    index: Option<W>,
+    index_interval: Duration,
+    /// Offset of the next packet in the log.
+    offset: u64,
+    packets: u64,
+    last_entry: Option<SystemTime>,
+    /// Last session seen of each device.
+    sessions: HashMap<DeviceRoute, u32>,
+}
+
+/// Whether the log at `path` is compressed, as told by its `.zst`
+/// extension.
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zst")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed logs need the zstd feature",
+    )
+}
+
+/// File a log is written to, compressed or not.
+pub enum LogOutput {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(crate::zstd::Encoder<BufWriter<File>>),
+}
+
+impl LogOutput {
+    /// Create the file at `path`, compressed if `is_compressed(path)`.
+    pub fn create(path: &Path) -> io::Result<LogOutput> {
+        let file = BufWriter::new(File::create(path)?);
+        if !is_compressed(path) {
+            return Ok(LogOutput::Plain(file));
+        }
+        #[cfg(feature = "zstd")]
+        return Ok(LogOutput::Zstd(crate::zstd::Encoder::new(file)?));
+        #[cfg(not(feature = "zstd"))]
+        Err(zstd_unsupported())
+    }
+}
+
+impl Write for LogOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            LogOutput::Plain(file) => file.write(data),
+            #[cfg(feature = "zstd")]
+            LogOutput::Zstd(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogOutput::Plain(file) => file.flush(),
+            #[cfg(feature = "zstd")]
+            LogOutput::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl LogWriter<LogOutput> {
+    /// Create the log at `path`, compressed if `is_compressed(path)`, and
+    /// its index at `index_path(path)` if `indexed`.
+    pub fn create(path: &Path, indexed: bool) -> io::Result<LogWriter<LogOutput>> {
+        let out = LogOutput::create(path)?;
+        Ok(if indexed {
+            let index = LogOutput::Plain(BufWriter::new(File::create(index_path(path))?));
+            LogWriter::with_index(out, index)
+        } else {
+            LogWriter::new(out)
+        })
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    /// A log without an index.
+    pub fn new(out: W) -> LogWriter<W> {
+        LogWriter {
+            out,
+            index: None,
+            index_interval: DEFAULT_INDEX_INTERVAL,
+            offset: 0,
+            packets: 0,
+            last_entry: None,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// A log with its index written to `index`.
+    pub fn with_index(out: W, index: W) -> LogWriter<W> {
+        LogWriter {
+            index: Some(index),
+            ..LogWriter::new(out)
+        }
+    }
+
+    /// Set the interval between index entries.
+    pub fn index_interval(mut self, interval: Duration) -> LogWriter<W> {
+        self.index_interval = interval;
+        self
+    }
+
+    /// Log a packet just received, at the time its port received it if
+    /// it was stamped.
+    pub fn write(&mut self, pkt: &Packet) -> io::Result<()> {
+        self.write_at(received_time(pkt), pkt)
+    }
+
+    /// Log a packet received at `time`.
+    pub fn write_at(&mut self, time: SystemTime, pkt: &Packet) -> io::Result<()> {
+        let raw = pkt
+            .serialize()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+        if let Some(index) = &mut self.index {
+            let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let entry = format!("{}.{:06} {}", t.as_secs(), t.subsec_micros(), self.offset);
+            let session = packet_session(pkt)
+                .filter(|session| self.sessions.get(&pkt.routing) != Some(session));
+            if let Some(session) = session {
+                writeln!(index, "{} session {} {}", entry, pkt.routing, session)?;
+                self.sessions.insert(pkt.routing.clone(), session);
+                self.last_entry = Some(time);
+            } else if self.last_entry.is_none_or(|last| {
+                time.duration_since(last).unwrap_or_default() >= self.index_interval
+            }) {
+                writeln!(index, "{}", entry)?;
+                self.last_entry = Some(time);
+            }
+        }
+        self.out.write_all(&raw)?;
+        self.offset += raw.len() as u64;
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// Number of packets logged.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// Number of bytes logged.
+    pub fn bytes(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if let Some(index) = &mut self.index {
+            index.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The log and its index, flushed.
+    pub fn into_inner(mut self) -> io::Result<(W, Option<W>)> {
+        self.flush()?;
+        Ok((self.out, self.index))
+    }
+}
+
+/// How `LogSplitter` splits packets between logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SplitBy {
+    /// A log per device route.
+    pub route: bool,
+    /// A log per device session, so a new log each time a device reboots.
+    pub session: bool,
+}
+
+impl std::str::FromStr for SplitBy {
+    type Err = String;
+
+    /// Parse a comma separated list of `route` and `session`. An empty
+    /// string does not split.
+    fn from_str(text: &str) -> Result<SplitBy, String> {
+        let mut by = SplitBy::default();
+        for key in text.split(',').filter(|key| !key.is_empty()) {
+            match key.trim() {
+                "route" => by.route = true,
+                "session" => by.session = true,
+                _ => return Err(format!("invalid split key {:?}", key)),
+            }
+        }
+        Ok(by)
+    }
+}
+
+/// Path of the log split from `base` by `by`, for the device at `route` in
+/// `session`: the route and the session are inserted before the extension,
+/// e.g. `run.0.1.s42.tio` for session 42 of the device at `/0/1`, or
+/// `run.root.tio` for the root device. Packets received before the session
+/// of their device is known go to a `nosession` log.
+pub fn split_path(base: &Path, by: SplitBy, route: &DeviceRoute, session: Option<u32>) -> PathBuf {
+    if by == SplitBy::default() {
+        return base.to_path_buf();
+    }
+    if is_compressed(base) {
+        let mut path = split_path(&base.with_extension(""), by, route, session).into_os_string();
+        path.push(".zst");
+        return PathBuf::from(path);
+    }
+    let mut name = base
+        .file_stem()
+        .map(|stem| stem.to_os_string())
+        .unwrap_or_default();
+    if by.route {
+        if route.is_empty() {
+            name.push(".root");
+        }
+        for hop in route.iter() {
+            name.push(format!(".{}", hop));
+        }
+    }
+    if by.session {
+        match session {
+            Some(session) => name.push(format!(".s{}", session)),
+            None => name.push(".nosession"),
+        }
+    }
+    if let Some(ext) = base.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    base.with_file_name(name)
+}
+
+/// Route and session of the packets of a split log, when split by them.
+type SplitKey = (Option<DeviceRoute>, Option<u32>);
+
+/// Returns the packets of a snapshot of the metadata of the device at a
+/// route, see `data::DeviceFullMetadata::make_updates`.
+pub type MetadataSource<'a> = Box<dyn FnMut(&DeviceRoute) -> Vec<Packet> + 'a>;
+
+/// Writes packets to several logs, split by device route and/or session,
+/// each optionally indexed, at the paths given by `split_path`. Logs are
+/// created at their first packet. The routes of packets are logged
+/// unchanged.
+pub struct LogSplitter<'a> {
+    base: PathBuf,
+    by: SplitBy,
+    indexed: bool,
+    metadata: Option<MetadataSource<'a>>,
+    /// Logs being written.
+    logs: HashMap<SplitKey, LogWriter<LogOutput>>,
+    sessions: HashMap<DeviceRoute, u32>,
+    paths: Vec<PathBuf>,
+    packets: u64,
+    bytes: u64,
+}
+
+impl<'a> LogSplitter<'a> {
+    /// Split packets into logs named after `base`. Splitting by nothing
+    /// writes a single log at `base`.
+    pub fn new(base: &Path, by: SplitBy) -> LogSplitter<'a> {
+        LogSplitter {
+            base: base.to_path_buf(),
+            by,
+            indexed: false,
+            metadata: None,
+            logs: HashMap::new(),
+            sessions: HashMap::new(),
+            paths: vec![],
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Write an index alongside each log.
+    pub fn indexed(mut self, indexed: bool) -> LogSplitter<'a> {
+        self.indexed = indexed;
+        self
+    }
+
+    /// Embed the metadata snapshot returned by `source` for the device at
+    /// the route of a packet when the packet starts a log, or announces a
+    /// new session of the device, before the packet. Logs are then
+    /// self-describing, even if the device does not send its metadata.
+    pub fn with_metadata(
+        mut self,
+        source: impl FnMut(&DeviceRoute) -> Vec<Packet> + 'a,
+    ) -> LogSplitter<'a> {
+        self.metadata = Some(Box::new(source));
+        self
+    }
+
+    /// Log a packet just received, at the time its port received it if
+    /// it was stamped.
+    pub fn write(&mut self, pkt: &Packet) -> io::Result<()> {
+        self.write_at(received_time(pkt), pkt)
+    }
+
+    /// Log a packet received at `time`.
+    pub fn write_at(&mut self, time: SystemTime, pkt: &Packet) -> io::Result<()> {
+        let mut snapshot = false;
+        if let Some(session) = packet_session(pkt) {
+            let previous = self.sessions.insert(pkt.routing.clone(), session);
+            if previous.is_some_and(|previous| previous != session) {
+                // The device rebooted, and its metadata may have changed.
+                snapshot = true;
+                if self.by.session {
+                    // Its previous log is done.
+                    let key = (self.route_key(&pkt.routing), previous);
+                    if let Some(mut log) = self.logs.remove(&key) {
+                        log.flush()?;
+                    }
+                }
+            }
+        }
+        let route = self.route_key(&pkt.routing);
+        let session = if self.by.session {
+            self.sessions.get(&pkt.routing).copied()
+        } else {
+            None
+        };
+        let key = (route, session);
+        if !self.logs.contains_key(&key) {
+            let path = split_path(&self.base, self.by, &pkt.routing, session);
+            let log = LogWriter::create(&path, self.indexed)?;
+            self.paths.push(path);
+            self.logs.insert(key.clone(), log);
+            snapshot = true;
+        }
+        let log = self.logs.get_mut(&key).expect("log was just created");
+        let (bytes, packets) = (log.bytes(), log.packets());
+        if let Some(source) = self.metadata.as_mut().filter(|_| snapshot) {
+            for mut meta in source(&pkt.routing) {
+                meta.routing = pkt.routing.clone();
+                log.write_at(time, &meta)?;
+            }
+        }
+        log.write_at(time, pkt)?;
+        self.bytes += log.bytes() - bytes;
+        self.packets += log.packets() - packets;
+        Ok(())
+    }
+
+    fn route_key(&self, route: &DeviceRoute) -> Option<DeviceRoute> {
+        if self.by.route {
+            Some(route.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Paths of the logs created so far.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Number of packets logged, in all logs.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// Number of bytes logged, in all logs.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for log in self.logs.values_mut() {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the index of a log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub time: SystemTime,
+    /// Offset of the first packet logged at or after `time`.
+    pub offset: u64,
+    /// Route and new session id of the device whose session changed.
+    pub session: Option<(DeviceRoute, u32)>,
+}
+
+impl IndexEntry {
+    fn parse(line: &str) -> Option<IndexEntry> {
+        let mut fields = line.split(' ');
+        let time = fields.next()?.parse::<f64>().ok()?;
+        if !time.is_finite() || time < 0.0 {
+            return None;
+        }
+        let offset = fields.next()?.parse().ok()?;
+        let session = match fields.next() {
+            None => None,
+            Some("session") => {
+                let route = DeviceRoute::from_str(fields.next()?).ok()?;
+                Some((route, fields.next()?.parse().ok()?))
+            }
+            Some(_) => return None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(IndexEntry {
+            time: UNIX_EPOCH + Duration::from_secs_f64(time),
+            offset,
+            session,
+        })
+    }
+}
+
+/// Index of a log, to find where a time range starts and ends in it.
+#[derive(Debug, Clone, Default)]
+pub struct LogIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl LogIndex {
+    /// Read an index, returning an `InvalidData` error for a line which is
+    /// not a valid entry. A partial last line, from an index still being
+    /// written, is ignored.
+    pub fn read<R: BufRead>(input: R) -> io::Result<LogIndex> {
+        let mut entries = vec![];
+        let mut lines = input.lines().peekable();
+        let mut n = 0;
+        while let Some(line) = lines.next() {
+            let line = line?;
+            n += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match IndexEntry::parse(&line) {
+                Some(entry) => entries.push(entry),
+                None if lines.peek().is_none() => break,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid index entry on line {}", n),
+                    ))
+                }
+            }
+        }
+        Ok(LogIndex { entries })
+    }
+
+    /// Read the index of the log at `log`.
+    pub fn open(log: &Path) -> io::Result<LogIndex> {
+        LogIndex::read(BufReader::new(File::open(index_path(log))?))
+    }
+
+    /// All the entries, in the order they were written.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The entries marking session changes.
+    pub fn sessions(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.iter().filter(|entry| entry.session.is_some())
+    }
+
+    /// Offset from which to read the packets logged from `time`: that of
+    /// the last entry at or before it, or the start of the log. Times are
+    /// assumed to increase through the log, so a clock going backwards
+    /// while logging makes this imprecise.
+    pub fn offset_before(&self, time: SystemTime) -> u64 {
+        let n = self.entries.partition_point(|entry| entry.time <= time);
+        n.checked_sub(1).map_or(0, |i| self.entries[i].offset)
+    }
+
+    /// Offset until which to read the packets logged up to `time`: that of
+    /// the first entry after it, or None for the end of the log.
+    pub fn offset_after(&self, time: SystemTime) -> Option<u64> {
+        let n = self.entries.partition_point(|entry| entry.time <= time);
+        self.entries.get(n).map(|entry| entry.offset)
+    }
+}
+
+/// Reads the packets of a log, in order.
+pub struct LogReader<R: Read> {
+    input: R,
+    buf: Vec<u8>,
+    /// Start of the unread data in `buf`.
+    pos: usize,
+    /// Offset in the log of the next packet.
+    offset: u64,
+    /// Offset at which to stop reading.
+    end: Option<u64>,
+    done: bool,
+}
+
+/// File a log is read from, decompressed if needed.
+pub enum LogInput {
+    Plain(BufReader<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(crate::zstd::Decoder<BufReader<File>>),
+}
+
+impl LogInput {
+    /// Open the file at `path`, decompressing it if it starts with the zstd
+    /// magic number, whatever its extension.
+    pub fn open(path: &Path) -> io::Result<LogInput> {
+        let mut file = BufReader::new(File::open(path)?);
+        let compressed = file.fill_buf()?.starts_with(&ZSTD_MAGIC);
+        if !compressed {
+            return Ok(LogInput::Plain(file));
+        }
+        #[cfg(feature = "zstd")]
+        return Ok(LogInput::Zstd(crate::zstd::Decoder::new(file)?));
+        #[cfg(not(feature = "zstd"))]
+        Err(zstd_unsupported())
+    }
+}
+
+impl Read for LogInput {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LogInput::Plain(file) => file.read(data),
+            #[cfg(feature = "zstd")]
+            LogInput::Zstd(decoder) => decoder.read(data),
+        }
+    }
+}
+
+impl Seek for LogInput {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            LogInput::Plain(file) => file.seek(pos),
+            #[cfg(feature = "zstd")]
+            LogInput::Zstd(decoder) => decoder.seek(pos),
+        }
+    }
+}
+
+impl LogReader<LogInput> {
+    /// Read the log at `path`, decompressing it if needed.
+    pub fn open(path: &Path) -> io::Result<LogReader<LogInput>> {
+        Ok(LogReader::new(LogInput::open(path)?))
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(input: R) -> LogReader<R> {
+        LogReader {
+            input,
+            buf: vec![],
+            pos: 0,
+            offset: 0,
+            end: None,
+            done: false,
+        }
+    }
+
+    /// Offset in the log of the next packet.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Stop reading at offset `end`, or at the end of the log if None.
+    pub fn set_end(&mut self, end: Option<u64>) {
+        self.end = end;
+    }
+
+    /// Read more of the log, returning false at its end.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        let n = (&mut self.input)
+            .take(READ_CHUNK as u64)
+            .read_to_end(&mut self.buf)?;
+        Ok(n > 0)
+    }
+}
+
+impl<R: Read + Seek> LogReader<R> {
+    /// Continue reading from `offset`, which must be the start of a packet.
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        self.input.seek(SeekFrom::Start(offset))?;
+        self.buf.clear();
+        self.pos = 0;
+        self.offset = offset;
+        self.done = false;
+        Ok(())
+    }
+
+    /// Read only the packets logged between `from` and `to`, according to
+    /// `index`. Since the index has an entry only every so often, packets
+    /// logged up to an index interval before and after the range are
+    /// included too.
+    pub fn seek_range(
+        &mut self,
+        index: &LogIndex,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> io::Result<()> {
+        self.seek(index.offset_before(from))?;
+        self.end = index.offset_after(to);
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = io::Result<Packet>;
+
+    /// The next packet, or an `InvalidData` error for a corrupted one, after
+    /// which reading stops. A partial last packet, from a log still being
+    /// written, ends the log.
+    fn next(&mut self) -> Option<io::Result<Packet>> {
+        loop {
+            if self.done || self.end.is_some_and(|end| self.offset >= end) {
+                return None;
+            }
+            match Packet::deserialize(&self.buf[self.pos..]) {
+                Ok((pkt, len)) => {
+                    self.pos += len;
+                    self.offset += len as u64;
+                    return Some(Ok(pkt));
+                }
+                Err(proto::Error::NeedMore) => match self.fill() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.done = true;
+                        return None;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                },
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid packet at offset {}: {:?}", self.offset, err),
+                    )));
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,13 @@
 use crate::tio::proto::{self, DeviceRoute, Packet, Payload};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Paths used by `#[derive(TioRpc)]`, which also works in `no_std` crates.
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::vec::Vec;
+}
 
 pub fn default_proxy_url() -> &'static str {
     "tcp://localhost"
@@ -22,6 +31,7 @@ impl PacketBuilder {
             }),
             routing: routing,
             ttl: 0,
+            received: None,
         }
     }
 
@@ -38,6 +48,7 @@ impl PacketBuilder {
             }),
             routing: routing,
             ttl: 0,
+            received: None,
         }
     }
 
@@ -45,11 +56,25 @@ impl PacketBuilder {
         Self::make_rpc_error(id, error, self.routing.clone())
     }
 
+    pub fn make_rpc_reply(id: u16, reply: Vec<u8>, routing: DeviceRoute) -> Packet {
+        Packet {
+            payload: Payload::RpcReply(proto::RpcReplyPayload { id, reply }),
+            routing,
+            ttl: 0,
+            received: None,
+        }
+    }
+
+    pub fn rpc_reply(&self, id: u16, reply: Vec<u8>) -> Packet {
+        Self::make_rpc_reply(id, reply, self.routing.clone())
+    }
+
     pub fn make_heartbeat(payload: Vec<u8>) -> Packet {
         Packet {
             payload: Payload::Heartbeat(proto::HeartbeatPayload::Any(payload)),
             routing: DeviceRoute::root(),
             ttl: 0,
+            received: None,
         }
     }
 
@@ -70,6 +95,10 @@ impl PacketBuilder {
     }
 }
 
+/// Implements the RPC traits below for structs of fixed size fields.
+#[cfg(feature = "derive")]
+pub use tio_derive::TioRpc;
+
 pub trait TioRpcRequestable<T> {
     fn to_request(&self) -> Vec<u8>;
 }
@@ -87,6 +116,15 @@ pub trait TioRpcReplyable<T> {
     }
 }
 
+/// Parse an RPC reply payload as a `T`, like `T::from_reply`, reporting a
+/// reply which does not match as `proto::Error::InvalidPayload`.
+///
+/// Whatever the reply, this returns an error rather than panicking, which
+/// makes it suitable as a fuzzing entry point.
+pub fn parse_reply<T: TioRpcReplyable<T>>(reply: &[u8]) -> Result<T, proto::Error> {
+    T::from_reply(reply).map_err(|_| proto::Error::InvalidPayload(reply.to_vec()))
+}
+
 pub trait TioRpcReplyableFixedSize {}
 
 impl TioRpcRequestable<()> for () {
@@ -113,7 +151,7 @@ macro_rules! make_tio_rpc_traits {
 
         impl TioRpcReplyable<$primitive> for $primitive {
             fn from_reply_prefix(reply: &[u8]) -> Result<($primitive, &[u8]), ()> {
-                let psize = std::mem::size_of::<$primitive>();
+                let psize = core::mem::size_of::<$primitive>();
                 if reply.len() < psize {
                     return Err(());
                 }
@@ -126,9 +164,36 @@ macro_rules! make_tio_rpc_traits {
             }
         }
         impl TioRpcReplyableFixedSize for $primitive {}
+
+        impl TioRpcRequestable<Be<$primitive>> for Be<$primitive> {
+            fn to_request(&self) -> Vec<u8> {
+                self.0.to_be_bytes().to_vec()
+            }
+        }
+
+        impl TioRpcReplyable<Be<$primitive>> for Be<$primitive> {
+            fn from_reply_prefix(reply: &[u8]) -> Result<(Be<$primitive>, &[u8]), ()> {
+                let psize = core::mem::size_of::<$primitive>();
+                if reply.len() < psize {
+                    return Err(());
+                }
+                let array = if let Ok(array) = reply[0..psize].try_into() {
+                    array
+                } else {
+                    return Err(());
+                };
+                Ok((Be($primitive::from_be_bytes(array)), &reply[psize..]))
+            }
+        }
+        impl TioRpcReplyableFixedSize for Be<$primitive> {}
     };
 }
 
+/// Big endian number, for devices which do not use the little endian byte
+/// order of the protocol in some RPC arguments or replies.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Be<T>(pub T);
+
 make_tio_rpc_traits!(u8);
 make_tio_rpc_traits!(i8);
 make_tio_rpc_traits!(u16);
@@ -147,7 +212,9 @@ impl TioRpcRequestable<&str> for &str {
     }
 }
 
-// String for requests and replies, but not fixed size
+// String for requests and replies, but not fixed size: as a reply, it takes
+// all the rest of the reply, so it can only be last. See also `RestAsString`
+// and `LengthPrefixedString`.
 impl TioRpcRequestable<String> for String {
     fn to_request(&self) -> Vec<u8> {
         self.as_bytes().to_vec()
@@ -190,3 +257,190 @@ impl<
     > TioRpcReplyableFixedSize for (A, B)
 {
 }
+
+// Arrays of fixed size elements, packed one after the other. As a reply, a
+// Vec takes all the elements until the end.
+impl<T: TioRpcRequestable<T>> TioRpcRequestable<Vec<T>> for Vec<T> {
+    fn to_request(&self) -> Vec<u8> {
+        self.iter().flat_map(|item| item.to_request()).collect()
+    }
+}
+
+impl<T: TioRpcReplyable<T> + TioRpcReplyableFixedSize> TioRpcReplyable<Vec<T>> for Vec<T> {
+    fn from_reply_prefix(reply: &[u8]) -> Result<(Vec<T>, &[u8]), ()> {
+        let mut ret = vec![];
+        let mut rest = reply;
+        while !rest.is_empty() {
+            let (item, next) = T::from_reply_prefix(rest)?;
+            if next.len() == rest.len() {
+                // Zero sized elements
+                return Err(());
+            }
+            ret.push(item);
+            rest = next;
+        }
+        Ok((ret, rest))
+    }
+}
+
+/// Array of fixed size elements preceded by their count, of type `N`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LengthPrefixed<T, N = u16>(pub Vec<T>, core::marker::PhantomData<N>);
+
+impl<T, N> LengthPrefixed<T, N> {
+    pub fn new(items: Vec<T>) -> LengthPrefixed<T, N> {
+        LengthPrefixed(items, core::marker::PhantomData)
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T: TioRpcRequestable<T>, N: TioRpcRequestable<N> + TryFrom<usize>>
+    TioRpcRequestable<LengthPrefixed<T, N>> for LengthPrefixed<T, N>
+{
+    /// Panics if there are more elements than `N` can count.
+    fn to_request(&self) -> Vec<u8> {
+        let count = match N::try_from(self.0.len()) {
+            Ok(count) => count,
+            Err(_) => panic!("too many elements for the length prefix"),
+        };
+        let mut ret = count.to_request();
+        ret.extend(self.0.to_request());
+        ret
+    }
+}
+
+impl<T, N> TioRpcReplyable<LengthPrefixed<T, N>> for LengthPrefixed<T, N>
+where
+    T: TioRpcReplyable<T> + TioRpcReplyableFixedSize,
+    N: TioRpcReplyable<N> + TioRpcReplyableFixedSize + TryInto<usize>,
+{
+    fn from_reply_prefix(reply: &[u8]) -> Result<(LengthPrefixed<T, N>, &[u8]), ()> {
+        let (count, mut rest) = N::from_reply_prefix(reply)?;
+        let count: usize = count.try_into().map_err(|_| ())?;
+        let mut ret = Vec::with_capacity(count.min(rest.len()));
+        for _ in 0..count {
+            let (item, next) = T::from_reply_prefix(rest)?;
+            if next.len() == rest.len() {
+                // Zero sized elements, which any count would fit
+                return Err(());
+            }
+            ret.push(item);
+            rest = next;
+        }
+        Ok((LengthPrefixed::new(ret), rest))
+    }
+}
+
+impl<T: TioRpcReplyableFixedSize, N: TioRpcReplyableFixedSize> TioRpcReplyableFixedSize
+    for LengthPrefixed<T, N>
+{
+}
+
+/// Plain data which can be converted to and from RPC payloads by copying its
+/// memory, typically a `#[repr(C, packed)]` struct of numbers mirroring a
+/// struct in the device firmware. The protocol being little endian, this is
+/// only correct on little endian hosts.
+///
+/// # Safety
+/// Any bit pattern of the size of the type must be a valid value, and the
+/// type must have no padding bytes.
+pub unsafe trait TioRpcPlainData: Copy {}
+
+/// Value of a `TioRpcPlainData` type, exchanged as its raw memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Raw<T: TioRpcPlainData>(pub T);
+
+impl<T: TioRpcPlainData> TioRpcRequestable<Raw<T>> for Raw<T> {
+    fn to_request(&self) -> Vec<u8> {
+        let size = core::mem::size_of::<T>();
+        // Safe as T has no padding, so all of its bytes are initialized.
+        unsafe { core::slice::from_raw_parts(&self.0 as *const T as *const u8, size) }.to_vec()
+    }
+}
+
+impl<T: TioRpcPlainData> TioRpcReplyable<Raw<T>> for Raw<T> {
+    fn from_reply_prefix(reply: &[u8]) -> Result<(Raw<T>, &[u8]), ()> {
+        let size = core::mem::size_of::<T>();
+        if reply.len() < size {
+            return Err(());
+        }
+        // Safe as any bit pattern is a valid T, and the read is unaligned.
+        let value = unsafe { core::ptr::read_unaligned(reply.as_ptr() as *const T) };
+        Ok((Raw(value), &reply[size..]))
+    }
+}
+
+impl<T: TioRpcPlainData> TioRpcReplyableFixedSize for Raw<T> {}
+
+/// String taking all the rest of a reply, which makes explicit that it can
+/// only come last in a composite reply such as `(u32, RestAsString)`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RestAsString(pub String);
+
+impl TioRpcRequestable<RestAsString> for RestAsString {
+    fn to_request(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+}
+
+impl TioRpcReplyable<RestAsString> for RestAsString {
+    fn from_reply_prefix(reply: &[u8]) -> Result<(RestAsString, &[u8]), ()> {
+        Ok((
+            RestAsString(String::from_utf8_lossy(reply).to_string()),
+            &[],
+        ))
+    }
+}
+
+/// String preceded by its length in bytes, of type `N`. It can be followed
+/// by other values in a composite reply, such as `(LengthPrefixedString, u32)`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LengthPrefixedString<N = u16>(pub String, core::marker::PhantomData<N>);
+
+impl<N> LengthPrefixedString<N> {
+    pub fn new(s: &str) -> LengthPrefixedString<N> {
+        LengthPrefixedString(s.to_string(), core::marker::PhantomData)
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl<N: TioRpcRequestable<N> + TryFrom<usize>> TioRpcRequestable<LengthPrefixedString<N>>
+    for LengthPrefixedString<N>
+{
+    /// Panics if the string is longer than `N` can count.
+    fn to_request(&self) -> Vec<u8> {
+        let len = match N::try_from(self.0.len()) {
+            Ok(len) => len,
+            Err(_) => panic!("string too long for the length prefix"),
+        };
+        let mut ret = len.to_request();
+        ret.extend(self.0.as_bytes());
+        ret
+    }
+}
+
+impl<N> TioRpcReplyable<LengthPrefixedString<N>> for LengthPrefixedString<N>
+where
+    N: TioRpcReplyable<N> + TioRpcReplyableFixedSize + TryInto<usize>,
+{
+    fn from_reply_prefix(reply: &[u8]) -> Result<(LengthPrefixedString<N>, &[u8]), ()> {
+        let (len, rest) = N::from_reply_prefix(reply)?;
+        let len: usize = len.try_into().map_err(|_| ())?;
+        if rest.len() < len {
+            return Err(());
+        }
+        let s = String::from_utf8_lossy(&rest[..len]).to_string();
+        Ok((
+            LengthPrefixedString(s, core::marker::PhantomData),
+            &rest[len..],
+        ))
+    }
+}
+
+impl<N: TioRpcReplyableFixedSize> TioRpcReplyableFixedSize for LengthPrefixedString<N> {}
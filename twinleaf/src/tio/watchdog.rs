@@ -0,0 +1,196 @@
+//! Watchdog
+//!
+//! Recovery of acquisitions which stall, so that long unattended captures
+//! heal themselves. A `Watchdog` is told of the data received from each
+//! stream, and when a stream it has seen, or every stream before any was
+//! seen, receives nothing for longer than the stall timeout, it takes its
+//! recovery actions in turn, one per stall timeout, until data flows again:
+//! calling an RPC of the device, such as one enabling its data, reopening
+//! the link to the device, or giving up.
+//! ```no_run
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy, watchdog::*};
+//! # use std::time::Duration;
+//! let proxy = proxy::Interface::new("serial:///dev/ttyACM0");
+//! let port = proxy.device_full(DeviceRoute::root()).unwrap();
+//! let mut watchdog = Watchdog::new(proxy.device_rpc(DeviceRoute::root()).unwrap())
+//!     .stall_timeout(Duration::from_secs(5))
+//!     .actions(vec![Recovery::Reconnect, Recovery::Reconnect, Recovery::Fatal]);
+//! loop {
+//!     if let Ok(pkt) = port.receiver().recv_timeout(Duration::from_secs(1)) {
+//!         watchdog.observe(&pkt);
+//!     }
+//!     for event in watchdog.poll() {
+//!         eprintln!("{:?}", event);
+//!         if let WatchdogEvent::Fatal = event {
+//!             return;
+//!         }
+//!     }
+//! }
+//! ```
+
+use super::proto::{DeviceRoute, Packet, Payload};
+use super::proxy::Port;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Default time without data after which a stream is stalled.
+pub static DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Action taken to restart a stalled acquisition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Call the RPC `name` of the device with `arg`, such as the RPC which
+    /// enables its data.
+    Rpc { name: String, arg: Vec<u8> },
+    /// Close the link of the proxy to the root device and open it again,
+    /// see `Port::reconnect`.
+    Reconnect,
+    /// Give up, reporting `WatchdogEvent::Fatal`.
+    Fatal,
+}
+
+/// What the watchdog noticed or did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchdogEvent {
+    /// A stream, or all of them before any data was seen, received no data
+    /// for this long.
+    Stalled(Option<(DeviceRoute, u8)>, Duration),
+    /// A recovery action was taken.
+    Recovering(Recovery),
+    /// A recovery action could not be taken, for this reason.
+    RecoveryFailed(Recovery, String),
+    /// Data flows again after a stall.
+    Recovered,
+    /// The data did not recover.
+    Fatal,
+}
+
+/// Watches the data of streams for stalls, and recovers from them.
+pub struct Watchdog {
+    port: Port,
+    stall_timeout: Duration,
+    actions: Vec<Recovery>,
+    /// When watching started, for stalls before any data is seen.
+    started: Instant,
+    last_data: BTreeMap<(DeviceRoute, u8), Instant>,
+    stalled: bool,
+    /// Number of actions taken during the current stall, and when the last
+    /// one was.
+    step: usize,
+    last_action: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Watchdog taking its recovery actions through `port`, reopening the
+    /// link once and then giving up by default.
+    pub fn new(port: Port) -> Watchdog {
+        Watchdog {
+            port,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            actions: vec![Recovery::Reconnect, Recovery::Fatal],
+            started: Instant::now(),
+            last_data: BTreeMap::new(),
+            stalled: false,
+            step: 0,
+            last_action: None,
+        }
+    }
+
+    pub fn stall_timeout(mut self, timeout: Duration) -> Watchdog {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Actions to take in turn during a stall. Once all were taken without
+    /// recovering, the watchdog waits for the data to recover by itself.
+    pub fn actions(mut self, actions: Vec<Recovery>) -> Watchdog {
+        self.actions = actions;
+        self
+    }
+
+    /// Account for data received from stream `stream_id` of the device at
+    /// `route`.
+    pub fn data_received(&mut self, route: &DeviceRoute, stream_id: u8) {
+        self.last_data
+            .insert((route.clone(), stream_id), Instant::now());
+    }
+
+    /// Account for a packet received, if it carries stream data.
+    pub fn observe(&mut self, pkt: &Packet) {
+        match &pkt.payload {
+            Payload::StreamData(data) => self.data_received(&pkt.routing, data.stream_id),
+            Payload::LegacyStreamData(_) => self.data_received(&pkt.routing, 0),
+            _ => {}
+        }
+    }
+
+    /// Forget the streams seen, as when the device is reconfigured to send
+    /// fewer streams.
+    pub fn reset(&mut self) {
+        self.started = Instant::now();
+        self.last_data.clear();
+    }
+
+    /// Whether data is currently stalled.
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// The stream which went the longest without data, if it stalled.
+    fn stall(&self, now: Instant) -> Option<(Option<(DeviceRoute, u8)>, Duration)> {
+        let (stream, since) = match self.last_data.iter().min_by_key(|(_, t)| **t) {
+            Some((stream, t)) => (Some(stream.clone()), *t),
+            None => (None, self.started),
+        };
+        let age = now.duration_since(since);
+        (age > self.stall_timeout).then_some((stream, age))
+    }
+
+    fn take_action(&mut self, action: Recovery) -> WatchdogEvent {
+        let result = match &action {
+            Recovery::Rpc { name, arg } => self
+                .port
+                .raw_rpc(name, arg)
+                .map(|_| ())
+                .map_err(|err| format!("{:?}", err)),
+            Recovery::Reconnect => self.port.reconnect().map_err(|err| format!("{:?}", err)),
+            Recovery::Fatal => return WatchdogEvent::Fatal,
+        };
+        match result {
+            Ok(()) => WatchdogEvent::Recovering(action),
+            Err(err) => WatchdogEvent::RecoveryFailed(action, err),
+        }
+    }
+
+    /// Check for stalls, taking the next recovery action when due. To be
+    /// called regularly, more often than the stall timeout.
+    pub fn poll(&mut self) -> Vec<WatchdogEvent> {
+        let now = Instant::now();
+        let mut events = vec![];
+        let Some((stream, age)) = self.stall(now) else {
+            if self.stalled {
+                self.stalled = false;
+                self.step = 0;
+                self.last_action = None;
+                events.push(WatchdogEvent::Recovered);
+            }
+            return events;
+        };
+        if !self.stalled {
+            self.stalled = true;
+            events.push(WatchdogEvent::Stalled(stream, age));
+        }
+        // Give each action a stall timeout to take effect.
+        let due = self
+            .last_action
+            .is_none_or(|last| now.duration_since(last) >= self.stall_timeout);
+        if due && self.step < self.actions.len() {
+            let action = self.actions[self.step].clone();
+            self.step += 1;
+            self.last_action = Some(now);
+            events.push(self.take_action(action));
+        }
+        events
+    }
+}
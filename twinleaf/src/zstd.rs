@@ -0,0 +1,148 @@
+//! Zstandard compression
+//!
+//! Streaming compression and decompression of logs, which get large for
+//! high rate captures but compress very well. Enabled with the `zstd`
+//! feature, using the `zstd` crate.
+//!
+//! `Encoder` compresses what is written to it into a zstd frame, and
+//! `Decoder` decompresses what is read through it, including the
+//! concatenation of several frames, as written by the `zstd` tool. Logs
+//! with a `.zst` extension are compressed this way by `tio::rawlog`.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+/// Default compression level, fast enough to keep up with live data.
+pub static DEFAULT_LEVEL: i32 = 3;
+
+/// Compresses what is written to it to `W`.
+pub struct Encoder<W: Write> {
+    /// Kept until finished.
+    inner: Option<zstd::stream::write::Encoder<'static, W>>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Compress at the default level.
+    pub fn new(out: W) -> io::Result<Encoder<W>> {
+        Encoder::with_level(out, DEFAULT_LEVEL)
+    }
+
+    /// Compress at `level`, from 1 (fastest) to 19 (smallest).
+    pub fn with_level(out: W, level: i32) -> io::Result<Encoder<W>> {
+        Ok(Encoder {
+            inner: Some(zstd::stream::write::Encoder::new(out, level)?),
+        })
+    }
+
+    fn inner(&mut self) -> &mut zstd::stream::write::Encoder<'static, W> {
+        self.inner.as_mut().expect("encoder is kept until finished")
+    }
+
+    /// End the frame and return the output. Dropping the encoder also ends
+    /// the frame, ignoring errors.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut out = self
+            .inner
+            .take()
+            .expect("encoder is kept until finished")
+            .finish()?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner().write(data)
+    }
+
+    /// Write out all the data written so far, so that it can be decompressed
+    /// even if the frame is never ended, at a small cost in compression.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        if let Some(inner) = &mut self.inner {
+            let _ = inner.do_finish();
+        }
+    }
+}
+
+/// Decompresses what is read from `R`.
+pub struct Decoder<R: BufRead> {
+    /// Only missing while starting over from a seek.
+    inner: Option<zstd::stream::read::Decoder<'static, R>>,
+    /// Number of bytes decompressed so far.
+    pos: u64,
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(input: R) -> io::Result<Decoder<R>> {
+        Ok(Decoder {
+            inner: Some(zstd::stream::read::Decoder::with_buffer(input)?),
+            pos: 0,
+        })
+    }
+
+    fn inner(&mut self) -> &mut zstd::stream::read::Decoder<'static, R> {
+        self.inner
+            .as_mut()
+            .expect("decoder is kept unless a seek failed")
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    /// Decompressed data. A truncated last frame, from a log still being
+    /// written, ends the data at the last point it was flushed.
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        match self.inner().read(data) {
+            Ok(n) => {
+                self.pos += n as u64;
+                Ok(n)
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<R: BufRead + Seek> Seek for Decoder<R> {
+    /// Seek in the decompressed data, by decompressing up to the position,
+    /// from the start if it is before the current one. Seeking from the end
+    /// is not supported.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "seek before the start")
+            })?,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "cannot seek from the end of compressed data",
+                ))
+            }
+        };
+        if target < self.pos {
+            let mut input = self
+                .inner
+                .take()
+                .expect("decoder is kept unless a seek failed")
+                .finish();
+            input.seek(SeekFrom::Start(0))?;
+            self.inner = Some(zstd::stream::read::Decoder::with_buffer(input)?);
+            self.pos = 0;
+        }
+        let skip = target - self.pos;
+        let skipped = io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "seek past the end of compressed data",
+            ));
+        }
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,238 @@
+//! Proxy behaviour against mock devices, see `tio::port::MockDevice`.
+//!
+//! Every test registers its mock under its own name, so that they can run
+//! in parallel.
+
+use std::thread;
+use std::time::{Duration, Instant};
+use twinleaf::tio::port::{MockDevice, MockReply, MockStep, RateInfo};
+use twinleaf::tio::proto::{self, DeviceRoute, HeartbeatPayload, Packet, Payload};
+use twinleaf::tio::proxy::{self, AutoRateStatus, LinkEvent, RpcError, SendError};
+
+static TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait until `done` holds, for at most `TIMEOUT`.
+fn wait_until(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+fn heartbeat(session: u32) -> Packet {
+    Packet {
+        payload: Payload::Heartbeat(HeartbeatPayload::Session(session)),
+        routing: DeviceRoute::root(),
+        ttl: 0,
+        received: None,
+    }
+}
+
+fn route(path: &str) -> DeviceRoute {
+    DeviceRoute::from_str(path).unwrap()
+}
+
+#[test]
+fn rate_negotiation() {
+    let rates = RateInfo {
+        default_bps: 115200,
+        target_bps: 2000000,
+    };
+    let mock = MockDevice::with_rates("rate_negotiation", Some(rates))
+        .rpc(
+            &DeviceRoute::root(),
+            "dev.port.rate.near",
+            &2000000u32.to_le_bytes(),
+        )
+        .rpc(
+            &DeviceRoute::root(),
+            "dev.port.rate",
+            &2000000u32.to_le_bytes(),
+        );
+    let proxy = proxy::Interface::builder()
+        .url("mock://rate_negotiation")
+        .spawn();
+    let port = proxy.tree_full().unwrap();
+    assert!(mock.wait_connections(1, TIMEOUT));
+
+    // Negotiation starts with the first session heartbeat.
+    mock.send(&heartbeat(1)).unwrap();
+    assert!(wait_until(|| mock.rates() == [2000000]));
+    assert_eq!(mock.rpc_count("dev.port.rate.near"), 1);
+    assert_eq!(mock.rpc_count("dev.port.rate"), 1);
+
+    // A packet at the new rate confirms it.
+    mock.send(&heartbeat(1)).unwrap();
+    assert!(wait_until(
+        || port.link_status().autorate == AutoRateStatus::Negotiated
+    ));
+    assert_eq!(port.link_status().rate_bps, Some(2000000));
+}
+
+#[test]
+fn rate_not_supported_by_device() {
+    let rates = RateInfo {
+        default_bps: 115200,
+        target_bps: 2000000,
+    };
+    let mock = MockDevice::with_rates("rate_not_supported", Some(rates)).rpc(
+        &DeviceRoute::root(),
+        "dev.port.rate.near",
+        &1000000u32.to_le_bytes(),
+    );
+    let proxy = proxy::Interface::builder()
+        .url("mock://rate_not_supported")
+        .spawn();
+    let port = proxy.tree_full().unwrap();
+    assert!(mock.wait_connections(1, TIMEOUT));
+
+    mock.send(&heartbeat(1)).unwrap();
+    assert!(wait_until(|| port.link_status().is_fallback()));
+    assert_eq!(mock.rpc_count("dev.port.rate"), 0);
+    assert!(mock.rates().is_empty());
+}
+
+#[test]
+fn silent_rpc_times_out() {
+    let mock = MockDevice::new("silent_rpc").rpc(&DeviceRoute::root(), "dev.name", b"mock");
+    mock.set_rpc(&DeviceRoute::root(), "dev.stuck", MockReply::Silent);
+    let proxy = proxy::Interface::builder().url("mock://silent_rpc").spawn();
+    let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+
+    let start = Instant::now();
+    let ret = port.raw_rpc_with_timeout("dev.stuck", &[], Duration::from_millis(200));
+    match ret {
+        Err(RpcError::ExecError(err)) => {
+            assert!(matches!(err.error, proto::RpcErrorCode::Timeout))
+        }
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(mock.rpc_count("dev.stuck"), 1);
+
+    // The port still works after the timeout.
+    let name: String = port.rpc("dev.name", ()).unwrap();
+    assert_eq!(name, "mock");
+}
+
+#[test]
+fn reconnect_replays_rpcs() {
+    let mock = MockDevice::new("reconnect_replay")
+        .rpc(&DeviceRoute::root(), "dev.name", b"mock")
+        .rpc(&DeviceRoute::root(), "data.rate", &[]);
+    let proxy = proxy::Interface::builder()
+        .url("mock://reconnect_replay")
+        .reconnect(TIMEOUT)
+        .spawn();
+    let port = proxy
+        .port()
+        .link_events()
+        .on_reconnect("data.rate", &200u32.to_le_bytes())
+        .open()
+        .unwrap();
+    assert!(mock.wait_connections(1, TIMEOUT));
+    assert_eq!(mock.rpc_count("data.rate"), 0);
+
+    mock.play(&[MockStep::Disconnect]).unwrap();
+    assert!(mock.wait_connections(2, TIMEOUT));
+    let events = port.link_events().unwrap();
+    assert_eq!(events.recv_timeout(TIMEOUT), Ok(LinkEvent::Disconnected));
+    assert_eq!(events.recv_timeout(TIMEOUT), Ok(LinkEvent::Reconnected));
+
+    assert!(wait_until(|| mock.rpc_count("data.rate") == 1));
+    let replayed = mock
+        .received()
+        .try_iter()
+        .find(|pkt| {
+            matches!(&pkt.payload, Payload::RpcRequest(req)
+            if matches!(&req.method, proto::RpcMethod::Name(name) if name == "data.rate"))
+        })
+        .expect("replayed request received");
+    match replayed.payload {
+        Payload::RpcRequest(req) => assert_eq!(req.arg, 200u32.to_le_bytes()),
+        _ => unreachable!(),
+    }
+
+    // The replies to the replayed RPCs are not sent to the port.
+    let name: String = port.rpc("dev.name", ()).unwrap();
+    assert_eq!(name, "mock");
+    assert!(port
+        .try_iter()
+        .all(|pkt| !matches!(pkt.payload, Payload::RpcReply(_))));
+}
+
+#[test]
+fn out_of_scope_packets_rejected() {
+    let mock = MockDevice::new("out_of_scope")
+        .rpc(&route("/1"), "dev.name", b"one")
+        .rpc(&route("/2"), "dev.name", b"two");
+    let proxy = proxy::Interface::builder()
+        .url("mock://out_of_scope")
+        .spawn();
+    let port = proxy.device_rpc(route("/1")).unwrap();
+    assert_eq!(port.scope(), &route("/1"));
+
+    // Routes are relative to the scope of the port.
+    let name: String = port.rpc("dev.name", ()).unwrap();
+    assert_eq!(name, "one");
+    let req = mock
+        .received()
+        .try_iter()
+        .find(|pkt| matches!(pkt.payload, Payload::RpcRequest(_)))
+        .expect("request received");
+    assert_eq!(req.routing, route("/1"));
+
+    // Nothing below the device is reachable through its port.
+    let pkt = heartbeat(1);
+    let out_of_scope = Packet {
+        routing: route("/0"),
+        ..pkt.clone()
+    };
+    match port.send(out_of_scope) {
+        Err(SendError::InvalidRoute(pkt)) => assert_eq!(pkt.routing, route("/0")),
+        other => panic!("expected an invalid route, got {:?}", other),
+    }
+    assert!(matches!(
+        port.device_port(route("/0")),
+        Err(proxy::PortError::InvalidRoute)
+    ));
+    assert!(port.send(pkt).is_ok());
+    thread::sleep(Duration::from_millis(100));
+    assert!(mock
+        .received()
+        .try_iter()
+        .all(|pkt| pkt.routing == route("/1")));
+    assert_eq!(mock.rpc_count("dev.name"), 1);
+}
+
+#[test]
+fn metadata_cache_cleared_on_reconnect() {
+    let mock =
+        MockDevice::new("metadata_cache").rpc(&DeviceRoute::root(), "dev.metadata", b"\x01\x03old");
+    let proxy = proxy::Interface::builder()
+        .url("mock://metadata_cache")
+        .reconnect(TIMEOUT)
+        .spawn();
+    let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+    assert!(mock.wait_connections(1, TIMEOUT));
+    mock.send(&heartbeat(1)).unwrap();
+    // The heartbeat reaches the proxy ahead of the reply.
+    assert_eq!(port.raw_rpc("dev.metadata", &[]).unwrap(), b"\x01\x03old");
+    assert_eq!(port.raw_rpc("dev.metadata", &[]).unwrap(), b"\x01\x03old");
+    assert_eq!(mock.rpc_count("dev.metadata"), 1);
+
+    // Another device, with the same session, is found after reconnecting.
+    mock.set_rpc(
+        &DeviceRoute::root(),
+        "dev.metadata",
+        MockReply::Value(b"\x01\x03new".to_vec()),
+    );
+    mock.play(&[MockStep::Disconnect]).unwrap();
+    assert!(mock.wait_connections(2, TIMEOUT));
+    assert!(wait_until(|| port.link_status().connected));
+    assert_eq!(port.raw_rpc("dev.metadata", &[]).unwrap(), b"\x01\x03new");
+}